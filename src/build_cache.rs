@@ -0,0 +1,298 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::{Buf, BytesMut};
+use futures_util::stream::TryStreamExt;
+use log::{info, warn};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{BytesCodec, FramedRead};
+use walkdir::WalkDir;
+use warp::{
+    http::{self, StatusCode},
+    hyper::{Body, Response},
+    path::Tail,
+    Filter, Rejection, Stream,
+};
+
+use crate::serve::ServeError;
+
+/// A content-addressed blob store for compiler caches, served at /build-cache/.
+///
+/// It speaks the subset of WebDAV that sccache's webdav backend uses (GET, HEAD, PUT and
+/// MKCOL), so CI on the offline network can share compiled artifacts through the same host as
+/// the mirror. Every request needs the bearer token, and the oldest blobs are removed once
+/// the cache grows past its size limit.
+pub struct BuildCache {
+    dir: PathBuf,
+    token: String,
+    max_size: u64,
+    /// Total size of the blobs in the cache, in bytes.
+    used: Mutex<u64>,
+    /// Whether old blobs are being removed, so uploads finishing meanwhile don't start another.
+    evicting: AtomicBool,
+    /// Counter for naming partial uploads.
+    uploads: AtomicU64,
+}
+
+impl BuildCache {
+    pub fn new(dir: PathBuf, token: String, max_size: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let used = blobs(&dir).map(|(_, size, _)| size).sum();
+        Ok(BuildCache {
+            dir,
+            token,
+            max_size,
+            used: Mutex::new(used),
+            evicting: AtomicBool::new(false),
+            uploads: AtomicU64::new(0),
+        })
+    }
+
+    async fn handle<S, B>(
+        &self,
+        tail: Tail,
+        method: http::Method,
+        authorization: Option<String>,
+        body: S,
+    ) -> Result<Response<Body>, ServeError>
+    where
+        S: Stream<Item = Result<B, warp::Error>> + Unpin,
+        B: Buf,
+    {
+        let token = authorization
+            .as_deref()
+            .and_then(|a| a.strip_prefix("Bearer "));
+        if !token.is_some_and(|t| constant_time_eq(t, &self.token)) {
+            return status(StatusCode::UNAUTHORIZED);
+        }
+        let Some(path) = blob_path(&self.dir, tail.as_str()) else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+
+        match method.as_str() {
+            "GET" | "HEAD" => {
+                let Ok(file) = File::open(&path).await else {
+                    return status(StatusCode::NOT_FOUND);
+                };
+                let meta = file.metadata().await?;
+                if !meta.is_file() {
+                    return status(StatusCode::NOT_FOUND);
+                }
+                let mut resp = if method == http::Method::HEAD {
+                    Response::new(Body::empty())
+                } else {
+                    let stream = FramedRead::new(file, BytesCodec::new()).map_ok(BytesMut::freeze);
+                    Response::new(Body::wrap_stream(stream))
+                };
+                resp.headers_mut()
+                    .insert(http::header::CONTENT_LENGTH, meta.len().into());
+                Ok(resp)
+            }
+            "PUT" => self.put(&path, body).await,
+            // Directories are created as blobs are written, so there's nothing to do.
+            "MKCOL" => status(StatusCode::CREATED),
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    /// Store an uploaded blob, replacing any blob already at `path`.
+    async fn put<S, B>(&self, path: &Path, mut body: S) -> Result<Response<Body>, ServeError>
+    where
+        S: Stream<Item = Result<B, warp::Error>> + Unpin,
+        B: Buf,
+    {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // Blobs are written to a partial file first, so a reader never sees half a blob.
+        let upload = self.uploads.fetch_add(1, Ordering::Relaxed);
+        let part_path = PathBuf::from(format!("{}.part-{upload}", path.display()));
+        let mut size = 0u64;
+        let mut file = File::create(&part_path).await?;
+        while let Some(chunk) = body.next().await {
+            let Ok(mut chunk) = chunk else {
+                drop(file);
+                fs::remove_file(&part_path).await?;
+                return status(StatusCode::BAD_REQUEST);
+            };
+            size += chunk.remaining() as u64;
+            if size > self.max_size {
+                drop(file);
+                fs::remove_file(&part_path).await?;
+                return status(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            file.write_all_buf(&mut chunk).await?;
+        }
+        file.flush().await?;
+        drop(file);
+
+        let mut used = self.used.lock().await;
+        let replaced = fs::metadata(path).await.map_or(0, |m| m.len());
+        fs::rename(&part_path, path).await?;
+        *used = (*used + size).saturating_sub(replaced);
+        let over = (*used > self.max_size).then_some(*used);
+        drop(used);
+
+        if let Some(used) = over {
+            self.evict(used).await;
+        }
+
+        status(StatusCode::CREATED)
+    }
+
+    /// Remove old blobs from a cache that has grown to `used` bytes. The cache directory is
+    /// walked on a blocking thread, and other uploads can finish while it is.
+    async fn evict(&self, used: u64) {
+        if self.evicting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let dir = self.dir.clone();
+        let max_size = self.max_size;
+        match tokio::task::spawn_blocking(move || evict(&dir, used, max_size)).await {
+            Ok(removed) => {
+                let mut used = self.used.lock().await;
+                *used = used.saturating_sub(removed);
+            }
+            Err(e) => warn!("Removing old blobs from the build cache failed: {e}"),
+        }
+        self.evicting.store(false, Ordering::Release);
+    }
+}
+
+/// Routes for the build cache, if it's enabled.
+pub fn routes(
+    cache: Option<Arc<BuildCache>>,
+) -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    warp::path("build-cache")
+        .and(warp::path::tail())
+        .and(warp::method())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::stream())
+        .and_then(move |tail, method, authorization, body| {
+            let cache = cache.clone();
+            async move {
+                let Some(cache) = cache else {
+                    return Err(warp::reject::not_found());
+                };
+                cache
+                    .handle(tail, method, authorization, body)
+                    .await
+                    .map_err(warp::reject::custom)
+            }
+        })
+}
+
+fn status(status: StatusCode) -> Result<Response<Body>, ServeError> {
+    Ok(Response::builder().status(status).body(Body::empty())?)
+}
+
+/// Get the path of a blob from its key, e.g. "a/b/c/abc123". Returns None for keys that
+/// could escape the cache directory.
+fn blob_path(dir: &Path, key: &str) -> Option<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for segment in key.trim_end_matches('/').split('/') {
+        let valid = !segment.is_empty()
+            && segment != "."
+            && segment != ".."
+            && !segment.contains(".part-")
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+        if !valid {
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+/// Every blob in the cache, as (modified time, size, path).
+fn blobs(dir: &Path) -> impl Iterator<Item = (SystemTime, u64, PathBuf)> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            Some((meta.modified().ok()?, meta.len(), e.into_path()))
+        })
+}
+
+/// Remove the oldest blobs until a cache of `used` bytes is back under 90% of `max_size`, so
+/// this doesn't run again for every upload. Returns the number of bytes removed.
+fn evict(dir: &Path, used: u64, max_size: u64) -> u64 {
+    let target = max_size / 10 * 9;
+    let mut blobs: Vec<_> = blobs(dir).collect();
+    blobs.sort();
+
+    let mut removed = 0usize;
+    let mut removed_bytes = 0u64;
+    for (_, size, path) in blobs {
+        if used.saturating_sub(removed_bytes) <= target {
+            break;
+        }
+        // Partial uploads are still being written.
+        if path.to_string_lossy().contains(".part-") {
+            continue;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                removed_bytes += size;
+                removed += 1;
+            }
+            Err(e) => warn!(
+                "Could not remove {} from the build cache: {e}",
+                path.display()
+            ),
+        }
+    }
+    info!("Removed {removed} old blob(s) from the build cache");
+    removed_bytes
+}
+
+/// Compare tokens without leaking how much of them matched through timing.
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod test {
+
+    mod blob_path {
+        use std::path::Path;
+
+        use crate::build_cache::blob_path;
+
+        #[test]
+        fn valid() {
+            let dir = Path::new("/cache");
+            assert_eq!(
+                blob_path(dir, "a/b/c/abc123").unwrap(),
+                Path::new("/cache/a/b/c/abc123")
+            );
+            assert_eq!(blob_path(dir, "a/b/").unwrap(), Path::new("/cache/a/b"));
+            assert!(blob_path(dir, ".sccache_check").is_some());
+        }
+
+        #[test]
+        fn invalid() {
+            let dir = Path::new("/cache");
+            assert!(blob_path(dir, "").is_none());
+            assert!(blob_path(dir, "a/../b").is_none());
+            assert!(blob_path(dir, "a//b").is_none());
+            assert!(blob_path(dir, "a/b%2Fc").is_none());
+            assert!(blob_path(dir, "abc.part-1").is_none());
+        }
+    }
+}
@@ -0,0 +1,1125 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::checksums::{self, CHECKSUMS_FILE};
+use crate::crates_index::rewrite_config_json;
+use crate::download::{append_to_path, file_hash, DownloadError};
+use crate::hashes::{manifest_hash, HashAlgorithm, Hasher};
+use crate::mirror::{load_mirror_toml, MirrorError};
+use crate::progress_bar::{padded_prefix_message, progress_bar, progress_bar_bytes, StepsProgress};
+use crate::replica::is_channel_manifest;
+use crate::rng::Xorshift;
+use crate::snapshot::{content_files, import_path, is_snapshot_state_file};
+use crate::staging;
+use crate::state;
+use crate::storage;
+use crate::timestamp::Timestamp;
+
+/// Name of the manifest, which is the first file in a bundle.
+const MANIFEST_FILE: &str = "panamax-bundle.json";
+
+/// Version of the bundle format. Bundles of a newer version are refused.
+const BUNDLE_VERSION: u32 = 1;
+
+/// Name of the state file recording the bundles exported from and imported into a mirror.
+pub const BUNDLES_FILE: &str = ".panamax-bundles";
+
+/// Suffix of the manifest of a bundle split into parts, which is written next to them.
+const PARTS_SUFFIX: &str = ".parts.json";
+
+/// Start of a file encrypted with age.
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+/// zstd compression level. Most of a mirror is compressed already, so higher levels gain little
+/// and slow the export down.
+const ZSTD_LEVEL: i32 = 3;
+
+/// What a bundle holds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Unique id of the bundle, which a delta made after it refers to.
+    pub id: String,
+    pub created: Timestamp,
+    /// Algorithm of the hashes of the files, as set by manifest_hash.
+    pub algorithm: HashAlgorithm,
+    /// For a delta, the id of the bundle it has the changes since, which has to have been
+    /// imported before it.
+    #[serde(default)]
+    pub base: Option<String>,
+    /// Every file in the bundle, by its path relative to the mirror root. State files are at
+    /// the top, as in a snapshot.
+    pub files: BTreeMap<String, BundleFile>,
+    /// For a delta, the files that were removed from the mirror since its base.
+    #[serde(default)]
+    pub removed: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundleFile {
+    pub hash: String,
+    pub size: u64,
+}
+
+/// The parts a bundle was split into, so import can check they're all there and intact before
+/// reading any of them.
+#[derive(Debug, Serialize, Deserialize)]
+struct Parts {
+    algorithm: HashAlgorithm,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Part {
+    /// File name of the part, in the same directory as the parts manifest.
+    name: String,
+    size: u64,
+    hash: String,
+}
+
+/// The bundles exported from and imported into a mirror.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Bundles {
+    /// The last bundle exported from the mirror, which the next delta is made from.
+    exported: Option<ExportRecord>,
+    /// Id of the last bundle imported into the mirror, which the next delta has to be made from.
+    imported: Option<String>,
+}
+
+/// A bundle that was exported, with the hash of every file the mirror had when it was, full or
+/// delta. Deltas are made by comparing hashes with these, so files that were only touched, or
+/// restored from a backup, aren't exported again.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportRecord {
+    id: String,
+    algorithm: HashAlgorithm,
+    files: BTreeMap<String, String>,
+}
+
+impl Bundles {
+    fn load(mirror_path: &Path) -> Result<Self, MirrorError> {
+        let path = state::path(mirror_path, BUNDLES_FILE);
+        match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                MirrorError::Bundle(format!("Could not read {}: {e}", path.display()))
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Bundles::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, mirror_path: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec(self).map_err(io::Error::from)?;
+        let path = state::path(mirror_path, BUNDLES_FILE);
+        let part_path = append_to_path(&path, ".part");
+        fs::write(&part_path, data)?;
+        fs::rename(part_path, path)
+    }
+}
+
+/// What exporting a bundle wrote.
+#[derive(Debug, Default)]
+pub struct Exported {
+    pub files: usize,
+    pub bytes: u64,
+    /// Removals listed in a delta.
+    pub removed: usize,
+}
+
+/// What importing a bundle did.
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub added: usize,
+    /// Files the mirror already had, which were left alone.
+    pub present: usize,
+    /// Files removed, as listed by a delta.
+    pub removed: usize,
+}
+
+/// Reads from `inner`, hashing and counting what it reads.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Box<dyn Hasher>,
+    len: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R, algorithm: HashAlgorithm) -> Self {
+        HashingReader {
+            inner,
+            hasher: algorithm.hasher(),
+            len: 0,
+        }
+    }
+
+    /// Get the length and hash of everything read.
+    fn finish(self) -> (u64, String) {
+        (self.len, self.hasher.finish())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.write_all(&buf[..n])?;
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Whether a bundle is to be written to stdout or read from stdin, given as `-`.
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Parse a size like `25GB`, `4.7GB` or `700MiB` into bytes. A plain number is in bytes.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1_000,
+        "M" | "MB" => 1_000_000,
+        "G" | "GB" => 1_000_000_000,
+        "T" | "TB" => 1_000_000_000_000,
+        "KIB" => 1 << 10,
+        "MIB" => 1 << 20,
+        "GIB" => 1 << 30,
+        "TIB" => 1 << 40,
+        _ => return Err(format!("unknown unit in {size}, e.g. use 25GB or 700MiB")),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{size} isn't a size, e.g. 25GB or 700MiB"))?;
+    let bytes = (number * multiplier as f64) as u64;
+    if bytes == 0 {
+        return Err("the size has to be more than 0 bytes".to_string());
+    }
+    Ok(bytes)
+}
+
+/// Writes a bundle to numbered parts of at most `split_size` bytes each, `<dest>.001`,
+/// `<dest>.002` and so on, then a manifest of them to `<dest>.parts.json`.
+struct SplitWriter {
+    dest: PathBuf,
+    split_size: u64,
+    algorithm: HashAlgorithm,
+    /// The part being written, its hasher, and how much has been written to it.
+    current: Option<(BufWriter<File>, Box<dyn Hasher>, u64)>,
+    parts: Vec<Part>,
+}
+
+impl SplitWriter {
+    fn new(dest: &Path, split_size: u64, algorithm: HashAlgorithm) -> Self {
+        SplitWriter {
+            dest: dest.to_path_buf(),
+            split_size,
+            algorithm,
+            current: None,
+            parts: Vec::new(),
+        }
+    }
+
+    fn part_path(&self, number: usize) -> PathBuf {
+        append_to_path(&self.dest, &format!(".{number:03}"))
+    }
+
+    fn finish_part(&mut self) -> io::Result<()> {
+        let Some((file, hasher, size)) = self.current.take() else {
+            return Ok(());
+        };
+        // Removable media may be unplugged as soon as the export is done.
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        let path = self.part_path(self.parts.len() + 1);
+        self.parts.push(Part {
+            name: path
+                .file_name()
+                .expect("parts are named after a file")
+                .to_string_lossy()
+                .into_owned(),
+            size,
+            hash: hasher.finish(),
+        });
+        Ok(())
+    }
+
+    /// Finish the last part, and write the manifest of the parts.
+    fn finish(mut self) -> io::Result<()> {
+        self.finish_part()?;
+        let parts = Parts {
+            algorithm: self.algorithm,
+            parts: std::mem::take(&mut self.parts),
+        };
+        let data = serde_json::to_vec_pretty(&parts).map_err(io::Error::from)?;
+        let path = append_to_path(&self.dest, PARTS_SUFFIX);
+        fs::write(&path, data)?;
+        File::open(path)?.sync_all()
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(_, _, size)| *size >= self.split_size)
+        {
+            self.finish_part()?;
+        }
+        if self.current.is_none() {
+            let file = File::create(self.part_path(self.parts.len() + 1))?;
+            self.current = Some((BufWriter::new(file), self.algorithm.hasher(), 0));
+        }
+        let (file, hasher, size) = self.current.as_mut().expect("opened above");
+        let n = buf.len().min((self.split_size - *size) as usize);
+        file.write_all(&buf[..n])?;
+        hasher.write_all(&buf[..n])?;
+        *size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some((file, _, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where a bundle is written to.
+enum Output {
+    Stdout(io::StdoutLock<'static>),
+    File(File),
+    Split(SplitWriter),
+}
+
+impl Output {
+    /// Make sure everything written is on disk.
+    fn close(self) -> io::Result<()> {
+        match self {
+            Output::Stdout(mut stdout) => stdout.flush(),
+            Output::File(file) => file.sync_all(),
+            Output::Split(split) => split.finish(),
+        }
+    }
+}
+
+/// The compressed bundle, on its way to the output, encrypted if it's to be.
+enum Sink {
+    Plain(BufWriter<Output>),
+    Encrypted(age::stream::StreamWriter<BufWriter<Output>>),
+}
+
+impl Sink {
+    fn finish(self) -> io::Result<Output> {
+        let out = match self {
+            Sink::Plain(out) => out,
+            Sink::Encrypted(writer) => writer.finish()?,
+        };
+        out.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(out) => out.write(buf),
+            Sink::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(out) => out.flush(),
+            Sink::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(stdout) => stdout.write(buf),
+            Output::File(file) => file.write(buf),
+            Output::Split(split) => split.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.flush(),
+            Output::Split(split) => split.flush(),
+        }
+    }
+}
+
+fn tar_header(size: u64, created: Timestamp) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(created.0);
+    header
+}
+
+/// The files of a mirror that go in a bundle, as (file, path) pairs: its content, as in a
+/// snapshot, and the state files a snapshot has.
+fn bundle_sources(mirror_path: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut sources: Vec<_> = content_files(mirror_path)?
+        .into_iter()
+        .map(|file| {
+            let path = mirror_path.join(&file);
+            (file, path)
+        })
+        .collect();
+    let state_dir = state::dir(mirror_path);
+    for name in state::files(&state_dir)? {
+        if is_snapshot_state_file(&name) {
+            let path = state_dir.join(&name);
+            sources.push((name, path));
+        }
+    }
+    Ok(sources)
+}
+
+/// Write a mirror's content to a zstd-compressed tar bundle at `dest`, which can be a file, a
+/// block device, or `-` for stdout, e.g. to pipe it through ssh. With `split_size`, the bundle
+/// is split into numbered parts of that size instead, to fit on several discs or drives. With
+/// `encrypt_to`, it's encrypted with age to those recipients, after compressing it.
+///
+/// With `delta`, the bundle only has the files whose hashes have changed since the last export,
+/// along with a list of the files removed since, for a mirror that imported that export.
+///
+/// Files are hashed first, for the manifest at the start of the bundle, then streamed into it
+/// one at a time, so no space is needed for a copy of the bundle. Each is hashed again as it's
+/// written, and the export fails if one changed in between. This shouldn't be run while a sync
+/// is in progress.
+pub async fn export(
+    mirror_path: &Path,
+    dest: &Path,
+    split_size: Option<u64>,
+    encrypt_to: &[String],
+    delta: bool,
+) -> Result<Exported, MirrorError> {
+    if split_size.is_some() && is_stdio(dest) {
+        return Err(MirrorError::CmdLine(
+            "A bundle written to stdout can't be split.".to_string(),
+        ));
+    }
+    let recipients = encrypt_to
+        .iter()
+        .map(|recipient| {
+            recipient.parse::<age::x25519::Recipient>().map_err(|e| {
+                MirrorError::CmdLine(format!("{recipient} isn't an age recipient: {e}"))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let algorithm = manifest_hash();
+    let mut bundles = Bundles::load(mirror_path)?;
+    let previous =
+        match bundles.exported.take() {
+            _ if !delta => None,
+            Some(previous) if previous.algorithm == algorithm => Some(previous),
+            Some(_) => return Err(MirrorError::CmdLine(
+                "manifest_hash has changed since the last export, so there's no delta from it. \
+                 Export without --delta."
+                    .to_string(),
+            )),
+            None => {
+                return Err(MirrorError::CmdLine(
+                    "No export to make a delta from. Export without --delta first.".to_string(),
+                ))
+            }
+        };
+    let sources = bundle_sources(mirror_path)?;
+    let created = Timestamp::now();
+    let mut manifest = Manifest {
+        version: BUNDLE_VERSION,
+        id: format!("{}-{:016x}", created.0, Xorshift::from_time().next_u64()),
+        created,
+        algorithm,
+        base: previous.as_ref().map(|previous| previous.id.clone()),
+        files: BTreeMap::new(),
+        removed: Vec::new(),
+    };
+    let mut hashes = BTreeMap::new();
+
+    let progress = StepsProgress::new(2, "Exporting mirror");
+    let pb = progress_bar(sources.len(), padded_prefix_message(1, 2, "Hashing mirror"));
+    for (file, path) in &sources {
+        let size = fs::metadata(path)?.len();
+        // State files are at the top, and aren't kept in the checksum database, as syncs
+        // append to them.
+        let hash = if file.contains('/') {
+            checksums::hash(path, algorithm).await?
+        } else {
+            file_hash(path, algorithm).await?
+        };
+        let unchanged = previous
+            .as_ref()
+            .is_some_and(|previous| previous.files.get(file) == Some(&hash));
+        if !unchanged {
+            manifest.files.insert(
+                file.clone(),
+                BundleFile {
+                    hash: hash.clone(),
+                    size,
+                },
+            );
+        }
+        hashes.insert(file.clone(), hash);
+        pb.inc(1);
+    }
+    pb.finish();
+    progress.step_done();
+    if let Some(previous) = &previous {
+        manifest.removed = previous
+            .files
+            .keys()
+            .filter(|file| !hashes.contains_key(*file))
+            .cloned()
+            .collect();
+    }
+
+    let out = match split_size {
+        _ if is_stdio(dest) => Output::Stdout(io::stdout().lock()),
+        Some(split_size) => Output::Split(SplitWriter::new(dest, split_size, algorithm)),
+        None => Output::File(File::create(dest)?),
+    };
+    let out = BufWriter::new(out);
+    let sink = if recipients.is_empty() {
+        Sink::Plain(out)
+    } else {
+        let encryptor =
+            age::Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+                .map_err(|e| MirrorError::Bundle(format!("Could not encrypt the bundle: {e}")))?;
+        Sink::Encrypted(encryptor.wrap_output(out)?)
+    };
+    let mut tar = tar::Builder::new(zstd::Encoder::new(sink, ZSTD_LEVEL)?);
+    let data = serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?;
+    tar.append_data(
+        &mut tar_header(data.len() as u64, manifest.created),
+        MANIFEST_FILE,
+        data.as_slice(),
+    )?;
+
+    let mut exported = Exported::default();
+    let total = manifest.files.values().map(|f| f.size).sum();
+    let pb = progress_bar_bytes(total, padded_prefix_message(2, 2, "Writing bundle"));
+    for (file, path) in &sources {
+        let Some(expected) = manifest.files.get(file) else {
+            continue;
+        };
+        let mut reader = HashingReader::new(File::open(path)?.take(expected.size), algorithm);
+        tar.append_data(
+            &mut tar_header(expected.size, manifest.created),
+            file,
+            &mut reader,
+        )?;
+        if reader.finish() != (expected.size, expected.hash.clone()) {
+            return Err(MirrorError::Bundle(format!(
+                "{file} changed while it was being exported. Don't export a mirror while it's \
+                 syncing."
+            )));
+        }
+        exported.files += 1;
+        exported.bytes += expected.size;
+        pb.inc(expected.size);
+    }
+    pb.finish();
+    progress.step_done();
+
+    // Removable media may be unplugged as soon as this returns, so it's all synced first.
+    tar.into_inner()?.finish()?.finish()?.close()?;
+    exported.removed = manifest.removed.len();
+
+    bundles.exported = Some(ExportRecord {
+        id: manifest.id,
+        algorithm,
+        files: hashes,
+    });
+    bundles.save(mirror_path)?;
+    Ok(exported)
+}
+
+/// Decrypt a bundle if it was encrypted with age, with the identities in `identity_files`.
+fn decrypt(
+    mut input: BufReader<Box<dyn Read>>,
+    identity_files: &[PathBuf],
+    src: &Path,
+) -> Result<Box<dyn Read>, MirrorError> {
+    if !input.fill_buf()?.starts_with(AGE_MAGIC) {
+        return Ok(Box::new(input));
+    }
+    if identity_files.is_empty() {
+        return Err(MirrorError::Bundle(format!(
+            "{} is encrypted. Give the identity file of one of its recipients with --identity.",
+            src.display()
+        )));
+    }
+    let mut identities = Vec::new();
+    for path in identity_files {
+        let file = age::IdentityFile::from_file(path.to_string_lossy().into_owned())?;
+        identities.extend(
+            file.into_identities().map_err(|e| {
+                MirrorError::Bundle(format!("Could not read {}: {e}", path.display()))
+            })?,
+        );
+    }
+    let decrypt_error = |e: age::DecryptError| {
+        MirrorError::Bundle(format!("Could not decrypt {}: {e}", src.display()))
+    };
+    let reader = age::Decryptor::new(input)
+        .map_err(decrypt_error)?
+        .decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        .map_err(decrypt_error)?;
+    Ok(Box::new(reader))
+}
+
+/// Check that all the parts of a split bundle are there and intact, and open them as one.
+async fn open_parts(src: &Path, parts_path: &Path) -> Result<Box<dyn Read>, MirrorError> {
+    let parts: Parts = serde_json::from_slice(&fs::read(parts_path)?).map_err(|e| {
+        MirrorError::Bundle(format!("Could not read {}: {e}", parts_path.display()))
+    })?;
+    let dir = parts_path.parent().unwrap_or(Path::new("."));
+    let pb = progress_bar_bytes(
+        parts.parts.iter().map(|part| part.size).sum(),
+        padded_prefix_message(1, 2, "Checking parts"),
+    );
+    let mut input: Box<dyn Read> = Box::new(io::empty());
+    for part in &parts.parts {
+        let path = dir.join(&part.name);
+        let size = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(MirrorError::Bundle(format!(
+                    "{} is missing part {}",
+                    src.display(),
+                    part.name
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if size != part.size || file_hash(&path, parts.algorithm).await? != part.hash {
+            return Err(MirrorError::Bundle(format!(
+                "Part {} of {} is damaged or incomplete",
+                part.name,
+                src.display()
+            )));
+        }
+        input = Box::new(input.chain(File::open(path)?));
+        pb.inc(part.size);
+    }
+    pb.finish();
+    Ok(input)
+}
+
+/// Add the files of a bundle made by `export`, read from `src` or from stdin for `-`, to a
+/// mirror. For a bundle split into parts, `src` is the name given to `export`, and every part
+/// is checked against the parts manifest before any is read.
+///
+/// An encrypted bundle is decrypted with the identities in `identity_files`. A delta is only
+/// imported into a mirror whose last import was the bundle it was made from, and the files it
+/// lists as removed are removed.
+///
+/// Every file is checked against the manifest, and staged, before any is moved into place, so a
+/// damaged or truncated bundle changes nothing. Files the mirror already has with the same hash
+/// are left alone. Then each part of the mirror is updated in turn, in `apply_order`, and the
+/// files a delta removes are removed last. The index's config.json is rewritten for this
+/// mirror's base_url, as the bundle has the exporting mirror's. With `dry_run`, the bundle is
+/// only checked, and what importing it would do is returned.
+pub async fn import(
+    mirror_path: &Path,
+    src: &Path,
+    identity_files: &[PathBuf],
+    dry_run: bool,
+) -> Result<Imported, MirrorError> {
+    let parts_path = append_to_path(src, PARTS_SUFFIX);
+    let split = !is_stdio(src) && parts_path.exists();
+    let steps = match (split, dry_run) {
+        (true, true) | (false, false) => 2,
+        (true, false) => 3,
+        (false, true) => 1,
+    };
+    let progress = StepsProgress::new(steps, "Importing bundle");
+    let input: Box<dyn Read> = if is_stdio(src) {
+        Box::new(io::stdin().lock())
+    } else if split {
+        let input = open_parts(src, &parts_path).await?;
+        progress.step_done();
+        input
+    } else {
+        Box::new(File::open(src)?)
+    };
+    let input = decrypt(BufReader::new(input), identity_files, src)?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(input)?);
+    let mut entries = archive.entries()?;
+
+    let manifest: Manifest = match entries.next() {
+        Some(entry) => {
+            let entry = entry?;
+            if entry.path()? != Path::new(MANIFEST_FILE) {
+                return Err(MirrorError::Bundle(format!(
+                    "{} doesn't start with a {MANIFEST_FILE}",
+                    src.display()
+                )));
+            }
+            serde_json::from_reader(entry)
+                .map_err(|e| MirrorError::Bundle(format!("Could not read {MANIFEST_FILE}: {e}")))?
+        }
+        None => return Err(MirrorError::Bundle(format!("{} is empty", src.display()))),
+    };
+    if manifest.version > BUNDLE_VERSION {
+        return Err(MirrorError::Bundle(format!(
+            "{} was made by a newer version of panamax",
+            src.display()
+        )));
+    }
+    if let Some(file) = manifest
+        .files
+        .keys()
+        .chain(&manifest.removed)
+        .find(|file| import_path(mirror_path, file).is_none())
+    {
+        return Err(MirrorError::Bundle(format!(
+            "{} lists {file}, which isn't part of a mirror",
+            src.display()
+        )));
+    }
+    let mut bundles = Bundles::load(mirror_path)?;
+    if let Some(base) = &manifest.base {
+        if bundles.imported.as_ref() != Some(base) {
+            return Err(MirrorError::Bundle(format!(
+                "{} has the changes since bundle {base}, but the last bundle imported into this \
+                 mirror was {}. Import the bundles in between first, or a full export.",
+                src.display(),
+                bundles.imported.as_deref().unwrap_or("none")
+            )));
+        }
+    }
+
+    let mut imported = Imported::default();
+    let mut read = HashSet::new();
+    let mut staged = Staged(Vec::new());
+    let pb = progress_bar_bytes(
+        manifest.files.values().map(|f| f.size).sum(),
+        padded_prefix_message(if split { 2 } else { 1 }, steps, "Checking files"),
+    );
+    for entry in entries {
+        let mut entry = entry?;
+        let file = entry.path()?.to_string_lossy().into_owned();
+        let Some(expected) = manifest.files.get(&file) else {
+            return Err(MirrorError::Bundle(format!(
+                "{} has {file}, which its manifest doesn't list",
+                src.display()
+            )));
+        };
+        read.insert(file.clone());
+        pb.inc(expected.size);
+        let path = import_path(mirror_path, &file).expect("checked against the manifest");
+
+        if is_mirrored(&file)
+            && path.exists()
+            && checksums::hash(&path, manifest.algorithm).await? == expected.hash
+        {
+            imported.present += 1;
+            continue;
+        }
+
+        let mut reader = HashingReader::new(&mut entry, manifest.algorithm);
+        if dry_run {
+            io::copy(&mut reader, &mut io::sink())?;
+        } else {
+            let part_path = staging::part_path(&path, ".import.part");
+            if let Some(parent) = part_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            staged.0.push((file.clone(), part_path.clone(), path));
+            io::copy(&mut reader, &mut File::create(&part_path)?)?;
+        }
+        if reader.finish() != (expected.size, expected.hash.clone()) {
+            return Err(MirrorError::Bundle(format!(
+                "{file} in {} doesn't match its hash. The bundle is damaged.",
+                src.display()
+            )));
+        }
+        imported.added += 1;
+    }
+    pb.finish();
+    progress.step_done();
+
+    let missing = manifest.files.len() - read.len();
+    if missing > 0 {
+        return Err(MirrorError::Bundle(format!(
+            "{} is missing {missing} of the files its manifest lists. It may have been cut \
+             short.",
+            src.display()
+        )));
+    }
+    let removed: Vec<PathBuf> = manifest
+        .removed
+        .iter()
+        .map(|file| import_path(mirror_path, file).expect("checked against the manifest"))
+        .filter(|path| path.exists())
+        .collect();
+    if dry_run {
+        imported.removed = removed.len();
+        return Ok(imported);
+    }
+
+    // Popped from the end, so in apply_order.
+    staged
+        .0
+        .sort_by_key(|(file, _, _)| Reverse(apply_order(file)));
+    let pb = progress_bar(
+        staged.0.len() + removed.len(),
+        padded_prefix_message(steps, steps, "Applying bundle"),
+    );
+    while let Some((file, part_path, path)) = staged.0.pop() {
+        if file == CHECKSUMS_FILE {
+            merge_checksums(mirror_path, &part_path)?;
+            fs::remove_file(&part_path)?;
+        } else {
+            storage::get().rename(&part_path, &path)?;
+            if is_mirrored(&file) {
+                let hash = &manifest.files[&file].hash;
+                checksums::record(&path, manifest.algorithm, hash)?;
+            }
+        }
+        pb.inc(1);
+    }
+    for path in &removed {
+        match fs::remove_file(path) {
+            Ok(()) => {
+                checksums::forget(path)?;
+                imported.removed += 1;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        pb.inc(1);
+    }
+    pb.finish();
+    progress.step_done();
+    bundles.imported = Some(manifest.id);
+    bundles.save(mirror_path)?;
+
+    let index_path = mirror_path.join("crates.io-index");
+    let has_index = read.iter().any(|f| f.starts_with("crates.io-index/"));
+    if has_index && mirror_path.join("mirror.toml").exists() {
+        if let Some(crates) = load_mirror_toml(mirror_path)?.crates {
+            if let Some(base_url) = &crates.base_url {
+                rewrite_config_json(
+                    &index_path,
+                    base_url,
+                    crates.dl_template.as_deref(),
+                    crates.omit_api.unwrap_or(false),
+                )?;
+            }
+        }
+    }
+
+    Ok(imported)
+}
+
+/// Files of a bundle that were checked and written to the staging directory, as (file, part
+/// path, path), to be moved into place once all of them have been. Any still staged when this
+/// is dropped, such as when a later file fails its check, are removed.
+struct Staged(Vec<(String, PathBuf, PathBuf)>);
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        for (_, part_path, _) in &self.0 {
+            let _ = fs::remove_file(part_path);
+        }
+    }
+}
+
+/// Whether a file of a bundle is a mirrored file, whose hash is kept in the checksum database,
+/// rather than one of the index's, which git changes in place, or a state file, which are at
+/// the top.
+fn is_mirrored(file: &str) -> bool {
+    file.contains('/') && !file.starts_with("crates.io-index/")
+}
+
+/// The order the files of a bundle are moved into place in, one part of the mirror after
+/// another: release files and crates first, then the channel manifests that list them, then
+/// the state files, and the index last, its git objects before the refs pointing to them, so
+/// nothing served ever refers to a file the mirror doesn't have yet.
+fn apply_order(file: &str) -> u8 {
+    match file.strip_prefix("crates.io-index/") {
+        Some(index_file) if index_file.starts_with(".git/objects/") => 3,
+        Some(_) => 4,
+        None if !file.contains('/') => 2,
+        None if is_channel_manifest(file) => 1,
+        None => 0,
+    }
+}
+
+/// Add the hashes first seen by the exporting mirror to this mirror's checksum database. Hashes
+/// this mirror has already seen are kept, with a warning if they differ.
+fn merge_checksums(mirror_path: &Path, db_path: &Path) -> Result<(), MirrorError> {
+    for (path, hash) in checksums::load_file(db_path)? {
+        match checksums::check(mirror_path, &path, &hash) {
+            Ok(()) | Err(DownloadError::UpstreamChanged { .. }) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    mod bundle {
+        use std::fs;
+
+        use crate::bundle::{export, import};
+
+        #[tokio::test]
+        async fn round_trip() {
+            let from = tempfile::tempdir().unwrap();
+            let to = tempfile::tempdir().unwrap();
+            let file = "dist/2024-01-01/cargo-1.75.0-x86_64-unknown-linux-gnu.tar.xz";
+            fs::create_dir_all(from.path().join("dist/2024-01-01")).unwrap();
+            fs::write(from.path().join(file), "cargo").unwrap();
+            fs::write(from.path().join("mirror-stable-history.toml"), "[]").unwrap();
+            // Not part of a bundle.
+            fs::write(from.path().join(".panamax-served"), "").unwrap();
+
+            let bundle = from.path().join("bundle.tar.zst");
+            let exported = export(from.path(), &bundle, None, &[], false)
+                .await
+                .unwrap();
+            assert_eq!(exported.files, 2);
+
+            let imported = import(to.path(), &bundle, &[], false).await.unwrap();
+            assert_eq!(imported.added, 2);
+            assert_eq!(fs::read(to.path().join(file)).unwrap(), b"cargo");
+            assert!(to.path().join("mirror-stable-history.toml").exists());
+            assert!(!to.path().join(".panamax-served").exists());
+
+            let imported = import(to.path(), &bundle, &[], false).await.unwrap();
+            assert_eq!(imported.present, 1);
+        }
+
+        #[tokio::test]
+        async fn split() {
+            let from = tempfile::tempdir().unwrap();
+            let to = tempfile::tempdir().unwrap();
+            let file = "crates/1/a/0.1.0/a-0.1.0.crate";
+            fs::create_dir_all(from.path().join("crates/1/a/0.1.0")).unwrap();
+            fs::write(from.path().join(file), "a".repeat(10_000)).unwrap();
+
+            let bundle = from.path().join("bundle.tar.zst");
+            export(from.path(), &bundle, Some(100), &[], false)
+                .await
+                .unwrap();
+            assert!(from.path().join("bundle.tar.zst.002").exists());
+            assert!(!bundle.exists());
+
+            import(to.path(), &bundle, &[], false).await.unwrap();
+            assert_eq!(
+                fs::read(to.path().join(file)).unwrap(),
+                "a".repeat(10_000).as_bytes()
+            );
+
+            // Nothing is imported from a bundle missing a part.
+            let to = tempfile::tempdir().unwrap();
+            fs::remove_file(from.path().join("bundle.tar.zst.002")).unwrap();
+            assert!(import(to.path(), &bundle, &[], false).await.is_err());
+            assert!(!to.path().join("crates").exists());
+        }
+    }
+
+    mod checked_first {
+        use std::fs;
+
+        use crate::bundle::{apply_order, export, import};
+        use crate::snapshot::content_files;
+
+        fn mirror_with_crates() -> tempfile::TempDir {
+            let from = tempfile::tempdir().unwrap();
+            let dir = from.path().join("crates/1/a");
+            fs::create_dir_all(&dir).unwrap();
+            for version in 1..=20 {
+                let data: String = (0..5000)
+                    .map(|i| ((i * version) % 251) as u8 as char)
+                    .collect();
+                fs::write(dir.join(format!("a-0.{version}.0.crate")), data).unwrap();
+            }
+            from
+        }
+
+        #[tokio::test]
+        async fn dry_run() {
+            let from = mirror_with_crates();
+            let to = tempfile::tempdir().unwrap();
+            let bundle = from.path().join("bundle.tar.zst");
+            export(from.path(), &bundle, None, &[], false)
+                .await
+                .unwrap();
+
+            let imported = import(to.path(), &bundle, &[], true).await.unwrap();
+            assert_eq!(imported.added, 20);
+            assert!(!to.path().join("crates").exists());
+            assert!(!to.path().join(".panamax-bundles").exists());
+        }
+
+        #[tokio::test]
+        async fn truncated() {
+            let from = mirror_with_crates();
+            let to = tempfile::tempdir().unwrap();
+            let bundle = from.path().join("bundle.tar.zst");
+            export(from.path(), &bundle, None, &[], false)
+                .await
+                .unwrap();
+            let data = fs::read(&bundle).unwrap();
+            fs::write(&bundle, &data[..data.len() / 2]).unwrap();
+
+            assert!(import(to.path(), &bundle, &[], false).await.is_err());
+            assert!(content_files(to.path()).unwrap().is_empty());
+            let staged = walkdir::WalkDir::new(to.path())
+                .into_iter()
+                .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+                .count();
+            assert_eq!(staged, 0);
+        }
+
+        #[test]
+        fn index_last() {
+            let mut files = vec![
+                "crates.io-index/.git/refs/heads/master",
+                "mirror-stable-history.toml",
+                "crates.io-index/.git/objects/pack/pack-1.pack",
+                "dist/channel-rust-stable.toml",
+                "crates/se/rd/serde/1.0.0/serde-1.0.0.crate",
+            ];
+            files.sort_by_key(|file| apply_order(file));
+            assert_eq!(
+                files,
+                [
+                    "crates/se/rd/serde/1.0.0/serde-1.0.0.crate",
+                    "dist/channel-rust-stable.toml",
+                    "mirror-stable-history.toml",
+                    "crates.io-index/.git/objects/pack/pack-1.pack",
+                    "crates.io-index/.git/refs/heads/master",
+                ]
+            );
+        }
+    }
+
+    mod delta {
+        use std::fs;
+        use std::time::{Duration, SystemTime};
+
+        use crate::bundle::{export, import};
+
+        #[tokio::test]
+        async fn changed_files_only() {
+            let from = tempfile::tempdir().unwrap();
+            let to = tempfile::tempdir().unwrap();
+            let dir = from.path().join("crates/1/a");
+            fs::create_dir_all(&dir).unwrap();
+            for name in ["a-0.1.0.crate", "a-0.2.0.crate", "a-0.3.0.crate"] {
+                fs::write(dir.join(name), name).unwrap();
+            }
+            let full = from.path().join("full.tar.zst");
+            export(from.path(), &full, None, &[], false).await.unwrap();
+            import(to.path(), &full, &[], false).await.unwrap();
+
+            // Touched, like a file restored from a backup, but the same.
+            fs::File::options()
+                .write(true)
+                .open(dir.join("a-0.1.0.crate"))
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(3600))
+                .unwrap();
+            fs::write(dir.join("a-0.2.0.crate"), "changed").unwrap();
+            fs::remove_file(dir.join("a-0.3.0.crate")).unwrap();
+            fs::write(dir.join("a-0.4.0.crate"), "new").unwrap();
+
+            let delta = from.path().join("delta.tar.zst");
+            let exported = export(from.path(), &delta, None, &[], true).await.unwrap();
+            assert_eq!((exported.files, exported.removed), (2, 1));
+
+            // A delta can't be imported without the bundle it was made from.
+            let fresh = tempfile::tempdir().unwrap();
+            assert!(import(fresh.path(), &delta, &[], false).await.is_err());
+
+            let imported = import(to.path(), &delta, &[], false).await.unwrap();
+            assert_eq!((imported.added, imported.removed), (2, 1));
+            let dir = to.path().join("crates/1/a");
+            assert_eq!(fs::read(dir.join("a-0.2.0.crate")).unwrap(), b"changed");
+            assert!(dir.join("a-0.4.0.crate").exists());
+            assert!(!dir.join("a-0.3.0.crate").exists());
+        }
+    }
+
+    mod encrypted {
+        use std::fs;
+
+        use age::secrecy::ExposeSecret;
+
+        use crate::bundle::{export, import};
+
+        #[tokio::test]
+        async fn round_trip() {
+            let from = tempfile::tempdir().unwrap();
+            let to = tempfile::tempdir().unwrap();
+            let file = "rustup/dist/x86_64-unknown-linux-gnu/rustup-init";
+            fs::create_dir_all(from.path().join("rustup/dist/x86_64-unknown-linux-gnu")).unwrap();
+            fs::write(from.path().join(file), "rustup-init").unwrap();
+
+            let identity = age::x25519::Identity::generate();
+            let identity_path = from.path().join("key.txt");
+            fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+            let recipient = identity.to_public().to_string();
+
+            let bundle = from.path().join("bundle.tar.zst.age");
+            export(from.path(), &bundle, None, &[recipient], false)
+                .await
+                .unwrap();
+            assert!(fs::read(&bundle)
+                .unwrap()
+                .starts_with(b"age-encryption.org/"));
+
+            assert!(import(to.path(), &bundle, &[], false).await.is_err());
+            let other_identity = from.path().join("other.txt");
+            let other = age::x25519::Identity::generate();
+            fs::write(&other_identity, other.to_string().expose_secret()).unwrap();
+            assert!(import(to.path(), &bundle, &[other_identity], false)
+                .await
+                .is_err());
+
+            import(to.path(), &bundle, &[identity_path], false)
+                .await
+                .unwrap();
+            assert_eq!(fs::read(to.path().join(file)).unwrap(), b"rustup-init");
+        }
+    }
+
+    mod parse_size {
+        use crate::bundle::parse_size;
+
+        #[test]
+        fn units() {
+            assert_eq!(parse_size("25GB"), Ok(25_000_000_000));
+            assert_eq!(parse_size("4.7 GB"), Ok(4_700_000_000));
+            assert_eq!(parse_size("700MiB"), Ok(700 << 20));
+            assert_eq!(parse_size("512"), Ok(512));
+            assert!(parse_size("25XB").is_err());
+            assert!(parse_size("GB").is_err());
+            assert!(parse_size("0GB").is_err());
+        }
+    }
+}
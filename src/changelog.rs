@@ -0,0 +1,585 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use git2::{Delta, Oid, Repository};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::mirror::{load_mirror_toml, MirrorError};
+use crate::rustup::get_channel_history;
+use crate::state;
+use crate::timestamp::{self, civil_from_days, Timestamp, DAY};
+
+/// Name of the log of what each sync changed, in the state directory. It has one JSON record
+/// per line, appended as each sync finishes.
+pub const SYNCS_FILE: &str = ".panamax-syncs";
+
+/// How to print a changelog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChangelogFormat {
+    /// A summary of each sync.
+    Text,
+    /// JSON, with a list of syncs.
+    Json,
+    /// An RSS feed with an item for each sync, for announcing what's new on the mirror.
+    Rss,
+}
+
+/// One release of a Rust channel, by the date of its manifest.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Toolchain {
+    channel: String,
+    date: String,
+}
+
+/// What a mirror serves at one point in time, to compare before and after a sync.
+pub struct Snapshot {
+    /// The commit the index's master branch is at.
+    index: Option<String>,
+    toolchains: BTreeSet<Toolchain>,
+}
+
+impl Snapshot {
+    pub fn take(mirror_path: &Path) -> Self {
+        let index = Repository::open(mirror_path.join("crates.io-index"))
+            .and_then(|repo| repo.refname_to_id("refs/heads/master"))
+            .ok()
+            .map(|oid| oid.to_string());
+
+        // Each channel kept has a history file, listing the dates of its releases in the mirror.
+        let mut toolchains = BTreeSet::new();
+        let entries = fs::read_dir(state::dir(mirror_path)).into_iter().flatten();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(channel) = name
+                .strip_prefix("mirror-")
+                .and_then(|name| name.strip_suffix("-history.toml"))
+            else {
+                continue;
+            };
+            if let Ok(history) = get_channel_history(mirror_path, channel) {
+                toolchains.extend(history.versions.into_keys().map(|date| Toolchain {
+                    channel: channel.to_string(),
+                    date,
+                }));
+            }
+        }
+
+        Snapshot { index, toolchains }
+    }
+}
+
+/// What one sync changed, as recorded in the sync log.
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncRecord {
+    /// When the sync started, in seconds since the UNIX epoch, which also identifies it.
+    id: u64,
+    finished: Timestamp,
+    index_before: Option<String>,
+    index_after: Option<String>,
+    toolchains_added: Vec<Toolchain>,
+    toolchains_removed: Vec<Toolchain>,
+}
+
+/// Add a sync that started at `started` to the sync log, comparing the mirror now with
+/// `before`, taken when it started.
+pub fn record(mirror_path: &Path, started: Timestamp, before: Snapshot) -> io::Result<()> {
+    let after = Snapshot::take(mirror_path);
+    let record = SyncRecord {
+        id: started.0,
+        finished: Timestamp::now(),
+        toolchains_added: after
+            .toolchains
+            .difference(&before.toolchains)
+            .cloned()
+            .collect(),
+        toolchains_removed: before
+            .toolchains
+            .difference(&after.toolchains)
+            .cloned()
+            .collect(),
+        index_before: before.index,
+        index_after: after.index,
+    };
+    let mut line = serde_json::to_string(&record).expect("sync records should always serialize");
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state::path(mirror_path, SYNCS_FILE))?
+        .write_all(line.as_bytes())
+}
+
+/// Read the sync log of a mirror, oldest first. A missing file means no syncs were logged.
+fn load(mirror_path: &Path) -> Vec<SyncRecord> {
+    fs::read_to_string(state::path(mirror_path, SYNCS_FILE))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// When the last logged sync finished, if any were.
+pub fn last_sync(mirror_path: &Path) -> Option<Timestamp> {
+    load(mirror_path).last().map(|record| record.finished)
+}
+
+/// Where a changelog starts: after a sync, or at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Since {
+    Sync(u64),
+    Time(Timestamp),
+}
+
+impl Since {
+    /// Parse a sync ID, or a date as `YYYY-MM-DD`, which starts at midnight in the display
+    /// time zone.
+    fn parse(s: &str) -> Option<Self> {
+        if let Ok(id) = s.parse() {
+            return Some(Since::Sync(id));
+        }
+        timestamp::parse_date(s).map(|days| Since::Time(timestamp::local_midnight(days)))
+    }
+
+    fn includes(self, record: &SyncRecord) -> bool {
+        match self {
+            Since::Sync(id) => record.id > id,
+            Since::Time(time) => record.id >= time.0,
+        }
+    }
+}
+
+/// Format seconds since the UNIX epoch as an RFC 822 date, as RSS uses.
+fn format_rfc822(time: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = time / DAY;
+    let (year, month, day) = civil_from_days(days);
+    let seconds = time % DAY;
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// A version of a crate in the index.
+#[derive(Debug, Serialize)]
+struct CrateVersion {
+    name: String,
+    version: String,
+}
+
+/// The fields of an index line that the changelog looks at.
+#[derive(Debug, Deserialize)]
+struct IndexVersion {
+    name: String,
+    vers: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// What a sync changed on the mirror.
+#[derive(Debug, Default, Serialize)]
+struct SyncChanges {
+    id: u64,
+    finished: Timestamp,
+    crates_added: Vec<CrateVersion>,
+    crates_yanked: Vec<CrateVersion>,
+    crates_removed: Vec<CrateVersion>,
+    toolchains_added: Vec<Toolchain>,
+    toolchains_removed: Vec<Toolchain>,
+}
+
+impl SyncChanges {
+    fn is_empty(&self) -> bool {
+        self.crates_added.is_empty()
+            && self.crates_yanked.is_empty()
+            && self.crates_removed.is_empty()
+            && self.toolchains_added.is_empty()
+            && self.toolchains_removed.is_empty()
+    }
+
+    /// A one-line summary, e.g. "12 new crate versions, 1 new toolchain".
+    fn summary(&self) -> String {
+        let counts = [
+            (self.crates_added.len(), "new crate version"),
+            (self.crates_yanked.len(), "yanked crate version"),
+            (self.crates_removed.len(), "removed crate version"),
+            (self.toolchains_added.len(), "new toolchain"),
+            (self.toolchains_removed.len(), "removed toolchain"),
+        ];
+        counts
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, what)| match count {
+                1 => format!("1 {what}"),
+                _ => format!("{count} {what}s"),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// A title for the sync, leading with the toolchains it added, as they're what most people
+    /// are waiting for, e.g. "stable 2023-06-01 mirrored (1 new toolchain, 12 new crate versions)".
+    fn headline(&self) -> String {
+        if self.toolchains_added.is_empty() {
+            return self.summary();
+        }
+        let toolchains: Vec<_> = self
+            .toolchains_added
+            .iter()
+            .map(|t| format!("{} {}", t.channel, t.date))
+            .collect();
+        format!("{} mirrored ({})", toolchains.join(", "), self.summary())
+    }
+
+    /// The changes as text, one section per kind of change.
+    fn details(&self) -> String {
+        let crates = |versions: &[CrateVersion]| {
+            versions
+                .iter()
+                .map(|v| format!("{} {}", v.name, v.version))
+                .collect::<Vec<_>>()
+        };
+        let toolchains = |toolchains: &[Toolchain]| {
+            toolchains
+                .iter()
+                .map(|t| format!("{} {}", t.channel, t.date))
+                .collect::<Vec<_>>()
+        };
+        let sections = [
+            ("New toolchains", toolchains(&self.toolchains_added)),
+            ("Removed toolchains", toolchains(&self.toolchains_removed)),
+            ("New crate versions", crates(&self.crates_added)),
+            ("Yanked crate versions", crates(&self.crates_yanked)),
+            ("Removed crate versions", crates(&self.crates_removed)),
+        ];
+
+        let mut details = String::new();
+        for (title, lines) in sections.iter().filter(|(_, lines)| !lines.is_empty()) {
+            details.push_str(&format!("  {title}:\n"));
+            for line in lines {
+                details.push_str(&format!("    {line}\n"));
+            }
+        }
+        details
+    }
+}
+
+/// Read the versions in an index file, with whether each is yanked.
+fn index_file_versions(repo: &Repository, blob: Oid) -> BTreeMap<(String, String), bool> {
+    if blob.is_zero() {
+        return BTreeMap::new();
+    }
+    let Ok(blob) = repo.find_blob(blob) else {
+        return BTreeMap::new();
+    };
+    String::from_utf8_lossy(blob.content())
+        .lines()
+        .filter_map(|line| serde_json::from_str::<IndexVersion>(line).ok())
+        .map(|v| ((v.name, v.vers), v.yanked))
+        .collect()
+}
+
+/// Compare the index at two commits, and note the crate versions added, yanked and removed.
+fn index_changes(
+    repo: &Repository,
+    before: &str,
+    after: &str,
+    changes: &mut SyncChanges,
+) -> Result<(), git2::Error> {
+    let before = repo.find_commit(Oid::from_str(before)?)?.tree()?;
+    let after = repo.find_commit(Oid::from_str(after)?)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&before), Some(&after), None)?;
+    for delta in diff.deltas() {
+        if !matches!(
+            delta.status(),
+            Delta::Added | Delta::Deleted | Delta::Modified
+        ) {
+            continue;
+        }
+        let old = index_file_versions(repo, delta.old_file().id());
+        let new = index_file_versions(repo, delta.new_file().id());
+        for ((name, version), &yanked) in &new {
+            let crate_version = || CrateVersion {
+                name: name.clone(),
+                version: version.clone(),
+            };
+            match old.get(&(name.clone(), version.clone())) {
+                None if !yanked => changes.crates_added.push(crate_version()),
+                Some(false) if yanked => changes.crates_yanked.push(crate_version()),
+                _ => {}
+            }
+        }
+        for (name, version) in old.keys().filter(|key| !new.contains_key(key)) {
+            changes.crates_removed.push(CrateVersion {
+                name: name.clone(),
+                version: version.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn to_rss(changes: &[SyncChanges]) -> String {
+    let mut rss = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\">\n<channel>\n",
+    );
+    rss.push_str("<title>Mirror changes</title>\n");
+    rss.push_str("<description>What each sync of the mirror changed.</description>\n");
+    // Feed readers show the newest items first.
+    for sync in changes.iter().rev() {
+        rss.push_str(&format!(
+            "<item>\n<title>{}</title>\n<guid isPermaLink=\"false\">panamax-sync-{}</guid>\n\
+             <pubDate>{}</pubDate>\n<description>{}</description>\n</item>\n",
+            xml_escape(&format!(
+                "Sync of {}: {}",
+                timestamp::format_display(sync.finished),
+                sync.summary()
+            )),
+            sync.id,
+            format_rfc822(sync.finished.0),
+            xml_escape(&sync.details())
+        ));
+    }
+    rss.push_str("</channel>\n</rss>\n");
+    rss
+}
+
+/// Work out what a sync changed from its record in the sync log.
+fn sync_changes(repo: Option<&Repository>, record: SyncRecord) -> SyncChanges {
+    let mut sync = SyncChanges {
+        id: record.id,
+        finished: record.finished,
+        toolchains_added: record.toolchains_added,
+        toolchains_removed: record.toolchains_removed,
+        ..Default::default()
+    };
+    if let (Some(repo), Some(before), Some(after)) =
+        (repo, &record.index_before, &record.index_after)
+    {
+        if before != after {
+            if let Err(e) = index_changes(repo, before, after, &mut sync) {
+                warn!(
+                    "Could not compare the index before and after sync {}: {e}",
+                    sync.id
+                );
+            }
+        }
+    }
+    sync
+}
+
+/// Most syncs listed in the Atom feed.
+const FEED_ENTRIES: usize = 20;
+
+/// The syncs in the Atom feed, with the length of the sync log they were read from. Comparing
+/// index commits can take a while, so they're only worked out again once another sync is logged.
+#[allow(clippy::type_complexity)]
+static FEED_CACHE: Mutex<Option<(u64, Arc<Vec<SyncChanges>>)>> = Mutex::new(None);
+
+/// The newest syncs that changed something, newest first.
+fn recent_changes(mirror_path: &Path) -> Arc<Vec<SyncChanges>> {
+    let log_len = fs::metadata(state::path(mirror_path, SYNCS_FILE)).map_or(0, |m| m.len());
+    let mut cache = FEED_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((len, changes)) = &*cache {
+        if *len == log_len {
+            return changes.clone();
+        }
+    }
+
+    let repo = Repository::open(mirror_path.join("crates.io-index")).ok();
+    let changes: Arc<Vec<_>> = Arc::new(
+        load(mirror_path)
+            .into_iter()
+            .rev()
+            .map(|r| sync_changes(repo.as_ref(), r))
+            .filter(|sync| !sync.is_empty())
+            .take(FEED_ENTRIES)
+            .collect(),
+    );
+    *cache = Some((log_len, changes.clone()));
+    changes
+}
+
+/// The ID of a mirror's Atom feed, which feed readers use to tell feeds apart, so it mustn't
+/// change with the hostname a client uses. It's the feed's URL under the mirror's URL if that's
+/// known, and otherwise a URN derived from where the mirror is.
+pub fn feed_id(mirror_path: &Path, mirror_url: Option<&str>) -> String {
+    if let Some(mirror_url) = mirror_url {
+        return format!("{mirror_url}/feed.atom");
+    }
+    let mirror_path = fs::canonicalize(mirror_path).unwrap_or_else(|_| mirror_path.to_path_buf());
+    let hash = Sha256::digest(mirror_path.to_string_lossy().as_bytes());
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(&hash[..16]);
+    // A version 8 UUID, the kind for names hashed in other ways than MD5 and SHA-1.
+    uuid[6] = (uuid[6] & 0x0f) | 0x80;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    let hex: String = uuid.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// An Atom feed of what the latest syncs changed, for serve, so developers can follow new
+/// toolchains and crates on the mirror in a feed reader or chat integration. `feed_id` is the
+/// feed's ID, from [`feed_id`], and `feed_url` is where the feed is served from.
+pub fn atom_feed(mirror_path: &Path, feed_id: &str, feed_url: &str) -> String {
+    let changes = recent_changes(mirror_path);
+    let updated = changes.first().map_or(Timestamp(0), |sync| sync.finished);
+    let mut atom = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+         <title>Mirror changes</title>\n\
+         <subtitle>New toolchains and crates on the mirror.</subtitle>\n\
+         <author><name>Panamax</name></author>\n",
+    );
+    atom.push_str(&format!(
+        "<id>{}</id>\n<link rel=\"self\" href=\"{}\"/>\n<updated>{}</updated>\n",
+        xml_escape(feed_id),
+        xml_escape(feed_url),
+        updated
+    ));
+    for sync in changes.iter() {
+        atom.push_str(&format!(
+            "<entry>\n<title>{}</title>\n<id>{}#sync-{}</id>\n<updated>{}</updated>\n\
+             <content type=\"text\">{}</content>\n</entry>\n",
+            xml_escape(&sync.headline()),
+            xml_escape(feed_id),
+            sync.id,
+            sync.finished,
+            xml_escape(&sync.details())
+        ));
+    }
+    atom.push_str("</feed>\n");
+    atom
+}
+
+/// Print what the syncs of a mirror changed, since a sync ID or a date.
+///
+/// New, yanked and removed crate versions come from comparing the index's master branch before
+/// and after each sync, and new and removed toolchains from the channel histories. Syncs that
+/// changed nothing are left out.
+pub fn changelog(path: &Path, since: &str, format: ChangelogFormat) -> Result<(), MirrorError> {
+    if path.join("mirror.toml").exists() {
+        crate::mirror::init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+    }
+    let since = Since::parse(since).ok_or_else(|| {
+        MirrorError::CmdLine(format!(
+            "{since} is neither a sync ID nor a date like 2023-06-01."
+        ))
+    })?;
+
+    let repo = Repository::open(path.join("crates.io-index")).ok();
+    let changes: Vec<_> = load(path)
+        .into_iter()
+        .filter(|r| since.includes(r))
+        .map(|r| sync_changes(repo.as_ref(), r))
+        .filter(|sync| !sync.is_empty())
+        .collect();
+
+    match format {
+        ChangelogFormat::Text => {
+            if changes.is_empty() {
+                eprintln!("No changes since then.");
+            }
+            for sync in &changes {
+                println!(
+                    "Sync {} ({}): {}",
+                    sync.id,
+                    timestamp::format_display(sync.finished),
+                    sync.summary()
+                );
+                print!("{}", sync.details());
+            }
+        }
+        ChangelogFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&changes).expect("changelogs should always serialize")
+        ),
+        ChangelogFormat::Rss => print!("{}", to_rss(&changes)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    mod feed_id {
+        use std::path::Path;
+
+        use crate::changelog::feed_id;
+
+        #[test]
+        fn stable() {
+            assert_eq!(
+                feed_id(Path::new("/srv/mirror"), Some("https://panamax.internal")),
+                "https://panamax.internal/feed.atom"
+            );
+            let id = feed_id(Path::new("/nonexistent/mirror"), None);
+            assert_eq!(id, feed_id(Path::new("/nonexistent/mirror"), None));
+            assert_ne!(id, feed_id(Path::new("/nonexistent/other"), None));
+            assert!(id.starts_with("urn:uuid:"));
+            assert_eq!(id.len(), "urn:uuid:".len() + 36);
+            assert_eq!(&id["urn:uuid:".len() + 14..][..1], "8");
+        }
+    }
+
+    mod since {
+        use crate::changelog::Since;
+        use crate::timestamp::{Timestamp, DAY};
+
+        #[test]
+        fn sync_id() {
+            assert_eq!(Since::parse("1686000000"), Some(Since::Sync(1686000000)));
+        }
+
+        #[test]
+        fn date() {
+            assert_eq!(
+                Since::parse("1970-01-02"),
+                Some(Since::Time(Timestamp(DAY)))
+            );
+            assert_eq!(
+                Since::parse("2023-06-01"),
+                Some(Since::Time(Timestamp(19509 * DAY)))
+            );
+            assert_eq!(Since::parse("2023-13-01"), None);
+            assert_eq!(Since::parse("yesterday"), None);
+        }
+    }
+
+    mod format_rfc822 {
+        use crate::changelog::format_rfc822;
+
+        #[test]
+        fn formats() {
+            // 2023-06-01 12:34:56 UTC, a Thursday.
+            assert_eq!(format_rfc822(1685622896), "Thu, 01 Jun 2023 12:34:56 +0000");
+            assert_eq!(format_rfc822(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        }
+    }
+}
@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use crate::download::{append_to_path, file_hash, DownloadError};
+use crate::hashes::{parse_expected, HashAlgorithm};
+use crate::{staging, state};
+
+/// Name of the checksum database, in the mirror's state directory.
+///
+/// Each `<sha256>  <path>` line is the hash upstream gave for an immutable artifact, as first
+/// seen. This is the same as `sha256sum` output, so the mirror can also be checked by running
+/// `sha256sum -c` on it from the mirror root.
+///
+/// Lines starting with `#`, which `sha256sum` skips, are the hashes of the files on disk:
+/// `#<hash> <size> <mtime> <path>`, or `#- <path>` for a file that was removed. Hashes other
+/// than SHA-256 have their algorithm as a prefix, like `#blake3:<hex>`, and a file can have one
+/// of each. Later lines replace earlier ones for the same path and algorithm.
+pub const CHECKSUMS_FILE: &str = ".panamax-checksums";
+
+/// Lock file held with a shared lock by every process that has the checksum database open.
+///
+/// The database is only compacted under an exclusive lock on it, so never while another
+/// process could still be appending to the file that's replaced.
+const USERS_LOCK_FILE: &str = ".panamax-checksums.lock";
+
+/// Lock file held while trying to take the exclusive lock on `USERS_LOCK_FILE`, so a process
+/// that gives up its shared lock to do so can't have the database compacted under it.
+const COMPACT_LOCK_FILE: &str = ".panamax-checksums.compact-lock";
+
+/// The size and modification time (in nanoseconds since the epoch) of a file, which change
+/// whenever it's written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    modified: u128,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> io::Result<Option<Self>> {
+        let metadata = fs::metadata(path)?;
+        let Ok(modified) = metadata.modified()?.duration_since(UNIX_EPOCH) else {
+            return Ok(None);
+        };
+        Ok(Some(Stamp {
+            size: metadata.len(),
+            modified: modified.as_nanos(),
+        }))
+    }
+}
+
+/// A line of the checksum database.
+#[derive(Debug, PartialEq, Eq)]
+enum Line<'a> {
+    FirstSeen {
+        path: &'a str,
+        hash: &'a str,
+    },
+    OnDisk {
+        path: &'a str,
+        stamp: Stamp,
+        algorithm: HashAlgorithm,
+        hash: &'a str,
+    },
+    Removed {
+        path: &'a str,
+    },
+}
+
+/// The hashes of every immutable artifact the mirror has seen, as first seen, and of every file
+/// in the mirror, as of when it was last hashed.
+struct ChecksumDb {
+    root: PathBuf,
+    seen: HashMap<String, String>,
+    on_disk: HashMap<String, HashMap<HashAlgorithm, (Stamp, String)>>,
+    file: File,
+    /// `USERS_LOCK_FILE`, which this holds a shared lock on for as long as it's open.
+    users_lock: File,
+    /// How much of the database has been read. Other processes, like a verify while a sync
+    /// runs, only ever append to it, so the rest is read when a path isn't found.
+    read_to: u64,
+    /// Lines of files on disk that a later line replaced.
+    superseded: usize,
+}
+
+/// The mirrors whose databases are used for the rest of this run, set by `open`, with each
+/// database read when it's first needed, so a run that doesn't download or hash anything never
+/// reads it.
+static DBS: Mutex<Vec<(PathBuf, Option<ChecksumDb>)>> = Mutex::new(Vec::new());
+
+impl ChecksumDb {
+    /// Read the database. If no other process has it open, it's compacted if its files on
+    /// disk have been replaced more than there are of them, or if a crash left half a line at
+    /// the end of it.
+    fn load(root: &Path) -> io::Result<Self> {
+        let users_lock = open_lock(root, USERS_LOCK_FILE)?;
+        let compact_lock = open_lock(root, COMPACT_LOCK_FILE)?;
+        compact_lock.lock()?;
+        let alone = try_lock_exclusive(&users_lock)?;
+        if !alone {
+            users_lock.lock_shared()?;
+        }
+        compact_lock.unlock()?;
+
+        let db_path = state::path(root, CHECKSUMS_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&db_path)?;
+        let mut db = ChecksumDb {
+            root: root.to_path_buf(),
+            seen: HashMap::new(),
+            on_disk: HashMap::new(),
+            file,
+            users_lock,
+            read_to: 0,
+            superseded: 0,
+        };
+        let complete = db.refresh()?;
+        if alone {
+            // Half a line can only be left by a crash, as nothing else is writing to it.
+            if !complete || db.superseded > db.on_disk.len() {
+                db.compact()?;
+            }
+            db.users_lock.lock_shared()?;
+        }
+        Ok(db)
+    }
+
+    /// Compact the database if no other process has it open, returning whether it was.
+    fn compact_if_alone(&mut self) -> io::Result<bool> {
+        let compact_lock = open_lock(&self.root, COMPACT_LOCK_FILE)?;
+        compact_lock.lock()?;
+        // The shared lock is given up first, as a process can't take an exclusive lock while
+        // it holds a shared one itself. Nothing can compact in between without compact_lock.
+        self.users_lock.unlock()?;
+        let alone = try_lock_exclusive(&self.users_lock)?;
+        if alone {
+            self.refresh()?;
+            self.compact()?;
+        }
+        self.users_lock.lock_shared()?;
+        compact_lock.unlock()?;
+        Ok(alone)
+    }
+
+    /// Read the lines added to the database since it was last read. Returns false if there was
+    /// half a line at the end of it.
+    fn refresh(&mut self) -> io::Result<bool> {
+        let mut file = File::open(state::path(&self.root, CHECKSUMS_FILE))?;
+        if file.metadata()?.len() < self.read_to {
+            // Another process compacted it, so it's read again from the start.
+            self.seen.clear();
+            self.on_disk.clear();
+            self.read_to = 0;
+            self.superseded = 0;
+        }
+        file.seek(SeekFrom::Start(self.read_to))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        // A line is only written once it's whole, so anything after the last newline was cut
+        // short, or is still being written, and is left for later.
+        let complete = data
+            .rsplit(|b| *b == b'\n')
+            .next()
+            .is_none_or(<[u8]>::is_empty);
+        let mut records: Vec<&[u8]> = data.split(|b| *b == b'\n').collect();
+        let partial = records.pop().map_or(0, <[u8]>::len);
+        for line in records {
+            let Some(line) = std::str::from_utf8(line).ok().and_then(parse_line) else {
+                continue;
+            };
+            match line {
+                Line::FirstSeen { path, hash } => {
+                    // The first line for a path is the one that counts.
+                    self.seen
+                        .entry(path.to_string())
+                        .or_insert_with(|| hash.to_string());
+                }
+                Line::OnDisk {
+                    path,
+                    stamp,
+                    algorithm,
+                    hash,
+                } => {
+                    let new = (stamp, hash.to_string());
+                    let hashes = self.on_disk.entry(path.to_string()).or_default();
+                    match hashes.insert(algorithm, new) {
+                        Some((old_stamp, old_hash)) if old_stamp == stamp && old_hash == hash => {}
+                        Some(_) => self.superseded += 1,
+                        None => {}
+                    }
+                }
+                Line::Removed { path } => {
+                    if let Some(hashes) = self.on_disk.remove(path) {
+                        self.superseded += hashes.len() + 1;
+                    }
+                }
+            }
+        }
+        self.read_to += (data.len() - partial) as u64;
+        Ok(complete)
+    }
+
+    /// Rewrite the database with only its current entries. Needs the exclusive lock on
+    /// `USERS_LOCK_FILE`, as any other process appending to the old file would lose its lines.
+    fn compact(&mut self) -> io::Result<()> {
+        let db_path = state::path(&self.root, CHECKSUMS_FILE);
+        let tmp_path = append_to_path(&db_path, ".tmp");
+        let mut data = String::new();
+        for (path, hash) in &self.seen {
+            data.push_str(&format!("{hash}  {path}\n"));
+        }
+        for (path, hashes) in &self.on_disk {
+            for (algorithm, (stamp, hash)) in hashes {
+                data.push_str(&format_on_disk(path, *stamp, *algorithm, hash));
+            }
+        }
+        fs::write(&tmp_path, &data)?;
+        fs::rename(&tmp_path, &db_path)?;
+        self.file = OpenOptions::new().append(true).open(&db_path)?;
+        self.read_to = data.len() as u64;
+        self.superseded = 0;
+        Ok(())
+    }
+
+    /// Get a file's path relative to the mirror root, as it's written in the database. Files
+    /// outside the mirror, or still being downloaded, aren't kept track of.
+    fn key(&self, path: &Path) -> Option<String> {
+        if path.starts_with(staging::dir(&self.root)) {
+            return None;
+        }
+        let key = path.strip_prefix(&self.root).ok()?.to_str()?;
+        (!key.is_empty() && !key.contains('\n')).then(|| key.replace('\\', "/"))
+    }
+
+    fn first_seen(&mut self, path: &str) -> io::Result<Option<String>> {
+        if !self.seen.contains_key(path) {
+            self.refresh()?;
+        }
+        Ok(self.seen.get(path).cloned())
+    }
+
+    fn on_disk(
+        &mut self,
+        key: &str,
+        stamp: Stamp,
+        algorithm: HashAlgorithm,
+    ) -> io::Result<Option<String>> {
+        if !self
+            .on_disk
+            .get(key)
+            .is_some_and(|hashes| hashes.contains_key(&algorithm))
+        {
+            self.refresh()?;
+        }
+        Ok(self
+            .on_disk
+            .get(key)
+            .and_then(|hashes| hashes.get(&algorithm))
+            .filter(|(recorded, _)| *recorded == stamp)
+            .map(|(_, hash)| hash.clone()))
+    }
+
+    fn insert_first_seen(&mut self, path: &str, hash: &str) -> io::Result<()> {
+        writeln!(self.file, "{hash}  {path}")?;
+        self.seen.insert(path.to_string(), hash.to_string());
+        Ok(())
+    }
+
+    fn insert_on_disk(
+        &mut self,
+        key: String,
+        stamp: Stamp,
+        algorithm: HashAlgorithm,
+        hash: &str,
+    ) -> io::Result<()> {
+        if self.on_disk(&key, stamp, algorithm)?.as_deref() == Some(hash) {
+            return Ok(());
+        }
+        // Each change is a single write of a whole line, so a crash can't leave a wrong entry.
+        self.file
+            .write_all(format_on_disk(&key, stamp, algorithm, hash).as_bytes())?;
+        let hashes = self.on_disk.entry(key).or_default();
+        if hashes
+            .insert(algorithm, (stamp, hash.to_string()))
+            .is_some()
+        {
+            self.superseded += 1;
+        }
+        Ok(())
+    }
+
+    fn remove_on_disk(&mut self, key: &str) -> io::Result<()> {
+        if let Some(hashes) = self.on_disk.remove(key) {
+            self.file.write_all(format!("#- {key}\n").as_bytes())?;
+            // Both the entries' lines and the removal can be dropped.
+            self.superseded += hashes.len() + 1;
+        }
+        Ok(())
+    }
+}
+
+/// Open one of the database's lock files, creating it if needed.
+fn open_lock(root: &Path, name: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(state::path(root, name))
+}
+
+/// Take an exclusive lock without waiting, returning false if another process holds the lock.
+fn try_lock_exclusive(lock: &File) -> io::Result<bool> {
+    match lock.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+fn format_on_disk(path: &str, stamp: Stamp, algorithm: HashAlgorithm, hash: &str) -> String {
+    let hash = algorithm.tag(hash);
+    format!("#{hash} {} {} {path}\n", stamp.size, stamp.modified)
+}
+
+fn parse_line(line: &str) -> Option<Line<'_>> {
+    let Some(line) = line.strip_prefix('#') else {
+        let (hash, path) = line.split_once("  ")?;
+        return Some(Line::FirstSeen { path, hash });
+    };
+    if let Some(path) = line.strip_prefix("- ") {
+        return Some(Line::Removed { path });
+    }
+    let mut parts = line.splitn(4, ' ');
+    let (algorithm, hash) = parse_expected(parts.next()?);
+    let size = parts.next()?.parse().ok()?;
+    let modified = parts.next()?.parse().ok()?;
+    let path = parts.next()?;
+    algorithm.is_digest(hash).then_some(Line::OnDisk {
+        path,
+        stamp: Stamp { size, modified },
+        algorithm,
+        hash,
+    })
+}
+
+/// Run `f` on the database of the opened mirror that `path` is in, reading it first if it
+/// hasn't been yet. Returns None if `path` isn't in a mirror whose database was opened.
+fn with_db<T>(
+    path: &Path,
+    f: impl FnOnce(&mut ChecksumDb) -> io::Result<T>,
+) -> io::Result<Option<T>> {
+    let mut dbs = DBS.lock().unwrap_or_else(|e| e.into_inner());
+    // A mirror inside another one has its own database.
+    let Some((root, db)) = dbs
+        .iter_mut()
+        .filter(|(root, _)| path.starts_with(root))
+        .max_by_key(|(root, _)| root.components().count())
+    else {
+        return Ok(None);
+    };
+    if db.is_none() {
+        *db = Some(ChecksumDb::load(root)?);
+    }
+    f(db.as_mut().expect("loaded above")).map(Some)
+}
+
+/// Read the hashes of a mirror's immutable artifacts, as first seen, as (path, hash) pairs.
+///
+/// A missing database is treated as empty.
+pub fn load(mirror_path: &Path) -> io::Result<Vec<(String, String)>> {
+    load_file(&state::path(mirror_path, CHECKSUMS_FILE))
+}
+
+/// Read the hashes first seen from a checksum database at `db_path`, like `load`.
+pub fn load_file(db_path: &Path) -> io::Result<Vec<(String, String)>> {
+    let f = match File::open(db_path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(f).lines() {
+        if let Some(Line::FirstSeen { path, hash }) = parse_line(&line?) {
+            entries.push((path.to_string(), hash.to_string()));
+        }
+    }
+    Ok(entries)
+}
+
+/// Use the checksum database of a mirror for the rest of this run. It's read when it's first
+/// needed, and created then if needed.
+pub fn open(mirror_path: &Path) {
+    let mut dbs = DBS.lock().unwrap_or_else(|e| e.into_inner());
+    if !dbs.iter().any(|(root, _)| root == mirror_path) {
+        dbs.push((mirror_path.to_path_buf(), None));
+    }
+}
+
+/// Check the hash upstream gives for an immutable artifact against the one first seen for it,
+/// recording it if it's new.
+///
+/// Upstream should never change the contents of a published crate or dated dist file, so a
+/// different hash means upstream has been tampered with or is serving corrupt data, and the
+/// file must not be replaced. Does nothing if the database of the mirror at `mirror_path`
+/// wasn't opened.
+pub fn check(mirror_path: &Path, path: &str, hash: &str) -> Result<(), DownloadError> {
+    let first_seen = with_db(mirror_path, |db| {
+        let first_seen = db.first_seen(path)?;
+        if first_seen.is_none() {
+            db.insert_first_seen(path, hash)?;
+        }
+        Ok(first_seen)
+    })?;
+
+    match first_seen.flatten() {
+        Some(first_seen) if first_seen != hash => {
+            eprintln!("WARNING: upstream is serving different content for {path}!");
+            eprintln!("It was first seen with hash {first_seen}, but now claims {hash}.");
+            Err(DownloadError::UpstreamChanged {
+                path: path.to_string(),
+                first_seen,
+                actual: hash.to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Calculate the hash of a file on disk, like `file_hash`.
+///
+/// Hashes are looked up in the database first, and only calculated if the file's size or
+/// modification time has changed since it was last hashed in that algorithm, so a sync doesn't
+/// have to read every file already in the mirror again.
+pub async fn hash(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    // The file is checked before it's read, so if it's written to while it's being hashed,
+    // what's recorded won't match it afterwards.
+    let Some(stamp) = Stamp::of(path)? else {
+        return file_hash(path, algorithm).await;
+    };
+    let recorded = with_db(path, |db| {
+        Ok(match db.key(path) {
+            Some(key) => Some((db.on_disk(&key, stamp, algorithm)?, key)),
+            None => None,
+        })
+    })?;
+    let key = match recorded.flatten() {
+        Some((Some(hash), _)) => return Ok(hash),
+        Some((None, key)) => key,
+        None => return file_hash(path, algorithm).await,
+    };
+
+    let hash = file_hash(path, algorithm).await?;
+    with_db(path, |db| db.insert_on_disk(key, stamp, algorithm, &hash))?;
+    Ok(hash)
+}
+
+/// Record the hash of a file that was just written and verified. Does nothing if the database
+/// of its mirror wasn't opened.
+pub fn record(path: &Path, algorithm: HashAlgorithm, hash: &str) -> io::Result<()> {
+    let Some(stamp) = Stamp::of(path)? else {
+        return Ok(());
+    };
+    with_db(path, |db| match db.key(path) {
+        Some(key) => db.insert_on_disk(key, stamp, algorithm, hash),
+        None => Ok(()),
+    })?;
+    Ok(())
+}
+
+/// Forget the hash of a file that was removed. Does nothing if the database of its mirror
+/// wasn't opened.
+pub fn forget(path: &Path) -> io::Result<()> {
+    with_db(path, |db| match db.key(path) {
+        Some(key) => db.remove_on_disk(&key),
+        None => Ok(()),
+    })?;
+    Ok(())
+}
+
+/// Remove the hashes of files that are no longer in the mirror, such as ones that were moved
+/// or deleted by hand, and compact the database if no other process, like a sync, has it open.
+/// Returns the number of entries removed.
+///
+/// The hashes first seen from upstream are kept, as they're needed for as long as upstream
+/// serves the file.
+pub fn prune(mirror_path: &Path) -> io::Result<usize> {
+    let prune = |db: &mut ChecksumDb| {
+        db.refresh()?;
+        let root = db.root.clone();
+        let gone: Vec<String> = db
+            .on_disk
+            .keys()
+            .filter(|path| !root.join(path).is_file())
+            .cloned()
+            .collect();
+        // Removals are appended like any other change, so they're kept even if the database
+        // can't be compacted now.
+        for path in &gone {
+            db.remove_on_disk(path)?;
+        }
+        db.compact_if_alone()?;
+        Ok(gone.len())
+    };
+    if let Some(pruned) = with_db(mirror_path, prune)? {
+        return Ok(pruned);
+    }
+    if !state::path(mirror_path, CHECKSUMS_FILE).exists() {
+        return Ok(0);
+    }
+    prune(&mut ChecksumDb::load(mirror_path)?)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod checksum_db {
+        use std::fs;
+        use std::io::Write;
+
+        use crate::checksums::{load, parse_line, ChecksumDb, Line, Stamp, CHECKSUMS_FILE};
+        use crate::hashes::HashAlgorithm::{Blake3, Sha256, Sha512};
+
+        #[test]
+        fn reload() {
+            let dir = tempfile::tempdir().unwrap();
+            let dir = dir.path();
+            fs::create_dir_all(dir.join("crates")).unwrap();
+            let path = dir.join("crates").join("foo-1.0.0.crate");
+            fs::write(&path, "foo").unwrap();
+            let stamp = Stamp::of(&path).unwrap().unwrap();
+
+            let mut db = ChecksumDb::load(dir).unwrap();
+            let key = db.key(&path).unwrap();
+            assert_eq!(key, "crates/foo-1.0.0.crate");
+            assert!(db.key(&std::env::temp_dir().join("other")).is_none());
+            db.insert_first_seen(&key, &"a".repeat(64)).unwrap();
+            db.insert_on_disk(key.clone(), stamp, Sha256, &"a".repeat(64))
+                .unwrap();
+            db.insert_on_disk(key.clone(), stamp, Sha256, &"b".repeat(64))
+                .unwrap();
+            db.insert_on_disk(key.clone(), stamp, Blake3, &"e".repeat(64))
+                .unwrap();
+            drop(db);
+
+            // A line cut short by a crash is ignored.
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(dir.join(CHECKSUMS_FILE))
+                .unwrap();
+            write!(file, "#{} 3 1 crates/foo", "c".repeat(64)).unwrap();
+            drop(file);
+
+            let mut db = ChecksumDb::load(dir).unwrap();
+            assert_eq!(
+                db.on_disk(&key, stamp, Sha256).unwrap(),
+                Some("b".repeat(64))
+            );
+            assert_eq!(
+                db.on_disk(&key, stamp, Blake3).unwrap(),
+                Some("e".repeat(64))
+            );
+            assert_eq!(db.on_disk(&key, stamp, Sha512).unwrap(), None);
+            assert_eq!(db.first_seen(&key).unwrap(), Some("a".repeat(64)));
+            // A file that has changed since it was hashed has to be hashed again.
+            let changed = Stamp { size: 4, ..stamp };
+            assert_eq!(db.on_disk(&key, changed, Sha256).unwrap(), None);
+            db.remove_on_disk(&key).unwrap();
+            drop(db);
+
+            let mut db = ChecksumDb::load(dir).unwrap();
+            assert_eq!(db.on_disk(&key, stamp, Sha256).unwrap(), None);
+            assert_eq!(db.on_disk(&key, stamp, Blake3).unwrap(), None);
+            // Only the hashes first seen are in sha256sum's format.
+            assert_eq!(load(dir).unwrap(), vec![(key, "a".repeat(64))]);
+        }
+
+        #[test]
+        fn appended_by_another_process() {
+            let dir = tempfile::tempdir().unwrap();
+            let mut db = ChecksumDb::load(dir.path()).unwrap();
+            let mut other = ChecksumDb::load(dir.path()).unwrap();
+            other
+                .insert_first_seen("dist/2024-01-01/cargo.tar.xz", &"d".repeat(64))
+                .unwrap();
+            assert_eq!(
+                db.first_seen("dist/2024-01-01/cargo.tar.xz").unwrap(),
+                Some("d".repeat(64))
+            );
+        }
+
+        #[test]
+        fn not_compacted_while_open_elsewhere() {
+            let dir = tempfile::tempdir().unwrap();
+            let dir = dir.path();
+            fs::create_dir_all(dir.join("crates")).unwrap();
+            let path = dir.join("crates").join("foo-1.0.0.crate");
+            fs::write(&path, "foo").unwrap();
+            let stamp = Stamp::of(&path).unwrap().unwrap();
+
+            let mut sync = ChecksumDb::load(dir).unwrap();
+            for hash in ["a", "b", "c"] {
+                sync.insert_on_disk(
+                    "crates/foo-1.0.0.crate".to_string(),
+                    stamp,
+                    Sha256,
+                    &hash.repeat(64),
+                )
+                .unwrap();
+            }
+            // A line the sync is still in the middle of writing.
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(dir.join(CHECKSUMS_FILE))
+                .unwrap();
+            write!(file, "{}  dist/2024-01", "f".repeat(64)).unwrap();
+            drop(file);
+
+            // Neither the superseded lines nor the partial one make another process replace
+            // the file the sync is appending to.
+            let mut maintain = ChecksumDb::load(dir).unwrap();
+            assert!(!maintain.compact_if_alone().unwrap());
+            writeln!(sync.file, "-01/cargo.tar.xz").unwrap();
+            sync.insert_first_seen("crates/foo-1.0.0.crate", &"d".repeat(64))
+                .unwrap();
+            assert_eq!(
+                maintain.first_seen("dist/2024-01-01/cargo.tar.xz").unwrap(),
+                Some("f".repeat(64))
+            );
+            assert_eq!(
+                maintain.first_seen("crates/foo-1.0.0.crate").unwrap(),
+                Some("d".repeat(64))
+            );
+
+            // Once the sync is done, it's compacted.
+            drop(sync);
+            assert!(maintain.compact_if_alone().unwrap());
+            assert_eq!(maintain.superseded, 0);
+            drop(maintain);
+            assert_eq!(load(dir).unwrap().len(), 2);
+        }
+
+        #[test]
+        fn lines() {
+            let hash = "0".repeat(64);
+            assert_eq!(
+                parse_line(&format!("{hash}  dist/a b")),
+                Some(Line::FirstSeen {
+                    path: "dist/a b",
+                    hash: &hash
+                })
+            );
+            assert_eq!(
+                parse_line("#- dist/a"),
+                Some(Line::Removed { path: "dist/a" })
+            );
+            assert_eq!(
+                parse_line(&format!("#blake3:{hash} 3 1 dist/a")),
+                Some(Line::OnDisk {
+                    path: "dist/a",
+                    stamp: Stamp {
+                        size: 3,
+                        modified: 1
+                    },
+                    algorithm: Blake3,
+                    hash: &hash
+                })
+            );
+            assert_eq!(parse_line("#abc 3 1 dist/a"), None);
+            assert_eq!(parse_line(&format!("#sha512:{hash} 3 1 dist/a")), None);
+        }
+    }
+}
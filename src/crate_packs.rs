@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use log::info;
+use sha2::{Digest, Sha256};
+
+use crate::mirror::ConfigCrates;
+
+/// Directory of the packs and their index, within the mirror.
+pub const PACKS_DIR: &str = "crates/packs";
+
+/// Name of the index of the packs, which has a line per crate file: its path within the
+/// mirror, the number of its pack, its offset and length there, and its SHA-256 hash.
+const INDEX_FILE: &str = "index";
+
+/// Size a pack grows to before the next one is started: 1 GiB.
+const MAX_PACK_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Whether crate files downloaded in this run are moved into packs.
+static ENABLED: OnceLock<bool> = OnceLock::new();
+
+/// The packs of the mirror of this run, opened when first needed.
+static PACKS: OnceLock<Packs> = OnceLock::new();
+
+/// Use the packed setting from mirror.toml for the rest of this run.
+pub fn init(crates: &ConfigCrates) {
+    // Only the first call sets it, as there's only one mirror per run.
+    let _ = ENABLED.set(crates.packed.unwrap_or(false));
+}
+
+pub fn enabled() -> bool {
+    ENABLED.get().copied().unwrap_or(false)
+}
+
+/// Get the packs of a mirror. Every call in a run is for the same mirror.
+pub fn get(mirror_path: &Path) -> &'static Packs {
+    PACKS.get_or_init(|| Packs::open(mirror_path))
+}
+
+/// Where a crate file is within the packs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub pack: u32,
+    pub offset: u64,
+    pub len: u64,
+    pub sha256: String,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    /// How much of the index file has been read. Other processes, like a sync while the mirror
+    /// is being served, only ever append to it, so the rest is read when a file isn't found.
+    read_to: u64,
+    /// The pack being appended to.
+    current: u32,
+}
+
+/// Crate files stored one after the other in large append-only files, instead of a file each,
+/// which saves an inode and a block's worth of slack for every crate in a full mirror.
+///
+/// A file's bytes are written to its pack before its line is added to the index, so a crash
+/// in between leaves some unused bytes in the pack, but never an index entry for a file that
+/// isn't there. Files in packs are never removed, as crates.io never deletes a crate file.
+pub struct Packs {
+    dir: PathBuf,
+    index: Mutex<Index>,
+}
+
+impl Packs {
+    fn open(mirror_path: &Path) -> Self {
+        Packs {
+            dir: mirror_path.join(PACKS_DIR),
+            index: Mutex::new(Index::default()),
+        }
+    }
+
+    /// Path of a pack, by its number.
+    pub fn pack_path(&self, pack: u32) -> PathBuf {
+        self.dir.join(format!("pack-{pack:06}.pack"))
+    }
+
+    /// Read the lines added to the index file since it was last read.
+    fn refresh(&self, index: &mut Index) -> io::Result<()> {
+        let mut file = match File::open(self.dir.join(INDEX_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        file.seek(SeekFrom::Start(index.read_to))?;
+        let mut new = Vec::new();
+        file.read_to_end(&mut new)?;
+        // A line still being written is read next time.
+        let Some(complete) = new.iter().rposition(|&b| b == b'\n').map(|end| end + 1) else {
+            return Ok(());
+        };
+        for line in String::from_utf8_lossy(&new[..complete]).lines() {
+            if let Some((file, entry)) = parse_line(line) {
+                index.current = index.current.max(entry.pack);
+                index.entries.insert(file, entry);
+            }
+        }
+        index.read_to += complete as u64;
+        Ok(())
+    }
+
+    /// Where a crate file is, by its path within the mirror, if it's in a pack.
+    pub fn get(&self, file: &str) -> Option<Entry> {
+        let mut index = self.index.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = index.entries.get(file) {
+            return Some(entry.clone());
+        }
+        if let Err(e) = self.refresh(&mut index) {
+            eprintln!("Could not read the crate pack index: {e}");
+        }
+        index.entries.get(file).cloned()
+    }
+
+    pub fn contains(&self, file: &str) -> bool {
+        self.get(file).is_some()
+    }
+
+    /// Move a crate file into the current pack, by its path within the mirror and where it is
+    /// on disk now.
+    pub fn store(&self, file: &str, loose_path: &Path) -> io::Result<()> {
+        let mut index = self.index.lock().unwrap_or_else(|e| e.into_inner());
+        self.refresh(&mut index)?;
+        fs::create_dir_all(&self.dir)?;
+
+        let data = fs::read(loose_path)?;
+        let mut pack_path = self.pack_path(index.current);
+        if fs::metadata(&pack_path).is_ok_and(|m| m.len() >= MAX_PACK_SIZE) {
+            index.current += 1;
+            pack_path = self.pack_path(index.current);
+        }
+        let mut pack = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&pack_path)?;
+        let offset = pack.seek(SeekFrom::End(0))?;
+        pack.write_all(&data)?;
+        pack.sync_data()?;
+
+        let entry = Entry {
+            pack: index.current,
+            offset,
+            len: data.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(&data)),
+        };
+        let mut index_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(INDEX_FILE))?;
+        index_file.write_all(format_line(file, &entry).as_bytes())?;
+        index_file.sync_data()?;
+        // The line is read back in on the next refresh, along with any from other processes.
+        index.entries.insert(file.to_string(), entry);
+        drop(index);
+
+        info!("Packed {file}");
+        fs::remove_file(loose_path)?;
+        // The version and crate directories are usually left empty, and would use up the
+        // inodes packing saves.
+        for dir in loose_path.ancestors().skip(1).take(2) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_line(file: &str, entry: &Entry) -> String {
+    format!(
+        "{file} {} {} {} {}\n",
+        entry.pack, entry.offset, entry.len, entry.sha256
+    )
+}
+
+fn parse_line(line: &str) -> Option<(String, Entry)> {
+    let mut parts = line.split(' ');
+    let file = parts.next()?.to_string();
+    let entry = Entry {
+        pack: parts.next()?.parse().ok()?,
+        offset: parts.next()?.parse().ok()?,
+        len: parts.next()?.parse().ok()?,
+        sha256: parts.next()?.to_string(),
+    };
+    if parts.next().is_some() || entry.sha256.len() != 64 {
+        return None;
+    }
+    Some((file, entry))
+}
+
+/// Move the crate files downloaded to the mirror into packs, if packed storage is on. Files
+/// that aren't on disk, because they failed to download or were packed already, are skipped.
+pub fn pack_downloaded<'a>(mirror_path: &Path, files: impl IntoIterator<Item = &'a str>) {
+    if !enabled() {
+        return;
+    }
+    let packs = get(mirror_path);
+    for file in files {
+        let loose_path = mirror_path.join(file);
+        if !loose_path.exists() {
+            continue;
+        }
+        if let Err(e) = packs.store(file, &loose_path) {
+            eprintln!("Could not pack {file}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod packs {
+        use std::fs;
+
+        use crate::crate_packs::{parse_line, Packs};
+
+        #[test]
+        fn store_and_get() {
+            let dir = tempfile::tempdir().unwrap();
+            let loose = dir.path().join("crates/1/a/0.1.0/a-0.1.0.crate");
+            fs::create_dir_all(loose.parent().unwrap()).unwrap();
+            fs::write(&loose, "crate a").unwrap();
+            let packs = Packs::open(dir.path());
+            packs
+                .store("crates/1/a/0.1.0/a-0.1.0.crate", &loose)
+                .unwrap();
+            assert!(!loose.exists());
+
+            // Another process, like serve, sees it once it looks for it.
+            let other = Packs::open(dir.path());
+            let entry = other.get("crates/1/a/0.1.0/a-0.1.0.crate").unwrap();
+            let pack = fs::read(other.pack_path(entry.pack)).unwrap();
+            let start = entry.offset as usize;
+            assert_eq!(&pack[start..start + entry.len as usize], b"crate a");
+            assert!(other.get("crates/1/b/0.1.0/b-0.1.0.crate").is_none());
+        }
+
+        #[test]
+        fn partial_lines_ignored() {
+            assert!(parse_line("crates/1/a/0.1.0/a-0.1.0.crate 0 16 7").is_none());
+            assert!(parse_line("crates/1/a/0.1.0/a-0.1.0.crate 0 16 7 abc").is_none());
+            let sha256 = "0".repeat(64);
+            let (file, entry) =
+                parse_line(&format!("crates/1/a/0.1.0/a-0.1.0.crate 2 16 7 {sha256}")).unwrap();
+            assert_eq!(file, "crates/1/a/0.1.0/a-0.1.0.crate");
+            assert_eq!((entry.pack, entry.offset, entry.len), (2, 16, 7));
+        }
+    }
+}
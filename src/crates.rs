@@ -1,17 +1,24 @@
+use crate::checksums;
+use crate::crate_packs;
 use crate::crates_index::{fast_forward, IndexSyncError};
-use crate::download::{download, DownloadError};
-use crate::mirror::{ConfigCrates, ConfigMirror};
-use crate::progress_bar::padded_prefix_message;
-use futures::StreamExt;
+use crate::disk::LowDiskSpace;
+use crate::download::{DownloadClient, DownloadError};
+use crate::events::{self, Action};
+use crate::maintenance::MaintenanceGuard;
+use crate::mirror::{ConfigCrates, ConfigMirror, Shard};
+use crate::plan::{
+    execute_downloads, run_download, DeleteTask, DownloadTask, SharedDownloads, Task,
+};
+use crate::progress_bar::{padded_prefix_message, progress_bar, progress_spinner};
+use crate::url_path::MirrorPath;
 use git2::Repository;
-use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+use log::info;
 use reqwest::header::HeaderValue;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::read_dir;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
 use std::{
     fs,
     io::{self, BufRead, Cursor},
@@ -34,7 +41,21 @@ pub enum SyncError {
 
     #[error("Index syncing error: {0}")]
     IndexSync(#[from] IndexSyncError),
+
+    #[error("Disk space error: {0}")]
+    DiskSpace(#[from] LowDiskSpace),
+
+    #[error("{0} crate file(s) could not be downloaded, so the served index was left as it was")]
+    IndexHeldBack(usize),
 }
+/// Size of a crate file assumed for the disk space check, before the mirror has any.
+const DEFAULT_CRATE_SIZE: u64 = 100_000;
+
+/// Bytes of crate file guessed for each byte of its entry in the index, for crates the mirror
+/// has no version of yet. Crates with more dependencies and features take up more of the index,
+/// and tend to be bigger.
+const CRATE_BYTES_PER_INDEX_BYTE: u64 = 100;
+
 /// One entry found in a crates.io-index file.
 /// These files are formatted as lines of JSON.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,6 +64,9 @@ pub struct CrateEntry {
     vers: String,
     cksum: Option<String>,
     yanked: Option<bool>,
+    /// Length of its line in the index, or 0 if it wasn't read from the index.
+    #[serde(skip)]
+    index_len: usize,
 }
 
 impl CrateEntry {
@@ -55,55 +79,93 @@ impl CrateEntry {
     }
 }
 
+/// Build the URL to download a crate file from.
+///
+/// If source is "https://crates.io/api/v1/crates" (the default, and thus a None here)
+/// download straight from the static.crates.io CDN, to avoid bogging down crates.io itself
+/// or affecting its statistics, and avoiding an extra redirect for each crate.
+///
+/// If source contains any of cargo's `{crate}`, `{version}`, `{prefix}`, `{lowerprefix}`
+/// or `{sha256-checksum}` markers, it is used as a template for non-standard upstream layouts.
+fn crate_download_url(source: Option<&str>, crate_entry: &CrateEntry) -> Option<String> {
+    let name = &crate_entry.name;
+    let vers = &crate_entry.vers;
+    match source {
+        None => Some(format!(
+            "https://static.crates.io/crates/{name}/{name}-{vers}.crate"
+        )),
+        Some(template) if template.contains('{') => {
+            let prefix = crate_prefix(name)?;
+            Some(
+                template
+                    .replace("{crate}", name)
+                    .replace("{version}", vers)
+                    .replace("{prefix}", &prefix)
+                    .replace("{lowerprefix}", &prefix.to_lowercase())
+                    .replace(
+                        "{sha256-checksum}",
+                        crate_entry.cksum.as_deref().unwrap_or_default(),
+                    ),
+            )
+        }
+        Some(source) => Some(format!("{source}/{name}/{vers}/download")),
+    }
+}
+
+/// The download task for one crate file.
+fn crate_download_task(
+    sources: &[String],
+    crate_entry: &CrateEntry,
+) -> Result<DownloadTask, DownloadError> {
+    let bad_crate = || DownloadError::BadCrate(crate_entry.name.clone());
+    let urls = sources
+        .iter()
+        .map(|source| {
+            let source = (source != "https://crates.io/api/v1/crates").then_some(source.as_str());
+            crate_download_url(source, crate_entry)
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(bad_crate)?;
+
+    let file = crate_file(&crate_entry.name, &crate_entry.vers)
+        .and_then(|file| MirrorPath::parse(&file).ok())
+        .ok_or_else(bad_crate)?;
+
+    Ok(DownloadTask {
+        file,
+        urls,
+        sha256: crate_entry.cksum.clone(),
+        size: estimated_crate_size(crate_entry),
+    })
+}
+
 /// Download one single crate file.
 pub async fn sync_one_crate_entry(
-    client: &Client,
+    client: &DownloadClient,
     path: &Path,
-    source: Option<&str>,
+    sources: &[String],
     retries: usize,
     crate_entry: &CrateEntry,
     user_agent: &HeaderValue,
 ) -> Result<(), DownloadError> {
-    // If source is "https://crates.io/api/v1/crates" (the default, and thus a None here)
-    // download straight from the static.crates.io CDN, to avoid bogging down crates.io itself
-    // or affecting its statistics, and avoiding an extra redirect for each crate.
-    let url = if let Some(source) = source {
-        format!(
-            "{}/{}/{}/download",
-            source, crate_entry.name, crate_entry.vers
-        )
-    } else {
-        format!(
-            "https://static.crates.io/crates/{}/{}-{}.crate",
-            crate_entry.name, crate_entry.name, crate_entry.vers
-        )
-    };
-
-    let file_path = get_crate_path(path, &crate_entry.name, &crate_entry.vers)
-        .ok_or_else(|| DownloadError::BadCrate(crate_entry.name.clone()))?;
-
-    download(
-        client,
-        &url[..],
-        &file_path,
-        crate_entry.cksum.as_deref(),
-        retries,
-        false,
-        user_agent,
-    )
-    .await
+    let task = crate_download_task(sources, crate_entry)?;
+    if crate_packs::enabled() && crate_packs::get(path).contains(task.file.as_str()) {
+        return Ok(());
+    }
+    run_download(client, path, &task, retries, user_agent).await?;
+    crate_packs::pack_downloaded(path, [task.file.as_str()]);
+    Ok(())
 }
 
-/// Synchronize the crate files themselves, using the index for a list of files.
-// TODO: There are still many unwraps in the foreach sections. This needs to be fixed.
-pub async fn sync_crates_files(
-    path: &Path,
+/// Find the crates changed in the index since the last sync, and the index files removed.
+///
+/// Only the crates in the vendor directory or Cargo.lock are returned if either is given.
+fn changed_crates(
+    repo: &Repository,
     vendor_path: Option<PathBuf>,
     cargo_lock_filepath: Option<PathBuf>,
-    mirror: &ConfigMirror,
-    crates: &ConfigCrates,
-    user_agent: &HeaderValue,
-) -> Result<(), SyncError> {
+    prefix: String,
+) -> Result<(Vec<CrateEntry>, Vec<PathBuf>), SyncError> {
     let is_crate_whitelist_only = vendor_path.is_some() || cargo_lock_filepath.is_some();
 
     // if a vendor_path, parse the filepath for Cargo.toml files for each crate, filling vendors
@@ -112,19 +174,6 @@ pub async fn sync_crates_files(
     // gather crates from Cargo.lock if supplied
     cargo_lock_to_mirror_entries(&mut mirror_entries, cargo_lock_filepath.as_ref());
 
-    let prefix = padded_prefix_message(2, 3, "Syncing crates files");
-
-    // For now, assume successful crates.io-index download
-    let repo_path = path.join("crates.io-index");
-    let repo = Repository::open(&repo_path)?;
-
-    // Set the crates.io URL, or None if default
-    let crates_source = if crates.source == "https://crates.io/api/v1/crates" {
-        None
-    } else {
-        Some(crates.source.as_str())
-    };
-
     // Find Reference for origin/master
     let origin_master = repo.find_reference("refs/remotes/origin/master")?;
     let origin_master_tree = origin_master.peel_to_tree()?;
@@ -138,17 +187,7 @@ pub async fn sync_crates_files(
     let mut changed_crates = Vec::new();
     let mut removed_crates = Vec::new();
 
-    let pb = ProgressBar::new_spinner()
-        .with_style(
-            ProgressStyle::default_bar()
-                .template("{prefix} {wide_bar} {spinner} [{elapsed_precise}]")
-                .expect("template is correct")
-                .progress_chars("  "),
-        )
-        .with_finish(ProgressFinish::AndLeave)
-        .with_prefix(prefix.clone());
-    pb.enable_steady_tick(Duration::from_millis(10));
-
+    let pb = progress_spinner(prefix);
     // Figure out which crates we need to update/remove.
     diff.foreach(
         &mut |delta, _| {
@@ -211,7 +250,10 @@ pub async fn sync_crates_files(
                     }
                 };
 
-                changed_crates.push(c);
+                changed_crates.push(CrateEntry {
+                    index_len: line.len(),
+                    ..c
+                });
             }
 
             true
@@ -223,20 +265,6 @@ pub async fn sync_crates_files(
     .unwrap();
 
     pb.finish_and_clear();
-    let pb = ProgressBar::new(changed_crates.len() as u64)
-        .with_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{prefix} {wide_bar} {pos}/{len} [{elapsed_precise} / {duration_precise}]",
-                )
-                .expect("template is correct")
-                .progress_chars("█▉▊▋▌▍▎▏  "),
-        )
-        .with_finish(ProgressFinish::AndLeave)
-        .with_prefix(prefix);
-    pb.enable_steady_tick(Duration::from_millis(10));
-
-    let client = Client::new();
 
     // Dirty hack:
     // Since we can't rely on diff tree because these crates are manually set
@@ -245,38 +273,104 @@ pub async fn sync_crates_files(
         changed_crates.append(&mut mirror_entries);
     }
 
-    let tasks = futures::stream::iter(changed_crates.into_iter())
-        .map(|c| {
-            let client = client.clone();
-            // Duplicate variables used in the async closure.
-            let path = path.to_owned();
-            let mirror_retries = mirror.retries;
-            let crates_source = crates_source.map(|s| s.to_string());
-            let user_agent = user_agent.to_owned();
-            let pb = pb.clone();
-
-            tokio::spawn(async move {
-                let out = sync_one_crate_entry(
-                    &client,
-                    &path,
-                    crates_source.as_deref(),
-                    mirror_retries,
-                    &c,
-                    &user_agent,
-                )
-                .await;
+    Ok((changed_crates, removed_crates))
+}
 
-                pb.inc(1);
+/// Synchronize the crate files themselves, using the index for a list of files.
+// TODO: There are still many unwraps in the foreach sections. This needs to be fixed.
+#[allow(clippy::too_many_arguments)]
+pub async fn sync_crates_files(
+    client: &DownloadClient,
+    path: &Path,
+    vendor_path: Option<PathBuf>,
+    cargo_lock_filepath: Option<PathBuf>,
+    mirror: &ConfigMirror,
+    crates: &ConfigCrates,
+    user_agent: &HeaderValue,
+    shard: Option<Shard>,
+) -> Result<(), SyncError> {
+    let prefix = padded_prefix_message(2, 3, "Syncing crates files");
 
-                out
-            })
+    // For now, assume successful crates.io-index download
+    let repo_path = path.join("crates.io-index");
+    let repo = Repository::open(&repo_path)?;
+
+    let (mut changed_crates, removed_crates) =
+        changed_crates(&repo, vendor_path, cargo_lock_filepath, prefix.clone())?;
+
+    if let Some(shard) = shard {
+        changed_crates.retain(|c| shard.contains(&format!("{}/{}", c.name, c.vers)));
+    }
+
+    crate::disk::warn_if_low_on_inodes(&path.join("crates"), changed_crates.len());
+    let new_crates = changed_crates
+        .iter()
+        .filter(|c| !has_crate_file(path, &c.name, &c.vers))
+        .count();
+    crate::disk::check_free_space(&path.join("crates"), new_crates, DEFAULT_CRATE_SIZE)?;
+
+    if client.small_files_first() {
+        // Sizes are looked up in the mirror, which is a lot of small reads on a big one.
+        let mirror_path = path.to_path_buf();
+        changed_crates = tokio::task::spawn_blocking(move || {
+            sort_by_size(&mirror_path, &mut changed_crates);
+            changed_crates
         })
-        .buffer_unordered(crates.download_threads)
-        .collect::<Vec<_>>()
-        .await;
+        .await
+        .expect("sorting crates shouldn't panic");
+    }
+
+    let pb = progress_bar(changed_crates.len(), prefix);
 
-    for t in tasks {
-        let res = t.unwrap();
+    // Crate files that should be there but aren't. Files upstream doesn't have, or has broken,
+    // won't be any different next time, so they don't count.
+    let mut missing = 0;
+
+    let packs = crate_packs::get(path);
+    let mut tasks = Vec::with_capacity(changed_crates.len());
+    for c in &changed_crates {
+        match crate_download_task(crates.source.all(), c) {
+            // A packed crate file is never downloaded again, as it can't have changed.
+            Ok(task) if crate_packs::enabled() && packs.contains(task.file.as_str()) => {
+                pb.inc(1);
+            }
+            Ok(task) => tasks.push(task),
+            Err(e) => {
+                eprintln!("Downloading failed: {e:?}");
+                pb.inc(1);
+                missing += 1;
+            }
+        }
+    }
+
+    // Crates listed more than once, e.g. in both the index diff and a Cargo.lock, are only
+    // downloaded once.
+    let shared = SharedDownloads::new(crates.download_threads);
+    let files: Vec<MirrorPath> = tasks.iter().map(|task| task.file.clone()).collect();
+    let results = execute_downloads(
+        client,
+        path,
+        tasks,
+        crates.download_threads,
+        mirror.retries,
+        user_agent,
+        &pb,
+        &shared,
+    )
+    .await;
+    crate_packs::pack_downloaded(path, files.iter().map(MirrorPath::as_str));
+
+    for t in results {
+        let res = match t {
+            Ok(res) => res,
+            Err(e) => {
+                // A panicking download shouldn't take the rest of the step down with it.
+                eprintln!("Download task failed: {e}");
+                pb.inc(1);
+                missing += 1;
+                continue;
+            }
+        };
         match res {
             Ok(())
             | Err(DownloadError::NotFound {
@@ -289,26 +383,80 @@ pub async fn sync_crates_files(
                 actual: _,
             }) => {}
 
+            // Crates skipped because the sync is stopping are still missing, but not worth
+            // a line each.
+            Err(DownloadError::Interrupted) => missing += 1,
+
             Err(e) => {
                 eprintln!("Downloading failed: {e:?}");
+                missing += 1;
             }
         }
     }
 
+    // The final sync after all shards are done removes crates and fast-forwards the index.
+    if shard.is_some() {
+        return Ok(());
+    }
+
+    // Cargo would see the new versions in the index and fail to download them, so the index
+    // only moves once every crate file it refers to is here. The next sync diffs from the same
+    // commit, so it tries the missing files again.
+    if missing > 0 {
+        return Err(SyncError::IndexHeldBack(missing));
+    }
+
     // Delete any removed crates
-    for rc in removed_crates {
-        // Try to remove the file, but ignore it if it doesn't exist
-        let _ = fs::remove_file(repo_path.join(rc));
+    if !removed_crates.is_empty() {
+        let _maintenance = MaintenanceGuard::start(path, "crates")?;
+        for rc in removed_crates {
+            info!("Removing {}", rc.display());
+            // Try to remove the file, but ignore it if it doesn't exist
+            let rc = repo_path.join(rc);
+            if fs::remove_file(&rc).is_ok() {
+                events::emit(Action::Deleted, &rc, None, None);
+                let _ = checksums::forget(&rc);
+            }
+        }
     }
 
     // Set master to origin/master.
     //
     // Note that this means config.json changes will have to be rewritten on every sync.
+    let _maintenance = MaintenanceGuard::start(path, "crates.io-index")?;
     fast_forward(&repo_path)?;
 
     Ok(())
 }
 
+/// Work out what a crates sync would download and remove, from the index as last fetched,
+/// without changing the mirror.
+pub fn plan(
+    path: &Path,
+    vendor_path: Option<PathBuf>,
+    cargo_lock_filepath: Option<PathBuf>,
+    crates: &ConfigCrates,
+) -> Result<Vec<Task>, SyncError> {
+    let repo = Repository::open(path.join("crates.io-index"))?;
+    let prefix = padded_prefix_message(1, 1, "Planning crates files");
+    let (changed_crates, removed_crates) =
+        changed_crates(&repo, vendor_path, cargo_lock_filepath, prefix)?;
+
+    let mut tasks = Vec::new();
+    for c in &changed_crates {
+        let task = crate_download_task(crates.source.all(), c)?;
+        if !has_crate_file(path, &c.name, &c.vers) {
+            tasks.push(Task::Download(task));
+        }
+    }
+    tasks.extend(removed_crates.into_iter().map(|rc| {
+        Task::Delete(DeleteTask {
+            file: Path::new("crates.io-index").join(rc),
+        })
+    }));
+    Ok(tasks)
+}
+
 /// Detect if the crates directory is using the old format.
 pub fn is_new_crates_format(path: &Path) -> Result<bool, io::Error> {
     if !path.exists() {
@@ -332,6 +480,8 @@ pub fn is_new_crates_format(path: &Path) -> Result<bool, io::Error> {
             "1" | "2" | "3" => continue,
             // 2-letter directories are used for crates longer than 3 characters.
             x if x.len() == 2 => continue,
+            // Packed crate files, see crate_packs.
+            "packs" => continue,
             // Unrecognized directory found, might be crate in old format.
             _ => {
                 return Ok(false);
@@ -342,25 +492,86 @@ pub fn is_new_crates_format(path: &Path) -> Result<bool, io::Error> {
     Ok(true)
 }
 
-pub fn get_crate_path(
-    mirror_path: &Path,
-    crate_name: &str,
-    crate_version: &str,
-) -> Option<PathBuf> {
-    let crate_path = match crate_name.len() {
-        1 => PathBuf::from("1"),
-        2 => PathBuf::from("2"),
+/// Get the index-style prefix directory of a crate, e.g. "se/rd" for serde.
+pub fn crate_prefix(crate_name: &str) -> Option<String> {
+    match crate_name.len() {
+        1 => Some("1".to_string()),
+        2 => Some("2".to_string()),
         3 => {
             let first_char = crate_name.get(0..1)?;
-            PathBuf::from("3").join(first_char)
+            Some(format!("3/{first_char}"))
         }
         n if n >= 4 => {
             let first_two = crate_name.get(0..2)?;
             let second_two = crate_name.get(2..4)?;
-            [first_two, second_two].iter().collect()
+            Some(format!("{first_two}/{second_two}"))
         }
-        _ => return None,
-    };
+        _ => None,
+    }
+}
+
+/// Guess the size of a crate file from its entry in the index.
+fn estimated_crate_size(crate_entry: &CrateEntry) -> u64 {
+    match crate_entry.index_len {
+        0 => DEFAULT_CRATE_SIZE,
+        len => len as u64 * CRATE_BYTES_PER_INDEX_BYTE,
+    }
+}
+
+/// Get the size of the version of a crate the mirror got most recently, if it has any.
+fn mirrored_crate_size(mirror_path: &Path, crate_name: &str) -> Option<u64> {
+    let crate_dir = get_crate_path(mirror_path, crate_name, "0")
+        .and_then(|path| path.parent()?.parent().map(Path::to_path_buf));
+    let newest = crate_dir
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let version = entry.ok()?.file_name();
+            let version = version.to_string_lossy();
+            let path = get_crate_path(mirror_path, crate_name, &version)?;
+            let metadata = fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .max();
+    newest.map(|(_, size)| size)
+}
+
+/// Sort crates so the smallest are downloaded first, for small_files_first. The index doesn't
+/// give sizes, so each crate's files are guessed to be the size of a version of it already in
+/// the mirror, or from their index entries for crates new to the mirror.
+fn sort_by_size(mirror_path: &Path, crates: &mut [CrateEntry]) {
+    let mut sizes: HashMap<String, Option<u64>> = HashMap::new();
+    crates.sort_by_cached_key(|c| {
+        sizes
+            .entry(c.name.clone())
+            .or_insert_with(|| mirrored_crate_size(mirror_path, &c.name))
+            .unwrap_or_else(|| estimated_crate_size(c))
+    });
+}
+
+/// Get the path of a crate file within the mirror, e.g.
+/// `crates/se/rd/serde/1.0.0/serde-1.0.0.crate`.
+pub fn crate_file(crate_name: &str, crate_version: &str) -> Option<String> {
+    let prefix = crate_prefix(crate_name)?;
+    Some(format!(
+        "crates/{prefix}/{crate_name}/{crate_version}/{crate_name}-{crate_version}.crate"
+    ))
+}
+
+/// Whether the mirror has a crate file, either as a file of its own or in a pack.
+pub fn has_crate_file(mirror_path: &Path, crate_name: &str, crate_version: &str) -> bool {
+    get_crate_path(mirror_path, crate_name, crate_version).is_some_and(|path| path.exists())
+        || crate_file(crate_name, crate_version)
+            .is_some_and(|file| crate_packs::get(mirror_path).contains(&file))
+}
+
+pub fn get_crate_path(
+    mirror_path: &Path,
+    crate_name: &str,
+    crate_version: &str,
+) -> Option<PathBuf> {
+    let crate_path: PathBuf = crate_prefix(crate_name)?.split('/').collect();
 
     Some(
         mirror_path
@@ -394,6 +605,7 @@ pub(crate) fn vendor_path_to_mirror_entries(
                         vers: version,
                         cksum: None,
                         yanked: None,
+                        index_len: 0,
                     });
                 }
             }
@@ -430,6 +642,7 @@ pub(crate) fn cargo_lock_to_mirror_entries(
                                         vers: version,
                                         cksum: Some(checksum),
                                         yanked: None,
+                                        index_len: 0,
                                     });
                                 }
                             }
@@ -442,3 +655,85 @@ pub(crate) fn cargo_lock_to_mirror_entries(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+
+    mod sort_by_size {
+        use std::fs;
+
+        use crate::crates::{get_crate_path, sort_by_size, CrateEntry};
+
+        fn entry(name: &str, index_len: usize) -> CrateEntry {
+            CrateEntry {
+                name: name.to_string(),
+                vers: "1.0.0".to_string(),
+                cksum: None,
+                yanked: None,
+                index_len,
+            }
+        }
+
+        #[test]
+        fn mirrored_then_index_sizes() {
+            let dir = tempfile::tempdir().unwrap();
+            // A tiny version of a crate with a long index entry is already in the mirror.
+            let mirrored = get_crate_path(dir.path(), "serde", "0.9.0").unwrap();
+            fs::create_dir_all(mirrored.parent().unwrap()).unwrap();
+            fs::write(&mirrored, "serde").unwrap();
+
+            let mut crates = vec![
+                entry("tokio", 5000),
+                entry("serde", 4000),
+                entry("itoa", 300),
+            ];
+            sort_by_size(dir.path(), &mut crates);
+            let names: Vec<_> = crates.iter().map(|c| c.name.as_str()).collect();
+            assert_eq!(names, ["serde", "itoa", "tokio"]);
+        }
+    }
+
+    mod download_url {
+        use crate::crates::{crate_download_url, CrateEntry};
+
+        fn entry(name: &str, vers: &str) -> CrateEntry {
+            CrateEntry {
+                name: name.to_string(),
+                vers: vers.to_string(),
+                cksum: Some("abc123".to_string()),
+                yanked: None,
+                index_len: 0,
+            }
+        }
+
+        #[test]
+        fn default_source() {
+            assert_eq!(
+                crate_download_url(None, &entry("serde", "1.0.0")).unwrap(),
+                "https://static.crates.io/crates/serde/serde-1.0.0.crate"
+            );
+        }
+
+        #[test]
+        fn plain_source() {
+            assert_eq!(
+                crate_download_url(Some("https://example.com/api"), &entry("serde", "1.0.0"))
+                    .unwrap(),
+                "https://example.com/api/serde/1.0.0/download"
+            );
+        }
+
+        #[test]
+        fn template_source() {
+            let template = "https://example.com/{prefix}/{lowerprefix}/{crate}-{version}?sha={sha256-checksum}";
+            assert_eq!(
+                crate_download_url(Some(template), &entry("Serde", "1.0.0")).unwrap(),
+                "https://example.com/Se/rd/se/rd/Serde-1.0.0?sha=abc123"
+            );
+            assert_eq!(
+                crate_download_url(Some(template), &entry("cde", "0.1.1")).unwrap(),
+                "https://example.com/3/c/3/c/cde-0.1.1?sha=abc123"
+            );
+        }
+    }
+}
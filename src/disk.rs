@@ -0,0 +1,181 @@
+use std::path::Path;
+use std::process::Command;
+use std::sync::OnceLock;
+
+use thiserror::Error;
+use walkdir::WalkDir;
+
+use crate::mirror::ConfigMirror;
+
+/// How many existing files are looked at to guess the size of new ones.
+const SIZE_SAMPLE: usize = 1000;
+
+/// Whether syncs check for enough free space before downloading.
+static CHECK_DISK_SPACE: OnceLock<bool> = OnceLock::new();
+
+#[derive(Error, Debug)]
+#[error(
+    "{path} has {:.2} GB free, but about {:.2} GB of new files are needed",
+    *free as f64 / 1e9,
+    *needed as f64 / 1e9
+)]
+pub struct LowDiskSpace {
+    pub path: String,
+    pub free: u64,
+    pub needed: u64,
+}
+
+/// Use the check_disk_space setting from mirror.toml for the rest of this run.
+pub fn init(mirror: &ConfigMirror) {
+    let _ = CHECK_DISK_SPACE.set(mirror.check_disk_space.unwrap_or(true));
+}
+
+/// Run `df` with `flags` on the filesystem containing `path`, which may not exist yet.
+fn df(flags: &str, path: &Path) -> Option<String> {
+    let path = path.ancestors().find(|p| p.exists())?;
+    let output = Command::new("df").arg(flags).arg(path).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Get the number of free inodes on the filesystem containing `path`.
+///
+/// There's no portable way of asking for this without unsafe code, so this asks `df`.
+/// Returns None if `df` isn't available, or the filesystem doesn't report inodes.
+pub fn free_inodes(path: &Path) -> Option<u64> {
+    parse_df_free_inodes(&df("-Pi", path)?)
+}
+
+/// Get the bytes free for unprivileged users on the filesystem containing `path`, by asking
+/// `df` like `free_inodes`.
+pub fn free_space(path: &Path) -> Option<u64> {
+    parse_df_available(&df("-Pk", path)?).map(|kib| kib * 1024)
+}
+
+/// Find the available space column in `df -Pk` output, in KiB. POSIX output has the same
+/// columns everywhere.
+fn parse_df_available(output: &str) -> Option<u64> {
+    let mut lines = output.lines();
+    let column = lines
+        .next()?
+        .split_whitespace()
+        .position(|c| c.eq_ignore_ascii_case("available"))?;
+    lines.next()?.split_whitespace().nth(column)?.parse().ok()
+}
+
+/// The mean size of files under `dir`, from a sample of them. Manifests and checksum sidecars
+/// are left out, as they're much smaller than the files being downloaded.
+fn mean_file_size(dir: &Path) -> Option<u64> {
+    let sizes: Vec<u64> = WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy();
+            e.file_type().is_file()
+                && crate::hashes::sidecar_of(e.path()).is_none()
+                && !name.ends_with(".toml")
+        })
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .take(SIZE_SAMPLE)
+        .collect();
+    (!sizes.is_empty()).then(|| sizes.iter().sum::<u64>() / sizes.len() as u64)
+}
+
+/// Make sure there's room under `dir` for `new_files` more files, before a sync starts on them.
+///
+/// Neither channel manifests nor the crates index give file sizes, so each new file is taken
+/// to be the mean size of those already in `dir`, or `default_size` in an empty mirror. A full
+/// disk otherwise shows up as one IO error after another, partway through the sync.
+pub fn check_free_space(
+    dir: &Path,
+    new_files: usize,
+    default_size: u64,
+) -> Result<(), LowDiskSpace> {
+    if new_files == 0 || !CHECK_DISK_SPACE.get().copied().unwrap_or(true) {
+        return Ok(());
+    }
+    let Some(free) = free_space(dir) else {
+        return Ok(());
+    };
+    let needed = new_files as u64 * mean_file_size(dir).unwrap_or(default_size);
+    if free < needed {
+        return Err(LowDiskSpace {
+            path: dir.display().to_string(),
+            free,
+            needed,
+        });
+    }
+    Ok(())
+}
+
+/// Find the free inodes column in `df -Pi` output. This is "IFree" on Linux, and "ifree" on
+/// macOS and the BSDs, where it's also in a different position.
+fn parse_df_free_inodes(output: &str) -> Option<u64> {
+    let mut lines = output.lines();
+    let column = lines
+        .next()?
+        .split_whitespace()
+        .position(|c| c.eq_ignore_ascii_case("ifree"))?;
+    lines.next()?.split_whitespace().nth(column)?.parse().ok()
+}
+
+/// Warn if creating `new_files` files under `path` would likely exhaust the filesystem's inodes.
+///
+/// Each file is counted twice, as most also get a new directory.
+pub fn warn_if_low_on_inodes(path: &Path, new_files: usize) {
+    let Some(free) = free_inodes(path) else {
+        return;
+    };
+    let needed = new_files as u64 * 2;
+    if free < needed {
+        eprintln!(
+            "WARNING: {} has {free} free inodes, but this sync may need up to {needed}.",
+            path.display()
+        );
+        eprintln!("The sync will likely fail partway with \"No space left on device\", even if");
+        eprintln!("there is free disk space. Consider a filesystem with more inodes, such as one");
+        eprintln!(
+            "created with a lower bytes-per-inode ratio, or one that allocates them dynamically."
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod df {
+        use crate::disk::{parse_df_available, parse_df_free_inodes};
+
+        #[test]
+        fn linux() {
+            let output = "Filesystem       Inodes  IUsed    IFree IUse% Mounted on\n\
+                          /dev/vda       16777216 474867 16302349    3% /\n";
+            assert_eq!(parse_df_free_inodes(output), Some(16302349));
+        }
+
+        #[test]
+        fn macos() {
+            let output =
+                "Filesystem 512-blocks Used Available Capacity iused ifree %iused Mounted on\n\
+                          /dev/disk1s1 976490576 21 491 2% 488 4881964 0% /\n";
+            assert_eq!(parse_df_free_inodes(output), Some(4881964));
+        }
+
+        #[test]
+        fn available() {
+            let output = "Filesystem     1024-blocks     Used Available Capacity Mounted on\n\
+                          /dev/vda         264212084 25823432  74978844      26% /\n";
+            assert_eq!(parse_df_available(output), Some(74978844));
+        }
+
+        #[test]
+        fn no_inodes() {
+            let output = "Filesystem Inodes IUsed IFree IUse% Mounted on\n\
+                          tmpfs - - - - /tmp\n";
+            assert_eq!(parse_df_free_inodes(output), None);
+        }
+    }
+}
@@ -1,12 +1,41 @@
-use reqwest::header::{HeaderValue, USER_AGENT};
-use reqwest::Client;
+use crate::checksums;
+use crate::events::{self, Action};
+use crate::hashes::{self, parse_expected, HashAlgorithm};
+use crate::host_stats;
+use crate::http_cache;
+use crate::mirror::ConfigMirror;
+use crate::shutdown;
+use crate::staging;
+use crate::storage;
+use crate::throttle;
+use crate::validators::{self, Validators};
+use log::{debug, info};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, RANGE, USER_AGENT,
+};
+use reqwest::{
+    Certificate, Client, ClientBuilder, Identity, NoProxy, Proxy, RequestBuilder, Response,
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::fs::File;
-use std::io::Write;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{fs, io};
 use thiserror::Error;
 use tokio::io::AsyncReadExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use warp::hyper::client::connect::dns::Name;
 
 #[derive(Error, Debug)]
 pub enum DownloadError {
@@ -24,22 +53,621 @@ pub enum DownloadError {
         url: String,
         data: String,
     },
+    #[error("Could not load CA certificate {path}: {error}")]
+    CaCert { path: String, error: String },
+    #[error("Could not load client certificate {path}: {error}")]
+    ClientCert { path: String, error: String },
+    #[error("Server error. Status: {status}, URL: {url}")]
+    ServerError { status: u16, url: String },
+    #[error("Client error. Status: {status}, URL: {url}")]
+    ClientError { status: u16, url: String },
+    #[error("No upstream URLs to download from")]
+    NoSources,
+    #[error("Could not resolve {url} with url_resolver: {error}")]
+    UrlResolver { url: String, error: String },
+    #[error("Invalid header {name}: {error}")]
+    InvalidHeader { name: String, error: String },
+    #[error("Timed out waiting for data from {0}")]
+    ReadTimeout(String),
+    #[error("Download of {url} ended after {actual} of {expected} bytes")]
+    ShortRead {
+        url: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Upstream changed {path} - first seen with hash '{first_seen}', now '{actual}'")]
+    UpstreamChanged {
+        path: String,
+        first_seen: String,
+        actual: String,
+    },
+    #[error("{0} doesn't start with a SHA-256 hash")]
+    BadSha256File(String),
+
+    #[error("Not downloaded, as the sync is stopping")]
+    Interrupted,
+}
+
+impl DownloadError {
+    /// Whether trying again might succeed: network trouble, timeouts, and errors upstream
+    /// says are temporary. Errors like a missing file or a bad certificate will just happen
+    /// again, so they don't use up retries.
+    fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Download(e) => !(e.is_builder() || e.is_redirect() || is_tls_error(e)),
+            DownloadError::ReadTimeout(_)
+            | DownloadError::ShortRead { .. }
+            | DownloadError::ServerError { .. }
+            | DownloadError::UrlResolver { .. } => true,
+            DownloadError::ClientError { status, .. } => {
+                *status == StatusCode::REQUEST_TIMEOUT.as_u16()
+                    || *status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+            }
+            // A mismatched hash has its own, smaller allowance in download_attempts.
+            _ => false,
+        }
+    }
+}
+
+/// Whether a request failed in the TLS handshake, like on an untrusted or expired certificate,
+/// rather than on the connection under it, which waiting won't fix.
+fn is_tls_error(e: &reqwest::Error) -> bool {
+    let mut tls = false;
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        if e.is::<native_tls::Error>() {
+            tls = true;
+        } else if tls && e.is::<io::Error>() {
+            // The connection was lost during the handshake.
+            return false;
+        }
+        source = e.source();
+    }
+    tls
+}
+
+/// Attempts at a download from each mirror that can end in a mismatched hash, in case the file
+/// was damaged on the way. If it's still wrong after that, it's what that mirror has, so trying
+/// it again won't help, though another mirror may have the right file.
+const MISMATCHED_HASH_ATTEMPTS: usize = 2;
+
+/// Default seconds to wait for a connection to upstream.
+const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+
+/// Default seconds to wait for more data from upstream, before retrying.
+const DEFAULT_READ_TIMEOUT: u64 = 60;
+
+/// How long to wait between attempts at a download.
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    /// Seconds to wait before the first retry.
+    delay: f64,
+    /// How much longer to wait before each retry than the one before.
+    multiplier: f64,
+    /// Most seconds to wait before any retry.
+    max_delay: f64,
+    /// Fraction of each wait that is random, so workers that failed together don't all retry
+    /// at the same moment.
+    jitter: f64,
+}
+
+impl Backoff {
+    fn new(mirror: &ConfigMirror) -> Self {
+        Backoff {
+            delay: mirror.retry_delay.unwrap_or(1.0).max(0.0),
+            multiplier: mirror.retry_multiplier.unwrap_or(2.0).max(1.0),
+            max_delay: mirror.retry_max_delay.unwrap_or(60.0).max(0.0),
+            jitter: mirror.retry_jitter.unwrap_or(0.5).clamp(0.0, 1.0),
+        }
+    }
+
+    /// How long to wait before retry number `retry` (starting at 0), given a `random` number
+    /// between 0 and 1.
+    fn delay(&self, retry: u32, random: f64) -> Duration {
+        let delay = (self.delay * self.multiplier.powi(retry as i32)).min(self.max_delay);
+        Duration::from_secs_f64(delay * (1.0 - self.jitter * random))
+    }
+}
+
+/// Default number of parallel ranged requests for a file above chunked_download_threshold.
+const DEFAULT_DOWNLOAD_CHUNKS: u64 = 4;
+
+/// Files of at least `threshold` bytes are downloaded in `chunks` parallel ranged requests, as
+/// a single stream can't fill a link with high latency.
+#[derive(Debug, Clone, Copy)]
+struct Chunking {
+    threshold: u64,
+    chunks: u64,
+}
+
+/// The download settings from mirror.toml, used by every request a `DownloadClient` makes.
+#[derive(Debug, Clone)]
+struct DownloadSettings {
+    /// How long a download can go without receiving any data. reqwest only has a timeout for
+    /// the whole request, which would cut off large files on slow links, so this is applied to
+    /// each read instead.
+    read_timeout: Duration,
+    backoff: Backoff,
+    chunking: Option<Chunking>,
+    /// Most connections open to any one upstream host at a time, shared by all downloads.
+    max_connections_per_host: Option<usize>,
+    /// Extra headers to send with every request to a source, by URL prefix.
+    source_headers: Vec<(String, HeaderMap)>,
+    /// Command that turns each upstream URL into the URL actually requested, e.g. a pre-signed
+    /// one from an egress gateway.
+    url_resolver: Option<Vec<String>>,
+    /// Whether files already on disk without a known hash are checked before being skipped.
+    verify_existing: bool,
+    /// Whether downloads are started smallest first, as far as their size can be guessed.
+    small_files_first: bool,
+}
+
+impl DownloadSettings {
+    /// The settings in `mirror`, with `source_headers` as pairs of a URL prefix and the extra
+    /// headers for URLs starting with it.
+    fn new(mirror: &ConfigMirror, source_headers: Vec<(String, HeaderMap)>) -> Self {
+        DownloadSettings {
+            read_timeout: Duration::from_secs(mirror.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT)),
+            backoff: Backoff::new(mirror),
+            chunking: mirror.chunked_download_threshold.map(|threshold| Chunking {
+                threshold,
+                chunks: mirror
+                    .download_chunks
+                    .unwrap_or(DEFAULT_DOWNLOAD_CHUNKS)
+                    .max(1),
+            }),
+            max_connections_per_host: mirror.max_connections_per_host.map(|n| n.max(1)),
+            source_headers,
+            url_resolver: mirror.url_resolver.clone(),
+            verify_existing: mirror.verify_existing.unwrap_or(false),
+            small_files_first: mirror.small_files_first.unwrap_or(false),
+        }
+    }
+}
+
+/// The HTTP clients for a run, along with the settings their downloads use.
+///
+/// Clones share their connections, and the per-host connection limit, so one client can be
+/// handed to every download of a sync.
+#[derive(Clone)]
+pub struct DownloadClient {
+    client: Client,
+    /// Client for text files, like manifests and .sha256 files, which asks for them gzipped.
+    ///
+    /// Everything else is downloaded by a client that doesn't, as it's compressed already, and
+    /// a client that asks for gzip also unpacks responses that say they're gzipped without
+    /// being asked, which would change the hash of a file a server labels that way.
+    text_client: Client,
+    settings: Arc<DownloadSettings>,
+    /// The connections free for each upstream host, by origin.
+    host_connections: Arc<Mutex<BTreeMap<String, Arc<Semaphore>>>>,
+}
+
+/// Extensions of the files worth asking for in a compressed transfer.
+const TEXT_EXTENSIONS: &[&str] = &[".toml", ".sha256", ".json"];
+
+/// A random number between 0 and 1, for jitter.
+fn random_fraction() -> f64 {
+    // Every RandomState is seeded differently, which is random enough to spread out retries.
+    let random = RandomState::new().build_hasher().finish();
+    random as f64 / u64::MAX as f64
+}
+
+/// Which addresses of upstream hosts to connect to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    /// IPv6 and IPv4 addresses, falling back to the other family if the first address tried
+    /// doesn't connect quickly ("happy eyeballs").
+    #[default]
+    Any,
+    /// Only IPv4 addresses.
+    Ipv4,
+    /// Only IPv6 addresses.
+    Ipv6,
+}
+
+impl IpFamily {
+    fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Ipv4 => ip.is_ipv4(),
+            Self::Ipv6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// Whether downloads use HTTP/2, which can carry many downloads at once over one connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Http2 {
+    /// Only use HTTP/1.1.
+    #[default]
+    Off,
+    /// Use HTTP/2 with upstreams that offer it when connecting over TLS, and HTTP/1.1 otherwise.
+    Alpn,
+    /// Always use HTTP/2, without asking first. Only for upstreams known to support it,
+    /// including over plain HTTP.
+    PriorKnowledge,
+}
+
+/// Resolves upstream hosts with the system resolver, keeping only the addresses of one family,
+/// so a site with broken routing for the other doesn't wait on it for every new connection.
+struct FamilyResolver(IpFamily);
+
+impl Resolve for FamilyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let family = self.0;
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| family.allows(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                let family = if family == IpFamily::Ipv4 {
+                    "IPv4"
+                } else {
+                    "IPv6"
+                };
+                return Err(format!("{host} has no {family} addresses").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Build the HTTP client for a sync.
+///
+/// One client is shared by all of a sync's downloads, so connections to upstream are pooled
+/// and reused, rather than opened again for every file. `source_headers` are the extra headers
+/// to send, as pairs of a URL prefix and the headers for URLs starting with it.
+pub fn new_client(
+    mirror: &ConfigMirror,
+    source_headers: Vec<(String, HeaderMap)>,
+    user_agent: &HeaderValue,
+) -> Result<DownloadClient, DownloadError> {
+    Ok(DownloadClient {
+        client: client_builder(mirror, user_agent)?.no_gzip().build()?,
+        text_client: client_builder(mirror, user_agent)?.gzip(true).build()?,
+        settings: Arc::new(DownloadSettings::new(mirror, source_headers)),
+        host_connections: Arc::default(),
+    })
+}
+
+/// Whether a proxy URL is for a SOCKS proxy, e.g. "socks5h://proxy.example.com:1080".
+pub fn is_socks_proxy(proxy_url: &str) -> bool {
+    proxy_url.starts_with("socks")
+}
+
+/// The proxy from mirror.toml as a URL, with proxy_username and proxy_password in it, if
+/// they're set. Returns None if there's no proxy, or its URL can't be parsed.
+pub fn proxy_url_with_credentials(mirror: &ConfigMirror) -> Option<String> {
+    let mut proxy_url = url::Url::parse(mirror.proxy.as_ref()?).ok()?;
+    if let Some(username) = &mirror.proxy_username {
+        proxy_url.set_username(username).ok()?;
+        proxy_url
+            .set_password(mirror.proxy_password.as_deref())
+            .ok()?;
+    }
+    Some(proxy_url.to_string())
+}
+
+/// The client settings from mirror.toml, shared by the client for text files and the one for
+/// everything else.
+fn client_builder(
+    mirror: &ConfigMirror,
+    user_agent: &HeaderValue,
+) -> Result<ClientBuilder, DownloadError> {
+    let mut builder = Client::builder()
+        .user_agent(user_agent.clone())
+        .connect_timeout(Duration::from_secs(
+            mirror.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        ));
+
+    builder = match mirror.http2.unwrap_or_default() {
+        Http2::Off => builder.http1_only(),
+        // Grow each connection's flow control window to fit the link, so a single connection
+        // to a faraway upstream isn't held back by round trips.
+        Http2::Alpn => builder.http2_adaptive_window(true),
+        Http2::PriorKnowledge => builder.http2_prior_knowledge().http2_adaptive_window(true),
+    };
+
+    let ip_family = mirror.ip_family.unwrap_or_default();
+    if ip_family != IpFamily::Any {
+        builder = builder.dns_resolver(Arc::new(FamilyResolver(ip_family)));
+    }
+
+    // Without a proxy in mirror.toml, reqwest uses the usual proxy environment variables.
+    if let Some(proxy_url) = &mirror.proxy {
+        let mut proxy = if is_socks_proxy(proxy_url) {
+            // reqwest only takes the credentials of a SOCKS proxy from its URL.
+            Proxy::all(proxy_url_with_credentials(mirror).unwrap_or_else(|| proxy_url.clone()))?
+        } else {
+            Proxy::all(proxy_url)?
+        };
+        if let Some(username) = mirror
+            .proxy_username
+            .as_ref()
+            .filter(|_| !is_socks_proxy(proxy_url))
+        {
+            proxy = proxy.basic_auth(username, mirror.proxy_password.as_deref().unwrap_or(""));
+        }
+        let no_proxy = mirror
+            .no_proxy
+            .as_ref()
+            .and_then(|hosts| NoProxy::from_string(&hosts.join(",")));
+        builder = builder.proxy(proxy.no_proxy(no_proxy));
+    }
+
+    if let Some(ca_cert) = &mirror.ca_cert {
+        let ca_cert_error = |error: String| DownloadError::CaCert {
+            path: ca_cert.display().to_string(),
+            error,
+        };
+        let pem = fs::read(ca_cert).map_err(|e| ca_cert_error(e.to_string()))?;
+        let certs = Certificate::from_pem_bundle(&pem).map_err(|e| ca_cert_error(e.to_string()))?;
+        if certs.is_empty() {
+            return Err(ca_cert_error("no certificates found".to_string()));
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    match (&mirror.client_cert, &mirror.client_key) {
+        (Some(client_cert), Some(client_key)) => {
+            let client_cert_error = |error: String| DownloadError::ClientCert {
+                path: client_cert.display().to_string(),
+                error,
+            };
+            let cert = fs::read(client_cert).map_err(|e| client_cert_error(e.to_string()))?;
+            let key = fs::read(client_key).map_err(|e| DownloadError::ClientCert {
+                path: client_key.display().to_string(),
+                error: e.to_string(),
+            })?;
+            let identity = Identity::from_pkcs8_pem(&cert, &key)
+                .map_err(|e| client_cert_error(e.to_string()))?;
+            builder = builder.identity(identity);
+        }
+        (Some(path), None) | (None, Some(path)) => {
+            return Err(DownloadError::ClientCert {
+                path: path.display().to_string(),
+                error: "client_cert and client_key must be set together".to_string(),
+            })
+        }
+        (None, None) => {}
+    }
+
+    if mirror.danger_accept_invalid_certs == Some(true) {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder)
+}
+
+impl DownloadClient {
+    /// The underlying client, for requests that aren't downloads, like posting reports.
+    pub fn http(&self) -> &Client {
+        &self.client
+    }
+
+    /// Whether small_files_first is set, so downloads should be started smallest first.
+    pub fn small_files_first(&self) -> bool {
+        self.settings.small_files_first
+    }
+
+    /// The client to download `url` with: the one asking for a compressed transfer if it's a
+    /// text file, so a manifest takes a fraction of the time on a slow link. Its hash is still
+    /// checked against what's unpacked, which is also what's written to the mirror.
+    fn client_for(&self, url: &str) -> &Client {
+        if is_text(url) {
+            &self.text_client
+        } else {
+            &self.client
+        }
+    }
+
+    /// Wait for a response or more data from upstream, failing if it stalls for longer than
+    /// the read timeout.
+    async fn with_read_timeout<T>(
+        &self,
+        url: &str,
+        read: impl Future<Output = Result<T, reqwest::Error>>,
+    ) -> Result<T, DownloadError> {
+        tokio::time::timeout(self.settings.read_timeout, read)
+            .await
+            .map_err(|_| DownloadError::ReadTimeout(url.to_string()))?
+            .map_err(DownloadError::from)
+    }
+
+    /// Send a request for `url`, with its source's extra headers, counting it in the statistics
+    /// of its host.
+    async fn send(
+        &self,
+        url: &str,
+        mut request: RequestBuilder,
+    ) -> Result<Response, DownloadError> {
+        if let Some((_, headers)) = self
+            .settings
+            .source_headers
+            .iter()
+            .filter(|(prefix, _)| url.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+        {
+            request = request.headers(headers.clone());
+        }
+
+        let started = Instant::now();
+        let res = self.with_read_timeout(url, request.send()).await;
+        host_stats::record_request(url, started.elapsed());
+        res
+    }
+
+    /// Wait until another connection can be opened to the host of `url`, if
+    /// max_connections_per_host is set. The connection counts against the limit until the
+    /// returned permit is dropped.
+    ///
+    /// Workers beyond the limit queue here rather than connect, so a high download_threads
+    /// doesn't trip an upstream's abuse protection.
+    async fn host_connection(&self, url: &str) -> Option<OwnedSemaphorePermit> {
+        let max_connections = self.settings.max_connections_per_host?;
+        let origin = url::Url::parse(url).ok()?.origin().ascii_serialization();
+        let semaphore = self
+            .host_connections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(origin)
+            .or_insert_with(|| Arc::new(Semaphore::new(max_connections)))
+            .clone();
+        semaphore.acquire_owned().await.ok()
+    }
+
+    /// Get the URL to request for an upstream URL, by running the url_resolver command with the
+    /// URL as its last argument, and taking the first line it prints. Without a url_resolver,
+    /// the URL is used as-is.
+    ///
+    /// The resolver is run for every request, right before it's sent, as pre-signed URLs
+    /// expire.
+    async fn resolve_url(&self, url: &str) -> Result<String, DownloadError> {
+        let Some((program, args)) = self
+            .settings
+            .url_resolver
+            .as_ref()
+            .and_then(|c| c.split_first())
+        else {
+            return Ok(url.to_string());
+        };
+        let resolver_error = |error: String| DownloadError::UrlResolver {
+            url: url.to_string(),
+            error,
+        };
+
+        let output = tokio::process::Command::new(program)
+            .args(args)
+            .arg(url)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| resolver_error(format!("could not run {program}: {e}")))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(resolver_error(format!(
+                "{program} failed with {}: {}",
+                output.status,
+                stderr.trim()
+            )));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match stdout.lines().next().map(str::trim) {
+            Some(resolved) if !resolved.is_empty() => Ok(resolved.to_string()),
+            _ => Err(resolver_error(format!("{program} printed no URL"))),
+        }
+    }
+}
+
+/// Whether `url` is for a text file, going by its extension.
+fn is_text(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    TEXT_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+/// Fail if fewer bytes were read than the response's Content-Length. A connection that's cut
+/// off, e.g. by a proxy, can look like the end of the body, and a file without a hash to check
+/// it against would otherwise be kept as if it were complete.
+fn check_length(url: &str, expected: Option<u64>, actual: u64) -> Result<(), DownloadError> {
+    match expected {
+        Some(expected) if actual < expected => Err(DownloadError::ShortRead {
+            url: url.to_string(),
+            expected,
+            actual,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Parse the extra headers of a source from mirror.toml. Their values are marked sensitive, as
+/// they're usually tokens, so they're left out of logs.
+pub fn header_map(headers: &BTreeMap<String, String>) -> Result<HeaderMap, DownloadError> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers {
+        let invalid = |error: String| DownloadError::InvalidHeader {
+            name: name.clone(),
+            error,
+        };
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(e.to_string()))?;
+        let mut value = HeaderValue::from_str(value).map_err(|e| invalid(e.to_string()))?;
+        value.set_sensitive(true);
+        header_map.insert(name, value);
+    }
+    Ok(header_map)
+}
+
+/// Get the file a source URL is for, if it's an absolute path or a `file://` URL, like another
+/// mirror on a USB disk or NFS share, rather than a server to download from.
+pub fn local_source(url: &str) -> Option<PathBuf> {
+    if url.starts_with("file:") {
+        return url::Url::parse(url).ok()?.to_file_path().ok();
+    }
+    let path = Path::new(url);
+    path.is_absolute().then(|| path.to_path_buf())
+}
+
+/// The error for a file that isn't in a local source, which is treated like a 404.
+fn local_not_found(url: &str, e: io::Error) -> DownloadError {
+    DownloadError::NotFound {
+        status: StatusCode::NOT_FOUND.as_u16(),
+        url: url.to_string(),
+        data: e.to_string(),
+    }
 }
 
 /// Download a URL and return it as a string.
+///
+/// If the HTTP cache has the URL, it's only downloaded again if upstream says it has changed.
+/// Files from a local source are just read.
 pub async fn download_string(
+    client: &DownloadClient,
     from: &str,
     user_agent: &HeaderValue,
 ) -> Result<String, DownloadError> {
-    let client = Client::new();
-
-    Ok(client
-        .get(from)
-        .header(USER_AGENT, user_agent)
-        .send()
-        .await?
-        .text()
-        .await?)
+    if let Some(source_path) = local_source(from) {
+        return match tokio::fs::read_to_string(source_path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(local_not_found(from, e)),
+            res => Ok(res?),
+        };
+    }
+    let cached = http_cache::get(from);
+    let request_url = client.resolve_url(from).await?;
+    let _connection = client.host_connection(&request_url).await;
+    let mut request = client
+        .client_for(from)
+        .get(request_url)
+        .header(USER_AGENT, user_agent);
+    if let Some(cached) = &cached {
+        request = request.headers(cached.validators.request_headers());
+    }
+    let res = client.send(from, request).await?;
+    if let (StatusCode::NOT_MODIFIED, Some(cached)) = (res.status(), cached) {
+        debug!("{from} is unchanged, using the cached copy");
+        return Ok(cached.body);
+    }
+    if res.status().is_server_error() {
+        return Err(DownloadError::ServerError {
+            status: res.status().as_u16(),
+            url: from.to_string(),
+        });
+    }
+    let is_success = res.status().is_success();
+    let validators = Validators::from_headers(res.headers());
+    let content_length = res.content_length();
+    let text = client.with_read_timeout(from, res.text()).await?;
+    host_stats::record_bytes(from, text.len());
+    if is_success {
+        check_length(from, content_length, text.len() as u64)?;
+        http_cache::put(from, validators, &text)?;
+    }
+    Ok(text)
 }
 
 /// Append a string to a path.
@@ -49,40 +677,20 @@ pub fn append_to_path(path: &Path, suffix: &str) -> PathBuf {
     PathBuf::from(new_path)
 }
 
-/// Write a string to a file, creating directories if needed.
+/// Write a string to a file, creating directories if needed. See `Storage::write`.
 pub fn write_file_create_dir(path: &Path, contents: &str) -> Result<(), DownloadError> {
-    let mut res = fs::write(path, contents);
-
-    if let Err(e) = &res {
-        if e.kind() == io::ErrorKind::NotFound {
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent)?;
-            }
-            res = fs::write(path, contents);
-        }
-    }
-
-    Ok(res?)
+    storage::get().write(path, contents.as_bytes())?;
+    Ok(())
 }
 
 /// Create a file, creating directories if needed.
 pub fn create_file_create_dir(path: &Path) -> Result<File, DownloadError> {
-    let mut file_res = File::create(path);
-    if let Err(e) = &file_res {
-        if e.kind() == io::ErrorKind::NotFound {
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent)?;
-            }
-            file_res = File::create(path);
-        }
-    }
-
-    Ok(file_res?)
+    Ok(storage::get().create(path)?)
 }
 
 pub fn move_if_exists(from: &Path, to: &Path) -> Result<(), DownloadError> {
     if from.exists() {
-        fs::rename(from, to)?;
+        storage::get().rename(from, to)?;
     }
     Ok(())
 }
@@ -91,58 +699,169 @@ pub fn move_if_exists_with_sha256(from: &Path, to: &Path) -> Result<(), Download
     let sha256_from_path = append_to_path(from, ".sha256");
     let sha256_to_path = append_to_path(to, ".sha256");
     move_if_exists(&sha256_from_path, &sha256_to_path)?;
+    for (sidecar_from, sidecar_to) in hashes::sidecar_paths(from).zip(hashes::sidecar_paths(to)) {
+        move_if_exists(&sidecar_from, &sidecar_to)?;
+    }
     move_if_exists(from, to)?;
     Ok(())
 }
 
-/// Copy a file and its .sha256, creating `to`'s directory if it doesn't exist.
-/// Fails if the source .sha256 does not exist.
+/// Copy a file and its .sha256, and any extra sidecar files it has, creating `to`'s directory
+/// if it doesn't exist. Fails if the source .sha256 does not exist.
 pub fn copy_file_create_dir_with_sha256(from: &Path, to: &Path) -> Result<(), DownloadError> {
     let sha256_from_path = append_to_path(from, ".sha256");
     let sha256_to_path = append_to_path(to, ".sha256");
     copy_file_create_dir(&sha256_from_path, &sha256_to_path)?;
+    for (sidecar_from, sidecar_to) in hashes::sidecar_paths(from).zip(hashes::sidecar_paths(to)) {
+        if sidecar_from.exists() {
+            copy_file_create_dir(&sidecar_from, &sidecar_to)?;
+        }
+    }
     copy_file_create_dir(from, to)?;
     Ok(())
 }
 
+/// Write a .sha256 file for a file panamax produced itself, in the same format as upstream's,
+/// so clients can verify it like any other mirrored file.
+///
+/// The .sha256 file is left alone if it already matches, so an unchanged file doesn't look
+/// modified to caches or rsync.
+pub fn write_sha256_file(path: &Path) -> Result<(), DownloadError> {
+    let hash = format!("{:x}", Sha256::digest(fs::read(path)?));
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let sha256_data = format!("{hash}  {file_name}\n");
+
+    let sha256_path = append_to_path(path, ".sha256");
+    if fs::read_to_string(&sha256_path).map_or(true, |current| current != sha256_data) {
+        write_file_create_dir(&sha256_path, &sha256_data)?;
+    }
+    Ok(())
+}
+
 /// Copy a file, creating `to`'s directory if it doesn't exist.
 pub fn copy_file_create_dir(from: &Path, to: &Path) -> Result<(), DownloadError> {
     if to.exists() {
         return Ok(());
     }
-    if let Some(parent) = to.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
-        }
-    }
-
-    fs::copy(from, to)?;
+    storage::get().copy(from, to)?;
     Ok(())
 }
 
+/// The result of a successful download.
+enum Fetched {
+    /// The file was downloaded, and upstream gave these validators for it.
+    Downloaded(Option<Validators>),
+    /// The file hasn't changed since upstream gave the validators sent with the request, and
+    /// nothing was downloaded.
+    NotModified,
+}
+
 async fn one_download(
-    client: &Client,
+    client: &DownloadClient,
     url: &str,
     path: &Path,
     hash: Option<&str>,
     user_agent: &HeaderValue,
-) -> Result<(), DownloadError> {
-    let mut http_res = client
-        .get(url)
-        .header(USER_AGENT, user_agent)
-        .send()
-        .await?;
-    let part_path = append_to_path(path, ".part");
-    let mut sha256 = Sha256::new();
+    validators: Option<&Validators>,
+) -> Result<Fetched, DownloadError> {
+    // Downloads are written to the staging directory, so a file shared over NFS or served by
+    // another web server never shows up in the live tree before it's been verified. By default
+    // that's within the mirror, so the final rename stays on one filesystem. Moving it into
+    // place gives it the SELinux label and ACL it would have got if it had been created in its
+    // own directory.
+    let part_path = staging::part_path(path, ".part");
+    if let Some(source_path) = local_source(url) {
+        return copy_local(url, &source_path, &part_path, path, hash).await;
+    }
+
+    // A partial file left by an earlier attempt is continued with a Range request, as long as
+    // the result can be checked against a hash. Otherwise it could be from an older version of
+    // the file.
+    let resume_from = match hash {
+        Some(_) => fs::metadata(&part_path).map_or(0, |m| m.len()),
+        None => 0,
+    };
+
+    let request_url = client.resolve_url(url).await?;
+    let connection = client.host_connection(&request_url).await;
+    let mut request = client
+        .client_for(url)
+        .get(&request_url)
+        .header(USER_AGENT, user_agent);
+    if resume_from > 0 {
+        debug!("Resuming {url} from byte {resume_from}");
+        request = request.header(RANGE, format!("bytes={resume_from}-"));
+    } else if let Some(validators) = validators {
+        request = request.headers(validators.request_headers());
+    }
+    let mut http_res = client.send(url, request).await?;
+    if http_res.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified);
+    }
+    if http_res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // The partial file is already as long as (or longer than) the file, so start over.
+        fs::remove_file(&part_path)?;
+        let request = client
+            .client_for(url)
+            .get(&request_url)
+            .header(USER_AGENT, user_agent);
+        http_res = client.send(url, request).await?;
+    }
+    if http_res.status().is_server_error() {
+        return Err(DownloadError::ServerError {
+            status: http_res.status().as_u16(),
+            url: url.to_string(),
+        });
+    }
+    // 403 and 404 are recorded as missing files below.
+    let status = http_res.status();
+    if status.is_client_error()
+        && status != StatusCode::FORBIDDEN
+        && status != StatusCode::NOT_FOUND
     {
-        let mut f = create_file_create_dir(&part_path)?;
+        return Err(DownloadError::ClientError {
+            status: status.as_u16(),
+            url: url.to_string(),
+        });
+    }
+
+    let new_validators = Validators::from_headers(http_res.headers());
+    let algorithm = hash.map_or(HashAlgorithm::Sha256, |h| parse_expected(h).0);
+    let mut hasher = algorithm.hasher();
+    // Only files with a hash are split up, so the assembled file is always checked.
+    let chunked_length = match (hash, &client.settings.chunking) {
+        (Some(_), Some(chunking)) if resume_from == 0 => chunked_length(&http_res, chunking),
+        _ => None,
+    };
+    if let Some((length, chunks)) = chunked_length {
+        download_chunks(
+            client,
+            url,
+            &request_url,
+            &part_path,
+            http_res,
+            connection,
+            length,
+            chunks,
+            user_agent,
+        )
+        .await?;
+        io::copy(&mut File::open(&part_path)?, &mut hasher)?;
+    } else {
         let status = http_res.status();
+        let mut f = if resume_from > 0 && status == StatusCode::PARTIAL_CONTENT {
+            io::copy(&mut File::open(&part_path)?, &mut hasher)?;
+            OpenOptions::new().append(true).open(&part_path)?
+        } else {
+            create_file_create_dir(&part_path)?
+        };
         if status == 403 || status == 404 {
-            let forbidden_path = append_to_path(path, ".notfound");
-            let text = http_res.text().await?;
-            fs::write(
-                forbidden_path,
-                format!("Server returned {}: {}", status, &text),
+            // Markers are kept with the partial downloads, out of the live tree.
+            let forbidden_path = staging::part_path(path, ".notfound");
+            let text = client.with_read_timeout(url, http_res.text()).await?;
+            write_file_create_dir(
+                &forbidden_path,
+                &format!("Server returned {}: {}", status, &text),
             )?;
             return Err(DownloadError::NotFound {
                 status: status.as_u16(),
@@ -151,38 +870,210 @@ async fn one_download(
             });
         }
 
-        while let Some(chunk) = http_res.chunk().await? {
+        // For a resumed download, this is the length of the rest of the file.
+        let content_length = http_res.content_length();
+        let mut read = 0;
+        while let Some(chunk) = client.with_read_timeout(url, http_res.chunk()).await? {
+            throttle::consume(chunk.len()).await;
+            host_stats::record_bytes(url, chunk.len());
             if hash.is_some() {
-                sha256.update(&chunk);
+                hasher.write_all(&chunk)?;
             }
             f.write_all(&chunk)?;
+            read += chunk.len() as u64;
         }
+        check_length(url, content_length, read)?;
     }
 
-    let f_hash = format!("{:x}", sha256.finalize());
+    finish_download(&part_path, path, hash, hasher.finish())?;
+    Ok(Fetched::Downloaded(new_validators))
+}
 
-    if let Some(h) = hash {
-        if f_hash == h {
-            move_if_exists(&part_path, path)?;
-            Ok(())
-        } else {
-            let badsha_path = append_to_path(path, ".badsha256");
-            fs::write(badsha_path, &f_hash)?;
-            Err(DownloadError::MismatchedHash {
-                expected: h.to_string(),
-                actual: f_hash,
-            })
+/// Copy a file from a local source, like `one_download`, hashing it on the way. A missing file
+/// fails like a 404, though without a .notfound file, as there's no response to keep.
+///
+/// Nothing is kept to continue from, as a copy that's cut short is quick to start over.
+async fn copy_local(
+    url: &str,
+    source_path: &Path,
+    part_path: &Path,
+    path: &Path,
+    hash: Option<&str>,
+) -> Result<Fetched, DownloadError> {
+    let mut source = match tokio::fs::File::open(source_path).await {
+        Ok(source) => source,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(local_not_found(url, e)),
+        Err(e) => return Err(e.into()),
+    };
+
+    let algorithm = hash.map_or(HashAlgorithm::Sha256, |h| parse_expected(h).0);
+    let mut hasher = algorithm.hasher();
+    let mut f = create_file_create_dir(part_path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = source.read(&mut buf).await?;
+        if n == 0 {
+            break;
         }
-    } else {
-        fs::rename(part_path, path)?;
+        hasher.write_all(&buf[..n])?;
+        f.write_all(&buf[..n])?;
+    }
+    drop(f);
+
+    finish_download(part_path, path, hash, hasher.finish())?;
+    Ok(Fetched::Downloaded(None))
+}
+
+/// Move a downloaded file from `part_path` into place, if its hash `f_hash` matches the
+/// expected `hash`. Otherwise it's removed, and the hash it had is written to a .badsha256
+/// file.
+fn finish_download(
+    part_path: &Path,
+    path: &Path,
+    hash: Option<&str>,
+    f_hash: String,
+) -> Result<(), DownloadError> {
+    let Some((algorithm, h)) = hash.map(parse_expected) else {
+        storage::get().rename(part_path, path)?;
+        return Ok(());
+    };
+    if f_hash == h {
+        move_if_exists(part_path, path)?;
+        checksums::record(path, algorithm, &f_hash)?;
         Ok(())
+    } else {
+        // Don't resume from a bad partial file on the next attempt.
+        fs::remove_file(part_path)?;
+        let badsha_path = staging::part_path(path, ".badsha256");
+        write_file_create_dir(&badsha_path, &f_hash)?;
+        Err(DownloadError::MismatchedHash {
+            expected: h.to_string(),
+            actual: f_hash,
+        })
     }
 }
 
-/// Download file, verifying its hash, and retrying if needed
-pub async fn download(
-    client: &Client,
+/// Get the length of a file and how many chunks to download it in, if the response is for a
+/// file big enough to split up, from a server that takes ranged requests.
+///
+/// A file gets no more chunks than it has bytes, and one that would only get one chunk is
+/// downloaded with a single request as usual.
+fn chunked_length(res: &Response, chunking: &Chunking) -> Option<(u64, u64)> {
+    let accepts_ranges = res
+        .headers()
+        .get(ACCEPT_RANGES)
+        .is_some_and(|v| v.as_bytes() == b"bytes");
+    let length = res.content_length()?;
+    let chunks = chunking.chunks.min(length);
+    (res.status() == StatusCode::OK && accepts_ranges && length >= chunking.threshold && chunks > 1)
+        .then_some((length, chunks))
+}
+
+/// Split `length` bytes into at most `chunks` ranges of the same size, apart from the last.
+/// Rounding the size up can leave fewer ranges than asked for, but never an empty one.
+fn chunk_ranges(length: u64, chunks: u64) -> Vec<(u64, u64)> {
+    let chunk_size = length.div_ceil(chunks.clamp(1, length.max(1))).max(1);
+    (0..length)
+        .step_by(chunk_size as usize)
+        .map(|start| (start, length.min(start + chunk_size)))
+        .collect()
+}
+
+/// Download a file of `length` bytes to `part_path` in `chunks` parallel ranged requests.
+///
+/// `first` is the response already started for the whole file, which is used for the first
+/// chunk rather than wasted, and `connection` its permit from the per-host connection limit.
+#[allow(clippy::too_many_arguments)]
+async fn download_chunks(
+    client: &DownloadClient,
     url: &str,
+    request_url: &str,
+    part_path: &Path,
+    first: Response,
+    connection: Option<OwnedSemaphorePermit>,
+    length: u64,
+    chunks: u64,
+    user_agent: &HeaderValue,
+) -> Result<(), DownloadError> {
+    debug!("Downloading {url} in {chunks} chunks");
+    create_file_create_dir(part_path)?.set_len(length)?;
+
+    let mut first = Some((first, connection));
+    let downloads = chunk_ranges(length, chunks)
+        .into_iter()
+        .map(|(start, end)| {
+            let first = first.take();
+            async move {
+                // The first chunk's connection is given back as soon as it's done, as the other
+                // chunks may be waiting for it.
+                let (mut res, _connection) = match first {
+                    Some(first) => first,
+                    None => {
+                        let connection = client.host_connection(request_url).await;
+                        let range = format!("bytes={start}-{}", end - 1);
+                        let request = client
+                            .client
+                            .get(request_url)
+                            .header(USER_AGENT, user_agent)
+                            .header(RANGE, range);
+                        let res = client.send(url, request).await?;
+                        if res.status() != StatusCode::PARTIAL_CONTENT {
+                            return Err(DownloadError::ServerError {
+                                status: res.status().as_u16(),
+                                url: url.to_string(),
+                            });
+                        }
+                        (res, connection)
+                    }
+                };
+
+                let mut f = OpenOptions::new().write(true).open(part_path)?;
+                f.seek(SeekFrom::Start(start))?;
+                let mut remaining = end - start;
+                while remaining > 0 {
+                    let Some(chunk) = client.with_read_timeout(url, res.chunk()).await? else {
+                        break;
+                    };
+                    // The first response is for the whole file, so stop at the end of its chunk.
+                    let chunk = &chunk[..chunk.len().min(remaining as usize)];
+                    throttle::consume(chunk.len()).await;
+                    host_stats::record_bytes(url, chunk.len());
+                    f.write_all(chunk)?;
+                    remaining -= chunk.len() as u64;
+                }
+                // The file was made full length up front, so a short chunk would leave zeros.
+                check_length(url, Some(end - start), end - start - remaining)
+            }
+        });
+    futures::future::try_join_all(downloads).await?;
+    Ok(())
+}
+
+/// Calculate the hash of a file on disk, as a lowercase hex string.
+pub async fn file_hash(path: &Path, algorithm: HashAlgorithm) -> Result<String, io::Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = [0u8; 4096];
+    let mut hasher = algorithm.hasher();
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.write_all(&buf[..n])?;
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Download file, verifying its hash, and retrying if needed.
+///
+/// `urls` are the same file on different mirrors. Each is tried in turn when one fails with
+/// a network or server error.
+pub async fn download(
+    client: &DownloadClient,
+    urls: &[String],
     path: &Path,
     hash: Option<&str>,
     retries: usize,
@@ -190,60 +1081,320 @@ pub async fn download(
     user_agent: &HeaderValue,
 ) -> Result<(), DownloadError> {
     if path.exists() && !force_download {
+        let url = urls.first().map(String::as_str);
         if let Some(h) = hash {
-            // Verify SHA-256 hash on the filesystem.
-            let mut file = tokio::fs::File::open(path).await?;
-            let mut buf = [0u8; 4096];
-            let mut sha256 = Sha256::new();
-
-            loop {
-                let n = file.read(&mut buf).await?;
-                if n == 0 {
-                    break;
-                }
-
-                sha256.update(&buf[..n]);
-            }
-
-            let f_hash = format!("{:x}", sha256.finalize());
+            // Verify the hash on the filesystem.
+            let (algorithm, h) = parse_expected(h);
+            let f_hash = checksums::hash(path, algorithm).await?;
             if h == f_hash {
                 // Calculated hash matches specified hash.
+                debug!("Skipping {}, already downloaded", path.display());
+                events::emit(Action::Skipped, path, url, None);
+                hashes::write_missing_sidecars(path)?;
                 return Ok(());
             }
-        } else {
+        } else if !client.settings.verify_existing
+            || existing_file_matches(client, urls, path, user_agent).await?
+        {
+            debug!("Skipping {}, already downloaded", path.display());
+            events::emit(Action::Skipped, path, url, None);
+            hashes::write_missing_sidecars(path)?;
             return Ok(());
         }
     }
 
-    let mut res = Ok(());
-    for _ in 0..=retries {
-        res = match one_download(client, url, path, hash, user_agent).await {
-            Ok(_) => break,
-            Err(e) => Err(e),
+    download_attempts(client, urls, path, hash, retries, user_agent, None).await?;
+    hashes::write_sidecars(path)
+}
+
+/// The hash in a file's `.sha256` sidecar, if it has one.
+fn sidecar_sha256(path: &Path) -> Option<String> {
+    let sha256_data = fs::read_to_string(append_to_path(path, ".sha256")).ok()?;
+    parse_sha256_file(&sha256_data)
+}
+
+/// Get the hash from the contents of a `.sha256` file, which is either just the hash, or the
+/// hash and the file's name, as written by `sha256sum`.
+fn parse_sha256_file(sha256_data: &str) -> Option<String> {
+    let hash = sha256_data.split_whitespace().next()?;
+    let is_sha256 = hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit());
+    is_sha256.then(|| hash.to_lowercase())
+}
+
+/// Download a `.sha256` file, and get the hash from it.
+async fn download_sha256_file(
+    client: &DownloadClient,
+    sha256_urls: &[String],
+    user_agent: &HeaderValue,
+) -> Result<(String, String), DownloadError> {
+    let sha256_data = download_string_from_mirrors(client, sha256_urls, user_agent).await?;
+    let sha256_hash = parse_sha256_file(&sha256_data).ok_or_else(|| {
+        DownloadError::BadSha256File(sha256_urls.first().cloned().unwrap_or_default())
+    })?;
+    Ok((sha256_data, sha256_hash))
+}
+
+/// Check a file that's already on disk but has no known hash, for verify_existing.
+///
+/// It's compared against the hash in its `.sha256` sidecar, or failing that, the size upstream
+/// gives for it. If upstream doesn't say, the file is assumed to be fine.
+async fn existing_file_matches(
+    client: &DownloadClient,
+    urls: &[String],
+    path: &Path,
+    user_agent: &HeaderValue,
+) -> Result<bool, DownloadError> {
+    if let Some(hash) = sidecar_sha256(path) {
+        let matches = checksums::hash(path, HashAlgorithm::Sha256).await? == hash;
+        if !matches {
+            info!("{} doesn't match its .sha256 file", path.display());
         }
+        return Ok(matches);
+    }
+
+    let Some(url) = urls.first() else {
+        return Ok(true);
+    };
+    let length = match local_source(url) {
+        Some(source_path) => fs::metadata(source_path).ok().map(|m| m.len()),
+        None => upstream_length(client, url, user_agent).await?,
+    };
+    match length {
+        Some(length) => {
+            let matches = fs::metadata(path)?.len() == length;
+            if !matches {
+                info!("{} doesn't match the size of {url}", path.display());
+            }
+            Ok(matches)
+        }
+        None => Ok(true),
+    }
+}
+
+/// Get the size upstream gives for a file with a HEAD request, if it gives one.
+async fn upstream_length(
+    client: &DownloadClient,
+    url: &str,
+    user_agent: &HeaderValue,
+) -> Result<Option<u64>, DownloadError> {
+    let request_url = client.resolve_url(url).await?;
+    let _connection = client.host_connection(&request_url).await;
+    let request = client
+        .client
+        .head(request_url)
+        .header(USER_AGENT, user_agent);
+    // The download is tried again anyway if the file doesn't match, so errors here only mean
+    // the file can't be checked.
+    let Ok(res) = client.send(url, request).await else {
+        return Ok(None);
+    };
+    Ok(res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok())
+        .filter(|_| res.status().is_success()))
+}
+
+/// Download a manifest that's replaced in place upstream, like a channel toml, only if it has
+/// changed since `current` was downloaded.
+///
+/// If upstream says it hasn't changed, `current` is copied to `path` instead. Returns whether
+/// anything was downloaded.
+pub async fn download_if_modified(
+    client: &DownloadClient,
+    urls: &[String],
+    path: &Path,
+    current: &Path,
+    hash: Option<&str>,
+    retries: usize,
+    user_agent: &HeaderValue,
+) -> Result<bool, DownloadError> {
+    // Validators are only kept for the first mirror, as other servers give different ones.
+    let Some(url) = urls.first() else {
+        return Err(DownloadError::NoSources);
+    };
+    let cached = validators::get(url).filter(|_| current.exists());
+    if let Some(validators) = &cached {
+        let fetched = download_attempts(
+            client,
+            urls,
+            path,
+            hash,
+            retries,
+            user_agent,
+            Some(validators),
+        )
+        .await?;
+        match fetched {
+            Fetched::Downloaded(new_validators) => {
+                validators::record(url, new_validators)?;
+                return Ok(true);
+            }
+            Fetched::NotModified => {
+                copy_file_create_dir(current, path)?;
+                // The copy is only used if it matches what upstream says it should be.
+                let unchanged = match hash.map(parse_expected) {
+                    Some((algorithm, h)) => file_hash(path, algorithm).await? == h,
+                    None => true,
+                };
+                if unchanged {
+                    debug!("Skipping {url}, not modified");
+                    events::emit(Action::Skipped, path, Some(url), None);
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    let fetched = download_attempts(client, urls, path, hash, retries, user_agent, None).await?;
+    if let Fetched::Downloaded(new_validators) = fetched {
+        validators::record(url, new_validators)?;
+    }
+    Ok(true)
+}
+
+/// Whether a download failed in a way that another mirror might not.
+fn is_mirror_failure(e: &DownloadError) -> bool {
+    matches!(
+        e,
+        DownloadError::Download(_)
+            | DownloadError::ReadTimeout(_)
+            | DownloadError::ShortRead { .. }
+            | DownloadError::ServerError { .. }
+            | DownloadError::ClientError { .. }
+    )
+}
+
+/// Whether a download from `url` should move on to the next mirror. A file missing from a
+/// local source is also looked for in the next one, so a disk with an older copy of upstream
+/// can be listed before it.
+fn can_fail_over(url: &str, e: &DownloadError) -> bool {
+    is_mirror_failure(e)
+        || (matches!(e, DownloadError::NotFound { .. }) && local_source(url).is_some())
+}
+
+/// Download a file from one of `urls`, retrying if needed.
+///
+/// Each mirror is tried in turn. A network or server error moves on to the next mirror straight
+/// away, and the last one gets up to `retries` retries, waiting before each. A file that keeps
+/// failing its hash check is looked for on the next mirror too. Only errors that might go away
+/// are retried, anything else fails the download straight away.
+async fn download_attempts(
+    client: &DownloadClient,
+    urls: &[String],
+    path: &Path,
+    hash: Option<&str>,
+    retries: usize,
+    user_agent: &HeaderValue,
+    validators: Option<&Validators>,
+) -> Result<Fetched, DownloadError> {
+    let mut res = Err(DownloadError::NoSources);
+    let mut tried = None;
+    'mirrors: for (mirror, url) in urls.iter().enumerate() {
+        // Validators from the first mirror mean nothing to the others.
+        let validators = validators.filter(|_| mirror == 0);
+        let next = urls.get(mirror + 1);
+        let mut waits = 0;
+        let mut mismatches = 0;
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                host_stats::record_retry(url);
+                let delay = client.settings.backoff.delay(waits, random_fraction());
+                waits += 1;
+                debug!("Retrying {url} in {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+            // Nothing new is started once the sync is stopping, including retries.
+            if shutdown::requested() {
+                return Err(DownloadError::Interrupted);
+            }
+
+            tried = Some(url);
+            res = one_download(client, url, path, hash, user_agent, validators).await;
+            if res.as_ref().is_err_and(is_mirror_failure) {
+                host_stats::record_error(url);
+            }
+            match &mut res {
+                Ok(Fetched::Downloaded(new_validators)) => {
+                    if mirror != 0 {
+                        *new_validators = None;
+                    }
+                    info!("Downloaded {url}");
+                    events::emit(Action::Downloaded, path, Some(url), None);
+                    break 'mirrors;
+                }
+                Ok(Fetched::NotModified) => break 'mirrors,
+                Err(e @ DownloadError::MismatchedHash { .. }) => {
+                    debug!("Downloading {url} failed: {e}");
+                    mismatches += 1;
+                    if mismatches >= MISMATCHED_HASH_ATTEMPTS {
+                        continue 'mirrors;
+                    }
+                }
+                Err(e) if can_fail_over(url, e) && next.is_some() => {
+                    info!(
+                        "Downloading {url} failed: {e}, trying {}",
+                        next.expect("checked above")
+                    );
+                    continue 'mirrors;
+                }
+                // Waiting won't make a missing file appear, or a certificate valid.
+                Err(e) if !e.is_transient() => {
+                    debug!("Downloading {url} failed: {e}");
+                    break 'mirrors;
+                }
+                Err(e) => debug!("Downloading {url} failed: {e}"),
+            }
+        }
+    }
+
+    if let Err(e) = &res {
+        events::emit(Action::Failed, path, tried.map(String::as_str), Some(e));
     }
 
     res
 }
 
+/// Download a URL and return it as a string, trying each mirror in `urls` in turn on network
+/// and server errors, or files missing from a local source.
+pub async fn download_string_from_mirrors(
+    client: &DownloadClient,
+    urls: &[String],
+    user_agent: &HeaderValue,
+) -> Result<String, DownloadError> {
+    let mut res = Err(DownloadError::NoSources);
+    for url in urls {
+        res = download_string(client, url, user_agent).await;
+        match &res {
+            Err(e) if is_mirror_failure(e) => {
+                host_stats::record_error(url);
+                debug!("Downloading {url} failed: {e}");
+            }
+            Err(e) if can_fail_over(url, e) => debug!("Downloading {url} failed: {e}"),
+            _ => break,
+        }
+    }
+    res
+}
+
 /// Download file and associated .sha256 file, verifying the hash, and retrying if needed
 pub async fn download_with_sha256_file(
-    client: &Client,
-    url: &str,
+    client: &DownloadClient,
+    urls: &[String],
+    sha256_urls: &[String],
     path: &Path,
     retries: usize,
     force_download: bool,
     user_agent: &HeaderValue,
 ) -> Result<(), DownloadError> {
-    let sha256_url = format!("{url}.sha256");
-    let sha256_data = download_string(&sha256_url, user_agent).await?;
+    let (sha256_data, sha256_hash) = download_sha256_file(client, sha256_urls, user_agent).await?;
 
-    let sha256_hash = &sha256_data[..64];
     download(
         client,
-        url,
+        urls,
         path,
-        Some(sha256_hash),
+        Some(&sha256_hash),
         retries,
         force_download,
         user_agent,
@@ -255,3 +1406,366 @@ pub async fn download_with_sha256_file(
 
     Ok(())
 }
+
+/// Download a manifest and associated .sha256 file like `download_if_modified`, verifying the
+/// hash, and retrying if needed.
+pub async fn download_with_sha256_file_if_modified(
+    client: &DownloadClient,
+    urls: &[String],
+    sha256_urls: &[String],
+    path: &Path,
+    current: &Path,
+    retries: usize,
+    user_agent: &HeaderValue,
+) -> Result<(), DownloadError> {
+    let (sha256_data, sha256_hash) = download_sha256_file(client, sha256_urls, user_agent).await?;
+
+    download_if_modified(
+        client,
+        urls,
+        path,
+        current,
+        Some(&sha256_hash),
+        retries,
+        user_agent,
+    )
+    .await?;
+
+    let sha256_path = append_to_path(path, ".sha256");
+    write_file_create_dir(&sha256_path, &sha256_data)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    mod sha256_file {
+        use crate::download::parse_sha256_file;
+
+        #[test]
+        fn parse() {
+            let hash = "ab".repeat(32);
+            assert_eq!(parse_sha256_file(&hash), Some(hash.clone()));
+            assert_eq!(
+                parse_sha256_file(&format!("\n{}  rustc.tar.xz\n", hash.to_uppercase())),
+                Some(hash.clone())
+            );
+            assert_eq!(parse_sha256_file(""), None);
+            assert_eq!(parse_sha256_file("abc"), None);
+            assert_eq!(parse_sha256_file("<html>Not found</html>"), None);
+            assert_eq!(parse_sha256_file(&"zz".repeat(32)), None);
+        }
+    }
+
+    mod is_text {
+        use crate::download::is_text;
+
+        #[test]
+        fn by_extension() {
+            assert!(is_text(
+                "https://static.rust-lang.org/dist/channel-rust-stable.toml"
+            ));
+            assert!(is_text(
+                "https://static.rust-lang.org/dist/channel-rust-stable.toml.sha256"
+            ));
+            assert!(is_text(
+                "https://static.rust-lang.org/rustup/release-stable.toml?X-Amz-Signature=abc"
+            ));
+            assert!(!is_text(
+                "https://static.rust-lang.org/dist/2024-01-01/cargo-1.75.0-x86_64-unknown-linux-gnu.tar.xz"
+            ));
+            assert!(!is_text(
+                "https://static.rust-lang.org/rustup/dist/x86_64-unknown-linux-gnu/rustup-init"
+            ));
+            assert!(!is_text(
+                "https://static.crates.io/crates/serde/serde-1.0.0.crate?file=config.json"
+            ));
+        }
+    }
+
+    mod write_sha256_file {
+        use std::fs;
+
+        use crate::download::write_sha256_file;
+
+        #[test]
+        fn matches_upstream_format() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("release-stable.toml");
+            fs::write(&path, "abc").unwrap();
+
+            write_sha256_file(&path).unwrap();
+            let sha256_data =
+                fs::read_to_string(dir.path().join("release-stable.toml.sha256")).unwrap();
+
+            assert_eq!(
+                sha256_data,
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  release-stable.toml\n"
+            );
+        }
+    }
+
+    mod sidecar_sha256 {
+        use std::fs;
+
+        use crate::download::sidecar_sha256;
+
+        #[test]
+        fn sidecar() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("rustup-init");
+            let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+            let missing = sidecar_sha256(&path);
+            fs::write(
+                dir.path().join("rustup-init.sha256"),
+                format!("{hash}  rustup-init\n"),
+            )
+            .unwrap();
+            let found = sidecar_sha256(&path);
+            fs::write(
+                dir.path().join("rustup-init.sha256"),
+                "<html>Not Found</html>",
+            )
+            .unwrap();
+            let invalid = sidecar_sha256(&path);
+
+            assert_eq!(missing, None);
+            assert_eq!(found.as_deref(), Some(hash));
+            assert_eq!(invalid, None);
+        }
+    }
+
+    mod local_source {
+        use std::path::PathBuf;
+
+        use crate::download::local_source;
+
+        #[cfg(unix)]
+        #[test]
+        fn paths_and_file_urls() {
+            assert_eq!(
+                local_source("/mnt/usb/rust/dist/channel-rust-stable.toml"),
+                Some(PathBuf::from("/mnt/usb/rust/dist/channel-rust-stable.toml"))
+            );
+            assert_eq!(
+                local_source("file:///mnt/usb/crates/se/rd/serde/1.0.0/serde-1.0.0.crate"),
+                Some(PathBuf::from(
+                    "/mnt/usb/crates/se/rd/serde/1.0.0/serde-1.0.0.crate"
+                ))
+            );
+            assert_eq!(
+                local_source("file:///mnt/my%20mirror"),
+                Some(PathBuf::from("/mnt/my mirror"))
+            );
+            assert_eq!(local_source("https://static.rust-lang.org/dist"), None);
+            assert_eq!(local_source("mirror/dist"), None);
+        }
+    }
+
+    mod download_attempts {
+        use std::fs;
+
+        use reqwest::header::HeaderValue;
+
+        use crate::download::{download, download_attempts, new_client, DownloadClient};
+        use crate::mirror::ConfigMirror;
+
+        fn client(mirror: ConfigMirror) -> DownloadClient {
+            let mirror = ConfigMirror {
+                retry_delay: Some(0.0),
+                ..mirror
+            };
+            new_client(
+                &mirror,
+                Vec::new(),
+                &HeaderValue::from_static("panamax-test"),
+            )
+            .unwrap()
+        }
+
+        #[cfg(unix)]
+        #[tokio::test]
+        async fn every_mirror_tried_without_retries() {
+            let dir = tempfile::tempdir().unwrap();
+            let (empty, full) = (dir.path().join("empty"), dir.path().join("full"));
+            fs::create_dir_all(&empty).unwrap();
+            fs::create_dir_all(&full).unwrap();
+            fs::write(full.join("cargo.tar.xz"), "cargo").unwrap();
+            let urls = [&empty, &empty, &full]
+                .map(|source| source.join("cargo.tar.xz").to_string_lossy().into_owned());
+            let path = dir.path().join("mirror/cargo.tar.xz");
+
+            let user_agent = HeaderValue::from_static("panamax-test");
+            let client = client(ConfigMirror::default());
+            download_attempts(&client, &urls, &path, None, 0, &user_agent, None)
+                .await
+                .unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "cargo");
+        }
+
+        #[cfg(unix)]
+        #[tokio::test]
+        async fn mismatched_hash_tries_next_mirror() {
+            let dir = tempfile::tempdir().unwrap();
+            let (bad, good) = (dir.path().join("bad"), dir.path().join("good"));
+            fs::create_dir_all(&bad).unwrap();
+            fs::create_dir_all(&good).unwrap();
+            fs::write(bad.join("cargo.tar.xz"), "damaged").unwrap();
+            fs::write(good.join("cargo.tar.xz"), "abc").unwrap();
+            let urls = [&bad, &good]
+                .map(|source| source.join("cargo.tar.xz").to_string_lossy().into_owned());
+            let path = dir.path().join("mirror/cargo.tar.xz");
+            let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+            let user_agent = HeaderValue::from_static("panamax-test");
+            download_attempts(
+                &client(ConfigMirror::default()),
+                &urls,
+                &path,
+                Some(hash),
+                5,
+                &user_agent,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "abc");
+        }
+
+        #[cfg(unix)]
+        #[tokio::test]
+        async fn verify_existing_per_client() {
+            let dir = tempfile::tempdir().unwrap();
+            let source = dir.path().join("source/cargo.tar.xz");
+            fs::create_dir_all(source.parent().unwrap()).unwrap();
+            fs::write(&source, "cargo").unwrap();
+            let urls = [source.to_string_lossy().into_owned()];
+            let path = dir.path().join("mirror/cargo.tar.xz");
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "truncated").unwrap();
+
+            // Each client keeps its own settings, whichever was built first.
+            let verifying = client(ConfigMirror {
+                verify_existing: Some(true),
+                ..ConfigMirror::default()
+            });
+            let trusting = client(ConfigMirror::default());
+            let user_agent = HeaderValue::from_static("panamax-test");
+
+            download(&trusting, &urls, &path, None, 0, false, &user_agent)
+                .await
+                .unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "truncated");
+            download(&verifying, &urls, &path, None, 0, false, &user_agent)
+                .await
+                .unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "cargo");
+        }
+    }
+
+    mod chunk_ranges {
+        use crate::download::chunk_ranges;
+
+        #[test]
+        fn even_and_uneven() {
+            assert_eq!(chunk_ranges(8, 4), [(0, 2), (2, 4), (4, 6), (6, 8)]);
+            assert_eq!(chunk_ranges(10, 4), [(0, 3), (3, 6), (6, 9), (9, 10)]);
+        }
+
+        #[test]
+        fn no_empty_ranges() {
+            // A size of 2 covers 10 bytes in 5 ranges, not 6.
+            assert_eq!(chunk_ranges(10, 6).len(), 5);
+            assert_eq!(chunk_ranges(3, 8), [(0, 1), (1, 2), (2, 3)]);
+            assert_eq!(chunk_ranges(5, 0), [(0, 5)]);
+            assert!(chunk_ranges(0, 4).is_empty());
+        }
+    }
+
+    mod backoff {
+        use std::time::Duration;
+
+        use crate::download::Backoff;
+
+        const BACKOFF: Backoff = Backoff {
+            delay: 1.0,
+            multiplier: 2.0,
+            max_delay: 5.0,
+            jitter: 0.5,
+        };
+
+        #[test]
+        fn exponential() {
+            assert_eq!(BACKOFF.delay(0, 0.0), Duration::from_secs(1));
+            assert_eq!(BACKOFF.delay(1, 0.0), Duration::from_secs(2));
+            assert_eq!(BACKOFF.delay(2, 0.0), Duration::from_secs(4));
+            assert_eq!(BACKOFF.delay(3, 0.0), Duration::from_secs(5));
+        }
+
+        #[test]
+        fn jitter() {
+            assert_eq!(BACKOFF.delay(1, 1.0), Duration::from_secs(1));
+            assert_eq!(BACKOFF.delay(3, 0.5), Duration::from_secs_f64(3.75));
+        }
+    }
+
+    mod check_length {
+        use crate::download::{check_length, DownloadError};
+
+        #[test]
+        fn short_reads() {
+            assert!(check_length("", Some(100), 100).is_ok());
+            assert!(check_length("", None, 50).is_ok());
+            assert!(matches!(
+                check_length("", Some(100), 50),
+                Err(DownloadError::ShortRead {
+                    expected: 100,
+                    actual: 50,
+                    ..
+                })
+            ));
+        }
+    }
+
+    mod is_transient {
+        use crate::download::DownloadError;
+
+        fn client_error(status: u16) -> DownloadError {
+            DownloadError::ClientError {
+                status,
+                url: "https://static.rust-lang.org/dist/channel-rust-stable.toml".to_string(),
+            }
+        }
+
+        #[test]
+        fn transient() {
+            assert!(DownloadError::ReadTimeout(String::new()).is_transient());
+            assert!(DownloadError::ShortRead {
+                url: String::new(),
+                expected: 100,
+                actual: 50
+            }
+            .is_transient());
+            assert!(DownloadError::ServerError {
+                status: 503,
+                url: String::new()
+            }
+            .is_transient());
+            assert!(client_error(429).is_transient());
+        }
+
+        #[test]
+        fn permanent() {
+            assert!(!client_error(401).is_transient());
+            assert!(!DownloadError::NotFound {
+                status: 404,
+                url: String::new(),
+                data: String::new()
+            }
+            .is_transient());
+            assert!(!DownloadError::NoSources.is_transient());
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use serde::Serialize;
+
+use crate::timestamp::Timestamp;
+
+/// Where events are written to, if enabled.
+static SINK: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/// How many times each action has happened in this run, indexed by action.
+static COUNTS: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// What happened to a file.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Downloaded,
+    Skipped,
+    Failed,
+    Deleted,
+}
+
+/// One line of the event stream.
+#[derive(Debug, Serialize)]
+struct Event<'a> {
+    time: Timestamp,
+    action: Action,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Start writing events to `dest` as newline-delimited JSON. A `dest` of `-` means stdout.
+pub fn open(dest: &Path) -> io::Result<()> {
+    let writer: Box<dyn Write + Send> = if dest == Path::new("-") {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(dest)?)
+    };
+    // Only one event stream is supported per run, so any later call is ignored.
+    let _ = SINK.set(Mutex::new(writer));
+    Ok(())
+}
+
+/// Totals of the actions taken on files during this run.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Counts {
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub deleted: usize,
+}
+
+/// Get the totals of the actions taken on files so far.
+pub fn counts() -> Counts {
+    let count = |action: Action| COUNTS[action as usize].load(Ordering::Relaxed);
+    Counts {
+        downloaded: count(Action::Downloaded),
+        skipped: count(Action::Skipped),
+        failed: count(Action::Failed),
+        deleted: count(Action::Deleted),
+    }
+}
+
+/// Record an action taken on a file. This is always counted, but only written out if an
+/// event stream was opened.
+pub fn emit(action: Action, path: &Path, url: Option<&str>, error: Option<&dyn Display>) {
+    COUNTS[action as usize].fetch_add(1, Ordering::Relaxed);
+
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+
+    let event = Event {
+        time: Timestamp::now(),
+        action,
+        path: path.display().to_string(),
+        url,
+        error: error.map(|e| e.to_string()),
+    };
+
+    let line = serde_json::to_string(&event).expect("events should always serialize");
+    // A poisoned lock only means another event failed to write, so keep going.
+    let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = writeln!(sink, "{line}").and_then(|_| sink.flush()) {
+        log::warn!("Could not write event: {e}");
+    }
+}
@@ -0,0 +1,650 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io::{BufRead, Cursor};
+use std::path::{Path, PathBuf};
+
+use git2::{ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::crates::crate_prefix;
+use crate::mirror::MirrorError;
+use crate::overlay::{self, overlay_path};
+
+/// How to print a dependency graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz, e.g. for `dot -Tsvg`.
+    Dot,
+    /// JSON, with lists of nodes and edges.
+    Json,
+}
+
+/// How to print the crates that depend on a crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RdepsFormat {
+    /// One dependent crate version per line.
+    Text,
+    /// JSON, with a list of dependent crate versions.
+    Json,
+}
+
+/// One version of a crate, as listed in an index file.
+#[derive(Debug, Deserialize)]
+struct IndexVersion {
+    name: String,
+    vers: String,
+    #[serde(default)]
+    deps: Vec<IndexDependency>,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// One dependency of a crate version, as listed in an index file.
+#[derive(Debug, Clone, Deserialize)]
+struct IndexDependency {
+    name: String,
+    req: String,
+    #[serde(default)]
+    optional: bool,
+    kind: Option<String>,
+    target: Option<String>,
+    /// The crate's real name, if it's renamed in Cargo.toml.
+    package: Option<String>,
+    /// Set for dependencies from a registry other than this one.
+    registry: Option<String>,
+}
+
+/// The crates of a mirror's index, read as they're served: from the overlay registry if a
+/// crate is there, or else from the index's master branch.
+struct Index {
+    repo: Option<Repository>,
+    tree: Option<Oid>,
+    overlay: Option<PathBuf>,
+    versions: HashMap<String, Vec<IndexVersion>>,
+}
+
+impl Index {
+    fn open(mirror_path: &Path) -> Result<Self, MirrorError> {
+        let repo_path = mirror_path.join("crates.io-index");
+        let repo = match repo_path.join(".git").exists() {
+            true => Some(Repository::open(repo_path)?),
+            false => None,
+        };
+        let tree = match &repo {
+            Some(repo) => repo
+                .find_reference("refs/heads/master")
+                .ok()
+                .map(|master| master.peel_to_tree())
+                .transpose()?
+                .map(|tree| tree.id()),
+            None => None,
+        };
+        let overlay = overlay_path(mirror_path);
+        if tree.is_none() && overlay.is_none() {
+            return Err(MirrorError::CmdLine(
+                "No crates.io-index repository has been synced yet.".to_string(),
+            ));
+        }
+        Ok(Index {
+            repo,
+            tree,
+            overlay,
+            versions: HashMap::new(),
+        })
+    }
+
+    fn read(&self, name: &str) -> Option<Vec<u8>> {
+        if let Some(file) = self
+            .overlay
+            .as_ref()
+            .and_then(|overlay| overlay::index_file(overlay, name))
+        {
+            if let Ok(contents) = std::fs::read(file) {
+                return Some(contents);
+            }
+        }
+
+        let (repo, tree) = (self.repo.as_ref()?, self.tree?);
+        let name = name.to_lowercase();
+        let file: PathBuf = crate_prefix(&name)?
+            .split('/')
+            .chain([name.as_str()])
+            .collect();
+        let blob = repo
+            .find_tree(tree)
+            .ok()?
+            .get_path(&file)
+            .ok()?
+            .to_object(repo)
+            .ok()?
+            .into_blob()
+            .ok()?;
+        Some(blob.content().to_vec())
+    }
+
+    /// Get the file names of every crate in the index and the overlay, which are the lowercased
+    /// crate names.
+    fn names(&self) -> Result<BTreeSet<String>, MirrorError> {
+        let mut names = BTreeSet::new();
+        if let Some(overlay) = &self.overlay {
+            names.extend(
+                WalkDir::new(overlay.join("index"))
+                    .min_depth(1)
+                    .into_iter()
+                    .filter_entry(|e| !e.file_name().to_string_lossy().starts_with('.'))
+                    .filter_map(|e| e.ok())
+                    .filter(|e| e.file_type().is_file() && e.file_name() != "config.json")
+                    .map(|e| e.file_name().to_string_lossy().into_owned()),
+            );
+        }
+        if let (Some(repo), Some(tree)) = (&self.repo, self.tree) {
+            repo.find_tree(tree)?
+                .walk(TreeWalkMode::PreOrder, |dir, entry| {
+                    // Crates are files within their prefix directories, so files at the top
+                    // level, like config.json, aren't crates.
+                    if let (false, Some(ObjectType::Blob), Some(name)) =
+                        (dir.is_empty(), entry.kind(), entry.name())
+                    {
+                        names.insert(name.to_string());
+                    }
+                    TreeWalkResult::Ok
+                })?;
+        }
+        Ok(names)
+    }
+
+    /// Get every version of a crate, or none if it isn't in the index.
+    fn versions(&mut self, name: &str) -> &[IndexVersion] {
+        if !self.versions.contains_key(name) {
+            let versions = self
+                .read(name)
+                .map(|contents| {
+                    Cursor::new(contents)
+                        .lines()
+                        .map_while(Result::ok)
+                        .filter_map(|line| serde_json::from_str(&line).ok())
+                        .collect()
+                })
+                .unwrap_or_default();
+            self.versions.insert(name.to_string(), versions);
+        }
+        &self.versions[name]
+    }
+
+    /// Pick the version of a crate cargo would use for `req`: the newest that matches it and
+    /// isn't yanked.
+    fn resolve(&mut self, name: &str, req: &VersionReq) -> Option<String> {
+        self.versions(name)
+            .iter()
+            .filter(|v| !v.yanked)
+            .filter_map(|v| Some((Version::parse(&v.vers).ok()?, &v.vers)))
+            .filter(|(version, _)| req.matches(version))
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, vers)| vers.clone())
+    }
+}
+
+/// A crate version in the graph.
+#[derive(Debug, Serialize)]
+struct Node {
+    name: String,
+    version: String,
+}
+
+/// A dependency of one crate version on another.
+#[derive(Debug, Serialize)]
+struct Edge {
+    from: String,
+    to: String,
+    req: String,
+    kind: String,
+    optional: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+}
+
+/// A dependency with no version in the index that matches it.
+#[derive(Debug, Serialize)]
+struct Unresolved {
+    from: String,
+    name: String,
+    req: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Graph {
+    root: String,
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+    unresolved: Vec<Unresolved>,
+}
+
+fn node_id(name: &str, version: &str) -> String {
+    format!("{name}@{version}")
+}
+
+/// Work out the dependency graph of a crate version from the index, following dependencies
+/// up to `depth` levels deep.
+///
+/// Each dependency resolves to the newest version that matches it, as it would in a fresh
+/// lockfile. Optional dependencies are included, as the graph doesn't know which features are
+/// enabled. Dev-dependencies, and dependencies from other registries, are left out.
+fn build_graph(index: &mut Index, name: &str, version: &str, depth: Option<usize>) -> Graph {
+    let root = node_id(name, version);
+    let mut nodes = BTreeMap::new();
+    let mut edges = Vec::new();
+    let mut unresolved = Vec::new();
+    let mut queue = VecDeque::from([(name.to_string(), version.to_string(), 0)]);
+    nodes.insert(
+        root.clone(),
+        Node {
+            name: name.to_string(),
+            version: version.to_string(),
+        },
+    );
+
+    while let Some((name, version, level)) = queue.pop_front() {
+        if depth.is_some_and(|depth| level >= depth) {
+            continue;
+        }
+        let from = node_id(&name, &version);
+        let deps: Vec<IndexDependency> = index
+            .versions(&name)
+            .iter()
+            .find(|v| v.vers == version)
+            .map(|v| v.deps.clone())
+            .unwrap_or_default();
+
+        for dep in deps {
+            let kind = dep.kind.unwrap_or_else(|| "normal".to_string());
+            if kind == "dev" || dep.registry.is_some() {
+                continue;
+            }
+            let dep_name = dep.package.unwrap_or(dep.name);
+            let req = dep.req;
+            let Some(dep_version) = VersionReq::parse(&req)
+                .ok()
+                .and_then(|parsed| index.resolve(&dep_name, &parsed))
+            else {
+                unresolved.push(Unresolved {
+                    from: from.clone(),
+                    name: dep_name,
+                    req,
+                });
+                continue;
+            };
+            let to = node_id(&dep_name, &dep_version);
+            if !nodes.contains_key(&to) {
+                nodes.insert(
+                    to.clone(),
+                    Node {
+                        name: dep_name.clone(),
+                        version: dep_version.clone(),
+                    },
+                );
+                queue.push_back((dep_name, dep_version, level + 1));
+            }
+            edges.push(Edge {
+                from: from.clone(),
+                to,
+                req,
+                kind,
+                optional: dep.optional,
+                target: dep.target,
+            });
+        }
+    }
+
+    Graph {
+        root,
+        nodes: nodes.into_values().collect(),
+        edges,
+        unresolved,
+    }
+}
+
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn to_dot(graph: &Graph) -> String {
+    let mut dot = format!("digraph {} {{\n", dot_string(&graph.root));
+    for node in &graph.nodes {
+        dot.push_str(&format!(
+            "    {};\n",
+            dot_string(&node_id(&node.name, &node.version))
+        ));
+    }
+    for edge in &graph.edges {
+        let mut attributes = vec![format!("label={}", dot_string(&edge.req))];
+        if edge.optional {
+            attributes.push("style=dashed".to_string());
+        }
+        if edge.kind == "build" {
+            attributes.push("color=gray".to_string());
+        }
+        dot.push_str(&format!(
+            "    {} -> {} [{}];\n",
+            dot_string(&edge.from),
+            dot_string(&edge.to),
+            attributes.join(", ")
+        ));
+    }
+    for dep in &graph.unresolved {
+        let missing = format!("{} {} (missing)", dep.name, dep.req);
+        dot.push_str(&format!(
+            "    {} [color=red];\n    {} -> {} [color=red];\n",
+            dot_string(&missing),
+            dot_string(&dep.from),
+            dot_string(&missing)
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Print the dependency graph of `spec`, a crate name with an optional `@version`, resolved
+/// from the mirror's index. Without a version, the newest one that isn't yanked is used.
+pub fn graph(
+    path: &Path,
+    spec: &str,
+    depth: Option<usize>,
+    format: GraphFormat,
+) -> Result<(), MirrorError> {
+    let mut index = Index::open(path)?;
+    let (name, version) = match spec.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (spec, None),
+    };
+
+    let version = match version {
+        Some(version) => index
+            .versions(name)
+            .iter()
+            .find(|v| v.vers == version)
+            .map(|v| v.vers.clone()),
+        None => index.resolve(name, &VersionReq::STAR),
+    }
+    .ok_or_else(|| MirrorError::CmdLine(format!("{spec} was not found in the index.")))?;
+
+    let graph = build_graph(&mut index, name, &version, depth);
+    match format {
+        GraphFormat::Dot => print!("{}", to_dot(&graph)),
+        GraphFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&graph).expect("graphs should always serialize")
+        ),
+    }
+    Ok(())
+}
+
+/// A crate version that depends on the crate being looked up.
+#[derive(Debug, Serialize)]
+struct Dependent {
+    name: String,
+    version: String,
+    req: String,
+    kind: String,
+    optional: bool,
+    yanked: bool,
+}
+
+/// Whether a dependency could use one of `versions`, the versions of the crate depended on
+/// that are being looked up.
+///
+/// Without versions to go by, every dependency on the crate counts. A requirement that can't be
+/// parsed counts too, as it can't be ruled out.
+fn depends_on_versions(dep_req: &str, versions: Option<&[Version]>) -> bool {
+    match (VersionReq::parse(dep_req), versions) {
+        (Ok(dep_req), Some(versions)) => versions.iter().any(|v| dep_req.matches(v)),
+        _ => true,
+    }
+}
+
+/// Find the crate versions in the index that depend on `name`, optionally only those whose
+/// requirement allows a version of it matching `req`.
+fn find_dependents(
+    index: &mut Index,
+    name: &str,
+    req: Option<&VersionReq>,
+) -> Result<Vec<Dependent>, MirrorError> {
+    let versions: Option<Vec<Version>> = req.map(|req| {
+        index
+            .versions(name)
+            .iter()
+            .filter_map(|v| Version::parse(&v.vers).ok())
+            .filter(|version| req.matches(version))
+            .collect()
+    });
+
+    let mut dependents = Vec::new();
+    // Only index files that mention the crate at all are parsed.
+    let needle = format!("\"{}\"", name.to_lowercase());
+    for candidate in index.names()? {
+        if candidate.eq_ignore_ascii_case(name) {
+            continue;
+        }
+        let Some(contents) = index.read(&candidate) else {
+            continue;
+        };
+        if !String::from_utf8_lossy(&contents)
+            .to_lowercase()
+            .contains(&needle)
+        {
+            continue;
+        }
+
+        for line in Cursor::new(contents).lines().map_while(Result::ok) {
+            let Ok(version) = serde_json::from_str::<IndexVersion>(&line) else {
+                continue;
+            };
+            for dep in &version.deps {
+                let dep_name = dep.package.as_deref().unwrap_or(&dep.name);
+                if dep.registry.is_some()
+                    || !dep_name.eq_ignore_ascii_case(name)
+                    || !depends_on_versions(&dep.req, versions.as_deref())
+                {
+                    continue;
+                }
+                dependents.push(Dependent {
+                    name: version.name.clone(),
+                    version: version.vers.clone(),
+                    req: dep.req.clone(),
+                    kind: dep.kind.clone().unwrap_or_else(|| "normal".to_string()),
+                    optional: dep.optional,
+                    yanked: version.yanked,
+                });
+            }
+        }
+    }
+    Ok(dependents)
+}
+
+/// Print the crate versions in the mirror's index that depend on `name`, e.g. to see what
+/// blocking or quarantining a crate would break. With `req`, only dependencies that allow a
+/// version of the crate matching it are listed.
+pub fn rdeps(
+    path: &Path,
+    name: &str,
+    req: Option<&str>,
+    format: RdepsFormat,
+) -> Result<(), MirrorError> {
+    let mut index = Index::open(path)?;
+    let req = req
+        .map(|req| {
+            VersionReq::parse(req).map_err(|_| {
+                MirrorError::CmdLine(format!("{req} is not a valid version requirement."))
+            })
+        })
+        .transpose()?;
+
+    let dependents = find_dependents(&mut index, name, req.as_ref())?;
+    match format {
+        RdepsFormat::Text => {
+            for dependent in &dependents {
+                let mut notes = vec![dependent.kind.as_str()];
+                if dependent.optional {
+                    notes.push("optional");
+                }
+                if dependent.yanked {
+                    notes.push("yanked");
+                }
+                println!(
+                    "{}@{} {} ({})",
+                    dependent.name,
+                    dependent.version,
+                    dependent.req,
+                    notes.join(", ")
+                );
+            }
+            let crates: BTreeSet<_> = dependents.iter().map(|d| d.name.as_str()).collect();
+            eprintln!(
+                "{} version(s) of {} crate(s) depend on {name}.",
+                dependents.len(),
+                crates.len()
+            );
+        }
+        RdepsFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&dependents).expect("dependents should always serialize")
+        ),
+    }
+    Ok(())
+}
+
+/// Get the newest release of a crate in the mirror's index, leaving out pre-releases and
+/// yanked versions, and whether it's newer than `than`. None if the index has no release of it.
+pub fn newest_release(
+    mirror_path: &Path,
+    name: &str,
+    than: &str,
+) -> Result<Option<(String, bool)>, MirrorError> {
+    let mut index = Index::open(mirror_path)?;
+    let newest = index
+        .versions(name)
+        .iter()
+        .filter(|v| !v.yanked)
+        .filter_map(|v| Some((Version::parse(&v.vers).ok()?, &v.vers)))
+        .filter(|(version, _)| version.pre.is_empty())
+        .max_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(newest.map(|(version, vers)| {
+        let is_newer = Version::parse(than).map_or(true, |than| version > than);
+        (vers.clone(), is_newer)
+    }))
+}
+
+#[cfg(test)]
+mod test {
+
+    mod depends_on_versions {
+        use semver::Version;
+
+        use crate::graph::depends_on_versions;
+
+        #[test]
+        fn versions() {
+            let versions = ["1.0.150", "1.0.151"].map(|v| Version::parse(v).unwrap());
+            assert!(depends_on_versions("^1.0.100", Some(&versions)));
+            assert!(depends_on_versions("=1.0.151", Some(&versions)));
+            assert!(!depends_on_versions("^1.0.152", Some(&versions)));
+            assert!(!depends_on_versions("0.9", Some(&versions)));
+        }
+
+        #[test]
+        fn any_version() {
+            assert!(depends_on_versions("^1.0", None));
+            assert!(!depends_on_versions("^1.0", Some(&[])));
+            assert!(depends_on_versions("not a requirement", Some(&[])));
+        }
+    }
+
+    mod newest_release {
+        use std::fs;
+
+        use crate::graph::newest_release;
+
+        #[test]
+        fn releases_only() {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            let index_dir = dir.join("overlay/index/pa/na");
+            fs::create_dir_all(&index_dir).unwrap();
+            let versions = [
+                ("1.0.9", false),
+                ("1.0.10", false),
+                ("1.1.0", true),
+                ("2.0.0-rc.1", false),
+            ];
+            let lines: Vec<String> = versions
+                .iter()
+                .map(|(vers, yanked)| {
+                    format!(r#"{{"name":"panamax","vers":"{vers}","deps":[],"yanked":{yanked}}}"#)
+                })
+                .collect();
+            fs::write(index_dir.join("panamax"), lines.join("\n")).unwrap();
+
+            let newest = |than| newest_release(dir, "panamax", than).unwrap();
+            assert_eq!(newest("1.0.9"), Some(("1.0.10".to_string(), true)));
+            assert_eq!(newest("1.0.10"), Some(("1.0.10".to_string(), false)));
+            assert_eq!(newest("1.1.0"), Some(("1.0.10".to_string(), false)));
+            assert_eq!(newest_release(dir, "serde", "1.0.0").unwrap(), None);
+        }
+    }
+
+    mod version_req {
+        use semver::{Version, VersionReq};
+
+        fn matches(req: &str, version: &str) -> bool {
+            VersionReq::parse(req)
+                .unwrap()
+                .matches(&Version::parse(version).unwrap())
+        }
+
+        #[test]
+        fn caret() {
+            assert!(matches("1.2.3", "1.9.0"));
+            assert!(!matches("1.2.3", "1.2.2"));
+            assert!(!matches("^1.2.3", "2.0.0"));
+            assert!(matches("^0.2.3", "0.2.9"));
+            assert!(!matches("^0.2.3", "0.3.0"));
+            assert!(matches("^0.0.3", "0.0.3"));
+            assert!(!matches("^0.0.3", "0.0.4"));
+            assert!(matches("0.2", "0.2.0"));
+            assert!(matches("1", "1.5.0"));
+        }
+
+        #[test]
+        fn tilde_and_wildcard() {
+            assert!(matches("~1.2.3", "1.2.9"));
+            assert!(!matches("~1.2.3", "1.3.0"));
+            assert!(matches("~1", "1.9.0"));
+            assert!(matches("1.*", "1.9.0"));
+            assert!(!matches("1.2.*", "1.3.0"));
+            assert!(matches("*", "0.1.0"));
+        }
+
+        #[test]
+        fn comparisons() {
+            assert!(matches(">=1.0, <2.0", "1.9.9"));
+            assert!(!matches(">=1.0, <2.0", "2.0.0"));
+            assert!(matches("=1.2.3", "1.2.3"));
+            assert!(!matches("=1.2.3", "1.2.4"));
+            assert!(matches(">1.2", "1.3.0"));
+            assert!(!matches(">1.2", "1.2.5"));
+            assert!(matches("<=1.2", "1.2.5"));
+        }
+
+        #[test]
+        fn pre_release() {
+            assert!(!matches("1.0.0", "1.1.0-beta.1"));
+            assert!(matches("^1.1.0-beta.1", "1.1.0-beta.2"));
+            assert!(!matches("^1.1.0-beta.2", "1.1.0-beta.1"));
+            let version = |v| Version::parse(v).unwrap();
+            assert!(version("1.0.0-alpha.2") < version("1.0.0-alpha.10"));
+            assert!(version("1.0.0-rc.1") < version("1.0.0"));
+            // Build metadata doesn't make a different release.
+            assert!(matches("=1.2.3", "1.2.3+build.5"));
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::download::{append_to_path, write_file_create_dir, DownloadError};
+use crate::mirror::ConfigMirror;
+
+/// A hash algorithm that downloads can be verified with, or sidecar files written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 3] = [Self::Sha256, Self::Sha512, Self::Blake3];
+
+    /// The name of the algorithm, as used for sidecar file extensions and in expected hashes.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// Name of a manifest of hashes in this algorithm, in the `<hash>  <file>` format that
+    /// `sha256sum -c`, `sha512sum -c` and `b3sum -c` check.
+    pub fn sums_file(self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA256SUMS",
+            Self::Sha512 => "SHA512SUMS",
+            Self::Blake3 => "B3SUMS",
+        }
+    }
+
+    /// A digest in this algorithm in the form `parse_expected` reads: bare for SHA-256, and
+    /// with the algorithm's name as a prefix otherwise.
+    pub fn tag(self, digest: &str) -> String {
+        match self {
+            Self::Sha256 => digest.to_string(),
+            _ => format!("{}:{digest}", self.name()),
+        }
+    }
+
+    /// Whether `digest` looks like a hex digest of this algorithm.
+    pub fn is_digest(self, digest: &str) -> bool {
+        let len = match self {
+            Self::Sha256 | Self::Blake3 => 64,
+            Self::Sha512 => 128,
+        };
+        digest.len() == len && digest.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            Self::Sha256 => Box::new(Sha256::new()),
+            Self::Sha512 => Box::new(Sha512::new()),
+            Self::Blake3 => Box::new(blake3::Hasher::new()),
+        }
+    }
+}
+
+/// A hash being calculated, which data is written into.
+pub trait Hasher: Write + Send {
+    /// Get the hash of everything written, as a lowercase hex string.
+    fn finish(self: Box<Self>) -> String;
+}
+
+impl Hasher for Sha256 {
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.finalize())
+    }
+}
+
+impl Hasher for Sha512 {
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.finalize())
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn finish(self: Box<Self>) -> String {
+        self.finalize().to_hex().to_string()
+    }
+}
+
+/// Split an expected hash into its algorithm and hex digest.
+///
+/// Hashes from rustup manifests and the crates index are bare SHA-256 digests. Other
+/// algorithms are given with their name as a prefix, e.g. `blake3:<hex>`.
+pub fn parse_expected(hash: &str) -> (HashAlgorithm, &str) {
+    HashAlgorithm::ALL
+        .into_iter()
+        .find_map(|algorithm| {
+            let digest = hash.strip_prefix(algorithm.name())?.strip_prefix(':')?;
+            Some((algorithm, digest))
+        })
+        .unwrap_or((HashAlgorithm::Sha256, hash))
+}
+
+/// Find the manifest of hashes in `dir`, whichever algorithm it was written with.
+pub fn find_sums_file(dir: &Path) -> Option<(HashAlgorithm, PathBuf)> {
+    HashAlgorithm::ALL.into_iter().find_map(|algorithm| {
+        let path = dir.join(algorithm.sums_file());
+        path.is_file().then_some((algorithm, path))
+    })
+}
+
+/// Extra sidecar files written next to each mirrored file, besides the .sha256 upstream has.
+static SIDECAR_HASHES: OnceLock<Vec<HashAlgorithm>> = OnceLock::new();
+
+/// Algorithm of the hashes panamax records itself, rather than getting from upstream.
+static MANIFEST_HASH: OnceLock<HashAlgorithm> = OnceLock::new();
+
+/// Use the sidecar_hashes and manifest_hash settings from mirror.toml for the rest of this run.
+pub fn init(mirror: &ConfigMirror) {
+    let _ = MANIFEST_HASH.set(mirror.manifest_hash.unwrap_or(HashAlgorithm::Sha256));
+
+    let extra = mirror
+        .sidecar_hashes
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|&algorithm| algorithm != HashAlgorithm::Sha256)
+        .collect();
+    let _ = SIDECAR_HASHES.set(extra);
+}
+
+/// The algorithm for the hashes panamax keeps of files itself: those of the files on disk in
+/// the checksum database, and the manifests of snapshots, images and export bundles. Files are
+/// always checked against upstream with the SHA-256 hashes it publishes.
+pub fn manifest_hash() -> HashAlgorithm {
+    MANIFEST_HASH
+        .get()
+        .copied()
+        .unwrap_or(HashAlgorithm::Sha256)
+}
+
+fn sidecar_hashes() -> &'static [HashAlgorithm] {
+    SIDECAR_HASHES.get().map_or(&[], Vec::as_slice)
+}
+
+/// The paths of a file's extra sidecar files.
+pub fn sidecar_paths(path: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    sidecar_hashes()
+        .iter()
+        .map(move |algorithm| append_to_path(path, &format!(".{}", algorithm.name())))
+}
+
+/// Write the extra sidecar files of a file, in the same `<hash>  <name>` format as upstream's
+/// .sha256 files. Those that already match are left alone.
+pub fn write_sidecars(path: &Path) -> Result<(), DownloadError> {
+    if sidecar_hashes().is_empty() {
+        return Ok(());
+    }
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut hashers: Vec<_> = sidecar_hashes().iter().map(|a| a.hasher()).collect();
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = io::Read::read(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for hasher in &mut hashers {
+            hasher.write_all(&buf[..n])?;
+        }
+    }
+
+    for (hasher, sidecar_path) in hashers.into_iter().zip(sidecar_paths(path)) {
+        let data = format!("{}  {file_name}\n", hasher.finish());
+        if fs::read_to_string(&sidecar_path).map_or(true, |current| current != data) {
+            write_file_create_dir(&sidecar_path, &data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the extra sidecar files of a file that was already mirrored, if any are missing.
+pub fn write_missing_sidecars(path: &Path) -> Result<(), DownloadError> {
+    if sidecar_paths(path).all(|p| p.exists()) {
+        return Ok(());
+    }
+    write_sidecars(path)
+}
+
+/// The file a sidecar file belongs to, if `path` is one.
+pub fn sidecar_of(path: &Path) -> Option<PathBuf> {
+    let extension = path.extension()?.to_str()?;
+    HashAlgorithm::ALL
+        .iter()
+        .any(|algorithm| algorithm.name() == extension)
+        .then(|| path.with_extension(""))
+}
+
+#[cfg(test)]
+mod test {
+
+    mod hashes {
+        use std::io::Write;
+        use std::path::Path;
+
+        use crate::hashes::{parse_expected, sidecar_of, HashAlgorithm};
+
+        #[test]
+        fn expected() {
+            assert_eq!(parse_expected("abc"), (HashAlgorithm::Sha256, "abc"));
+            assert_eq!(parse_expected("sha512:abc"), (HashAlgorithm::Sha512, "abc"));
+            assert_eq!(parse_expected("blake3:abc"), (HashAlgorithm::Blake3, "abc"));
+        }
+
+        #[test]
+        fn digests() {
+            let hash = |algorithm: HashAlgorithm| {
+                let mut hasher = algorithm.hasher();
+                hasher.write_all(b"abc").unwrap();
+                hasher.finish()
+            };
+            assert_eq!(
+                hash(HashAlgorithm::Sha256),
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+            );
+            assert_eq!(
+                hash(HashAlgorithm::Blake3),
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+            );
+            assert!(hash(HashAlgorithm::Sha512).starts_with("ddaf35a193617aba"));
+        }
+
+        #[test]
+        fn tags() {
+            let digest = "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85";
+            for algorithm in HashAlgorithm::ALL {
+                assert_eq!(parse_expected(&algorithm.tag(digest)), (algorithm, digest));
+            }
+            assert!(HashAlgorithm::Blake3.is_digest(digest));
+            assert!(!HashAlgorithm::Sha512.is_digest(digest));
+            assert!(!HashAlgorithm::Sha256.is_digest("xyz"));
+        }
+
+        #[test]
+        fn sidecar() {
+            assert_eq!(
+                sidecar_of(Path::new("dist/2023-06-01/cargo.tar.xz.blake3")),
+                Some(Path::new("dist/2023-06-01/cargo.tar.xz").to_path_buf())
+            );
+            assert_eq!(sidecar_of(Path::new("dist/2023-06-01/cargo.tar.xz")), None);
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use console::style;
+use serde::{Deserialize, Serialize};
+
+use crate::download::DownloadClient;
+use crate::events::Counts;
+use crate::mirror::ConfigMirror;
+use crate::state;
+use crate::timestamp::{Timestamp, DAY};
+
+/// Name of the file with the mirror's health as of its last sync, in the state directory.
+pub const HEALTH_FILE: &str = ".panamax-health";
+
+/// Score below which a mirror is unhealthy, if health_threshold isn't set.
+pub const DEFAULT_HEALTH_THRESHOLD: u8 = 50;
+
+/// Points taken off when a tenth or more of a sync's downloads failed, and proportionally less
+/// for fewer.
+const FAILURE_POINTS: f64 = 40.0;
+
+/// Points taken off for each day without a complete sync, after the first, up to
+/// MAX_STALE_POINTS.
+const STALE_POINTS_PER_DAY: u64 = 10;
+const MAX_STALE_POINTS: u64 = 40;
+
+/// Points taken off for each crate file verification found missing, up to
+/// MAX_VERIFICATION_POINTS.
+const VERIFICATION_POINTS_PER_ERROR: usize = 2;
+const MAX_VERIFICATION_POINTS: usize = 20;
+
+static THRESHOLD: OnceLock<u8> = OnceLock::new();
+
+/// Use the health_threshold from mirror.toml for the rest of this run.
+pub fn init(mirror: &ConfigMirror) {
+    // Only the first call sets it, as there's only one mirror per run.
+    let _ = THRESHOLD.set(mirror.health_threshold.unwrap_or(DEFAULT_HEALTH_THRESHOLD));
+}
+
+pub fn threshold() -> u8 {
+    THRESHOLD.get().copied().unwrap_or(DEFAULT_HEALTH_THRESHOLD)
+}
+
+/// What the health of a mirror is worked out from, as recorded after each sync.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Recorded {
+    /// When the last sync finished.
+    synced: Timestamp,
+    /// When the last sync that downloaded everything finished, or the UNIX epoch if none has.
+    last_complete: Timestamp,
+    /// Share of the downloads of the last sync that failed.
+    failure_ratio: f64,
+    /// Crate files the last verification found missing.
+    verification_errors: usize,
+    /// Whether the mirror was unhealthy when last recorded, so an alert is only sent when that
+    /// changes.
+    unhealthy: bool,
+}
+
+/// The health of a mirror, as a score from 0 to 100 and what it's made up of.
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    pub score: u8,
+    pub healthy: bool,
+    pub synced: Timestamp,
+    pub last_complete: Timestamp,
+    pub failure_ratio: f64,
+    /// Full days since the last complete sync. A mirror that stops syncing altogether gets
+    /// less healthy each day, without a sync to record it.
+    pub stale_days: u64,
+    pub verification_errors: usize,
+}
+
+impl Recorded {
+    fn load(mirror_path: &Path) -> Option<Self> {
+        fs::read(state::path(mirror_path, HEALTH_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+    }
+
+    fn save(&self, mirror_path: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec(self).expect("health should always serialize");
+        fs::write(state::path(mirror_path, HEALTH_FILE), data)
+    }
+
+    fn health(&self, now: Timestamp) -> Health {
+        let stale_days = now.0.saturating_sub(self.last_complete.0) / DAY;
+        let score = score(self.failure_ratio, stale_days, self.verification_errors);
+        Health {
+            score,
+            healthy: score >= threshold(),
+            synced: self.synced,
+            last_complete: self.last_complete,
+            failure_ratio: self.failure_ratio,
+            stale_days,
+            verification_errors: self.verification_errors,
+        }
+    }
+}
+
+/// Score a mirror from 0 to 100, taking points off for failed downloads, days without a
+/// complete sync, and missing crate files.
+fn score(failure_ratio: f64, stale_days: u64, verification_errors: usize) -> u8 {
+    let failures = (failure_ratio * 10.0).min(1.0) * FAILURE_POINTS;
+    let stale = (stale_days.saturating_sub(1) * STALE_POINTS_PER_DAY).min(MAX_STALE_POINTS);
+    let verification =
+        (verification_errors * VERIFICATION_POINTS_PER_ERROR).min(MAX_VERIFICATION_POINTS);
+    (100.0 - failures.ceil() - stale as f64 - verification as f64).max(0.0) as u8
+}
+
+/// The health of a mirror now, if a sync has recorded it.
+pub fn current(mirror_path: &Path) -> Option<Health> {
+    Recorded::load(mirror_path).map(|recorded| recorded.health(Timestamp::now()))
+}
+
+/// Record the health of a mirror after a sync, from what the sync downloaded and whether it
+/// finished, and the missing crates found by scheduled maintenance, if it ran.
+///
+/// When the mirror becomes unhealthy, or healthy again, an alert is posted to
+/// health_alert_url, if it's set.
+pub async fn record(
+    mirror_path: &Path,
+    mirror: &ConfigMirror,
+    mirror_id: &str,
+    counts: &Counts,
+    complete: bool,
+    verification_errors: Option<usize>,
+    client: &DownloadClient,
+) -> Health {
+    let now = Timestamp::now();
+    let mut recorded = Recorded::load(mirror_path).unwrap_or_default();
+    let was_unhealthy = recorded.unhealthy;
+
+    recorded.synced = now;
+    if complete {
+        recorded.last_complete = now;
+    }
+    let attempted = counts.downloaded + counts.failed;
+    recorded.failure_ratio = match attempted {
+        0 => 0.0,
+        _ => counts.failed as f64 / attempted as f64,
+    };
+    if let Some(errors) = verification_errors {
+        recorded.verification_errors = errors;
+    }
+    let health = recorded.health(now);
+    recorded.unhealthy = !health.healthy;
+
+    if let Err(e) = recorded.save(mirror_path) {
+        eprintln!("Could not record the mirror's health: {e}");
+    }
+
+    if !health.healthy {
+        eprintln!(
+            "{}",
+            style(format!(
+                "Mirror health is {}, below the threshold of {}.",
+                health.score,
+                threshold()
+            ))
+            .yellow()
+            .bold()
+        );
+    }
+    if health.healthy == was_unhealthy {
+        alert(client, mirror, mirror_id, &health).await;
+    }
+    health
+}
+
+/// Record what a verification of the mirror found, for its health.
+pub fn record_verification(mirror_path: &Path, missing_crates: usize) -> io::Result<()> {
+    let Some(mut recorded) = Recorded::load(mirror_path) else {
+        // Nothing to add it to until a sync has recorded the rest.
+        return Ok(());
+    };
+    recorded.verification_errors = missing_crates;
+    recorded.save(mirror_path)
+}
+
+/// Post an alert that the mirror's health crossed the threshold to health_alert_url.
+async fn alert(client: &DownloadClient, mirror: &ConfigMirror, mirror_id: &str, health: &Health) {
+    let Some(alert_url) = &mirror.health_alert_url else {
+        return;
+    };
+    #[derive(Serialize)]
+    struct Alert<'a> {
+        mirror_id: &'a str,
+        threshold: u8,
+        #[serde(flatten)]
+        health: &'a Health,
+    }
+    let alert = Alert {
+        mirror_id: mirror.mirror_id.as_deref().unwrap_or(mirror_id),
+        threshold: threshold(),
+        health,
+    };
+    if let Err(e) =
+        crate::report::post(client, alert_url, mirror.report_token.as_deref(), &alert).await
+    {
+        eprintln!("Sending health alert to {alert_url} failed: {e}");
+    }
+}
+
+/// The health of a mirror in the Prometheus text format, for scraping.
+pub fn metrics(health: Option<&Health>) -> String {
+    let Some(health) = health else {
+        return "# No sync has recorded the mirror's health yet.\n".to_string();
+    };
+    let gauges: [(&str, &str, f64); 5] = [
+        (
+            "panamax_health_score",
+            "Health of the mirror, from 0 to 100.",
+            health.score as f64,
+        ),
+        (
+            "panamax_healthy",
+            "Whether the health score is at or above health_threshold.",
+            health.healthy as u8 as f64,
+        ),
+        (
+            "panamax_health_failure_ratio",
+            "Share of the downloads of the last sync that failed.",
+            health.failure_ratio,
+        ),
+        (
+            "panamax_health_stale_days",
+            "Full days since the last complete sync.",
+            health.stale_days as f64,
+        ),
+        (
+            "panamax_health_verification_errors",
+            "Crate files the last verification found missing.",
+            health.verification_errors as f64,
+        ),
+    ];
+    gauges
+        .iter()
+        .map(|(name, help, value)| {
+            format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+
+    mod score {
+        use crate::health::score;
+
+        #[test]
+        fn healthy() {
+            assert_eq!(score(0.0, 0, 0), 100);
+            // A daily sync is never more than a day old.
+            assert_eq!(score(0.0, 1, 0), 100);
+        }
+
+        #[test]
+        fn failures() {
+            assert_eq!(score(0.01, 0, 0), 96);
+            assert_eq!(score(0.1, 0, 0), 60);
+            assert_eq!(score(1.0, 0, 0), 60);
+        }
+
+        #[test]
+        fn stale() {
+            assert_eq!(score(0.0, 3, 0), 80);
+            assert_eq!(score(0.0, 365, 0), 60);
+        }
+
+        #[test]
+        fn everything_wrong() {
+            assert_eq!(score(0.5, 30, 1000), 0);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::download::local_source;
+
+/// What a sync's requests to one upstream host added up to.
+#[derive(Debug, Default)]
+struct HostStats {
+    requests: u64,
+    bytes: u64,
+    errors: u64,
+    retries: u64,
+    /// Time waiting for response headers, across all requests.
+    latency: Duration,
+}
+
+/// Statistics for one upstream host, as reported at the end of a sync.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HostSummary {
+    pub requests: u64,
+    pub bytes: u64,
+    /// Attempts that failed with a network error or a 5xx response.
+    pub errors: u64,
+    pub retries: u64,
+    /// Mean time waiting for response headers, in milliseconds.
+    pub mean_latency_ms: u64,
+}
+
+/// Statistics of this run, by upstream host.
+static STATS: Mutex<BTreeMap<String, HostStats>> = Mutex::new(BTreeMap::new());
+
+/// The host a URL is counted under, with its port if it isn't the default.
+fn host(url: &str) -> String {
+    let Ok(url) = url::Url::parse(url) else {
+        return "unknown".to_string();
+    };
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => "unknown".to_string(),
+    }
+}
+
+fn update(url: &str, f: impl FnOnce(&mut HostStats)) {
+    // Files copied from a local source aren't from a host.
+    if local_source(url).is_some() {
+        return;
+    }
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    f(stats.entry(host(url)).or_default());
+}
+
+/// Count a request to `url`, which took `latency` to get a response, or to fail.
+pub fn record_request(url: &str, latency: Duration) {
+    update(url, |stats| {
+        stats.requests += 1;
+        stats.latency += latency;
+    });
+}
+
+/// Count bytes received from `url`.
+pub fn record_bytes(url: &str, bytes: usize) {
+    update(url, |stats| stats.bytes += bytes as u64);
+}
+
+/// Count an attempt at `url` that failed with a network error or a 5xx response.
+pub fn record_error(url: &str) {
+    update(url, |stats| stats.errors += 1);
+}
+
+/// Count a download of `url` being tried again.
+pub fn record_retry(url: &str) {
+    update(url, |stats| stats.retries += 1);
+}
+
+/// Get the statistics of every host requested so far.
+pub fn summary() -> BTreeMap<String, HostSummary> {
+    let stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    stats
+        .iter()
+        .map(|(host, stats)| {
+            let mean_latency = stats
+                .latency
+                .checked_div(stats.requests as u32)
+                .unwrap_or_default();
+            let summary = HostSummary {
+                requests: stats.requests,
+                bytes: stats.bytes,
+                errors: stats.errors,
+                retries: stats.retries,
+                mean_latency_ms: mean_latency.as_millis() as u64,
+            };
+            (host.clone(), summary)
+        })
+        .collect()
+}
+
+/// Print the statistics of every host requested, to tell whether a slow sync is down to one
+/// upstream or the local network.
+pub fn print_summary() {
+    let summary = summary();
+    if summary.is_empty() {
+        return;
+    }
+    eprintln!("Upstream hosts:");
+    for (host, stats) in summary {
+        eprintln!(
+            "  {host}: {} request(s), {:.2} MB, mean latency {} ms, {} error(s), {} retries",
+            stats.requests,
+            stats.bytes as f64 / 1e6,
+            stats.mean_latency_ms,
+            stats.errors,
+            stats.retries
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod host {
+        use crate::host_stats::host;
+
+        #[test]
+        fn host_and_port() {
+            assert_eq!(
+                host("https://static.crates.io/crates/a/a-1.0.0.crate"),
+                "static.crates.io"
+            );
+            assert_eq!(host("http://localhost:8080/dist/x"), "localhost:8080");
+            assert_eq!(host("https://example.com:443/x"), "example.com");
+            assert_eq!(host("not a url"), "unknown");
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::validators::Validators;
+
+/// Directory small upstream files are cached in, in the mirror root. Nothing under it is
+/// served.
+pub const HTTP_CACHE_DIR: &str = ".panamax-http-cache";
+
+/// Cached files that no sync has asked for in this long are removed by maintenance.
+const MAX_UNUSED: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// A cached response, with what upstream said identifies its version.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    url: String,
+    pub validators: Validators,
+    pub body: String,
+}
+
+/// Where the cache is, if one was opened.
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Cache small files fetched as strings, like .sha256 files and channel manifests, for the rest
+/// of this run, so they're only downloaded again if upstream says they've changed.
+pub fn open(mirror_path: &Path) {
+    // Only one mirror is synced per run, so any later call is ignored.
+    let _ = DIR.set(mirror_path.join(HTTP_CACHE_DIR));
+}
+
+fn entry_path(dir: &Path, url: &str) -> PathBuf {
+    dir.join(format!("{:x}", Sha256::digest(url.as_bytes())))
+}
+
+/// Get the cached response for a URL. Always None if no cache was opened.
+pub fn get(url: &str) -> Option<Entry> {
+    get_in(DIR.get()?, url)
+}
+
+fn get_in(dir: &Path, url: &str) -> Option<Entry> {
+    let path = entry_path(dir, url);
+    let entry: Entry = serde_json::from_slice(&fs::read(&path).ok()?).ok()?;
+    if entry.url != url {
+        return None;
+    }
+    // Mark the entry as used, so maintenance keeps it.
+    if let Ok(file) = File::options().append(true).open(&path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+    Some(entry)
+}
+
+/// Cache a response to a URL, or forget it if upstream gave no validators to check it against
+/// later.
+pub fn put(url: &str, validators: Option<Validators>, body: &str) -> io::Result<()> {
+    match DIR.get() {
+        Some(dir) => put_in(dir, url, validators, body),
+        None => Ok(()),
+    }
+}
+
+fn put_in(dir: &Path, url: &str, validators: Option<Validators>, body: &str) -> io::Result<()> {
+    let path = entry_path(dir, url);
+    let Some(validators) = validators else {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    };
+
+    let entry = Entry {
+        url: url.to_string(),
+        validators,
+        body: body.to_string(),
+    };
+    let data = serde_json::to_vec(&entry).expect("cache entries should always serialize");
+    fs::create_dir_all(dir)?;
+    // Write to a temporary file of its own first, so other syncs and shards writing the same
+    // entry never read half of it, or overwrite each other's.
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(&data)?;
+    tmp.persist(path)?;
+    Ok(())
+}
+
+/// Remove cached files that haven't been used in a while, as their URLs are likely for
+/// releases that are no longer synced. Returns the number of files removed.
+pub fn prune(mirror_path: &Path) -> io::Result<usize> {
+    let entries = match fs::read_dir(mirror_path.join(HTTP_CACHE_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut pruned = 0;
+    for entry in entries {
+        let entry = entry?;
+        let unused = entry
+            .metadata()?
+            .modified()?
+            .elapsed()
+            .is_ok_and(|unused| unused > MAX_UNUSED);
+        if unused {
+            fs::remove_file(entry.path())?;
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod cache {
+        use std::fs::File;
+        use std::time::{Duration, SystemTime};
+
+        use reqwest::header::{HeaderMap, HeaderValue, ETAG};
+
+        use crate::http_cache::{entry_path, get_in, prune, put_in, HTTP_CACHE_DIR, MAX_UNUSED};
+        use crate::validators::Validators;
+
+        const URL: &str = "https://static.rust-lang.org/dist/channel-rust-stable.toml.sha256";
+
+        fn etag(etag: &'static str) -> Option<Validators> {
+            let mut headers = HeaderMap::new();
+            headers.insert(ETAG, HeaderValue::from_static(etag));
+            Validators::from_headers(&headers)
+        }
+
+        #[test]
+        fn hit_and_miss() {
+            let mirror = tempfile::tempdir().unwrap();
+            let dir = mirror.path().join(HTTP_CACHE_DIR);
+            assert!(get_in(&dir, URL).is_none());
+
+            put_in(&dir, URL, etag("\"a\""), "abc").unwrap();
+            let entry = get_in(&dir, URL).unwrap();
+            assert_eq!(entry.body, "abc");
+            assert_eq!(Some(entry.validators), etag("\"a\""));
+            assert!(get_in(&dir, "https://static.rust-lang.org/other.sha256").is_none());
+
+            // Without validators, there's nothing to check it against later.
+            put_in(&dir, URL, None, "abc").unwrap();
+            assert!(get_in(&dir, URL).is_none());
+        }
+
+        #[test]
+        fn overwrite() {
+            let mirror = tempfile::tempdir().unwrap();
+            let dir = mirror.path().join(HTTP_CACHE_DIR);
+            put_in(&dir, URL, etag("\"a\""), "abc").unwrap();
+            put_in(&dir, URL, etag("\"b\""), "def").unwrap();
+            let entry = get_in(&dir, URL).unwrap();
+            assert_eq!(entry.body, "def");
+            assert_eq!(Some(entry.validators), etag("\"b\""));
+            // No temporary files are left behind.
+            assert_eq!(dir.read_dir().unwrap().count(), 1);
+        }
+
+        #[test]
+        fn expiry() {
+            let mirror = tempfile::tempdir().unwrap();
+            let dir = mirror.path().join(HTTP_CACHE_DIR);
+            let old_url = "https://static.rust-lang.org/dist/2020-01-01/channel.sha256";
+            put_in(&dir, URL, etag("\"a\""), "abc").unwrap();
+            put_in(&dir, old_url, etag("\"a\""), "abc").unwrap();
+            let long_ago = SystemTime::now() - MAX_UNUSED - Duration::from_secs(60);
+            File::options()
+                .append(true)
+                .open(entry_path(&dir, old_url))
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap();
+
+            assert_eq!(prune(mirror.path()).unwrap(), 1);
+            assert!(get_in(&dir, URL).is_some());
+            assert!(get_in(&dir, old_url).is_none());
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::checksums;
+use crate::download::append_to_path;
+use crate::hashes::manifest_hash;
+use crate::mirror::MirrorError;
+use crate::progress_bar::{padded_prefix_message, progress_bar};
+use crate::timestamp::Timestamp;
+
+/// Starts and ends an archive image, so a truncated or foreign file is never served. The
+/// number is the version of the format.
+const MAGIC: &[u8; 16] = b"PANAMAX-IMAGE-1\n";
+
+/// Directories of a mirror that hold mirrored files.
+const CONTENT_DIRS: [&str; 4] = ["dist", "rustup", "crates", "crates.io-index"];
+
+/// How a mirror is packed into an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageFormat {
+    /// A single file that `panamax serve` can serve from directly, without unpacking it. The
+    /// index's git repository is left out, so cargo has to use the sparse protocol.
+    Archive,
+    /// A squashfs filesystem made with mksquashfs, holding the whole mirror along with a
+    /// manifest of its files' hashes. It's served by mounting it and serving the mount.
+    Squashfs,
+}
+
+/// Where a file is within an archive image, and its hash. This is always SHA-256, whatever
+/// manifest_hash is set to, as serve gives it as the file's ETag.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub offset: u64,
+    pub len: u64,
+    pub sha256: String,
+}
+
+/// The manifest at the end of an archive image.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    /// When the image was packed.
+    packed: Timestamp,
+    files: HashMap<String, Entry>,
+}
+
+/// What packing a mirror did.
+pub struct Packed {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Pack the mirrored files of a mirror into an image at `image_path`.
+///
+/// The image is written next to its destination and renamed into place once it's complete,
+/// so a failed pack never leaves an image behind that looks whole.
+pub async fn pack(
+    mirror_path: &Path,
+    image_path: &Path,
+    format: ImageFormat,
+) -> Result<Packed, MirrorError> {
+    if image_path.exists() {
+        return Err(MirrorError::CmdLine(format!(
+            "{} already exists.",
+            image_path.display()
+        )));
+    }
+    let mut files = content_files(mirror_path)?;
+    if format == ImageFormat::Archive {
+        files.retain(|file| !file.starts_with("crates.io-index/.git/"));
+    }
+    let sizes = files
+        .iter()
+        .map(|file| fs::metadata(mirror_path.join(file)).map(|m| m.len()))
+        .collect::<io::Result<Vec<u64>>>()?;
+    let total = sizes.iter().sum();
+
+    let part_path = append_to_path(image_path, ".part");
+    let written = match format {
+        ImageFormat::Archive => write_archive(mirror_path, &files, &part_path),
+        ImageFormat::Squashfs => write_squashfs(mirror_path, &files, &part_path).await,
+    };
+    if let Err(e) = written {
+        let _ = fs::remove_file(&part_path);
+        return Err(e);
+    }
+    fs::rename(&part_path, image_path)?;
+
+    Ok(Packed {
+        files: files.len(),
+        bytes: total,
+    })
+}
+
+/// The mirrored files within a mirror, relative to its root and sorted, skipping files left
+/// behind by failed downloads.
+fn content_files(mirror_path: &Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for dir in CONTENT_DIRS {
+        // A mirror that doesn't sync rustup or crates may not have their directories.
+        if !mirror_path.join(dir).exists() {
+            continue;
+        }
+        // Symlinks are not followed, same as when cleaning old files during sync.
+        for entry in walkdir::WalkDir::new(mirror_path.join(dir)).sort_by_file_name() {
+            let entry = entry.map_err(io::Error::from)?;
+            let is_leftover = entry
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "part" || ext == "notfound");
+            if !entry.file_type().is_file() || is_leftover {
+                continue;
+            }
+            let relative = entry
+                .path()
+                .strip_prefix(mirror_path)
+                .expect("walked paths are within the mirror");
+            let Some(relative) = relative.to_str() else {
+                continue;
+            };
+            files.push(relative.replace('\\', "/"));
+        }
+    }
+    Ok(files)
+}
+
+/// Write an archive image: the magic, then every file one after the other, then the manifest
+/// as JSON, its offset and the magic again. The manifest is read from the end, so nothing has
+/// to be known about the files before they're written.
+fn write_archive(
+    mirror_path: &Path,
+    files: &[String],
+    part_path: &Path,
+) -> Result<(), MirrorError> {
+    let mut out = BufWriter::new(File::create(part_path)?);
+    out.write_all(MAGIC)?;
+    let mut offset = MAGIC.len() as u64;
+
+    let pb = progress_bar(files.len(), padded_prefix_message(1, 1, "Packing mirror"));
+    let mut manifest = Manifest {
+        packed: Timestamp::now(),
+        files: HashMap::with_capacity(files.len()),
+    };
+    let mut buf = vec![0u8; 64 * 1024];
+    for file in files {
+        let mut input = File::open(mirror_path.join(file))?;
+        let mut sha256 = Sha256::new();
+        let mut len = 0u64;
+        loop {
+            let n = input.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            sha256.update(&buf[..n]);
+            out.write_all(&buf[..n])?;
+            len += n as u64;
+        }
+        manifest.files.insert(
+            file.clone(),
+            Entry {
+                offset,
+                len,
+                sha256: format!("{:x}", sha256.finalize()),
+            },
+        );
+        offset += len;
+        pb.inc(1);
+    }
+    pb.finish();
+
+    serde_json::to_writer(&mut out, &manifest).map_err(io::Error::from)?;
+    out.write_all(&offset.to_le_bytes())?;
+    out.write_all(MAGIC)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
+
+/// Write a squashfs image of the mirror's content directories, along with a manifest of the
+/// hashes of the files in them.
+async fn write_squashfs(
+    mirror_path: &Path,
+    files: &[String],
+    part_path: &Path,
+) -> Result<(), MirrorError> {
+    let algorithm = manifest_hash();
+    let pb = progress_bar(files.len(), padded_prefix_message(1, 2, "Hashing mirror"));
+    let mut manifest = String::new();
+    for file in files {
+        let hash = checksums::hash(&mirror_path.join(file), algorithm).await?;
+        manifest.push_str(&format!("{hash}  {file}\n"));
+        pb.inc(1);
+    }
+    pb.finish();
+
+    // mksquashfs puts each source at the root of the image by its file name.
+    let manifest_dir = append_to_path(part_path, ".manifest");
+    fs::create_dir_all(&manifest_dir)?;
+    fs::write(manifest_dir.join(algorithm.sums_file()), manifest)?;
+    let sources = CONTENT_DIRS
+        .into_iter()
+        .map(|dir| mirror_path.join(dir))
+        .filter(|dir| dir.exists());
+
+    crate::progress_bar::step_message(format!(
+        "{} Running mksquashfs...",
+        crate::progress_bar::current_step_prefix(2, 2)
+    ));
+    let status = Command::new("mksquashfs")
+        .args(sources)
+        .arg(manifest_dir.join(algorithm.sums_file()))
+        .arg(part_path)
+        .args(["-noappend", "-quiet"])
+        .status();
+    let _ = fs::remove_dir_all(&manifest_dir);
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(MirrorError::Image(format!("mksquashfs failed: {status}"))),
+        Err(e) => Err(MirrorError::Image(format!("Could not run mksquashfs: {e}"))),
+    }
+}
+
+/// An archive image made by `pack`, opened for serving.
+pub struct Image {
+    path: PathBuf,
+    packed: Timestamp,
+    files: HashMap<String, Entry>,
+}
+
+impl Image {
+    /// Open an archive image, reading its manifest.
+    pub fn open(path: &Path) -> Result<Self, MirrorError> {
+        let invalid = || MirrorError::Image(format!("{} isn't a Panamax image", path.display()));
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let trailer_len = 8 + MAGIC.len() as u64;
+        if len < MAGIC.len() as u64 + trailer_len {
+            return Err(invalid());
+        }
+
+        let mut magic = [0u8; 16];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid());
+        }
+        let mut trailer = [0u8; 24];
+        file.seek(SeekFrom::End(-(trailer_len as i64)))?;
+        file.read_exact(&mut trailer)?;
+        if &trailer[8..] != MAGIC {
+            return Err(invalid());
+        }
+        let manifest_offset = u64::from_le_bytes(trailer[..8].try_into().expect("8 bytes"));
+        if manifest_offset < MAGIC.len() as u64 || manifest_offset > len - trailer_len {
+            return Err(invalid());
+        }
+
+        file.seek(SeekFrom::Start(manifest_offset))?;
+        let manifest: Manifest =
+            serde_json::from_reader(file.take(len - trailer_len - manifest_offset))
+                .map_err(|_| invalid())?;
+        if manifest
+            .files
+            .values()
+            .any(|entry| entry.offset.saturating_add(entry.len) > manifest_offset)
+        {
+            return Err(invalid());
+        }
+
+        Ok(Image {
+            path: path.to_path_buf(),
+            packed: manifest.packed,
+            files: manifest.files,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// When the image was packed.
+    pub fn packed(&self) -> Timestamp {
+        self.packed
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// The entry of a file, by its path within the mirror, e.g. `dist/channel-rust-stable.toml`.
+    pub fn get(&self, file: &str) -> Option<&Entry> {
+        self.files.get(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod archive {
+        use std::fs;
+        use std::io::Read;
+
+        use crate::image::{write_archive, Image};
+
+        #[test]
+        fn round_trip() {
+            let dir = tempfile::tempdir().unwrap();
+            let mirror = dir.path().join("mirror");
+            fs::create_dir_all(mirror.join("dist")).unwrap();
+            fs::create_dir_all(mirror.join("crates/1/a/0.1.0")).unwrap();
+            fs::write(mirror.join("dist/channel-rust-stable.toml"), "manifest").unwrap();
+            fs::write(mirror.join("crates/1/a/0.1.0/a-0.1.0.crate"), "crate").unwrap();
+            let files = vec![
+                "crates/1/a/0.1.0/a-0.1.0.crate".to_string(),
+                "dist/channel-rust-stable.toml".to_string(),
+            ];
+            let image_path = dir.path().join("mirror.img");
+            write_archive(&mirror, &files, &image_path).unwrap();
+
+            let image = Image::open(&image_path).unwrap();
+            assert_eq!(image.file_count(), 2);
+            let entry = image.get("dist/channel-rust-stable.toml").unwrap();
+            let mut contents = vec![0u8; entry.len as usize];
+            let mut file = fs::File::open(&image_path).unwrap();
+            std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(entry.offset)).unwrap();
+            file.read_exact(&mut contents).unwrap();
+            assert_eq!(contents, b"manifest");
+            assert!(image.get("dist/missing").is_none());
+        }
+
+        #[test]
+        fn truncated() {
+            let dir = tempfile::tempdir().unwrap();
+            let mirror = dir.path().join("mirror");
+            fs::create_dir_all(mirror.join("dist")).unwrap();
+            fs::write(mirror.join("dist/a"), "a").unwrap();
+            let image_path = dir.path().join("mirror.img");
+            write_archive(&mirror, &["dist/a".to_string()], &image_path).unwrap();
+
+            let image = fs::read(&image_path).unwrap();
+            fs::write(&image_path, &image[..image.len() - 1]).unwrap();
+            assert!(Image::open(&image_path).is_err());
+        }
+    }
+}
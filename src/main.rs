@@ -1,18 +1,76 @@
 #![forbid(unsafe_code)]
-use clap::Parser;
+use clap::{ArgAction, ArgGroup, Parser, Subcommand};
+use log::LevelFilter;
 use std::{net::IpAddr, path::PathBuf};
 
+mod build_cache;
+mod bundle;
+mod changelog;
+mod checksums;
+mod crate_packs;
 mod crates;
 mod crates_index;
+mod disk;
 mod download;
+mod events;
+mod graph;
+mod hashes;
+mod health;
+mod host_stats;
+mod http_cache;
+mod image;
+mod maintenance;
 mod mirror;
+mod overlay;
+mod pack_cache;
+mod permissions;
+mod plan;
 mod progress_bar;
+mod replica;
+mod report;
+mod rng;
 mod rustup;
+mod schedule;
+mod scrub;
+mod self_check;
+mod self_update;
+mod selftest;
 mod serve;
+mod served;
+mod shards;
+mod shutdown;
+mod snapshot;
+mod staging;
+mod state;
+mod status;
+mod storage;
+mod throttle;
+mod timestamp;
+mod url_path;
+mod validators;
 mod verify;
 
 /// Mirror rustup and crates.io repositories, for offline Rust and cargo usage.
 #[derive(Debug, Parser)]
+struct Opt {
+    /// Only print errors and the final summary.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log every file acted on. Repeat (-vv) to also trace HTTP requests.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Report steps and progress as a JSON object per line on stderr, instead of drawing
+    /// progress bars.
+    #[arg(long, global = true, conflicts_with = "quiet")]
+    json_progress: bool,
+
+    #[command(subcommand)]
+    command: Panamax,
+}
+
+#[derive(Debug, Subcommand)]
 enum Panamax {
     /// Create a new mirror directory.
     Init {
@@ -22,6 +80,11 @@ enum Panamax {
         /// set [rustup] sync = false
         #[arg(long)]
         ignore_rustup: bool,
+
+        /// Contact email or URL for the user agent, set as [mirror] contact.
+        /// Asked for if not given, when run interactively.
+        #[arg(long)]
+        contact: Option<String>,
     },
 
     /// Update an existing mirror directory.
@@ -40,6 +103,31 @@ enum Panamax {
 
         #[arg(long)]
         skip_rustup: bool,
+
+        /// Only sync this part of the mirror.
+        #[arg(long, value_enum, conflicts_with = "skip_rustup")]
+        only: Option<mirror::SyncOnly>,
+
+        #[command(flatten)]
+        retention: mirror::RetentionOverrides,
+
+        /// Only download one part of the files, e.g. "2/4", so several hosts sharing the mirror
+        /// directory can split a large sync between them. The last shard to finish completes
+        /// the mirror.
+        #[arg(long)]
+        shard: Option<mirror::Shard>,
+
+        /// Write one JSON event per file downloaded, skipped, failed, or deleted to this file.
+        /// Use `-` for stdout.
+        #[arg(long)]
+        events_ndjson: Option<PathBuf>,
+
+        /// Print what the sync would download and remove, one JSON object per file, without
+        /// downloading or removing any mirrored files. This still contacts upstream for the
+        /// channel manifests and the index, and the fetched index commits are stored in the
+        /// mirror's crates.io-index repository, without changing the served index.
+        #[arg(long, conflicts_with = "shard")]
+        print_plan: bool,
     },
 
     /// Rewrite the config.json within crates.io-index.
@@ -55,12 +143,20 @@ enum Panamax {
         /// Base URL used for rewriting. Overrides value in mirror.toml.
         #[arg(short, long)]
         base_url: Option<String>,
+
+        /// Template for the crate download URL. Overrides value in mirror.toml.
+        #[arg(long)]
+        dl_template: Option<String>,
+
+        /// Leave the api URL out of config.json, as with omit_api in mirror.toml.
+        #[arg(long)]
+        omit_api: bool,
     },
 
     /// Serve a mirror directory.
     #[command(name = "serve")]
     Serve {
-        /// Mirror directory.
+        /// Mirror directory, or an archive image made by panamax pack.
         #[arg(value_parser)]
         path: PathBuf,
 
@@ -82,6 +178,76 @@ enum Panamax {
         /// Also requires cert_path.
         #[arg(long)]
         key_path: Option<PathBuf>,
+
+        /// Listen on a Unix socket at this path instead of a TCP port, for use behind
+        /// another web server. Can't be combined with TLS.
+        #[arg(long)]
+        unix_socket: Option<PathBuf>,
+
+        /// How to handle problems found when checking the mirror on startup.
+        #[arg(long, value_enum, default_value_t = self_check::Strictness::Warn)]
+        self_check: self_check::Strictness,
+
+        /// Verify this many mirrored files per hour in the background, logging any that
+        /// are corrupt.
+        #[arg(long)]
+        scrub_per_hour: Option<u32>,
+
+        /// Directory to cache the packs sent for full clones of the git index in, so they
+        /// are only generated once per index update. Should be outside the mirror.
+        #[arg(long)]
+        git_pack_cache: Option<PathBuf>,
+
+        /// Directory to store a compiler cache in, served at /build-cache/ for sccache's
+        /// WebDAV backend. Should be outside the mirror. Also requires a token, from
+        /// build_cache_token_file or the PANAMAX_BUILD_CACHE_TOKEN environment variable.
+        #[arg(long)]
+        build_cache_dir: Option<PathBuf>,
+
+        /// File holding the bearer token that clients of the build cache must send. It's read
+        /// from a file rather than given here so it doesn't show up in the process list.
+        #[arg(long)]
+        build_cache_token_file: Option<PathBuf>,
+
+        /// Size limit of the build cache in bytes. The oldest entries are removed when it's
+        /// exceeded. Defaults to 10 GiB.
+        #[arg(long)]
+        build_cache_max_size: Option<u64>,
+
+        /// Serve the crates registry in protected mode, where cargo has to send a token with
+        /// every request to it, as set up with `cargo login`. The token is read from
+        /// auth_token_file or the PANAMAX_AUTH_TOKEN environment variable. Needs cargo 1.74 or
+        /// newer and the sparse index.
+        #[arg(long)]
+        auth_required: bool,
+
+        /// File holding the token cargo must send in protected mode.
+        #[arg(long)]
+        auth_token_file: Option<PathBuf>,
+
+        /// Show a notice on the home page while the mirror's index has a newer release of
+        /// panamax than the one serving it.
+        #[arg(long)]
+        update_notice: bool,
+    },
+
+    /// Repack the index and remove leftover files from failed downloads.
+    ///
+    /// This is intended to be run periodically, e.g. from a weekly cronjob,
+    /// but not while a sync is running.
+    #[command(name = "maintain")]
+    Maintain {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+    },
+
+    /// Show how complete a mirror is, and how much space it uses.
+    #[command(name = "status")]
+    Status {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
     },
 
     /// List platforms currently available.
@@ -123,31 +289,366 @@ enum Panamax {
         #[arg(long = "cargo-lock")]
         cargo_lock_filepath: Option<PathBuf>,
     },
+
+    /// Print the dependency graph of a crate, resolved from the mirror's index.
+    ///
+    /// Each dependency resolves to its newest matching version that isn't yanked.
+    /// Dev-dependencies are left out.
+    #[command(name = "graph")]
+    Graph {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Crate to start from, e.g. "serde" or "serde@1.0.150". Defaults to the newest version.
+        #[arg(value_parser)]
+        krate: String,
+
+        /// Only follow dependencies this many levels deep.
+        #[arg(long)]
+        depth: Option<usize>,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = graph::GraphFormat::Dot)]
+        format: graph::GraphFormat,
+    },
+
+    /// List the crate versions in a mirror's index that depend on a crate.
+    ///
+    /// Dependencies of every kind are listed, including on renamed crates, but not those from
+    /// other registries.
+    #[command(name = "rdeps")]
+    Rdeps {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Crate to find the dependents of, e.g. "serde".
+        #[arg(value_parser)]
+        krate: String,
+
+        /// Only list dependents that allow a version of the crate matching this requirement,
+        /// e.g. "=1.0.150" or "<1.0.150".
+        #[arg(long)]
+        req: Option<String>,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = graph::RdepsFormat::Text)]
+        format: graph::RdepsFormat,
+    },
+
+    /// Summarize what syncs have changed on the mirror: new, yanked and removed crate versions,
+    /// and new and removed toolchains.
+    #[command(name = "changelog")]
+    Changelog {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Only include syncs after this sync ID, or since this date, e.g. "2023-06-01".
+        #[arg(long)]
+        since: String,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = changelog::ChangelogFormat::Text)]
+        format: changelog::ChangelogFormat,
+    },
+
+    /// Pack a mirror into a single image file with a manifest of its files' hashes, e.g. to
+    /// hand a frozen mirror to a disconnected site. An archive image can be served directly
+    /// with panamax serve.
+    Pack {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Image file to write.
+        #[arg(value_parser)]
+        image: PathBuf,
+
+        /// Kind of image to write.
+        #[arg(long, value_enum, default_value_t = image::ImageFormat::Archive)]
+        format: image::ImageFormat,
+    },
+
+    /// Back up or restore a mirror's state, i.e. its channel history, checksum database and
+    /// other metadata, which can't be downloaded again.
+    #[command(name = "state", subcommand)]
+    State(StateCommand),
+
+    /// Share frozen snapshots of a mirror between sites over BitTorrent or IPFS, or seed a new
+    /// mirror from one.
+    #[command(name = "snapshot", subcommand)]
+    Snapshot(SnapshotCommand),
+
+    /// Write a mirror's content to a single zstd-compressed tar bundle, e.g. to carry to an
+    /// air-gapped mirror on removable media.
+    ///
+    /// The bundle is streamed as it's written, so it needs no space besides its destination.
+    Export {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// File or block device to write the bundle to, or - for stdout, e.g. to pipe it
+        /// through ssh.
+        #[arg(value_parser)]
+        dest: PathBuf,
+
+        /// Split the bundle into numbered parts of this size, e.g. 25GB for Blu-ray discs,
+        /// along with a manifest of them that import checks first.
+        #[arg(long, value_parser = bundle::parse_size)]
+        split_size: Option<u64>,
+
+        /// Encrypt the bundle with age to this recipient, an age1... public key. May be given
+        /// more than once, for the bundle to be readable by any of them.
+        #[arg(long = "encrypt-to")]
+        encrypt_to: Vec<String>,
+
+        /// Only write the files that changed since the last export, by their hashes, and list
+        /// those removed since, for a mirror that imported it.
+        #[arg(long)]
+        delta: bool,
+    },
+
+    /// Add the files of a bundle made by export to a mirror. Every file is checked against the
+    /// bundle's manifest before any is moved into place, and the index is updated last.
+    Import {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Bundle to import, or - for stdin. For a split bundle, the name it was exported to.
+        #[arg(value_parser)]
+        src: PathBuf,
+
+        /// age identity file to decrypt an encrypted bundle with. May be given more than once.
+        #[arg(long = "identity")]
+        identities: Vec<PathBuf>,
+
+        /// Only check the bundle, and print what importing it would do.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Copy a mirror to a standby replica, which can be served and promoted to take over if
+    /// this host fails. Only files that changed since the last run are copied.
+    ///
+    /// This is intended to be run after each sync, but not while one is running.
+    Replicate {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Directory or file:// URL to replicate to, e.g. the standby host's disk mounted over
+        /// NFS. May be given more than once. Defaults to replicas in mirror.toml.
+        #[arg(long = "to")]
+        to: Vec<String>,
+
+        /// Replace the destination even if it's a mirror of its own rather than a replica,
+        /// e.g. an old primary that was failed over from.
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Turn a replica into a primary mirror that can be synced, e.g. after the primary fails.
+    Promote {
+        /// Replica directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Base URL the replica is served at, if it's not the primary's. Written to mirror.toml
+        /// and the index's config.json.
+        #[arg(long)]
+        base_url: Option<String>,
+    },
+
+    /// Check panamax itself against the releases in the mirror.
+    #[command(name = "self", subcommand)]
+    SelfCommands(SelfCommand),
+
+    /// Serve a tiny synthetic mirror, and check that real cargo and rustup clients can use it.
+    ///
+    /// Everything is done in a temporary directory, so the user's own caches and toolchains
+    /// are left alone.
+    Selftest {
+        /// Only test this client, instead of both.
+        #[arg(long, value_enum)]
+        client: Option<selftest::SelftestClient>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum StateCommand {
+    /// Copy the state files to a new snapshot in a directory, with a manifest of their hashes.
+    Export {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Directory to keep snapshots in.
+        #[arg(value_parser)]
+        dest: PathBuf,
+
+        /// Number of snapshots to keep in the directory, removing older ones.
+        #[arg(long, default_value_t = 5)]
+        keep: usize,
+    },
+
+    /// Restore the state files from a snapshot, after checking them against its manifest.
+    Import {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Snapshot to restore, or a directory of snapshots to restore the newest of.
+        #[arg(value_parser)]
+        src: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SelfCommand {
+    /// Check whether the mirror's index has a newer release of panamax than this one.
+    CheckUpdate {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SnapshotCommand {
+    /// Hard link the mirror's files into a new snapshot directory, with a manifest of their
+    /// hashes, ready to share.
+    #[command(group(ArgGroup::new("share").required(true).multiple(true)))]
+    Publish {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Directory to create the snapshot in. It should be on the same filesystem as the
+        /// mirror, so files can be hard linked rather than copied.
+        #[arg(value_parser)]
+        dest: PathBuf,
+
+        /// Write a .torrent file for the snapshot.
+        #[arg(long, group = "share")]
+        torrent: bool,
+
+        /// Print how to add the snapshot to IPFS.
+        #[arg(long, group = "share")]
+        ipfs: bool,
+
+        /// Tracker to list in the .torrent file. May be given more than once.
+        #[arg(long = "tracker", requires = "torrent")]
+        trackers: Vec<String>,
+
+        /// URL of the directory the snapshot is also served from over HTTP, for clients to
+        /// fall back to when there are few peers. May be given more than once.
+        #[arg(long = "web-seed", requires = "torrent")]
+        web_seeds: Vec<String>,
+    },
+
+    /// Add the files of a fetched snapshot to a mirror, checking each against the manifest,
+    /// before syncing it over HTTP.
+    Import {
+        /// Mirror directory.
+        #[arg(value_parser)]
+        path: PathBuf,
+
+        /// Snapshot directory, as downloaded by a BitTorrent or IPFS client.
+        #[arg(value_parser)]
+        src: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-    let opt = Panamax::parse();
-    match opt {
+    let opt = Opt::parse();
+    init_logging(opt.verbose);
+    progress_bar::set_mode(if opt.quiet {
+        progress_bar::Mode::Quiet
+    } else if opt.json_progress {
+        progress_bar::Mode::Json
+    } else {
+        progress_bar::Mode::Bars
+    });
+
+    match opt.command {
         Panamax::Init {
             path,
             ignore_rustup,
-        } => mirror::init(&path, ignore_rustup),
+            contact,
+        } => mirror::init(&path, ignore_rustup, contact),
         Panamax::Sync {
             path,
             vendor_path,
             cargo_lock_filepath,
             skip_rustup,
-        } => mirror::sync(&path, vendor_path, cargo_lock_filepath, skip_rustup).await,
-        Panamax::Rewrite { path, base_url } => mirror::rewrite(&path, base_url),
+            only,
+            retention,
+            shard,
+            events_ndjson,
+            print_plan,
+        } => {
+            mirror::sync(
+                &path,
+                vendor_path,
+                cargo_lock_filepath,
+                skip_rustup,
+                only,
+                retention,
+                shard,
+                events_ndjson,
+                print_plan,
+            )
+            .await
+        }
+        Panamax::Rewrite {
+            path,
+            base_url,
+            dl_template,
+            omit_api,
+        } => mirror::rewrite(&path, base_url, dl_template, omit_api),
         Panamax::Serve {
             path,
             listen,
             port,
             cert_path,
             key_path,
-        } => mirror::serve(path, listen, port, cert_path, key_path).await,
+            unix_socket,
+            self_check,
+            scrub_per_hour,
+            git_pack_cache,
+            build_cache_dir,
+            build_cache_token_file,
+            build_cache_max_size,
+            auth_required,
+            auth_token_file,
+            update_notice,
+        } => {
+            mirror::serve(
+                path,
+                listen,
+                port,
+                cert_path,
+                key_path,
+                unix_socket,
+                self_check,
+                scrub_per_hour,
+                git_pack_cache,
+                build_cache_dir,
+                build_cache_token_file,
+                build_cache_max_size,
+                auth_required,
+                auth_token_file,
+                update_notice,
+            )
+            .await
+        }
+        Panamax::Maintain { path } => mirror::maintain(&path),
+        Panamax::Status { path } => status::status(&path),
         Panamax::ListPlatforms { source, channel } => mirror::list_platforms(source, channel).await,
         Panamax::Verify {
             path,
@@ -156,9 +657,93 @@ async fn main() {
             vendor_path,
             cargo_lock_filepath,
         } => mirror::verify(path, dry_run, assume_yes, vendor_path, cargo_lock_filepath).await,
+        Panamax::Graph {
+            path,
+            krate,
+            depth,
+            format,
+        } => graph::graph(&path, &krate, depth, format),
+        Panamax::Rdeps {
+            path,
+            krate,
+            req,
+            format,
+        } => graph::rdeps(&path, &krate, req.as_deref(), format),
+        Panamax::Changelog {
+            path,
+            since,
+            format,
+        } => changelog::changelog(&path, &since, format),
+        Panamax::Pack {
+            path,
+            image,
+            format,
+        } => mirror::pack(&path, &image, format).await,
+        Panamax::State(StateCommand::Export { path, dest, keep }) => {
+            mirror::state_export(&path, &dest, keep)
+        }
+        Panamax::State(StateCommand::Import { path, src }) => mirror::state_import(&path, &src),
+        Panamax::Snapshot(SnapshotCommand::Publish {
+            path,
+            dest,
+            torrent,
+            ipfs,
+            trackers,
+            web_seeds,
+        }) => {
+            let options = snapshot::PublishOptions {
+                torrent,
+                trackers,
+                web_seeds,
+            };
+            mirror::snapshot_publish(&path, &dest, options, ipfs).await
+        }
+        Panamax::Snapshot(SnapshotCommand::Import { path, src }) => {
+            mirror::snapshot_import(&path, &src).await
+        }
+        Panamax::Export {
+            path,
+            dest,
+            split_size,
+            encrypt_to,
+            delta,
+        } => mirror::export(&path, &dest, split_size, &encrypt_to, delta).await,
+        Panamax::Import {
+            path,
+            src,
+            identities,
+            dry_run,
+        } => mirror::import(&path, &src, &identities, dry_run).await,
+        Panamax::Replicate { path, to, force } => mirror::replicate(&path, to, force),
+        Panamax::Promote { path, base_url } => mirror::promote(&path, base_url),
+        Panamax::SelfCommands(SelfCommand::CheckUpdate { path }) => {
+            self_update::check_update(&path)
+        }
+        Panamax::Selftest { client } => selftest::selftest(client).await,
     }
     .unwrap_or_else(|e| {
         eprintln!("Panamax command failed! {e}");
         std::process::exit(1);
     });
 }
+
+/// Set up logging based on the verbosity level, on top of any `RUST_LOG` filters.
+fn init_logging(verbose: u8) {
+    let mut builder = env_logger::Builder::from_default_env();
+    match verbose {
+        // Warnings, like hints about misconfigured clients, are shown unless RUST_LOG says otherwise.
+        0 if std::env::var_os("RUST_LOG").is_none() => {
+            builder.filter_module("panamax", LevelFilter::Warn);
+        }
+        0 => {}
+        1 => {
+            builder.filter_module("panamax", LevelFilter::Info);
+        }
+        _ => {
+            builder
+                .filter_module("panamax", LevelFilter::Debug)
+                .filter_module("reqwest", LevelFilter::Trace);
+        }
+    }
+    builder.init();
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Markers older than this are assumed to be left over from a sync that was killed.
+const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// How long clients are asked to wait before retrying.
+pub const RETRY_AFTER_SECS: u64 = 30;
+
+/// Marks one directory of the mirror as being modified, until dropped.
+///
+/// While the marker exists, serve responds to requests for that directory with a 503,
+/// so clients back off instead of downloading files that are being moved or deleted.
+pub struct MaintenanceGuard {
+    marker_path: PathBuf,
+}
+
+impl MaintenanceGuard {
+    /// Mark `dir` (relative to the mirror root, e.g. "dist") as under maintenance.
+    pub fn start(mirror_path: &Path, dir: &str) -> io::Result<Self> {
+        let marker_path = marker_path(mirror_path, dir);
+        fs::write(&marker_path, "")?;
+        Ok(Self { marker_path })
+    }
+}
+
+impl Drop for MaintenanceGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.marker_path);
+    }
+}
+
+/// Check whether `dir` (relative to the mirror root) is currently under maintenance.
+pub fn is_under_maintenance(mirror_path: &Path, dir: &str) -> bool {
+    fs::metadata(marker_path(mirror_path, dir))
+        .and_then(|m| m.modified())
+        .map(|modified| modified.elapsed().map_or(true, |age| age < STALE_AFTER))
+        .unwrap_or(false)
+}
+
+fn marker_path(mirror_path: &Path, dir: &str) -> PathBuf {
+    mirror_path.join(format!(".panamax-maintenance-{dir}"))
+}
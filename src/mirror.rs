@@ -1,17 +1,35 @@
+use std::collections::BTreeMap;
 use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::str::FromStr;
+use std::{fmt, fs, io};
 
 use console::style;
-use reqwest::header::HeaderValue;
+use log::{info, warn};
+use reqwest::header::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
+use crate::build_cache::BuildCache;
 use crate::crates::is_new_crates_format;
 use crate::crates_index::rewrite_config_json;
+use crate::download::{new_client, DownloadClient, Http2, IpFamily};
+use crate::image::ImageFormat;
+use crate::pack_cache::PackCache;
+use crate::plan::Plan;
+use crate::progress_bar::{
+    current_step_prefix, padded_prefix_message, progress_spinner, step_message, StepsProgress,
+};
 
+use crate::report::MaintenanceReport;
 use crate::rustup::download_platform_list;
+use crate::schedule::Schedule;
+use crate::self_check::Strictness;
 use crate::serve::TlsConfig;
+use crate::shards::ShardCoordinator;
+use crate::throttle;
+use crate::timestamp::{Timestamp, UtcOffset};
 use crate::verify;
 
 #[derive(Error, Debug)]
@@ -36,38 +54,279 @@ pub enum MirrorError {
 
     #[error("Toml error: {0}")]
     Serialize(#[from] toml_edit::TomlError),
+
+    #[error("Mirror self-check found {0} problem(s)")]
+    SelfCheck(usize),
+
+    #[error("Serve error: {0}")]
+    Serve(#[from] crate::serve::ServeError),
+    #[error("Image error: {0}")]
+    Image(String),
+
+    #[error("Plan error: {0}")]
+    Plan(String),
+
+    #[error("Index sync error: {0}")]
+    IndexSync(#[from] crate::crates_index::IndexSyncError),
+
+    #[error("State snapshot error: {0}")]
+    StateSnapshot(String),
+
+    #[error("Snapshot error: {0}")]
+    Snapshot(String),
+
+    #[error("Replica error: {0}")]
+    Replica(String),
+
+    #[error("Bundle error: {0}")]
+    Bundle(String),
+
+    #[error("Sync stopped before it finished. Run it again to pick up where it left off.")]
+    Interrupted,
+
+    #[error("Sync finished, but some downloads failed. Run it again to retry them.")]
+    Incomplete,
+
+    #[error("{0} missing crate(s) could not be downloaded. Run verify again to retry them.")]
+    RepairIncomplete(usize),
+
+    #[error("Self-test error: {0}")]
+    SelfTest(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Which part of the mirror to sync, when not syncing everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SyncOnly {
+    /// Rustup and Rust release files.
+    Rustup,
+    /// The crates.io-index repository and crate files.
+    Crates,
+    /// Fetch the crates.io-index repository, without downloading any crate files.
+    ///
+    /// The served index is only moved forward once the new crates are downloaded by a crates
+    /// sync, so that cargo never sees crates the mirror doesn't have.
+    Index,
+}
+
+/// Retention settings given on the command line, overriding those in mirror.toml for one run.
+#[derive(Debug, Default, clap::Args)]
+pub struct RetentionOverrides {
+    /// Number of stable versions to keep, instead of keep_latest_stables.
+    #[arg(long)]
+    pub keep_stables: Option<usize>,
+
+    /// Number of beta versions to keep, instead of keep_latest_betas.
+    #[arg(long)]
+    pub keep_betas: Option<usize>,
+
+    /// Number of nightly versions to keep, instead of keep_latest_nightlies.
+    #[arg(long)]
+    pub keep_nightlies: Option<usize>,
+
+    /// Remove old files even if clients downloaded them recently, ignoring keep_served_days.
+    #[arg(long)]
+    pub force: bool,
+}
+
+impl RetentionOverrides {
+    /// Apply the overrides on top of the rustup section of mirror.toml.
+    pub fn apply(&self, rustup: &mut ConfigRustup) {
+        if let Some(n) = self.keep_stables {
+            rustup.keep_latest_stables = Some(n);
+        }
+        if let Some(n) = self.keep_betas {
+            rustup.keep_latest_betas = Some(n);
+        }
+        if let Some(n) = self.keep_nightlies {
+            rustup.keep_latest_nightlies = Some(n);
+        }
+        if self.force {
+            rustup.keep_served_days = Some(0);
+        }
+    }
+}
+
+/// One part of a sync split between several hosts sharing the mirror directory, e.g. "2/4".
+///
+/// Shards only download files. Once every shard is done, the last one to finish completes the
+/// mirror with a normal sync, which fast-forwards the index, records channel history, and cleans
+/// old files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// Which shard this is, starting at 1.
+    pub index: u64,
+    /// How many shards the sync is split into.
+    pub count: u64,
+}
+
+impl Shard {
+    /// Whether this shard is responsible for a file, identified by its path in the mirror.
+    ///
+    /// This hashes the path with SHA-256 rather than std's hasher, so every host agrees on the
+    /// partition regardless of the Panamax version they run.
+    pub fn contains(&self, path: &str) -> bool {
+        let hash = Sha256::digest(path.as_bytes());
+        let n = u64::from_be_bytes(hash[..8].try_into().expect("SHA-256 is 32 bytes"));
+        n % self.count == self.index - 1
+    }
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| "shard must be in the form \"index/count\", e.g. \"2/4\"".to_string())?;
+        let index: u64 = index
+            .parse()
+            .map_err(|e| format!("invalid shard index: {e}"))?;
+        let count: u64 = count
+            .parse()
+            .map_err(|e| format!("invalid shard count: {e}"))?;
+        if index == 0 || index > count {
+            return Err(format!("shard index must be between 1 and {count}"));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// One or more upstream URLs for the same files, tried in order when one fails.
+///
+/// Written in mirror.toml as either a single URL, or a list of them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "SourcesToml", into = "SourcesToml")]
+pub struct Sources(Vec<String>);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SourcesToml {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl TryFrom<SourcesToml> for Sources {
+    type Error = &'static str;
+
+    fn try_from(sources: SourcesToml) -> Result<Self, Self::Error> {
+        match sources {
+            SourcesToml::One(source) => Ok(Sources(vec![source])),
+            SourcesToml::Many(sources) if sources.is_empty() => {
+                Err("a list of sources needs at least one URL")
+            }
+            SourcesToml::Many(sources) => Ok(Sources(sources)),
+        }
+    }
+}
+
+impl From<Sources> for SourcesToml {
+    fn from(mut sources: Sources) -> Self {
+        if sources.0.len() == 1 {
+            SourcesToml::One(sources.0.remove(0))
+        } else {
+            SourcesToml::Many(sources.0)
+        }
+    }
+}
+
+impl Sources {
+    /// All of the URLs, in the order to try them.
+    pub fn all(&self) -> &[String] {
+        &self.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ConfigMirror {
     pub retries: usize,
+    pub retry_delay: Option<f64>,
+    pub retry_multiplier: Option<f64>,
+    pub retry_max_delay: Option<f64>,
+    pub retry_jitter: Option<f64>,
+    pub fail_threshold: Option<crate::rustup::FailThreshold>,
     pub contact: Option<String>,
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub ip_family: Option<IpFamily>,
+    pub http2: Option<Http2>,
+    pub proxy: Option<String>,
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<String>,
+    pub no_proxy: Option<Vec<String>>,
+    pub ca_cert: Option<PathBuf>,
+    pub danger_accept_invalid_certs: Option<bool>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub bandwidth_limit: Option<u64>,
+    pub bandwidth_schedule: Option<Vec<crate::throttle::BandwidthWindow>>,
+    pub chunked_download_threshold: Option<u64>,
+    pub download_chunks: Option<u64>,
+    pub url_resolver: Option<Vec<String>>,
+    pub max_connections_per_host: Option<usize>,
+    pub verify_existing: Option<bool>,
+    pub small_files_first: Option<bool>,
+    pub check_disk_space: Option<bool>,
+    pub sidecar_hashes: Option<Vec<crate::hashes::HashAlgorithm>>,
+    pub manifest_hash: Option<crate::hashes::HashAlgorithm>,
+    pub state_dir: Option<PathBuf>,
+    pub staging_dir: Option<PathBuf>,
+    pub storage: Option<crate::storage::StorageKind>,
+    pub fsync: Option<bool>,
+    pub replicas: Option<Vec<String>>,
+    pub maintain_every_syncs: Option<u32>,
+    pub maintain_every_days: Option<u64>,
+    pub display_time_zone: Option<UtcOffset>,
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub chown: Option<String>,
+    pub report_url: Option<String>,
+    pub report_token: Option<String>,
+    pub mirror_id: Option<String>,
+    pub health_threshold: Option<u8>,
+    pub health_alert_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigRustup {
     pub sync: bool,
     pub download_threads: usize,
-    pub source: String,
+    pub source: Sources,
     pub download_dev: Option<bool>,
     pub download_gz: Option<bool>,
     pub download_xz: Option<bool>,
     pub platforms_unix: Option<Vec<String>>,
     pub platforms_windows: Option<Vec<String>>,
+    pub target_platforms: Option<Vec<String>>,
+    pub bandwidth_limit: Option<u64>,
     pub keep_latest_stables: Option<usize>,
     pub keep_latest_betas: Option<usize>,
     pub keep_latest_nightlies: Option<usize>,
+    pub keep_served_days: Option<u64>,
     pub pinned_rust_versions: Option<Vec<String>>,
+    pub pin_from_toolchain_files: Option<Vec<String>>,
+    pub parallel_pinned_syncs: Option<usize>,
+    pub headers: Option<BTreeMap<String, String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigCrates {
     pub sync: bool,
     pub download_threads: usize,
-    pub source: String,
+    pub source: Sources,
     pub source_index: String,
     pub use_new_crates_format: Option<bool>,
     pub base_url: Option<String>,
+    pub dl_template: Option<String>,
+    pub omit_api: Option<bool>,
+    pub bandwidth_limit: Option<u64>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub packed: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -90,13 +349,26 @@ pub fn create_mirror_directories(path: &Path, ignore_rustup: bool) -> Result<(),
     Ok(())
 }
 
-pub fn create_mirror_toml(path: &Path, ignore_rustup: bool) -> Result<bool, MirrorError> {
+pub fn create_mirror_toml(
+    path: &Path,
+    ignore_rustup: bool,
+    contact: Option<&str>,
+) -> Result<bool, MirrorError> {
     if path.join("mirror.toml").exists() {
         return Ok(false);
     }
 
     // Read the defautlt toml, edit if required, using toml_edit to keep format
     let config = include_str!("mirror.default.toml");
+    // Uncomment the contact line, so it stays next to its explanation.
+    let config = match contact {
+        Some(contact) => config.replacen(
+            &format!("# contact = \"{PLACEHOLDER_CONTACT}\""),
+            &format!("contact = {}", toml_edit::Value::from(contact)),
+            1,
+        ),
+        None => config.to_string(),
+    };
     let mut config = config.parse::<toml_edit::Document>()?;
 
     if ignore_rustup {
@@ -116,9 +388,20 @@ pub fn load_mirror_toml(path: &Path) -> Result<Config, MirrorError> {
     )?)?)
 }
 
-pub fn init(path: &Path, ignore_rustup: bool) -> Result<(), MirrorError> {
+pub fn init(path: &Path, ignore_rustup: bool, contact: Option<String>) -> Result<(), MirrorError> {
+    // Ask for contact information when setting up a new mirror by hand, as it's needed to sync
+    // from crates.io.
+    let contact = match contact {
+        Some(contact) => Some(contact),
+        None if !path.join("mirror.toml").exists() && console::user_attended() => prompt_contact()?,
+        None => None,
+    };
+    if let Some(contact) = &contact {
+        validate_contact(contact)?;
+    }
+
     create_mirror_directories(path, ignore_rustup)?;
-    if create_mirror_toml(path, ignore_rustup)? {
+    if create_mirror_toml(path, ignore_rustup, contact.as_deref())? {
         eprintln!("Successfully created mirror base at `{}`.", path.display());
     } else {
         eprintln!("Mirror base already exists at `{}`.", path.display());
@@ -132,15 +415,110 @@ pub fn init(path: &Path, ignore_rustup: bool) -> Result<(), MirrorError> {
     Ok(())
 }
 
+/// Ask for the contact information to put in mirror.toml. An empty answer leaves it unset.
+fn prompt_contact() -> Result<Option<String>, MirrorError> {
+    eprintln!(
+        "{}",
+        style("Contact email or URL, sent to upstream in the user agent (required to sync crates.io, leave empty to set it later):").bold()
+    );
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let input = input.trim();
+    Ok((!input.is_empty()).then(|| input.to_string()))
+}
+
 pub fn default_user_agent() -> String {
     format!("Panamax/{}", env!("CARGO_PKG_VERSION"))
 }
 
+/// The placeholder contact information in the default mirror.toml.
+const PLACEHOLDER_CONTACT: &str = "your@email.com";
+
+/// Check that contact information is an email address or an http(s) URL that fits in the
+/// comment of a user agent, so upstream can tell who runs the mirror.
+pub fn validate_contact(contact: &str) -> Result<(), MirrorError> {
+    let is_email = contact.split_once('@').is_some_and(|(user, domain)| {
+        !user.is_empty()
+            && domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.')
+    });
+    let is_url = contact.starts_with("https://") || contact.starts_with("http://");
+
+    if contact == PLACEHOLDER_CONTACT {
+        Err(MirrorError::Config(format!(
+            "contact is still the placeholder \"{PLACEHOLDER_CONTACT}\""
+        )))
+    } else if !contact.is_ascii()
+        || contact
+            .chars()
+            .any(|c| c.is_ascii_whitespace() || c.is_ascii_control() || c == '(' || c == ')')
+    {
+        Err(MirrorError::Config(format!(
+            "contact \"{contact}\" must not contain spaces, parentheses or non-ASCII characters"
+        )))
+    } else if !is_email && !is_url {
+        Err(MirrorError::Config(format!(
+            "contact \"{contact}\" must be an email address or an http(s) URL"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether crates are downloaded from crates.io, rather than from another registry.
+fn is_crates_io(source: &str) -> bool {
+    url::Url::parse(source)
+        .ok()
+        .and_then(|url| {
+            url.host_str()
+                .map(|h| h == "crates.io" || h.ends_with(".crates.io"))
+        })
+        .unwrap_or(false)
+}
+
+/// Build the user agent for a sync, e.g. "Panamax/0.3.6 (admin@example.com)".
+///
+/// crates.io's crawler policy asks for a way to contact whoever runs a crawler, and may block
+/// mirrors that don't give one. So syncing crates from crates.io without a contact, or with the
+/// placeholder from the default mirror.toml, is refused, and any contact that is set must be
+/// valid.
+pub fn user_agent(
+    mirror: &ConfigMirror,
+    crates: Option<&ConfigCrates>,
+) -> Result<HeaderValue, MirrorError> {
+    let syncs_crates_io = crates
+        .is_some_and(|crates| crates.sync && crates.source.all().iter().any(|s| is_crates_io(s)));
+
+    let user_agent_str = match &mirror.contact {
+        Some(contact) if contact == PLACEHOLDER_CONTACT && !syncs_crates_io => default_user_agent(),
+        Some(contact) => {
+            validate_contact(contact)?;
+            format!("{} ({contact})", default_user_agent())
+        }
+        None if syncs_crates_io => {
+            return Err(MirrorError::Config(
+                "contact must be set in mirror.toml to sync crates from crates.io".to_string(),
+            ))
+        }
+        None => default_user_agent(),
+    };
+
+    HeaderValue::from_str(&user_agent_str)
+        .map_err(|e| MirrorError::Config(format!("invalid user agent \"{user_agent_str}\": {e}")))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn sync(
     path: &Path,
     vendor_path: Option<PathBuf>,
     cargo_lock_filepath: Option<PathBuf>,
     skip_rustup: bool,
+    only: Option<SyncOnly>,
+    retention: RetentionOverrides,
+    shard: Option<Shard>,
+    events_ndjson: Option<PathBuf>,
+    print_plan: bool,
 ) -> Result<(), MirrorError> {
     if !path.join("mirror.toml").exists() {
         eprintln!(
@@ -149,12 +527,32 @@ pub async fn sync(
         );
         return Ok(());
     }
-    let mirror = load_mirror_toml(path)?;
+    if let Some(replica) = crate::replica::load(path) {
+        return Err(MirrorError::Replica(format!(
+            "{} is a replica of {}, so it's only updated by panamax replicate. Run panamax \
+             promote {} first to sync it.",
+            path.display(),
+            replica.primary.display(),
+            path.display()
+        )));
+    }
+    let mut mirror = load_mirror_toml(path)?;
+    let started = Timestamp::now();
+    let mirror_id = path.display().to_string();
+
+    if let Some(events_ndjson) = &events_ndjson {
+        crate::events::open(events_ndjson)?;
+    }
+
+    init_mirror(path, &mirror.mirror)?;
+    crate::shutdown::listen();
+    let before = crate::changelog::Snapshot::take(path);
 
     // Fail if use_new_crates_format is not true, and old format is detected.
     // If use_new_crates_format is true and new format is detected, warn the user.
     // If use_new_crates_format is true, ignore the format and assume it's new.
     if let Some(crates) = &mirror.crates {
+        crate::crate_packs::init(crates);
         if crates.sync && !is_new_crates_format(&path.join("crates"))? {
             eprintln!("Your crates directory is using the old 0.2 format, however");
             eprintln!("Panamax 0.3+ has deprecated this format for a new one.");
@@ -163,66 +561,323 @@ pub async fn sync(
         }
     }
 
-    // Handle the contact information
-    let user_agent_str = if let Some(ref contact) = mirror.mirror.contact {
-        if contact != "your@email.com" {
-            format!("Panamax/{} ({})", env!("CARGO_PKG_VERSION"), contact)
-        } else {
-            default_user_agent()
+    let user_agent = user_agent(&mirror.mirror, mirror.crates.as_ref())?;
+    let client = new_client(&mirror.mirror, source_headers(&mirror)?, &user_agent)?;
+
+    if let Some(rustup) = &mut mirror.rustup {
+        retention.apply(rustup);
+    }
+
+    if print_plan {
+        let plan = plan_sync(
+            &client,
+            path,
+            &mirror,
+            vendor_path,
+            cargo_lock_filepath,
+            skip_rustup,
+            only,
+            &user_agent,
+        )
+        .await?;
+        plan.print();
+        return Ok(());
+    }
+
+    let coordinator = match shard {
+        Some(shard) => {
+            let coordinator = ShardCoordinator::new(path, shard)?;
+            if coordinator.try_lead()? {
+                step_message(format!(
+                    "Shard {shard} is leading, fetching the index for all shards..."
+                ));
+                if let Some(crates) = &mirror.crates {
+                    if crates.sync && only != Some(SyncOnly::Rustup) {
+                        crate::crates_index::sync_crates_repo(path, &mirror.mirror, crates)?;
+                    }
+                }
+                coordinator.plan_ready()?;
+            } else {
+                coordinator.wait_for_plan().await?;
+            }
+
+            step_message(format!("Syncing shard {shard}..."));
+            let shard_complete = match sync_parts(
+                &client,
+                path,
+                &mirror,
+                vendor_path.clone(),
+                cargo_lock_filepath.clone(),
+                skip_rustup,
+                only,
+                Some(shard),
+                true,
+                &user_agent,
+            )
+            .await
+            {
+                Ok(shard_complete) => shard_complete,
+                Err(e) => {
+                    let result = Err(e);
+                    crate::report::send(
+                        &client,
+                        &mirror.mirror,
+                        &mirror_id,
+                        started,
+                        &result,
+                        None,
+                        None,
+                    )
+                    .await;
+                    return result;
+                }
+            };
+
+            if !coordinator.finish()? {
+                eprintln!(
+                    "Shard {shard} complete. The last shard to finish will complete the mirror."
+                );
+                let result = if shard_complete {
+                    Ok(())
+                } else {
+                    Err(MirrorError::Incomplete)
+                };
+                crate::report::send(
+                    &client,
+                    &mirror.mirror,
+                    &mirror_id,
+                    started,
+                    &result,
+                    None,
+                    None,
+                )
+                .await;
+                return result;
+            }
+            step_message(style("All shards are done, completing the mirror...").bold());
+            Some((coordinator, shard_complete))
+        }
+        None => None,
+    };
+
+    let mut complete = match sync_parts(
+        &client,
+        path,
+        &mirror,
+        vendor_path,
+        cargo_lock_filepath,
+        skip_rustup,
+        only,
+        None,
+        coordinator.is_some(),
+        &user_agent,
+    )
+    .await
+    {
+        Ok(complete) => complete,
+        Err(e) => {
+            // Releases that finished before the sync was stopped are in the mirror now.
+            let health = if matches!(e, MirrorError::Interrupted) {
+                if let Err(e) = crate::changelog::record(path, started, before) {
+                    eprintln!("Could not record this sync for the changelog: {e}");
+                }
+                crate::host_stats::print_summary();
+                None
+            } else {
+                let health = crate::health::record(
+                    path,
+                    &mirror.mirror,
+                    &mirror_id,
+                    &crate::events::counts(),
+                    false,
+                    None,
+                    &client,
+                )
+                .await;
+                Some(health)
+            };
+            let result = Err(e);
+            crate::report::send(
+                &client,
+                &mirror.mirror,
+                &mirror_id,
+                started,
+                &result,
+                None,
+                health.as_ref(),
+            )
+            .await;
+            return result;
         }
-    } else {
-        default_user_agent()
     };
 
-    // Set the user agent with contact information.
-    let user_agent = match HeaderValue::from_str(&user_agent_str) {
-        Ok(h) => h,
+    if let Some((coordinator, shard_complete)) = coordinator {
+        coordinator.cleanup()?;
+        complete &= shard_complete;
+    }
+
+    let maintenance = match scheduled_maintenance(path, &mirror.mirror).await {
+        Ok(maintenance) => maintenance,
         Err(e) => {
-            eprintln!("Your contact information contains invalid characters!");
-            eprintln!("It's recommended to use a URL or email address as contact information.");
-            eprintln!("{e:?}");
-            return Ok(());
+            eprintln!("Scheduled maintenance failed: {e}");
+            None
         }
     };
 
-    if let Some(rustup) = mirror.rustup {
+    if let Err(e) = crate::changelog::record(path, started, before) {
+        eprintln!("Could not record this sync for the changelog: {e}");
+    }
+
+    crate::host_stats::print_summary();
+    if complete {
+        eprintln!("Sync complete.");
+    }
+
+    let health = crate::health::record(
+        path,
+        &mirror.mirror,
+        &mirror_id,
+        &crate::events::counts(),
+        complete,
+        maintenance.as_ref().map(|m| m.missing_crates),
+        &client,
+    )
+    .await;
+
+    let result = if complete {
+        Ok(())
+    } else {
+        Err(MirrorError::Incomplete)
+    };
+    crate::report::send(
+        &client,
+        &mirror.mirror,
+        &mirror_id,
+        started,
+        &result,
+        maintenance.as_ref(),
+        Some(&health),
+    )
+    .await;
+    result
+}
+
+/// Sync the rustup and crates parts of a mirror, or only one shard of their files. Returns
+/// whether both were synced completely.
+#[allow(clippy::too_many_arguments)]
+async fn sync_parts(
+    client: &DownloadClient,
+    path: &Path,
+    mirror: &Config,
+    vendor_path: Option<PathBuf>,
+    cargo_lock_filepath: Option<PathBuf>,
+    skip_rustup: bool,
+    only: Option<SyncOnly>,
+    shard: Option<Shard>,
+    index_fetched: bool,
+    user_agent: &HeaderValue,
+) -> Result<bool, MirrorError> {
+    let skip_rustup = skip_rustup || only.is_some_and(|only| only != SyncOnly::Rustup);
+    let skip_crates = only == Some(SyncOnly::Rustup);
+    let mut complete = true;
+
+    if let Some(rustup) = &mirror.rustup {
         if rustup.sync && !skip_rustup {
-            crate::rustup::sync(path, &mirror.mirror, &rustup, &user_agent).await?;
+            throttle::set_limit(rustup.bandwidth_limit.or(mirror.mirror.bandwidth_limit));
+            complete &=
+                crate::rustup::sync(client, path, &mirror.mirror, rustup, user_agent, shard)
+                    .await?;
         } else {
-            eprintln!("Rustup sync is disabled, skipping...");
+            step_message("Rustup sync is disabled, skipping...");
         }
     } else {
-        eprintln!("Rustup section missing, skipping...");
+        step_message("Rustup section missing, skipping...");
     }
 
-    if let Some(crates) = mirror.crates {
-        if crates.sync {
-            sync_crates(
+    // The crates index is fetched before any crate is downloaded, so don't start on it.
+    if crate::shutdown::requested() {
+        return Err(MirrorError::Interrupted);
+    }
+
+    if let Some(crates) = &mirror.crates {
+        if crates.sync && !skip_crates {
+            throttle::set_limit(crates.bandwidth_limit.or(mirror.mirror.bandwidth_limit));
+            complete &= sync_crates(
+                client,
                 path,
                 vendor_path,
                 cargo_lock_filepath,
                 &mirror.mirror,
-                &crates,
-                &user_agent,
+                crates,
+                user_agent,
+                only == Some(SyncOnly::Index),
+                shard,
+                index_fetched,
             )
             .await;
         } else {
-            eprintln!("Crates sync is disabled, skipping...");
+            step_message("Crates sync is disabled, skipping...");
         }
     } else {
-        eprintln!("Crates section missing, skipping...");
+        step_message("Crates section missing, skipping...");
     }
 
-    eprintln!("Sync complete.");
+    if crate::shutdown::requested() {
+        return Err(MirrorError::Interrupted);
+    }
+    Ok(complete)
+}
 
-    Ok(())
+/// Work out everything a sync would download and remove, without doing any of it.
+///
+/// This still goes to upstream: the Rust channel manifests are downloaded with `client`, and
+/// the crates.io-index repository is fetched (or cloned, if the mirror doesn't have it yet) to
+/// see what's new in it. The fetched commits are written to the mirror's index repository, but
+/// the served index stays where it was.
+#[allow(clippy::too_many_arguments)]
+async fn plan_sync(
+    client: &DownloadClient,
+    path: &Path,
+    mirror: &Config,
+    vendor_path: Option<PathBuf>,
+    cargo_lock_filepath: Option<PathBuf>,
+    skip_rustup: bool,
+    only: Option<SyncOnly>,
+    user_agent: &HeaderValue,
+) -> Result<Plan, MirrorError> {
+    let skip_rustup = skip_rustup || only.is_some_and(|only| only != SyncOnly::Rustup);
+    let skip_crates = only == Some(SyncOnly::Rustup);
+    let mut plan = Plan::default();
+
+    if let Some(rustup) = mirror.rustup.as_ref().filter(|r| r.sync && !skip_rustup) {
+        step_message("Planning the Rustup sync...");
+        plan.extend(crate::rustup::plan(client, path, &mirror.mirror, rustup, user_agent).await?);
+    }
+
+    if let Some(crates) = mirror.crates.as_ref().filter(|c| c.sync && !skip_crates) {
+        step_message("Planning the Crates sync...");
+        crate::crates_index::sync_crates_repo(path, &mirror.mirror, crates)
+            .map_err(|e| MirrorError::Plan(format!("Fetching crates.io-index failed: {e}")))?;
+        if only != Some(SyncOnly::Index) {
+            let tasks = crate::crates::plan(path, vendor_path, cargo_lock_filepath, crates)
+                .map_err(|e| MirrorError::Plan(format!("Planning crates failed: {e}")))?;
+            plan.extend(tasks);
+        }
+    }
+
+    Ok(plan)
 }
 
 /// Rewrite the config.toml only.
 ///
 /// Note that this will also fast-forward the repository
 /// from origin/master, to keep a clean slate.
-pub fn rewrite(path: &Path, base_url: Option<String>) -> Result<(), MirrorError> {
+pub fn rewrite(
+    path: &Path,
+    base_url: Option<String>,
+    dl_template: Option<String>,
+    omit_api: bool,
+) -> Result<(), MirrorError> {
     if !path.join("mirror.toml").exists() {
         eprintln!(
             "Mirror base not found! Run panamax init {} first.",
@@ -234,7 +889,14 @@ pub fn rewrite(path: &Path, base_url: Option<String>) -> Result<(), MirrorError>
 
     if let Some(crates) = mirror.crates {
         if let Some(base_url) = base_url.as_deref().or(crates.base_url.as_deref()) {
-            if let Err(e) = rewrite_config_json(&path.join("crates.io-index"), base_url) {
+            let dl_template = dl_template.as_deref().or(crates.dl_template.as_deref());
+            let omit_api = omit_api || crates.omit_api.unwrap_or(false);
+            if let Err(e) = rewrite_config_json(
+                &path.join("crates.io-index"),
+                base_url,
+                dl_template,
+                omit_api,
+            ) {
                 eprintln!("Updating crates.io-index config failed: {e:?}");
             }
         } else {
@@ -250,53 +912,683 @@ pub fn rewrite(path: &Path, base_url: Option<String>) -> Result<(), MirrorError>
     Ok(())
 }
 
-/// Synchronize and handle the crates.io-index repository.
+/// The extra headers of the rustup and crates sources, to be sent with every request to those
+/// sources, by URL prefix.
+fn source_headers(config: &Config) -> Result<Vec<(String, HeaderMap)>, MirrorError> {
+    let mut source_headers = Vec::new();
+    if let Some(rustup) = &config.rustup {
+        for source in rustup.source.all() {
+            source_headers.push((source.clone(), rustup.headers.as_ref()));
+        }
+    }
+    if let Some(crates) = &config.crates {
+        for source in crates.source.all() {
+            // crates.io's crate files are downloaded from its CDN instead.
+            let source = if source == "https://crates.io/api/v1/crates" {
+                "https://static.crates.io/crates"
+            } else {
+                source
+            };
+            source_headers.push((source.to_string(), crates.headers.as_ref()));
+        }
+    }
+
+    source_headers
+        .into_iter()
+        .filter_map(|(source, headers)| Some((source, headers?)))
+        .map(|(source, headers)| {
+            // Templates match on everything before their first marker.
+            let prefix = source.split('{').next().unwrap_or_default().to_string();
+            Ok((prefix, crate::download::header_map(headers)?))
+        })
+        .collect()
+}
+
+/// Use the settings of mirror.toml for the rest of this run, and the mirror's checksum
+/// database, validators and HTTP cache.
+///
+/// Every command that reads or writes a mirror calls this first, so none of them can miss a
+/// setting, like a state directory, that changes where or how it finds the mirror's files.
+pub(crate) fn init_mirror(path: &Path, mirror: &ConfigMirror) -> Result<(), MirrorError> {
+    crate::state::init(path, mirror)?;
+    crate::staging::init(path, mirror);
+    crate::checksums::open(path);
+    crate::validators::open(path);
+    crate::http_cache::open(path);
+    crate::permissions::init(mirror)?;
+    crate::storage::init(mirror);
+    crate::disk::init(mirror);
+    crate::health::init(mirror);
+    crate::hashes::init(mirror);
+    crate::timestamp::init(mirror);
+    crate::throttle::init(mirror);
+    Ok(())
+}
+
+/// Back up a mirror's state files to a new snapshot in `dest`.
+pub fn state_export(path: &Path, dest: &Path, keep: usize) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+
+    let snapshot = crate::state::export(path, dest, keep)?;
+    eprintln!("Exported mirror state to {}.", snapshot.display());
+    Ok(())
+}
+
+/// Restore a mirror's state files from a snapshot. This shouldn't be run while a sync is in
+/// progress.
+pub fn state_import(path: &Path, src: &Path) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+
+    let snapshot = crate::state::import(path, src)?;
+    eprintln!("Imported mirror state from {}.", snapshot.display());
+    Ok(())
+}
+
+/// Freeze a mirror's content into a snapshot in `dest`, to share with other sites over
+/// BitTorrent or IPFS.
+pub async fn snapshot_publish(
+    path: &Path,
+    dest: &Path,
+    options: crate::snapshot::PublishOptions,
+    ipfs: bool,
+) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+
+    let published = crate::snapshot::publish(path, dest, &options).await?;
+    eprintln!("Published snapshot to {}.", published.dir.display());
+    if let Some((torrent_path, magnet)) = &published.torrent {
+        eprintln!(
+            "Wrote {}. Seed it from {}, or share its magnet link:",
+            torrent_path.display(),
+            dest.display()
+        );
+        eprintln!("  {magnet}");
+    }
+    if ipfs {
+        eprintln!("Add it to IPFS with:");
+        eprintln!("  ipfs add -r --cid-version=1 {}", published.dir.display());
+    }
+    Ok(())
+}
+
+/// Bring replicas of a mirror up to date: those given with --to, or else those in mirror.toml.
+pub fn replicate(path: &Path, to: Vec<String>, force: bool) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    let mirror = load_mirror_toml(path)?.mirror;
+    init_mirror(path, &mirror)?;
+
+    let destinations = if to.is_empty() {
+        mirror.replicas.unwrap_or_default()
+    } else {
+        to
+    };
+    if destinations.is_empty() {
+        return Err(MirrorError::CmdLine(
+            "No replicas to copy to. Give one with --to, or set replicas in mirror.toml."
+                .to_string(),
+        ));
+    }
+    for destination in destinations {
+        let replica_path = crate::replica::destination(&destination)?;
+        step_message(format!("Replicating to {}...", replica_path.display()));
+        let replicated = crate::replica::replicate(path, &replica_path, force)?;
+        eprintln!(
+            "Replicated to {}: {} file(s) copied, {} unchanged, {} removed.",
+            replica_path.display(),
+            replicated.copied,
+            replicated.unchanged,
+            replicated.removed
+        );
+    }
+    Ok(())
+}
+
+/// Turn a replica made by `replicate` into a primary mirror, so it can take over serving and
+/// syncing.
+pub fn promote(path: &Path, base_url: Option<String>) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    let replica = crate::replica::promote(path, base_url.as_deref())?;
+    eprintln!(
+        "{} is no longer a replica of {}.",
+        path.display(),
+        replica.primary.display()
+    );
+    eprintln!(
+        "Run panamax sync {} to keep it up to date from now on.",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Seed a mirror from a snapshot made by `snapshot_publish`, after it's been fetched.
+pub async fn snapshot_import(path: &Path, src: &Path) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+
+    let imported = crate::snapshot::import(path, src).await?;
+    eprintln!(
+        "Imported {} file(s) from {}, {} already in the mirror.",
+        imported.added,
+        src.display(),
+        imported.present
+    );
+    if !imported.mismatched.is_empty() {
+        eprintln!(
+            "{}",
+            style(format!(
+                "{} file(s) didn't match the snapshot's manifest and were skipped:",
+                imported.mismatched.len()
+            ))
+            .yellow()
+            .bold()
+        );
+        for file in &imported.mismatched {
+            eprintln!("  {file}");
+        }
+    }
+    eprintln!(
+        "Run panamax sync {} to bring the mirror up to date.",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Write a mirror's content to a bundle, to carry to a mirror that can't reach upstream.
+pub async fn export(
+    path: &Path,
+    dest: &Path,
+    split_size: Option<u64>,
+    encrypt_to: &[String],
+    delta: bool,
+) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+
+    let exported = crate::bundle::export(path, dest, split_size, encrypt_to, delta).await?;
+    eprintln!(
+        "Exported {} file(s), {} MB, to {}.",
+        exported.files,
+        exported.bytes / 1_000_000,
+        dest.display()
+    );
+    if delta {
+        eprintln!("It also removes {} file(s).", exported.removed);
+    }
+    Ok(())
+}
+
+/// Add the files of a bundle made by `export` to a mirror, or only check it with `dry_run`.
+/// This shouldn't be run while a sync is in progress.
+pub async fn import(
+    path: &Path,
+    src: &Path,
+    identities: &[PathBuf],
+    dry_run: bool,
+) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+
+    let imported = crate::bundle::import(path, src, identities, dry_run).await?;
+    if dry_run {
+        eprintln!(
+            "{} is intact. Importing it would add {} file(s), leave {} already in the mirror, \
+             and remove {}.",
+            src.display(),
+            imported.added,
+            imported.present,
+            imported.removed
+        );
+        return Ok(());
+    }
+    eprintln!(
+        "Imported {} file(s) from {}, {} already in the mirror, and removed {}.",
+        imported.added,
+        src.display(),
+        imported.present,
+        imported.removed
+    );
+    Ok(())
+}
+
+/// Run periodic housekeeping on a mirror.
+///
+/// This repacks the crates.io-index repository, and removes files left behind by
+/// interrupted or failed downloads. It shouldn't be run while a sync is in progress.
+pub fn maintain(path: &Path) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+    let progress = StepsProgress::new(2, "Maintaining mirror");
+    let pruned = repack_and_prune(path, 2, &progress)?;
+    drop(progress);
+    eprintln!("Maintenance complete. Removed {pruned} leftover file(s).");
+
+    Ok(())
+}
+
+/// Repack the index and prune leftover files, as the first two of `num_steps` steps.
+/// Returns the number of files pruned.
+fn repack_and_prune(
+    path: &Path,
+    num_steps: usize,
+    progress: &StepsProgress,
+) -> Result<usize, io::Error> {
+    let repo_path = path.join("crates.io-index");
+    if repo_path.exists() {
+        let _pb = progress_spinner(padded_prefix_message(
+            1,
+            num_steps,
+            "Repacking crates.io-index",
+        ));
+        if let Err(e) = crate::crates_index::gc(&repo_path) {
+            eprintln!("Repacking crates.io-index failed: {e}");
+        }
+    } else {
+        step_message(format!(
+            "{} No crates.io-index, skipping repack.",
+            current_step_prefix(1, num_steps)
+        ));
+    }
+    progress.step_done();
+
+    let pb = progress_spinner(padded_prefix_message(
+        2,
+        num_steps,
+        "Pruning leftover files",
+    ));
+    let mut pruned = 0usize;
+    for dir in ["dist", "rustup", "crates"] {
+        pruned += prune_leftover_files(&path.join(dir))?;
+        pb.tick();
+    }
+    pruned += prune_leftover_files(&crate::staging::dir(path))?;
+    pruned += crate::http_cache::prune(path)?;
+    pruned += crate::checksums::prune(path)?;
+    pb.finish();
+    progress.step_done();
+
+    Ok(pruned)
+}
+
+/// After a sync, run maintenance and a quick check for missing crates, if mirror.toml
+/// schedules it and it's due.
+async fn scheduled_maintenance(
+    path: &Path,
+    mirror: &ConfigMirror,
+) -> Result<Option<MaintenanceReport>, MirrorError> {
+    if mirror.maintain_every_syncs.is_none() && mirror.maintain_every_days.is_none() {
+        return Ok(None);
+    }
+
+    let now = Timestamp::now();
+    let mut schedule = Schedule::load(path);
+    if !schedule.record_sync(mirror.maintain_every_syncs, mirror.maintain_every_days, now) {
+        schedule.save(path)?;
+        return Ok(None);
+    }
+
+    step_message(style("Running scheduled maintenance...").bold());
+    let num_steps = 3;
+    let progress = StepsProgress::new(num_steps, "Maintaining mirror");
+    let pruned_files = repack_and_prune(path, num_steps, &progress)?;
+
+    let missing_crates = if path.join("crates.io-index/.git").exists() {
+        let mut step = 3;
+        verify::verify_mirror(path.to_path_buf(), &mut step, num_steps, None, None)
+            .await?
+            .map_or(0, |missing| missing.len())
+    } else {
+        step_message(format!(
+            "{} No crates.io-index, skipping verification.",
+            current_step_prefix(3, num_steps)
+        ));
+        0
+    };
+    progress.step_done();
+    drop(progress);
+    if missing_crates > 0 {
+        eprintln!(
+            "{missing_crates} crate file(s) referenced by the index are missing. Run panamax verify to download them."
+        );
+    }
+
+    schedule.ran(now);
+    schedule.save(path)?;
+    step_message(style("Scheduled maintenance complete!").bold());
+
+    Ok(Some(MaintenanceReport {
+        pruned_files,
+        missing_crates,
+    }))
+}
+
+/// Remove partial downloads, and the markers written when a download fails.
+fn prune_leftover_files(dir: &Path) -> Result<usize, io::Error> {
+    let mut leftovers = Vec::new();
+    // Symlinks are not followed, same as when cleaning old files during sync.
+    for entry in walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let is_leftover = entry
+            .path()
+            .extension()
+            .is_some_and(|ext| ext == "part" || ext == "notfound" || ext == "badsha256");
+        if is_leftover {
+            info!("Removing {}", entry.path().display());
+            leftovers.push(entry.into_path());
+        }
+    }
+    let removed = crate::storage::get().remove_files(&leftovers);
+    for res in removed {
+        res?;
+    }
+    Ok(leftovers.len())
+}
+
+/// Synchronize and handle the crates.io-index repository. Returns whether the index and crates
+/// were synced completely.
+#[allow(clippy::too_many_arguments)]
 pub async fn sync_crates(
+    client: &DownloadClient,
     path: &Path,
     vendor_path: Option<PathBuf>,
     cargo_lock_filepath: Option<PathBuf>,
     mirror: &ConfigMirror,
     crates: &ConfigCrates,
     user_agent: &HeaderValue,
-) {
-    eprintln!("{}", style("Syncing Crates repositories...").bold());
+    index_only: bool,
+    shard: Option<Shard>,
+    index_fetched: bool,
+) -> bool {
+    step_message(style("Syncing Crates repositories...").bold());
 
-    if let Err(e) = crate::crates_index::sync_crates_repo(path, crates) {
+    // Shards share the index, which the leading shard fetches for all of them. The sync that
+    // completes the mirror after them uses it too, rather than fetching a newer one, so it
+    // removes and fast-forwards to exactly what the shards downloaded.
+    if shard.is_some() || index_fetched {
+        if !path.join("crates.io-index/.git").exists() {
+            eprintln!(
+                "The crates.io-index repository the shards share is missing, skipping crates."
+            );
+            return false;
+        }
+    } else if let Err(e) = crate::crates_index::sync_crates_repo(path, mirror, crates) {
         eprintln!("Downloading crates.io-index repository failed: {e:?}");
         eprintln!("You will need to sync again to finish this download.");
-        return;
+        return false;
+    }
+
+    if index_only {
+        step_message("Only fetching the index, skipping crates files...");
+        return true;
     }
 
     if let Err(e) = crate::crates::sync_crates_files(
+        client,
         path,
         vendor_path,
         cargo_lock_filepath,
         mirror,
         crates,
         user_agent,
+        shard,
     )
     .await
     {
         eprintln!("Downloading crates failed: {e:?}");
         eprintln!("You will need to sync again to finish this download.");
-        return;
+        return false;
+    }
+
+    if shard.is_some() {
+        step_message(style("Syncing Crates shard complete!").bold());
+        return true;
     }
 
     if let Err(e) = crate::crates_index::update_crates_config(path, crates) {
         eprintln!("Updating crates.io-index config failed: {e:?}");
         eprintln!("You will need to sync again to finish this download.");
+        return false;
     }
 
-    eprintln!("{}", style("Syncing Crates repositories complete!").bold());
+    step_message(style("Syncing Crates repositories complete!").bold());
+    true
 }
 
+/// Size limit of the build cache when serving, if not given: 10 GiB.
+const DEFAULT_BUILD_CACHE_MAX_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+
+/// Environment variable holding the build cache's token, if it isn't in a file.
+const BUILD_CACHE_TOKEN_VAR: &str = "PANAMAX_BUILD_CACHE_TOKEN";
+
+/// Environment variable holding the registry's token in protected mode, if it isn't in a file.
+const AUTH_TOKEN_VAR: &str = "PANAMAX_AUTH_TOKEN";
+
+/// Read a token from the file given with `file_option`, or else from the environment variable
+/// `var`. `needed_by` names what needs it, for the error when there's none.
+fn read_token(
+    token_file: Option<&Path>,
+    file_option: &str,
+    var: &str,
+    needed_by: &str,
+) -> Result<String, MirrorError> {
+    let token = match token_file {
+        Some(token_file) => fs::read_to_string(token_file).map_err(|e| {
+            MirrorError::CmdLine(format!(
+                "Could not read {file_option} {}: {e}",
+                token_file.display()
+            ))
+        })?,
+        None => std::env::var(var).unwrap_or_default(),
+    };
+    // A token file usually ends with a newline.
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(MirrorError::CmdLine(format!(
+            "{needed_by} needs a token, from {file_option} or {var}."
+        )));
+    }
+    Ok(token.to_string())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn serve(
     path: PathBuf,
     listen: Option<IpAddr>,
     port: Option<u16>,
     cert_path: Option<PathBuf>,
     key_path: Option<PathBuf>,
+    unix_socket: Option<PathBuf>,
+    self_check: Strictness,
+    scrub_per_hour: Option<u32>,
+    git_pack_cache: Option<PathBuf>,
+    build_cache_dir: Option<PathBuf>,
+    build_cache_token_file: Option<PathBuf>,
+    build_cache_max_size: Option<u64>,
+    auth_required: bool,
+    auth_token_file: Option<PathBuf>,
+    update_notice: bool,
 ) -> Result<(), MirrorError> {
+    // An image made by panamax pack is a file, rather than a mirror directory.
+    if path.is_file() {
+        if scrub_per_hour.is_some()
+            || git_pack_cache.is_some()
+            || build_cache_dir.is_some()
+            || auth_required
+        {
+            return Err(MirrorError::CmdLine(
+                "scrub_per_hour, git_pack_cache, build_cache_dir and auth_required need a mirror directory, not an image.".to_string(),
+            ));
+        }
+        let image = crate::image::Image::open(&path)?;
+        eprintln!(
+            "Serving image {}, packed {}.",
+            path.display(),
+            image.packed()
+        );
+        let (socket_addr, tls_paths) = listen_config(listen, port, cert_path, key_path)?;
+        if unix_socket.is_some() && tls_paths.is_some() {
+            return Err(MirrorError::CmdLine(
+                "TLS is not supported when serving on a Unix socket.".to_string(),
+            ));
+        }
+        crate::serve::serve_image(image, socket_addr, tls_paths, unix_socket).await?;
+        return Ok(());
+    }
+
+    if path.join("mirror.toml").exists() {
+        init_mirror(&path, &load_mirror_toml(&path)?.mirror)?;
+    }
+
+    if update_notice {
+        if let Some(newer) = crate::self_update::newer_release(&path) {
+            warn!(
+                "Panamax {newer} is available in the mirror, this is {}.",
+                crate::self_update::VERSION
+            );
+        }
+    }
+
+    if self_check != Strictness::Off {
+        let problems = crate::self_check::self_check(&path).await;
+        for problem in &problems {
+            eprintln!("Mirror self-check: {problem}");
+        }
+        if !problems.is_empty() && self_check == Strictness::Strict {
+            return Err(MirrorError::SelfCheck(problems.len()));
+        }
+    }
+
+    if let Some(files_per_hour) = scrub_per_hour.filter(|&n| n > 0) {
+        tokio::spawn(crate::scrub::run(path.clone(), files_per_hour));
+    }
+
+    tokio::spawn(crate::served::run(path.clone()));
+
+    let pack_cache = git_pack_cache.map(PackCache::new).transpose()?;
+
+    let build_cache = match build_cache_dir {
+        Some(dir) => Some(BuildCache::new(
+            dir,
+            read_token(
+                build_cache_token_file.as_deref(),
+                "build_cache_token_file",
+                BUILD_CACHE_TOKEN_VAR,
+                "build_cache_dir",
+            )?,
+            build_cache_max_size.unwrap_or(DEFAULT_BUILD_CACHE_MAX_SIZE),
+        )?),
+        None => None,
+    };
+
+    let auth_token = if auth_required {
+        Some(read_token(
+            auth_token_file.as_deref(),
+            "auth_token_file",
+            AUTH_TOKEN_VAR,
+            "auth_required",
+        )?)
+    } else {
+        None
+    };
+
+    let mirror_url = load_mirror_toml(&path)
+        .ok()
+        .and_then(|config| config.crates?.base_url)
+        .and_then(|base_url| crate::serve::mirror_url(&base_url));
+
+    let (socket_addr, tls_paths) = listen_config(listen, port, cert_path, key_path)?;
+
+    if unix_socket.is_some() && tls_paths.is_some() {
+        return Err(MirrorError::CmdLine(
+            "TLS is not supported when serving on a Unix socket.".to_string(),
+        ));
+    }
+
+    crate::serve::serve(
+        path,
+        socket_addr,
+        tls_paths,
+        unix_socket,
+        pack_cache,
+        build_cache,
+        auth_token,
+        update_notice,
+        mirror_url,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// The address to serve on, and the TLS certificate and key to serve with, if any.
+fn listen_config(
+    listen: Option<IpAddr>,
+    port: Option<u16>,
+    cert_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+) -> Result<(SocketAddr, Option<TlsConfig>), MirrorError> {
     let listen = listen.unwrap_or_else(|| {
         "::".parse()
             .expect(":: IPv6 address should never fail to parse")
@@ -304,19 +1596,12 @@ pub async fn serve(
     let port = port.unwrap_or_else(|| if cert_path.is_some() { 8443 } else { 8080 });
     let socket_addr = SocketAddr::new(listen, port);
 
-    match (cert_path, key_path) {
-        (Some(cert_path), Some(key_path)) => {
-            crate::serve::serve(
-                path,
-                socket_addr,
-                Some(TlsConfig {
-                    cert_path,
-                    key_path,
-                }),
-            )
-            .await
-        }
-        (None, None) => crate::serve::serve(path, socket_addr, None).await,
+    let tls_paths = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+            cert_path,
+            key_path,
+        }),
+        (None, None) => None,
         (Some(_), None) => {
             return Err(MirrorError::CmdLine(
                 "cert_path set but key_path not set.".to_string(),
@@ -328,13 +1613,39 @@ pub async fn serve(
             ))
         }
     };
+    Ok((socket_addr, tls_paths))
+}
 
+/// Pack a mirror into an image file, to hand to a site that can't sync.
+pub async fn pack(path: &Path, image: &Path, format: ImageFormat) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+    init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+
+    let packed = crate::image::pack(path, image, format).await?;
+    eprintln!(
+        "Packed {} files ({} bytes) into {}.",
+        packed.files,
+        packed.bytes,
+        image.display()
+    );
+    if format == ImageFormat::Squashfs {
+        eprintln!("Mount it and serve the mount point with panamax serve.");
+    }
     Ok(())
 }
 
 /// Print out a list of all platforms.
 pub(crate) async fn list_platforms(source: String, channel: String) -> Result<(), MirrorError> {
-    let targets = download_platform_list(source.as_str(), channel.as_str()).await?;
+    let user_agent = HeaderValue::from_str(&default_user_agent())
+        .expect("Hardcoded user agent string should never fail.");
+    let client = new_client(&ConfigMirror::default(), Vec::new(), &user_agent)?;
+    let targets = download_platform_list(&client, &[source], channel.as_str(), &user_agent).await?;
 
     println!("All currently available platforms for the {channel} channel:");
     for t in targets {
@@ -362,6 +1673,8 @@ pub(crate) async fn verify(
         return Ok(());
     }
     let config = load_mirror_toml(&path)?;
+    init_mirror(&path, &config.mirror)?;
+    let source_headers = source_headers(&config)?;
 
     // Fail if use_new_crates_format is not true, and old format is detected.
     // If use_new_crates_format is true and new format is detected, warn the user.
@@ -375,7 +1688,7 @@ pub(crate) async fn verify(
         }
     }
 
-    eprintln!("{}", style("Verifying mirror state...").bold());
+    step_message(style("Verifying mirror state...").bold());
 
     // Getting crates.sync config state
     let crates_config = config.crates.as_ref();
@@ -385,15 +1698,24 @@ pub(crate) async fn verify(
     let steps = if dry_run || !sync { 1 } else { 2 };
     let mut current_step = 1;
 
-    if let Some(mut missing_crates) = verify::verify_mirror(
+    // Only a check of the whole index says how healthy the mirror is.
+    let whole_index = vendor_path.is_none() && cargo_lock_filepath.is_none();
+    let missing = verify::verify_mirror(
         path.clone(),
         &mut current_step,
         steps,
         vendor_path,
         cargo_lock_filepath,
     )
-    .await?
-    {
+    .await?;
+    if whole_index {
+        let missing = missing.as_ref().map_or(0, Vec::len);
+        if let Err(e) = crate::health::record_verification(&path, missing) {
+            eprintln!("Could not record the mirror's health: {e}");
+        }
+    }
+
+    if let Some(mut missing_crates) = missing {
         if dry_run || !sync {
             if !sync {
                 eprintln!("Crates sync is disabled, only printing missing crates...");
@@ -417,16 +1739,125 @@ pub(crate) async fn verify(
         let mirror_config = &config.mirror;
 
         // Downloading missing crates
-        verify::fix_mirror(
+        let fixed = verify::fix_mirror(
             mirror_config,
             crates_config,
-            path,
+            source_headers,
+            path.clone(),
             missing_crates,
             &mut current_step,
             steps,
         )
-        .await?;
+        .await;
+        // Unless some were left out when asked, whatever wasn't repaired is still missing.
+        let still_missing = match &fixed {
+            Ok(()) => Some(0),
+            Err(MirrorError::RepairIncomplete(failed)) => Some(*failed),
+            Err(_) => None,
+        };
+        if let Some(missing) = still_missing.filter(|_| whole_index && assume_yes) {
+            if let Err(e) = crate::health::record_verification(&path, missing) {
+                eprintln!("Could not record the mirror's health: {e}");
+            }
+        }
+        fixed?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+
+    mod contact {
+        use crate::mirror::validate_contact;
+
+        #[test]
+        fn valid() {
+            assert!(validate_contact("mirror-admin@example.com").is_ok());
+            assert!(validate_contact("https://example.com/mirror").is_ok());
+        }
+
+        #[test]
+        fn invalid() {
+            assert!(validate_contact("your@email.com").is_err());
+            assert!(validate_contact("Mirror Admin <admin@example.com>").is_err());
+            assert!(validate_contact("admin@localhost").is_err());
+            assert!(validate_contact("example.com").is_err());
+            assert!(validate_contact("(admin@example.com)").is_err());
+        }
+    }
+
+    mod user_agent {
+        use crate::mirror::{user_agent, ConfigCrates, ConfigMirror};
+
+        fn crates(source: &str) -> ConfigCrates {
+            toml_edit::easy::from_str(&format!(
+                "sync = true\ndownload_threads = 1\nsource = \"{source}\"\nsource_index = \"\""
+            ))
+            .unwrap()
+        }
+
+        #[test]
+        fn contact_required_for_crates_io() {
+            let mirror = ConfigMirror::default();
+            let crates_io = crates("https://crates.io/api/v1/crates");
+            assert!(user_agent(&mirror, Some(&crates_io)).is_err());
+            assert!(user_agent(&mirror, Some(&crates("https://mirror.example/crates"))).is_ok());
+            assert!(user_agent(&mirror, None).is_ok());
+
+            let mirror = ConfigMirror {
+                contact: Some("mirror-admin@example.com".to_string()),
+                ..Default::default()
+            };
+            let user_agent = user_agent(&mirror, Some(&crates_io)).unwrap();
+            assert!(user_agent
+                .to_str()
+                .unwrap()
+                .ends_with(" (mirror-admin@example.com)"));
+        }
+    }
+
+    mod sources {
+        use crate::mirror::ConfigRustup;
+
+        fn parse(source: &str) -> Result<ConfigRustup, toml_edit::de::Error> {
+            toml_edit::easy::from_str(&format!(
+                "sync = true\ndownload_threads = 1\nsource = {source}"
+            ))
+        }
+
+        #[test]
+        fn one_or_many() {
+            let one = parse("\"https://a.example\"").unwrap();
+            assert_eq!(one.source.all(), ["https://a.example"]);
+            let many = parse("[\"https://a.example\", \"https://b.example\"]").unwrap();
+            assert_eq!(
+                many.source.all(),
+                ["https://a.example", "https://b.example"]
+            );
+            assert!(parse("[]").is_err());
+        }
+    }
+
+    mod shard {
+        use crate::mirror::Shard;
+
+        #[test]
+        fn parse() {
+            assert_eq!("2/4".parse(), Ok(Shard { index: 2, count: 4 }));
+            assert!("0/4".parse::<Shard>().is_err());
+            assert!("5/4".parse::<Shard>().is_err());
+            assert!("2".parse::<Shard>().is_err());
+        }
+
+        #[test]
+        fn every_path_in_exactly_one_shard() {
+            let shards: Vec<Shard> = (1..=4).map(|index| Shard { index, count: 4 }).collect();
+            for i in 0..1000 {
+                let path = format!("dist/2023-01-01/file-{i}.tar.xz");
+                assert_eq!(shards.iter().filter(|s| s.contains(&path)).count(), 1);
+            }
+        }
+    }
+}
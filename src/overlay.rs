@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use git2::{ObjectType, Repository, TreeWalkMode, TreeWalkResult};
+use walkdir::WalkDir;
+
+use crate::crates::crate_prefix;
+
+/// Directory of the overlay registry, relative to the mirror root.
+///
+/// It holds internal crates in the same layout as the rest of the mirror: index files under
+/// `index/`, and crate files under `crates/`.
+pub const OVERLAY_DIR: &str = "overlay";
+
+/// Get the overlay registry directory of a mirror, if it has one.
+pub fn overlay_path(mirror_path: &Path) -> Option<PathBuf> {
+    let path = mirror_path.join(OVERLAY_DIR);
+    path.join("index").is_dir().then_some(path)
+}
+
+/// Get the path of a crate's file in the overlay index. Index files are named after the
+/// lowercased crate name, as cargo requests them.
+pub fn index_file(overlay_path: &Path, crate_name: &str) -> Option<PathBuf> {
+    let name = crate_name.to_lowercase();
+    let prefix: PathBuf = crate_prefix(&name)?.split('/').collect();
+    Some(overlay_path.join("index").join(prefix).join(name))
+}
+
+/// The name crates.io compares crate names by, as it doesn't allow names that only differ by
+/// case, or by `-` and `_`.
+fn canonical_name(crate_name: &str) -> String {
+    crate_name.to_lowercase().replace('-', "_")
+}
+
+/// Find crates in the overlay whose names clash with crates in the crates.io index, as pairs
+/// of (overlay name, crates.io name).
+///
+/// The overlay's crate is served in place of the crates.io one, so a clash means an internal
+/// crate is hiding a public one, or was published to crates.io by someone else.
+pub fn collisions(mirror_path: &Path) -> Result<Vec<(String, String)>, git2::Error> {
+    let Some(overlay_path) = overlay_path(mirror_path) else {
+        return Ok(Vec::new());
+    };
+    let overlay_crates: HashMap<String, String> = WalkDir::new(overlay_path.join("index"))
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && e.file_name() != "config.json")
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .map(|name| (canonical_name(&name), name))
+        .collect();
+    if overlay_crates.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let repo = Repository::open(mirror_path.join("crates.io-index"))?;
+    let tree = repo.find_reference("refs/heads/master")?.peel_to_tree()?;
+    let mut collisions = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+        // Only files are crates, directories are their prefixes.
+        if let (Some(ObjectType::Blob), Some(name)) = (entry.kind(), entry.name()) {
+            if let Some(overlay_name) = overlay_crates.get(&canonical_name(name)) {
+                collisions.push((overlay_name.clone(), name.to_string()));
+            }
+        }
+        TreeWalkResult::Ok
+    })?;
+    collisions.sort();
+    Ok(collisions)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod names {
+        use std::path::Path;
+
+        use crate::overlay::{canonical_name, index_file};
+
+        #[test]
+        fn layout() {
+            let overlay = Path::new("/mirror/overlay");
+            assert_eq!(
+                index_file(overlay, "Acme-Utils").unwrap(),
+                Path::new("/mirror/overlay/index/ac/me/acme-utils")
+            );
+            assert_eq!(
+                index_file(overlay, "abc").unwrap(),
+                Path::new("/mirror/overlay/index/3/a/abc")
+            );
+            assert!(index_file(overlay, "").is_none());
+        }
+
+        #[test]
+        fn canonical() {
+            assert_eq!(canonical_name("Acme-Utils"), canonical_name("acme_utils"));
+            assert_ne!(canonical_name("acme-utils"), canonical_name("acmeutils"));
+        }
+    }
+}
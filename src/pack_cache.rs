@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use flate2::read::GzDecoder;
+use git2::Repository;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// A cache of `git http-backend` responses to full clones of the crates.io-index repository.
+///
+/// Every full clone of the same index commit by the same git version sends the same request,
+/// and gets the same multi-hundred-MB pack back. Generating that pack is expensive, so it's
+/// done once per request and index commit, and any clones that arrive while it's being
+/// generated wait for it instead of starting their own.
+pub struct PackCache {
+    dir: PathBuf,
+    /// One lock per cached response, held while it's being generated, and removed once no
+    /// request is waiting on it.
+    generating: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl PackCache {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(PackCache {
+            dir,
+            generating: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Get the name of the cached response for an upload-pack request, if it's a full clone.
+    ///
+    /// Requests that say which objects the client already has are left to git. Compressed
+    /// requests should be unpacked with `decompress` first.
+    pub async fn entry_name(&self, mirror_path: &Path, request: &[u8]) -> Option<String> {
+        if !is_full_clone(request) {
+            return None;
+        }
+
+        let repo_path = mirror_path.join("crates.io-index");
+        let master = tokio::task::spawn_blocking(move || {
+            Repository::open(repo_path)?.refname_to_id("refs/heads/master")
+        })
+        .await
+        .ok()?
+        .ok()?;
+
+        let request_hash = format!("{:x}", Sha256::digest(request));
+        Some(format!("{master}-{}", &request_hash[..16]))
+    }
+
+    /// Open the cached response for `name`, running `cmd` with `request` to generate it first
+    /// if needed.
+    pub async fn response(&self, name: &str, cmd: Command, request: &[u8]) -> io::Result<File> {
+        let lock = self
+            .generating
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        let guard = lock.lock().await;
+        let response = self.open_or_generate(name, cmd, request).await;
+        drop(guard);
+
+        // Requests that come later find the response on disk, so the lock is only kept while
+        // another request still holds it or waits for it.
+        let mut generating = self.generating.lock().unwrap_or_else(|e| e.into_inner());
+        if Arc::strong_count(&lock) == 2 {
+            generating.remove(name);
+        }
+        response
+    }
+
+    async fn open_or_generate(
+        &self,
+        name: &str,
+        mut cmd: Command,
+        request: &[u8],
+    ) -> io::Result<File> {
+        let path = self.dir.join(name);
+        if let Ok(f) = File::open(&path).await {
+            debug!("Serving cached pack {name}");
+            return Ok(f);
+        }
+
+        // The index has moved on, so packs for older commits won't be asked for again.
+        if let Some((master, _)) = name.split_once('-') {
+            self.remove_stale(master);
+        }
+
+        debug!("Generating pack {name}");
+        let part_path = self.dir.join(format!("{name}.part"));
+        cmd.stdout(Stdio::from(std::fs::File::create(&part_path)?));
+        let mut child = cmd.spawn()?;
+        let mut git_input = child
+            .stdin
+            .take()
+            .expect("Process should always have stdin");
+        git_input.write_all(request).await?;
+        drop(git_input);
+
+        if child.wait().await?.success() {
+            tokio::fs::rename(&part_path, &path).await?;
+            File::open(&path).await
+        } else {
+            // Still send git's response, but don't keep it around.
+            let f = File::open(&part_path).await?;
+            tokio::fs::remove_file(&part_path).await?;
+            Ok(f)
+        }
+    }
+
+    /// Remove cached responses for any index commit other than `master`.
+    fn remove_stale(&self, master: &str) {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            // Partial files may still be written to by another request.
+            if file_name.starts_with(master) || file_name.ends_with(".part") {
+                continue;
+            }
+            if let Err(e) = std::fs::remove_file(entry.path()) {
+                warn!("Could not remove old cached pack {file_name}: {e}");
+            }
+        }
+    }
+}
+
+/// Unpack an upload-pack request that git compressed, which it does for larger requests, so
+/// it can be told apart from other fetches. Anything else is returned as it is.
+///
+/// The unpacked request is what's sent on to git, which is never told it was compressed.
+pub fn decompress(request: Vec<u8>) -> Vec<u8> {
+    if !request.starts_with(&[0x1f, 0x8b]) {
+        return request;
+    }
+    let mut unpacked = Vec::new();
+    match GzDecoder::new(&request[..]).read_to_end(&mut unpacked) {
+        Ok(_) => unpacked,
+        Err(_) => request,
+    }
+}
+
+/// Whether an upload-pack request is for a full clone: it wants objects, but doesn't have any.
+fn is_full_clone(request: &[u8]) -> bool {
+    let contains = |needle: &[u8]| request.windows(needle.len()).any(|w| w == needle);
+    // Compressed requests start with the gzip magic number.
+    !request.starts_with(&[0x1f, 0x8b])
+        && contains(b"want ")
+        && contains(b"done")
+        && !contains(b"have ")
+}
+
+#[cfg(test)]
+mod test {
+
+    mod full_clone {
+        use std::io::Write;
+
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        use crate::pack_cache::{decompress, is_full_clone};
+
+        const WANT: &[u8] = b"0032want 0123456789012345678901234567890123456789\n";
+
+        #[test]
+        fn clone() {
+            assert!(is_full_clone(&[WANT, b"00000009done\n"].concat()));
+        }
+
+        #[test]
+        fn fetch() {
+            let have = b"0032have 9876543210987654321098765432109876543210\n";
+            assert!(!is_full_clone(
+                &[WANT, b"0000", have, b"0009done\n"].concat()
+            ));
+        }
+
+        #[test]
+        fn compressed() {
+            assert!(!is_full_clone(&[&[0x1f, 0x8b], WANT, b"done"].concat()));
+
+            let request = [WANT, b"00000009done\n"].concat();
+            let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+            gzip.write_all(&request).unwrap();
+            let unpacked = decompress(gzip.finish().unwrap());
+            assert_eq!(unpacked, request);
+            assert!(is_full_clone(&unpacked));
+        }
+    }
+
+    mod generating {
+        use std::path::PathBuf;
+        use std::process::Stdio;
+
+        use tokio::process::Command;
+
+        use crate::pack_cache::PackCache;
+
+        #[tokio::test]
+        async fn pruned_once_done() {
+            let dir = tempfile::tempdir().unwrap();
+            let cache = PackCache::new(PathBuf::from(dir.path())).unwrap();
+            let mut cmd = Command::new("cat");
+            cmd.stdin(Stdio::piped());
+            cache.response("abc-123", cmd, b"pack").await.unwrap();
+            assert!(cache.generating.lock().unwrap().is_empty());
+            assert_eq!(std::fs::read(dir.path().join("abc-123")).unwrap(), b"pack");
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use log::warn;
+
+use crate::mirror::{ConfigMirror, MirrorError};
+
+/// Permissions and ownership to give files and directories written into the mirror.
+#[derive(Debug, Default)]
+struct Permissions {
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+static PERMISSIONS: OnceLock<Permissions> = OnceLock::new();
+
+/// Set up permissions from the mirror section of mirror.toml.
+pub fn init(mirror: &ConfigMirror) -> Result<(), MirrorError> {
+    let (uid, gid) = match &mirror.chown {
+        Some(chown) => parse_chown(chown).ok_or_else(|| {
+            MirrorError::Config(format!(
+                "chown must be numeric \"uid:gid\", \"uid\" or \":gid\", got \"{chown}\""
+            ))
+        })?,
+        None => (None, None),
+    };
+
+    if cfg!(not(unix))
+        && (mirror.file_mode.is_some() || mirror.dir_mode.is_some() || uid.or(gid).is_some())
+    {
+        eprintln!("file_mode, dir_mode and chown are only supported on Unix, ignoring them.");
+    }
+
+    // Only one set of permissions is used per run, so any later call is ignored.
+    let _ = PERMISSIONS.set(Permissions {
+        file_mode: mirror.file_mode,
+        dir_mode: mirror.dir_mode,
+        uid,
+        gid,
+    });
+    Ok(())
+}
+
+/// Parse a numeric "uid:gid", where either side may be left empty.
+fn parse_chown(chown: &str) -> Option<(Option<u32>, Option<u32>)> {
+    let (uid, gid) = chown.split_once(':').unwrap_or((chown, ""));
+    let parse = |id: &str| -> Option<Option<u32>> {
+        if id.is_empty() {
+            Some(None)
+        } else {
+            id.parse().ok().map(Some)
+        }
+    };
+    Some((parse(uid)?, parse(gid)?))
+}
+
+/// Apply the configured mode and ownership to a newly written file.
+pub fn apply_to_file(path: &Path) -> io::Result<()> {
+    apply(path, |p| p.file_mode)
+}
+
+/// Apply the configured mode and ownership to a newly created directory.
+fn apply_to_dir(path: &Path) -> io::Result<()> {
+    apply(path, |p| p.dir_mode)
+}
+
+#[cfg(unix)]
+fn apply(path: &Path, mode: impl Fn(&Permissions) -> Option<u32>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Some(permissions) = PERMISSIONS.get() else {
+        return Ok(());
+    };
+    if let Some(mode) = mode(permissions) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if permissions.uid.is_some() || permissions.gid.is_some() {
+        std::os::unix::fs::chown(path, permissions.uid, permissions.gid)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply(_path: &Path, _mode: impl Fn(&Permissions) -> Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Give a file moved in from another directory, like the staging directory, the SELinux label
+/// and ACL it would have got if it had been created in its own directory, as a rename keeps
+/// the ones it was created with. The configured mode and ownership are applied again after, as
+/// they would be to a new file.
+#[cfg(target_os = "linux")]
+pub fn inherit_from_dir(path: &Path) -> io::Result<()> {
+    let Some(dir) = path.parent() else {
+        return Ok(());
+    };
+    if let Some(label) = get_xattr(dir, SELINUX_XATTR)? {
+        if get_xattr(path, SELINUX_XATTR)?.as_ref() != Some(&label) {
+            xattr::set(path, SELINUX_XATTR, &label)?;
+        }
+    }
+    match get_xattr(dir, ACL_DEFAULT_XATTR)? {
+        Some(default_acl) => xattr::set(
+            path,
+            ACL_ACCESS_XATTR,
+            &inherited_acl(default_acl, CREATE_MODE),
+        )?,
+        None if get_xattr(path, ACL_ACCESS_XATTR)?.is_some() => {
+            xattr::remove(path, ACL_ACCESS_XATTR)?
+        }
+        None => {}
+    }
+    apply_to_file(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn inherit_from_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Warn that a file couldn't be given its directory's label and ACL, once per run, as it's
+/// likely to fail the same way for every file.
+pub fn warn_inherit_failed(path: &Path, e: &io::Error) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        warn!(
+            "Could not give {} the SELinux label and ACL of its directory: {e}",
+            path.display()
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+const SELINUX_XATTR: &str = "security.selinux";
+#[cfg(target_os = "linux")]
+const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+#[cfg(target_os = "linux")]
+const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+/// Mode files are created with, before the umask or a default ACL is applied.
+#[cfg(target_os = "linux")]
+const CREATE_MODE: u32 = 0o666;
+
+/// EOPNOTSUPP, for a filesystem without extended attributes, or without the one asked for.
+#[cfg(target_os = "linux")]
+const EOPNOTSUPP: i32 = 95;
+
+/// Get an extended attribute, or None if it isn't set or the filesystem doesn't have it.
+#[cfg(target_os = "linux")]
+fn get_xattr(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    match xattr::get(path, name) {
+        Err(e) if e.raw_os_error() == Some(EOPNOTSUPP) => Ok(None),
+        res => res,
+    }
+}
+
+/// The access ACL a file created with `mode` gets from its directory's default ACL, which is the
+/// default ACL with the owner, group (or mask) and other entries limited to `mode`, as the
+/// kernel does on create. Both are stored as a version number followed by (tag, permissions,
+/// id) entries, in little-endian.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn inherited_acl(mut acl: Vec<u8>, mode: u32) -> Vec<u8> {
+    const USER_OBJ: u16 = 0x01;
+    const GROUP_OBJ: u16 = 0x04;
+    const MASK: u16 = 0x10;
+    const OTHER: u16 = 0x20;
+
+    let has_mask = acl
+        .get(4..)
+        .unwrap_or_default()
+        .chunks_exact(8)
+        .any(|entry| u16::from_le_bytes([entry[0], entry[1]]) == MASK);
+    for entry in acl.get_mut(4..).unwrap_or_default().chunks_exact_mut(8) {
+        let allowed = match u16::from_le_bytes([entry[0], entry[1]]) {
+            USER_OBJ => mode >> 6,
+            GROUP_OBJ if !has_mask => mode >> 3,
+            MASK => mode >> 3,
+            OTHER => mode,
+            _ => continue,
+        } as u16
+            & 0o7;
+        let perm = u16::from_le_bytes([entry[2], entry[3]]) & allowed;
+        entry[2..4].copy_from_slice(&perm.to_le_bytes());
+    }
+    acl
+}
+
+/// Recursively create a directory, applying the configured mode and ownership to every
+/// directory that didn't exist yet.
+pub fn create_dir_all(path: &Path) -> io::Result<()> {
+    if path.as_os_str().is_empty() || path.is_dir() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    match fs::create_dir(path) {
+        Ok(()) => apply_to_dir(path),
+        // Another download may have just created it.
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod inherited_acl {
+        use crate::permissions::inherited_acl;
+
+        fn acl(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+            let mut acl = 2u32.to_le_bytes().to_vec();
+            for (tag, perm, id) in entries {
+                acl.extend(tag.to_le_bytes());
+                acl.extend(perm.to_le_bytes());
+                acl.extend(id.to_le_bytes());
+            }
+            acl
+        }
+
+        #[test]
+        fn limited_to_mode() {
+            let u32_max = u32::MAX;
+            let default_acl = acl(&[
+                (0x01, 0o7, u32_max),
+                (0x02, 0o7, 1000),
+                (0x04, 0o5, u32_max),
+                (0x10, 0o7, u32_max),
+                (0x20, 0o5, u32_max),
+            ]);
+            // Named users and the owning group are left to the mask.
+            assert_eq!(
+                inherited_acl(default_acl, 0o666),
+                acl(&[
+                    (0x01, 0o6, u32_max),
+                    (0x02, 0o7, 1000),
+                    (0x04, 0o5, u32_max),
+                    (0x10, 0o6, u32_max),
+                    (0x20, 0o4, u32_max),
+                ])
+            );
+        }
+
+        #[test]
+        fn group_without_mask() {
+            let default_acl = acl(&[(0x01, 0o7, 0), (0x04, 0o7, 0), (0x20, 0o7, 0)]);
+            assert_eq!(
+                inherited_acl(default_acl, 0o640),
+                acl(&[(0x01, 0o6, 0), (0x04, 0o4, 0), (0x20, 0o0, 0)])
+            );
+        }
+    }
+
+    mod chown {
+        use crate::permissions::parse_chown;
+
+        #[test]
+        fn valid() {
+            assert_eq!(parse_chown("1000:33"), Some((Some(1000), Some(33))));
+            assert_eq!(parse_chown("1000"), Some((Some(1000), None)));
+            assert_eq!(parse_chown(":33"), Some((None, Some(33))));
+        }
+
+        #[test]
+        fn invalid() {
+            assert_eq!(parse_chown("www-data"), None);
+            assert_eq!(parse_chown("1000:www-data"), None);
+        }
+    }
+}
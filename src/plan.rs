@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::StreamExt;
+use indicatif::ProgressBar;
+use reqwest::header::HeaderValue;
+use tokio::sync::Semaphore;
+use tokio::task::JoinError;
+
+use crate::checksums;
+use crate::download::{download, DownloadClient, DownloadError};
+use crate::url_path::MirrorPath;
+
+/// A file for a sync to download into the mirror.
+#[derive(Debug, Clone)]
+pub struct DownloadTask {
+    pub file: MirrorPath,
+    /// Where to download it from, tried in order.
+    pub urls: Vec<String>,
+    /// What upstream says its SHA-256 hash is, if it says.
+    pub sha256: Option<String>,
+    /// A guess at its size in bytes, for estimates and to download small files first.
+    pub size: u64,
+}
+
+/// A file for a sync to remove from the mirror.
+#[derive(Debug, Clone)]
+pub struct DeleteTask {
+    /// Path relative to the mirror root.
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub enum Task {
+    Download(DownloadTask),
+    Delete(DeleteTask),
+}
+
+impl Task {
+    fn file(&self) -> String {
+        match self {
+            Task::Download(task) => task.file.to_string(),
+            Task::Delete(task) => task.file.to_string_lossy().replace('\\', "/"),
+        }
+    }
+
+    /// The task as a line of JSON, for --print-plan.
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Task::Download(task) => serde_json::json!({
+                "task": "download",
+                "file": task.file.as_str(),
+                "urls": task.urls,
+                "sha256": task.sha256,
+                "size": task.size,
+            }),
+            Task::Delete(_) => serde_json::json!({
+                "task": "delete",
+                "file": self.file(),
+            }),
+        }
+    }
+}
+
+/// Everything a sync is going to download and remove, decided before any of it is done.
+///
+/// Channels and crates that need the same file only add it once.
+#[derive(Debug, Default)]
+pub struct Plan {
+    tasks: Vec<Task>,
+    files: HashSet<String>,
+}
+
+impl Plan {
+    pub fn add(&mut self, task: Task) {
+        if self.files.insert(task.file()) {
+            self.tasks.push(task);
+        }
+    }
+
+    pub fn extend(&mut self, tasks: impl IntoIterator<Item = Task>) {
+        for task in tasks {
+            self.add(task);
+        }
+    }
+
+    /// Print the plan to stdout, a JSON object per task, and a summary to stderr.
+    pub fn print(&self) {
+        for task in &self.tasks {
+            println!("{}", task.to_json());
+        }
+        let (downloads, bytes, deletes) =
+            self.tasks
+                .iter()
+                .fold((0, 0, 0), |(downloads, bytes, deletes), task| match task {
+                    Task::Download(task) => (downloads + 1, bytes + task.size, deletes),
+                    Task::Delete(_) => (downloads, bytes, deletes + 1),
+                });
+        eprintln!(
+            "Plan: {downloads} file(s) to download, about {} MB, and {deletes} file(s) to remove.",
+            bytes / 1_000_000
+        );
+    }
+}
+
+/// Whether a file was synced, locked while a task is syncing it.
+type FileSlot = Arc<tokio::sync::Mutex<bool>>;
+
+/// What the download tasks of one run share: the files any of them synced, so tasks for the
+/// same file in overlapping channels only download or verify it once, and the download_threads
+/// budget, so steps running at once don't download more files at a time than one would.
+pub struct SharedDownloads {
+    /// Each file, as (path, hash).
+    files: Mutex<HashMap<(MirrorPath, String), FileSlot>>,
+    permits: Semaphore,
+}
+
+impl SharedDownloads {
+    pub fn new(threads: usize) -> Arc<Self> {
+        Arc::new(SharedDownloads {
+            files: Mutex::new(HashMap::new()),
+            permits: Semaphore::new(threads),
+        })
+    }
+
+    fn slot(&self, file: &MirrorPath, hash: &str) -> FileSlot {
+        self.files
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry((file.clone(), hash.to_string()))
+            .or_default()
+            .clone()
+    }
+
+    /// Whether a file was synced already. A file another task is still syncing isn't.
+    pub fn is_synced(&self, file: &MirrorPath, hash: &str) -> bool {
+        self.files
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&(file.clone(), hash.to_string()))
+            .is_some_and(|slot| slot.try_lock().is_ok_and(|synced| *synced))
+    }
+}
+
+/// Download one file, after checking its hash against the one first seen for it.
+pub async fn run_download(
+    client: &DownloadClient,
+    path: &Path,
+    task: &DownloadTask,
+    retries: usize,
+    user_agent: &HeaderValue,
+) -> Result<(), DownloadError> {
+    if let Some(sha256) = &task.sha256 {
+        checksums::check(path, task.file.as_str(), sha256)?;
+    }
+
+    download(
+        client,
+        &task.urls,
+        &task.file.on_disk(path),
+        task.sha256.as_deref(),
+        retries,
+        false,
+        user_agent,
+    )
+    .await
+}
+
+/// Run download tasks, up to `threads` at a time and within the permits of `shared`, ticking
+/// `pb` as each one finishes. Returns the result of each task, in no particular order.
+#[allow(clippy::too_many_arguments)]
+pub async fn execute_downloads(
+    client: &DownloadClient,
+    path: &Path,
+    tasks: Vec<DownloadTask>,
+    threads: usize,
+    retries: usize,
+    user_agent: &HeaderValue,
+    pb: &ProgressBar,
+    shared: &Arc<SharedDownloads>,
+) -> Vec<Result<Result<(), DownloadError>, JoinError>> {
+    futures::stream::iter(tasks)
+        .map(|task| {
+            // Clone the variables that will be moved into the tokio task.
+            let client = client.clone();
+            let path = path.to_path_buf();
+            let user_agent = user_agent.clone();
+            let pb = pb.clone();
+            let shared = shared.clone();
+
+            tokio::spawn(async move {
+                // A step running at the same time may have the file too, so only one of them
+                // syncs it, and the other waits for it.
+                let slot = shared.slot(&task.file, task.sha256.as_deref().unwrap_or_default());
+                let mut synced = slot.lock().await;
+                let out = if *synced {
+                    Ok(())
+                } else {
+                    let _permit = shared.permits.acquire().await;
+                    run_download(&client, &path, &task, retries, &user_agent).await
+                };
+                *synced = out.is_ok();
+
+                pb.inc(1);
+
+                out
+            })
+        })
+        .buffer_unordered(threads)
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod test {
+
+    mod plan {
+        use std::path::PathBuf;
+
+        use crate::plan::{DeleteTask, DownloadTask, Plan, Task};
+        use crate::url_path::MirrorPath;
+
+        fn download(file: &str) -> Task {
+            Task::Download(DownloadTask {
+                file: MirrorPath::parse(file).unwrap(),
+                urls: vec![format!("https://example.com/{file}")],
+                sha256: None,
+                size: 1,
+            })
+        }
+
+        #[test]
+        fn dedupes_files() {
+            let mut plan = Plan::default();
+            plan.add(download("dist/2024-01-01/cargo.tar.xz"));
+            plan.add(download("dist/2024-01-01/cargo.tar.xz"));
+            plan.add(download("dist/2024-01-01/rustc.tar.xz"));
+            plan.add(Task::Delete(DeleteTask {
+                file: PathBuf::from("dist/2023-01-01/cargo.tar.xz"),
+            }));
+            assert_eq!(plan.tasks.len(), 3);
+        }
+
+        #[test]
+        fn json() {
+            let task = download("crates/1/a/0.1.0/a-0.1.0.crate");
+            assert_eq!(
+                task.to_json().to_string(),
+                r#"{"file":"crates/1/a/0.1.0/a-0.1.0.crate","sha256":null,"size":1,"task":"download","urls":["https://example.com/crates/1/a/0.1.0/a-0.1.0.crate"]}"#
+            );
+        }
+    }
+
+    mod shared_downloads {
+        use crate::plan::SharedDownloads;
+        use crate::url_path::MirrorPath;
+
+        #[test]
+        fn synced_once_unlocked() {
+            let shared = SharedDownloads::new(4);
+            let file = MirrorPath::parse("dist/2024-01-01/cargo-1.75.0.tar.xz").unwrap();
+            assert!(!shared.is_synced(&file, "abc"));
+
+            let slot = shared.slot(&file, "abc");
+            let mut synced = slot.try_lock().unwrap();
+            *synced = true;
+            // Another channel is still syncing it.
+            assert!(!shared.is_synced(&file, "abc"));
+            drop(synced);
+            assert!(shared.is_synced(&file, "abc"));
+            assert!(!shared.is_synced(&file, "def"));
+        }
+    }
+}
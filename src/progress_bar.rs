@@ -1,15 +1,273 @@
-use console::{pad_str, style};
+use std::fmt::Display;
+use std::io;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use console::{pad_str, strip_ansi_codes, style};
+use indicatif::{
+    MultiProgress, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressStyle, TermLike,
+    WeakProgressBar,
+};
+
+/// How progress is reported, as a `Mode` stored as its number.
+static MODE: AtomicU8 = AtomicU8::new(Mode::Bars as u8);
+
+/// How progress is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Progress bars and step messages on stderr.
+    Bars = 0,
+    /// Nothing but errors and final summaries.
+    Quiet = 1,
+    /// A JSON object per line on stderr for each step and each update of a progress bar, for
+    /// wrappers and CI logs that can't show bars.
+    Json = 2,
+}
+
+/// How often a progress bar is reported in JSON mode, at most.
+const JSON_UPDATES_PER_SECOND: u8 = 2;
+
+/// The progress bars being drawn, so messages from outside their step can be printed above
+/// them instead of being drawn over.
+static BARS: Mutex<Vec<WeakProgressBar>> = Mutex::new(Vec::new());
+
+/// Progress bars of steps running at the same time, drawn together instead of over each other.
+static GROUP: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Draws the progress bars started while it's alive together, one above the other.
+pub struct ProgressGroup;
+
+impl Drop for ProgressGroup {
+    fn drop(&mut self) {
+        GROUP.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+}
+
+/// Draw the progress bars started from now on together, for steps that run at the same time,
+/// until the returned group is dropped.
+pub fn group() -> ProgressGroup {
+    *GROUP.lock().unwrap_or_else(|e| e.into_inner()) = Some(MultiProgress::new());
+    ProgressGroup
+}
+
+/// A bar for the steps of a whole command, drawn above the bar of each step as it runs, so it's
+/// clear how far along the command is and not just the step.
+pub struct StepsProgress {
+    overall: ProgressBar,
+    _group: ProgressGroup,
+}
+
+impl StepsProgress {
+    pub fn new(steps: usize, msg: &str) -> Self {
+        let group = group();
+        let overall = progress_bar(steps, pad(msg));
+        StepsProgress {
+            overall,
+            _group: group,
+        }
+    }
+
+    /// Count a step as done.
+    pub fn step_done(&self) {
+        self.overall.inc(1);
+    }
+}
+
+fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::Quiet,
+        2 => Mode::Json,
+        _ => Mode::Bars,
+    }
+}
+
+pub fn set_mode(mode: Mode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// Print a step message to stderr, unless running quietly.
+pub fn step_message(msg: impl Display) {
+    match mode() {
+        Mode::Bars => eprintln!("{msg}"),
+        Mode::Quiet => {}
+        Mode::Json => eprintln!("{}", json_event("step", &msg.to_string())),
+    }
+}
+
+/// A JSON line for a message, with the styling taken out.
+fn json_event(kind: &str, msg: &str) -> String {
+    serde_json::json!({ "type": kind, "message": strip_ansi_codes(msg).trim() }).to_string()
+}
+
+/// Start drawing a progress bar, unless running quietly. In JSON mode, it's drawn as a line of
+/// JSON instead, following `json_template`, where literal braces are doubled.
+fn start_drawing(pb: &ProgressBar, json_template: &str) {
+    match mode() {
+        Mode::Quiet => pb.set_draw_target(ProgressDrawTarget::hidden()),
+        Mode::Json => {
+            // The prefix is put in the template as a JSON string, quotes included.
+            let prefix = serde_json::Value::from(strip_ansi_codes(&pb.prefix()).trim()).to_string();
+            pb.set_prefix(prefix);
+            pb.set_style(ProgressStyle::with_template(json_template).expect("template is correct"));
+            pb.set_draw_target(ProgressDrawTarget::term_like_with_hz(
+                Box::<JsonLines>::default(),
+                JSON_UPDATES_PER_SECOND,
+            ));
+        }
+        Mode::Bars => {
+            if let Some(group) = GROUP.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+                group.add(pb.clone());
+            }
+            pb.enable_steady_tick(Duration::from_millis(10));
+            let mut bars = BARS.lock().unwrap_or_else(|e| e.into_inner());
+            bars.retain(|bar| bar.upgrade().is_some_and(|bar| !bar.is_finished()));
+            bars.push(pb.downgrade());
+        }
+    }
+}
+
+/// Print a message to stderr above the progress bar being drawn, if there is one. Even when
+/// running quietly, as it's something the user needs to know.
+pub fn print_above_progress(msg: impl Display) {
+    if mode() == Mode::Json {
+        eprintln!("{}", json_event("message", &msg.to_string()));
+        return;
+    }
+    let bar = BARS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .rev()
+        .filter_map(WeakProgressBar::upgrade)
+        .find(|bar| !bar.is_finished() && !bar.is_hidden());
+    match bar {
+        Some(bar) => bar.println(msg.to_string()),
+        None => eprintln!("{msg}"),
+    }
+}
 
 pub fn current_step_prefix(step: usize, steps: usize) -> String {
     style(format!("[{step}/{steps}]")).bold().to_string()
 }
 
 pub fn padded_prefix_message(step: usize, steps: usize, msg: &str) -> String {
-    pad_str(
-        &format!("{} {}...", current_step_prefix(step, steps), msg),
-        34,
-        console::Alignment::Left,
-        None,
-    )
-    .to_string()
+    pad(&format!("{} {}...", current_step_prefix(step, steps), msg))
+}
+
+fn pad(prefix: &str) -> String {
+    pad_str(prefix, 34, console::Alignment::Left, None).to_string()
+}
+
+/// Create a progress bar for a step with a known number of items.
+pub fn progress_bar(len: usize, prefix: String) -> ProgressBar {
+    let pb = ProgressBar::new(len as u64)
+        .with_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{prefix} {wide_bar} {pos}/{len} [{elapsed_precise} / {duration_precise}]",
+                )
+                .expect("template is correct")
+                .progress_chars("█▉▊▋▌▍▎▏  "),
+        )
+        .with_finish(ProgressFinish::AndLeave)
+        .with_prefix(prefix);
+    start_drawing(
+        &pb,
+        r#"{{"type":"progress","step":{prefix},"pos":{pos},"len":{len}}}"#,
+    );
+    pb
+}
+
+/// Create a progress bar for a step that goes through a known number of bytes, like copying or
+/// hashing files, showing the bytes done and the rate.
+pub fn progress_bar_bytes(len: u64, prefix: String) -> ProgressBar {
+    let pb = ProgressBar::new(len)
+        .with_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{prefix} {wide_bar} {bytes}/{total_bytes} {binary_bytes_per_sec} \
+                     [{elapsed_precise} / {duration_precise}]",
+                )
+                .expect("template is correct")
+                .progress_chars("█▉▊▋▌▍▎▏  "),
+        )
+        .with_finish(ProgressFinish::AndLeave)
+        .with_prefix(prefix);
+    start_drawing(
+        &pb,
+        r#"{{"type":"progress","step":{prefix},"pos":{pos},"len":{len},"unit":"bytes"}}"#,
+    );
+    pb
+}
+
+/// Create a spinner for a step where the amount of work isn't known ahead of time.
+pub fn progress_spinner(prefix: String) -> ProgressBar {
+    let pb = ProgressBar::new_spinner()
+        .with_style(
+            ProgressStyle::default_bar()
+                .template("{prefix} {wide_bar} {spinner} [{elapsed_precise}]")
+                .expect("template is correct")
+                .progress_chars("  "),
+        )
+        .with_finish(ProgressFinish::AndLeave)
+        .with_prefix(prefix);
+    start_drawing(&pb, r#"{{"type":"progress","step":{prefix},"pos":{pos}}}"#);
+    pb
+}
+
+/// A terminal for progress bars drawn as JSON, which prints each line it's given once, on a line
+/// of its own, and ignores the cursor movements used to redraw bars in place.
+#[derive(Debug, Default)]
+struct JsonLines {
+    last: Mutex<String>,
+}
+
+impl TermLike for JsonLines {
+    fn width(&self) -> u16 {
+        // Wide enough that no line is wrapped.
+        1000
+    }
+
+    fn move_cursor_up(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        self.write_str(s)
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        // Lines are padded out to the width, and redrawn after a carriage return.
+        let line = s.trim();
+        if !line.starts_with('{') {
+            return Ok(());
+        }
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        if *last != line {
+            eprintln!("{line}");
+            *last = line.to_string();
+        }
+        Ok(())
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
 }
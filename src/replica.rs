@@ -0,0 +1,341 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use console::style;
+use serde::{Deserialize, Serialize};
+
+use crate::crates_index::rewrite_config_json;
+use crate::download::append_to_path;
+use crate::mirror::{load_mirror_toml, MirrorError};
+use crate::progress_bar::{padded_prefix_message, progress_bar, progress_bar_bytes, StepsProgress};
+use crate::snapshot::content_files;
+use crate::state;
+use crate::timestamp::Timestamp;
+
+/// Name of the file marking a mirror as a replica of another, in the replica's root.
+pub const REPLICA_FILE: &str = ".panamax-replica";
+
+/// What a replica is a copy of.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Replica {
+    /// The mirror it's a copy of.
+    pub primary: PathBuf,
+    /// When it was last brought up to date, or None if the first copy never finished.
+    pub replicated: Option<Timestamp>,
+}
+
+impl Replica {
+    fn write(&self, replica_path: &Path) -> io::Result<()> {
+        let data =
+            serde_json::to_vec_pretty(self).expect("replica markers should always serialize");
+        let path = replica_path.join(REPLICA_FILE);
+        let part_path = append_to_path(&path, ".part");
+        fs::write(&part_path, data)?;
+        fs::rename(part_path, path)
+    }
+}
+
+/// What bringing a replica up to date did.
+#[derive(Debug, Default)]
+pub struct Replicated {
+    pub copied: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+/// Read the marker of a replica. None if the mirror isn't one.
+pub fn load(mirror_path: &Path) -> Option<Replica> {
+    serde_json::from_slice(&fs::read(mirror_path.join(REPLICA_FILE)).ok()?).ok()
+}
+
+/// Get the directory to replicate to from a path or a `file://` URL. Replicas on other hosts
+/// are written through a mount of their disk, as panamax has no way of its own to reach them.
+pub fn destination(to: &str) -> Result<PathBuf, MirrorError> {
+    if to.starts_with("file:") {
+        return url::Url::parse(to)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| MirrorError::Replica(format!("{to} isn't a valid file:// URL")));
+    }
+    if to.contains("://") {
+        return Err(MirrorError::Replica(format!(
+            "can't replicate to {to}, only to a directory. Mount the replica's disk, e.g. over \
+             NFS, and replicate to that."
+        )));
+    }
+    Ok(PathBuf::from(to))
+}
+
+/// Whether a mirrored file is a manifest that's replaced in place upstream, listing the release
+/// files of a channel.
+pub(crate) fn is_channel_manifest(file: &str) -> bool {
+    file.strip_prefix("dist/")
+        .is_some_and(|name| name.starts_with("channel-rust-") && !name.contains('/'))
+        || file.starts_with("rustup/release-")
+}
+
+/// The order files are copied to a replica in, so a replica that's served while it's being
+/// updated never lists a file it doesn't have yet: release files and crates first, then the
+/// index's git objects, then the rest of the index, and the channel manifests last.
+fn copy_order(file: &str) -> u8 {
+    match file.strip_prefix("crates.io-index/") {
+        Some(index_file) if index_file.starts_with(".git/objects/") => 1,
+        Some(_) => 2,
+        None if is_channel_manifest(file) => 3,
+        None => 0,
+    }
+}
+
+/// Copy a file to a replica, unless the replica's copy has the same size and modification time,
+/// which it's given when it's copied. Returns whether it was copied.
+///
+/// The file is copied next to its destination and moved into place, so it's never served half
+/// written.
+fn copy_if_changed(from: &Path, to: &Path) -> io::Result<bool> {
+    let metadata = fs::metadata(from)?;
+    if let Ok(existing) = fs::metadata(to) {
+        if existing.len() == metadata.len() && existing.modified()? == metadata.modified()? {
+            return Ok(false);
+        }
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let part_path = append_to_path(to, ".part");
+    fs::copy(from, &part_path)?;
+    File::options()
+        .write(true)
+        .open(&part_path)?
+        .set_modified(metadata.modified()?)?;
+    fs::rename(part_path, to)?;
+    Ok(true)
+}
+
+/// Bring a replica of a mirror up to date, creating it if needed.
+///
+/// Only files that changed since the last run are copied, and files the mirror no longer has
+/// are removed from the replica last, so it can be served all along. Its mirror.toml is only
+/// copied the first time, as the replica's host may need its own settings.
+///
+/// This shouldn't be run while the mirror is syncing. `force` replaces a mirror that isn't a
+/// replica, like an old primary that was failed over from.
+pub fn replicate(
+    mirror_path: &Path,
+    replica_path: &Path,
+    force: bool,
+) -> Result<Replicated, MirrorError> {
+    let primary = fs::canonicalize(mirror_path)?;
+    if fs::canonicalize(replica_path).is_ok_and(|replica| replica == primary) {
+        return Err(MirrorError::Replica(format!(
+            "{} is the mirror itself",
+            replica_path.display()
+        )));
+    }
+    let is_replica = replica_path.join(REPLICA_FILE).exists();
+    if !is_replica && !force {
+        if replica_path.join("mirror.toml").exists() {
+            return Err(MirrorError::Replica(format!(
+                "{} is a mirror of its own, not a replica. Use --force to replace it.",
+                replica_path.display()
+            )));
+        }
+        if fs::read_dir(replica_path).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(MirrorError::Replica(format!(
+                "{} isn't empty. Use --force to replicate to it anyway.",
+                replica_path.display()
+            )));
+        }
+    }
+
+    // The replica is marked first, so it's never synced while it's half copied.
+    fs::create_dir_all(replica_path)?;
+    let mut replica = load(replica_path).unwrap_or(Replica {
+        primary: primary.clone(),
+        replicated: None,
+    });
+    replica.primary = primary;
+    replica.write(replica_path)?;
+
+    let mut files = content_files(mirror_path)?;
+    files.sort_by_key(|file| copy_order(file));
+    let state_dir = state::dir(mirror_path);
+    let state_files = state::files(&state_dir)?;
+
+    // State files are kept in the replica's root, whatever the mirror's state_dir is, until
+    // it's promoted.
+    let copies = files
+        .iter()
+        .map(|file| (mirror_path.join(file), replica_path.join(file)))
+        .chain(
+            state_files
+                .iter()
+                .map(|name| (state_dir.join(name), replica_path.join(name))),
+        )
+        .map(|(from, to)| Ok((fs::metadata(&from)?.len(), from, to)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut replicated = Replicated::default();
+    let progress = StepsProgress::new(2, "Replicating mirror");
+    let pb = progress_bar_bytes(
+        copies.iter().map(|(size, _, _)| size).sum(),
+        padded_prefix_message(1, 2, "Copying files"),
+    );
+    for (size, from, to) in &copies {
+        if copy_if_changed(from, to)? {
+            replicated.copied += 1;
+        } else {
+            replicated.unchanged += 1;
+        }
+        pb.inc(*size);
+    }
+    pb.finish();
+    progress.step_done();
+    if !replica_path.join("mirror.toml").exists() {
+        fs::copy(
+            mirror_path.join("mirror.toml"),
+            replica_path.join("mirror.toml"),
+        )?;
+    }
+
+    let files: HashSet<String> = files.into_iter().chain(state_files).collect();
+    let stale: Vec<String> = content_files(replica_path)?
+        .into_iter()
+        .chain(state::files(replica_path)?)
+        .filter(|file| !files.contains(file))
+        .collect();
+    let pb = progress_bar(
+        stale.len(),
+        padded_prefix_message(2, 2, "Removing old files"),
+    );
+    for file in &stale {
+        fs::remove_file(replica_path.join(file))?;
+        replicated.removed += 1;
+        pb.inc(1);
+    }
+    pb.finish();
+    progress.step_done();
+
+    replica.replicated = Some(Timestamp::now());
+    replica.write(replica_path)?;
+    Ok(replicated)
+}
+
+/// Turn a replica into a primary mirror that can be synced, e.g. after the primary has failed.
+///
+/// If it's served at a different URL from the primary, `base_url` is written to its mirror.toml.
+/// The index's config.json is rewritten for the replica's base_url either way, as it still has
+/// the primary's. If mirror.toml sets a state_dir, the state files copied from the primary are
+/// moved there, replacing any older ones.
+pub fn promote(mirror_path: &Path, base_url: Option<&str>) -> Result<Replica, MirrorError> {
+    let Some(replica) = load(mirror_path) else {
+        return Err(MirrorError::Replica(format!(
+            "{} isn't a replica",
+            mirror_path.display()
+        )));
+    };
+    if replica.replicated.is_none() {
+        eprintln!(
+            "{}",
+            style("The first copy from the primary never finished, so some files are missing.")
+                .yellow()
+                .bold()
+        );
+        eprintln!("They'll be downloaded by the next sync.");
+    }
+
+    if let Some(base_url) = base_url {
+        let toml_path = mirror_path.join("mirror.toml");
+        let mut config = fs::read_to_string(&toml_path)?.parse::<toml_edit::Document>()?;
+        let Some(crates) = config.get_mut("crates").and_then(|c| c.as_table_mut()) else {
+            return Err(MirrorError::Replica(
+                "mirror.toml has no [crates] section to set base_url in".to_string(),
+            ));
+        };
+        crates["base_url"] = toml_edit::value(base_url);
+        fs::write(toml_path, config.to_string())?;
+    }
+    let config = load_mirror_toml(mirror_path)?;
+
+    if let Some(state_dir) = &config.mirror.state_dir {
+        fs::create_dir_all(state_dir)?;
+        for name in state::files(mirror_path)? {
+            // The state directory may be on another filesystem, so this can't just be a rename.
+            let part_path = state_dir.join(format!("{name}.part"));
+            fs::copy(mirror_path.join(&name), &part_path)?;
+            fs::rename(part_path, state_dir.join(&name))?;
+            fs::remove_file(mirror_path.join(&name))?;
+        }
+    }
+
+    let index_path = mirror_path.join("crates.io-index");
+    if let Some(crates) = config.crates.filter(|_| index_path.join(".git").exists()) {
+        if let Some(base_url) = &crates.base_url {
+            rewrite_config_json(
+                &index_path,
+                base_url,
+                crates.dl_template.as_deref(),
+                crates.omit_api.unwrap_or(false),
+            )?;
+        }
+    }
+
+    fs::remove_file(mirror_path.join(REPLICA_FILE))?;
+    Ok(replica)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod copy_order {
+        use crate::replica::copy_order;
+
+        #[test]
+        fn manifests_last() {
+            let mut files = vec![
+                "dist/channel-rust-stable.toml",
+                "crates.io-index/.git/refs/heads/master",
+                "crates.io-index/config.json",
+                "rustup/release-stable.toml",
+                "crates.io-index/.git/objects/pack/pack-1.pack",
+                "dist/2023-06-01/cargo-1.70.0-x86_64-unknown-linux-gnu.tar.xz",
+                "crates/se/rd/serde/1.0.0/serde-1.0.0.crate",
+            ];
+            files.sort_by_key(|file| copy_order(file));
+            assert_eq!(
+                files,
+                [
+                    "dist/2023-06-01/cargo-1.70.0-x86_64-unknown-linux-gnu.tar.xz",
+                    "crates/se/rd/serde/1.0.0/serde-1.0.0.crate",
+                    "crates.io-index/.git/objects/pack/pack-1.pack",
+                    "crates.io-index/.git/refs/heads/master",
+                    "crates.io-index/config.json",
+                    "dist/channel-rust-stable.toml",
+                    "rustup/release-stable.toml",
+                ]
+            );
+        }
+    }
+
+    mod destination {
+        use std::path::PathBuf;
+
+        use crate::replica::destination;
+
+        #[test]
+        fn paths_and_urls() {
+            assert_eq!(
+                destination("/mnt/standby/panamax").unwrap(),
+                PathBuf::from("/mnt/standby/panamax")
+            );
+            assert_eq!(destination("standby").unwrap(), PathBuf::from("standby"));
+            #[cfg(unix)]
+            assert_eq!(
+                destination("file:///mnt/standby/panamax").unwrap(),
+                PathBuf::from("/mnt/standby/panamax")
+            );
+            assert!(destination("ssh://standby/srv/panamax").is_err());
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+
+use crate::download::{DownloadClient, DownloadError};
+use crate::events::{self, Counts};
+use crate::health::Health;
+use crate::host_stats::{self, HostSummary};
+use crate::mirror::{ConfigMirror, MirrorError};
+use crate::timestamp::Timestamp;
+
+/// Summary of one sync run, sent to `report_url` when it's configured.
+#[derive(Debug, Serialize)]
+struct SyncReport<'a> {
+    mirror_id: &'a str,
+    version: &'a str,
+    /// Sent as RFC 3339 dates, where versions before them sent seconds since the UNIX epoch.
+    started: Timestamp,
+    finished: Timestamp,
+    /// Whether the sync finished with everything downloaded.
+    success: bool,
+    /// Why it didn't, if it didn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    counts: Counts,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maintenance: Option<&'a MaintenanceReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health: Option<&'a Health>,
+    /// Requests to each upstream host.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    hosts: BTreeMap<String, HostSummary>,
+}
+
+/// What scheduled maintenance did, when it ran after a sync.
+#[derive(Debug, Serialize)]
+pub struct MaintenanceReport {
+    pub pruned_files: usize,
+    pub missing_crates: usize,
+}
+
+/// POST a report of this sync, which ended with `result`, to the mirror's `report_url`, if it
+/// has one.
+///
+/// A failed upload only prints a warning, as it doesn't affect the mirror itself.
+pub async fn send(
+    client: &DownloadClient,
+    mirror: &ConfigMirror,
+    mirror_id: &str,
+    started: Timestamp,
+    result: &Result<(), MirrorError>,
+    maintenance: Option<&MaintenanceReport>,
+    health: Option<&Health>,
+) {
+    let Some(report_url) = &mirror.report_url else {
+        return;
+    };
+
+    let report = SyncReport {
+        mirror_id: mirror.mirror_id.as_deref().unwrap_or(mirror_id),
+        version: env!("CARGO_PKG_VERSION"),
+        started,
+        finished: Timestamp::now(),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(ToString::to_string),
+        counts: events::counts(),
+        maintenance,
+        health,
+        hosts: host_stats::summary(),
+    };
+
+    if let Err(e) = post(client, report_url, mirror.report_token.as_deref(), &report).await {
+        eprintln!("Uploading sync report to {report_url} failed: {e}");
+    }
+}
+
+/// POST a JSON body to a URL, with `token` as a bearer token if there is one.
+///
+/// This goes through the sync's own client, so it uses the same proxy, certificates and user
+/// agent as the downloads do.
+pub(crate) async fn post(
+    client: &DownloadClient,
+    url: &str,
+    token: Option<&str>,
+    body: &impl Serialize,
+) -> Result<(), DownloadError> {
+    let body = serde_json::to_vec(body).expect("reports should always serialize");
+    let mut request = client
+        .http()
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
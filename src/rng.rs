@@ -0,0 +1,35 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A small xorshift PRNG, for picking samples and generating test inputs without an extra
+/// dependency. It's nowhere near good enough for anything that has to be unpredictable.
+pub struct Xorshift(u64);
+
+impl Xorshift {
+    /// A generator that always gives the same numbers for the same seed.
+    pub fn new(seed: u64) -> Self {
+        // Zero is the one state xorshift never leaves.
+        Xorshift(seed | 1)
+    }
+
+    /// A generator seeded from the clock, for a different sequence on each run.
+    pub fn from_time() -> Self {
+        Xorshift::new(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(1),
+        )
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number below `n`, which must not be 0.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
@@ -1,23 +1,68 @@
+use crate::checksums;
+use crate::disk::LowDiskSpace;
 use crate::download::{
-    append_to_path, copy_file_create_dir_with_sha256, download, download_string,
-    download_with_sha256_file, move_if_exists, move_if_exists_with_sha256, write_file_create_dir,
+    append_to_path, copy_file_create_dir_with_sha256, download_if_modified,
+    download_string_from_mirrors, download_with_sha256_file, download_with_sha256_file_if_modified,
+    move_if_exists_with_sha256, write_file_create_dir, write_sha256_file, DownloadClient,
     DownloadError,
 };
-use crate::mirror::{ConfigMirror, ConfigRustup, MirrorError};
-use crate::progress_bar::{current_step_prefix, padded_prefix_message};
+use crate::events::{self, Action};
+use crate::hashes;
+use crate::maintenance::MaintenanceGuard;
+use crate::mirror::{ConfigMirror, ConfigRustup, MirrorError, Shard};
+use crate::plan::{execute_downloads, DeleteTask, DownloadTask, SharedDownloads, Task};
+use crate::progress_bar::{current_step_prefix, padded_prefix_message, progress_bar, step_message};
+use crate::served::{self, days_since_served};
+use crate::staging;
+use crate::state;
+use crate::storage;
+use crate::timestamp::{self, Timestamp};
+use crate::url_path::{MirrorPath, PathError};
+use crate::validators;
 use console::style;
 use futures::StreamExt;
-use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+use indicatif::ProgressBar;
+use log::{debug, info};
 use reqwest::header::HeaderValue;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::Arc;
 use std::{fs, io};
 use thiserror::Error;
 use tokio::task::JoinError;
 
+/// Size of a release file assumed for the disk space check, before the mirror has any.
+const DEFAULT_RELEASE_FILE_SIZE: u64 = 20_000_000;
+
+/// Typical sizes of the packages in a Rust release, in MB of .tar.xz, for small_files_first.
+/// Packages not listed are guessed to be DEFAULT_RELEASE_FILE_SIZE.
+const TYPICAL_PACKAGE_SIZES: &[(&str, u64)] = &[
+    ("rust-analysis", 1),
+    ("rustfmt", 3),
+    ("clippy", 3),
+    ("miri", 3),
+    ("rls", 3),
+    ("rust-src", 3),
+    ("rust-mingw", 4),
+    ("cargo", 9),
+    ("rust-docs-json", 10),
+    ("rust-analyzer", 15),
+    ("rust-docs", 20),
+    ("rustc-docs", 25),
+    ("llvm-tools", 40),
+    ("rust-std", 40),
+    ("rustc", 70),
+    ("rustc-dev", 110),
+    ("rust", 250),
+];
+
+/// Days an old file is kept after serve last handed it out, if keep_served_days isn't set.
+const DEFAULT_KEEP_SERVED_DAYS: u64 = 30;
+
+/// Suffix of the manifests downloaded to plan a sync, apart from those a sync downloads.
+const PLAN_PART_SUFFIX: &str = ".plan.part";
+
 // The allowed platforms to validate the configuration
 // Note: These platforms should match the list on https://rust-lang.github.io/rustup/installation/other.html
 
@@ -47,10 +92,77 @@ pub enum SyncError {
     #[error("Path prefix strip error: {0}")]
     StripPrefix(#[from] std::path::StripPrefixError),
 
+    #[error("Disk space error: {0}")]
+    DiskSpace(#[from] LowDiskSpace),
+
+    #[error("Path error: {0}")]
+    Path(#[from] PathError),
+
     #[error("Failed {count} downloads")]
     FailedDownloads { count: usize },
 }
 
+/// How many of a release's files can fail to download while it's still published, written in
+/// mirror.toml as a number of files, or a percentage of them like "1%".
+///
+/// The default is none, so a release is only published once all of its files are in the mirror.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "FailThresholdToml", into = "FailThresholdToml")]
+pub enum FailThreshold {
+    Files(usize),
+    Percent(f64),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum FailThresholdToml {
+    Files(usize),
+    Percent(String),
+}
+
+impl TryFrom<FailThresholdToml> for FailThreshold {
+    type Error = String;
+
+    fn try_from(threshold: FailThresholdToml) -> Result<Self, Self::Error> {
+        match threshold {
+            FailThresholdToml::Files(files) => Ok(FailThreshold::Files(files)),
+            FailThresholdToml::Percent(s) => s
+                .strip_suffix('%')
+                .and_then(|percent| percent.trim().parse::<f64>().ok())
+                .filter(|percent| (0.0..=100.0).contains(percent))
+                .map(FailThreshold::Percent)
+                .ok_or_else(|| {
+                    format!("invalid fail_threshold {s:?}, expected a number of files or \"1%\"")
+                }),
+        }
+    }
+}
+
+impl From<FailThreshold> for FailThresholdToml {
+    fn from(threshold: FailThreshold) -> Self {
+        match threshold {
+            FailThreshold::Files(files) => FailThresholdToml::Files(files),
+            FailThreshold::Percent(percent) => FailThresholdToml::Percent(format!("{percent}%")),
+        }
+    }
+}
+
+impl Default for FailThreshold {
+    fn default() -> Self {
+        FailThreshold::Files(0)
+    }
+}
+
+impl FailThreshold {
+    /// Whether a release with `failed` of its `total` files missing can still be published.
+    pub fn allows(&self, failed: usize, total: usize) -> bool {
+        match *self {
+            FailThreshold::Files(files) => failed <= files,
+            FailThreshold::Percent(percent) => failed as f64 <= total as f64 * percent / 100.0,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TargetUrls {
     pub url: String,
@@ -90,6 +202,9 @@ struct Release {
 pub struct Platforms {
     unix: Vec<String>,
     windows: Vec<String>,
+    /// Platforms that are only cross-compiled to, so only need the standard library.
+    #[serde(default)]
+    targets: Vec<String>,
 }
 
 impl Platforms {
@@ -102,19 +217,142 @@ impl Platforms {
         self.unix.contains(platform) || self.windows.contains(platform)
     }
 
+    /// Whether a package is needed for a platform, either as a host or as a
+    /// cross-compilation target.
+    pub fn needs(&self, pkg_name: &str, platform: &String) -> bool {
+        self.contains(platform) || (pkg_name == "rust-std" && self.targets.contains(platform))
+    }
+
     pub fn len(&self) -> usize {
         self.unix.len() + self.windows.len()
     }
 }
 
+/// A channel available in the mirror.
+#[derive(Debug, Serialize)]
+pub struct MirroredChannel {
+    pub name: String,
+    pub date: String,
+}
+
+/// The components of a mirrored channel that are available for each target.
+#[derive(Debug, Serialize)]
+pub struct MirroredTargets {
+    pub name: String,
+    pub date: String,
+    pub targets: BTreeMap<String, Vec<String>>,
+}
+
+/// Get the path of a channel's manifest in the mirror, e.g. "stable", "1.70.0", or
+/// "nightly-2023-01-01". Returns None for names that can't be channels.
+fn channel_manifest_path(path: &Path, name: &str) -> Option<PathBuf> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    {
+        return None;
+    }
+    Some(match name.strip_prefix("nightly-") {
+        Some(date) => path.join(format!("dist/{date}/channel-rust-nightly.toml")),
+        None => path.join(format!("dist/channel-rust-{name}.toml")),
+    })
+}
+
+fn read_channel(manifest_path: &Path) -> Option<Channel> {
+    let channel_str = fs::read_to_string(manifest_path).ok()?;
+    toml_edit::easy::from_str(&channel_str).ok()
+}
+
+/// List the channels available in the mirror, including pinned versions and older nightlies.
+pub fn mirrored_channels(path: &Path) -> Vec<MirroredChannel> {
+    let mut names = Vec::new();
+    if let Ok(rd) = fs::read_dir(path.join("dist")) {
+        for entry in rd.filter_map(|e| e.ok()) {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if let Some(name) = file_name
+                .strip_prefix("channel-rust-")
+                .and_then(|n| n.strip_suffix(".toml"))
+            {
+                names.push(name.to_string());
+            } else if entry.path().join("channel-rust-nightly.toml").exists() {
+                names.push(format!("nightly-{file_name}"));
+            }
+        }
+    }
+    names.sort();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let channel = read_channel(&channel_manifest_path(path, &name)?)?;
+            Some(MirroredChannel {
+                name,
+                date: channel.date,
+            })
+        })
+        .collect()
+}
+
+/// List the components of a channel that are actually in the mirror, for each target.
+pub fn mirrored_targets(path: &Path, name: &str) -> Option<MirroredTargets> {
+    let channel = read_channel(&channel_manifest_path(path, name)?)?;
+
+    let mut targets: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (pkg_name, pkg) in channel.pkg {
+        for (target, t) in pkg.target {
+            let Some(urls) = t.target_urls else {
+                continue;
+            };
+            let is_mirrored = [urls.xz_url, urls.url]
+                .iter()
+                .filter_map(|url| MirrorPath::from_url(url).ok())
+                .any(|p| p.on_disk(path).exists());
+            if is_mirrored {
+                targets.entry(target).or_default().push(pkg_name.clone());
+            }
+        }
+    }
+    for components in targets.values_mut() {
+        components.sort();
+    }
+
+    Some(MirroredTargets {
+        name: name.to_string(),
+        date: channel.date,
+        targets,
+    })
+}
+
+/// Build the upstream URL for a path, relative to the root of the rustup source.
+///
+/// If `source` contains a `{path}` marker, it is used as a template, so upstreams with
+/// non-standard layouts can be mirrored. Otherwise the path is appended to it.
+pub fn source_url(source: &str, path: &str) -> String {
+    if source.contains("{path}") {
+        source.replace("{path}", path)
+    } else {
+        format!("{source}/{path}")
+    }
+}
+
+/// Build the upstream URLs for a path on each of the rustup sources, in the order to try them.
+pub fn source_urls(sources: &[String], path: &str) -> Vec<String> {
+    sources
+        .iter()
+        .map(|source| source_url(source, path))
+        .collect()
+}
+
 pub async fn download_platform_list(
-    source: &str,
+    client: &DownloadClient,
+    sources: &[String],
     channel: &str,
+    user_agent: &HeaderValue,
 ) -> Result<Vec<String>, MirrorError> {
-    let channel_url = format!("{source}/dist/channel-rust-{channel}.toml");
-    let user_agent = HeaderValue::from_str(&format!("Panamax/{}", env!("CARGO_PKG_VERSION")))
-        .expect("Hardcoded user agent string should never fail.");
-    let channel_str = download_string(&channel_url, &user_agent).await?;
+    let channel_urls = source_urls(sources, &format!("dist/channel-rust-{channel}.toml"));
+    let channel_str = download_string_from_mirrors(client, &channel_urls, user_agent).await?;
     let channel_data: Channel = toml_edit::easy::from_str(&channel_str)?;
 
     let mut targets = HashSet::new();
@@ -134,8 +372,12 @@ pub async fn download_platform_list(
     Ok(targets)
 }
 
-pub async fn get_platforms(rustup: &ConfigRustup) -> Result<Platforms, MirrorError> {
-    let all = download_platform_list(&rustup.source, "nightly").await?;
+pub async fn get_platforms(
+    client: &DownloadClient,
+    rustup: &ConfigRustup,
+    user_agent: &HeaderValue,
+) -> Result<Platforms, MirrorError> {
+    let all = download_platform_list(client, rustup.source.all(), "nightly", user_agent).await?;
 
     let unix = match &rustup.platforms_unix {
         Some(p) => p.clone(),
@@ -150,15 +392,69 @@ pub async fn get_platforms(rustup: &ConfigRustup) -> Result<Platforms, MirrorErr
         Some(p) => p.clone(),
         None => PLATFORMS_WINDOWS.iter().map(|x| x.to_string()).collect(),
     };
-    Ok(Platforms { unix, windows })
+
+    let targets = rustup.target_platforms.clone().unwrap_or_default();
+
+    Ok(Platforms {
+        unix,
+        windows,
+        targets,
+    })
+}
+
+/// A manifest downloaded next to where it's served from, waiting on the files it lists.
+///
+/// Clients go straight from a manifest to the files in it, so it's only moved into place,
+/// along with its .sha256 file, once all of them are on disk. If it's dropped unpublished, it's
+/// removed, and upstream's validators for it are forgotten, as they describe this manifest
+/// rather than the one still being served.
+struct PendingManifest {
+    part_path: PathBuf,
+    path: PathBuf,
+    url: Option<String>,
+    published: bool,
+}
+
+impl PendingManifest {
+    fn new(path: &Path, part_suffix: &str, urls: &[String]) -> Self {
+        PendingManifest {
+            part_path: staging::part_path(path, part_suffix),
+            path: path.to_path_buf(),
+            // Validators are only kept for the first mirror.
+            url: urls.first().cloned(),
+            published: false,
+        }
+    }
+
+    fn publish(mut self) -> Result<(), DownloadError> {
+        move_if_exists_with_sha256(&self.part_path, &self.path)?;
+        self.published = true;
+        hashes::write_sidecars(&self.path)
+    }
+}
+
+impl Drop for PendingManifest {
+    fn drop(&mut self) {
+        if self.published {
+            return;
+        }
+        if let Some(url) = &self.url {
+            let _ = validators::record(url, None);
+        }
+        let _ = fs::remove_file(append_to_path(&self.part_path, ".sha256"));
+        for sidecar_path in hashes::sidecar_paths(&self.part_path) {
+            let _ = fs::remove_file(sidecar_path);
+        }
+        let _ = fs::remove_file(&self.part_path);
+    }
 }
 
 /// Synchronize one rustup-init file.
 #[allow(clippy::too_many_arguments)]
 pub async fn sync_one_init(
-    client: &Client,
+    client: &DownloadClient,
     path: &Path,
-    source: &str,
+    sources: &[String],
     platform: &str,
     is_exe: bool,
     rustup_version: &str,
@@ -182,51 +478,46 @@ pub async fn sync_one_init(
         "rustup-init"
     });
 
-    let source_url = if is_exe {
-        format!("{source}/rustup/dist/{platform}/rustup-init.exe")
+    let init_path = if is_exe {
+        format!("rustup/dist/{platform}/rustup-init.exe")
     } else {
-        format!("{source}/rustup/dist/{platform}/rustup-init")
+        format!("rustup/dist/{platform}/rustup-init")
     };
 
-    download_with_sha256_file(client, &source_url, &local_path, retries, false, user_agent).await?;
+    download_with_sha256_file(
+        client,
+        &source_urls(sources, &init_path),
+        &source_urls(sources, &format!("{init_path}.sha256")),
+        &local_path,
+        retries,
+        false,
+        user_agent,
+    )
+    .await?;
     copy_file_create_dir_with_sha256(&local_path, &archive_path)?;
 
     Ok(())
 }
 
-fn panamax_progress_bar(size: usize, prefix: String) -> ProgressBar {
-    ProgressBar::new(size as u64)
-        .with_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{prefix} {wide_bar} {pos}/{len} [{elapsed_precise} / {duration_precise}]",
-                )
-                .expect("template is correct")
-                .progress_chars("█▉▊▋▌▍▎▏  "),
-        )
-        .with_finish(ProgressFinish::AndLeave)
-        .with_prefix(prefix)
-}
-
 #[allow(clippy::too_many_arguments)]
 async fn create_sync_tasks(
+    client: &DownloadClient,
     platforms: &[String],
     is_exe: bool,
     rustup_version: &str,
     path: &Path,
-    source: &str,
+    sources: &[String],
     retries: usize,
     user_agent: &HeaderValue,
     threads: usize,
     pb: &ProgressBar,
 ) -> Vec<Result<Result<(), DownloadError>, JoinError>> {
-    let client = Client::new();
     futures::stream::iter(platforms.iter())
         .map(|platform| {
             let client = client.clone();
             let rustup_version = rustup_version.to_string();
             let path = path.to_path_buf();
-            let source = source.to_string();
+            let sources = sources.to_vec();
             let user_agent = user_agent.clone();
             let platform = platform.clone();
             let pb = pb.clone();
@@ -235,7 +526,7 @@ async fn create_sync_tasks(
                 let out = sync_one_init(
                     &client,
                     &path,
-                    &source,
+                    &sources,
                     platform.as_str(),
                     is_exe,
                     &rustup_version,
@@ -255,10 +546,12 @@ async fn create_sync_tasks(
 }
 
 /// Synchronize all rustup-init files.
+#[allow(clippy::too_many_arguments)]
 pub async fn sync_rustup_init(
+    client: &DownloadClient,
     path: &Path,
     threads: usize,
-    source: &str,
+    sources: &[String],
     prefix: String,
     retries: usize,
     user_agent: &HeaderValue,
@@ -266,37 +559,33 @@ pub async fn sync_rustup_init(
 ) -> Result<(), SyncError> {
     let mut errors_occurred = 0usize;
 
-    let client = Client::new();
-
     // Download rustup release file
-    let release_url = format!("{source}/rustup/release-stable.toml");
+    let release_url = source_urls(sources, "rustup/release-stable.toml");
     let release_path = path.join("rustup/release-stable.toml");
-    let release_part_path = append_to_path(&release_path, ".part");
+    let release = PendingManifest::new(&release_path, ".part", &release_url);
 
-    download(
-        &client,
+    download_if_modified(
+        client,
         &release_url,
-        &release_part_path,
+        &release.part_path,
+        &release_path,
         None,
         retries,
-        false,
         user_agent,
     )
     .await?;
 
-    let rustup_version = get_rustup_version(&release_part_path)?;
+    let rustup_version = get_rustup_version(&release.part_path)?;
 
-    move_if_exists(&release_part_path, &release_path)?;
-
-    let pb = panamax_progress_bar(platforms.len(), prefix);
-    pb.enable_steady_tick(Duration::from_millis(10));
+    let pb = progress_bar(platforms.len(), prefix);
 
     let unix_tasks = create_sync_tasks(
+        client,
         &platforms.unix,
         false,
         &rustup_version,
         path,
-        source,
+        sources,
         retries,
         user_agent,
         threads,
@@ -305,11 +594,12 @@ pub async fn sync_rustup_init(
     .await;
 
     let win_tasks = create_sync_tasks(
+        client,
         &platforms.windows,
         true,
         &rustup_version,
         path,
-        source,
+        sources,
         retries,
         user_agent,
         threads,
@@ -318,12 +608,21 @@ pub async fn sync_rustup_init(
     .await;
 
     for res in unix_tasks.into_iter().chain(win_tasks) {
-        // Unwrap the join result.
-        let res = res.unwrap();
+        // A panicking download is counted as a failure, rather than taking the sync down.
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                errors_occurred += 1;
+                eprintln!("Download task failed: {e}");
+                pb.inc(1);
+                continue;
+            }
+        };
 
         if let Err(e) = res {
             match e {
                 DownloadError::NotFound { .. } => {}
+                DownloadError::Interrupted => errors_occurred += 1,
                 _ => {
                     errors_occurred += 1;
                     eprintln!("Download failed: {e:?}");
@@ -333,6 +632,9 @@ pub async fn sync_rustup_init(
     }
 
     if errors_occurred == 0 {
+        release.publish()?;
+        // Upstream doesn't publish a .sha256 for the release file, so make one.
+        write_sha256_file(&release_path)?;
         Ok(())
     } else {
         Err(SyncError::FailedDownloads {
@@ -341,81 +643,71 @@ pub async fn sync_rustup_init(
     }
 }
 
-/// Get the rustup file downloads, in pairs of URLs and sha256 hashes.
+/// Get the rustup file downloads, in pairs of the paths they're mirrored at and sha256 hashes.
 pub fn rustup_download_list(
     path: &Path,
     download_dev: bool,
     download_gz: bool,
     download_xz: bool,
     platforms: &Platforms,
-) -> Result<(String, Vec<(String, String)>), SyncError> {
+) -> Result<(String, Vec<(MirrorPath, String)>), SyncError> {
     let channel_str = fs::read_to_string(path).map_err(DownloadError::Io)?;
     let channel: Channel = toml_edit::easy::from_str(&channel_str)?;
 
-    Ok((
-        channel.date,
-        channel
-            .pkg
-            .into_iter()
-            .filter(|(pkg_name, _)| download_dev || pkg_name != "rustc-dev")
-            .flat_map(|(_, pkg)| {
-                pkg.target
-                    .into_iter()
-                    .filter(
-                        |(name, _)| platforms.contains(name) || name == "*", // The * platform contains rust-src, always download
-                    )
-                    .flat_map(|(_, target)| -> Vec<(String, String)> {
-                        target
-                            .target_urls
-                            .map(|urls| {
-                                let mut v = Vec::new();
-                                if download_gz {
-                                    v.push((urls.url, urls.hash));
-                                }
-                                if download_xz {
-                                    v.push((urls.xz_url, urls.xz_hash));
-                                }
-
-                                v
-                            })
-                            .into_iter()
-                            .flatten()
-                            .map(|(url, hash)| {
-                                (url.split('/').collect::<Vec<&str>>()[3..].join("/"), hash)
-                            })
-                            .collect()
-                    })
-            })
-            .collect(),
-    ))
-}
-
-pub async fn sync_one_rustup_target(
-    client: &Client,
-    path: &Path,
-    source: &str,
-    url: &str,
-    hash: &str,
-    retries: usize,
-    user_agent: &HeaderValue,
-) -> Result<(), DownloadError> {
-    // Chop off the source portion of the URL, to mimic the rest of the path
-    //let target_url = path.join(url[source.len()..].trim_start_matches("/"));
-    let target_url = format!("{source}/{url}");
-    let target_path: PathBuf = std::iter::once(path.to_owned())
-        .chain(url.split('/').map(PathBuf::from))
+    let files: Vec<(String, String)> = channel
+        .pkg
+        .into_iter()
+        .filter(|(pkg_name, _)| download_dev || pkg_name != "rustc-dev")
+        .flat_map(|(pkg_name, pkg)| {
+            pkg.target
+                .into_iter()
+                .filter(
+                    move |(name, _)| platforms.needs(&pkg_name, name) || name == "*", // The * platform contains rust-src, always download
+                )
+                .flat_map(|(_, target)| -> Vec<(String, String)> {
+                    target
+                        .target_urls
+                        .map(|urls| {
+                            let mut v = Vec::new();
+                            if download_gz {
+                                v.push((urls.url, urls.hash));
+                            }
+                            if download_xz {
+                                v.push((urls.xz_url, urls.xz_hash));
+                            }
+
+                            v
+                        })
+                        .into_iter()
+                        .flatten()
+                        .collect()
+                })
+        })
         .collect();
+    let files = files
+        .into_iter()
+        .map(|(url, hash)| Ok((MirrorPath::from_url(&url)?, hash)))
+        .collect::<Result<_, PathError>>()?;
+    Ok((channel.date, files))
+}
 
-    download(
-        client,
-        &target_url,
-        &target_path,
-        Some(hash),
-        retries,
-        false,
-        user_agent,
-    )
-    .await
+/// Guess the size of a release file from its package, so small ones can be downloaded first.
+fn typical_size(url: &str) -> u64 {
+    let file_name = url.rsplit('/').next().unwrap_or(url);
+    let size = TYPICAL_PACKAGE_SIZES
+        .iter()
+        .filter(|(package, _)| {
+            file_name
+                .strip_prefix(package)
+                .is_some_and(|rest| rest.starts_with('-') || rest.starts_with('.'))
+        })
+        .max_by_key(|(package, _)| package.len())
+        .map_or(DEFAULT_RELEASE_FILE_SIZE, |(_, mb)| mb * 1_000_000);
+    // gzip doesn't compress nearly as well as xz.
+    match file_name.ends_with(".gz") {
+        true => size * 3 / 2,
+        false => size,
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -432,20 +724,49 @@ pub fn latest_dates_from_channel_history(
         .keys()
         .map(|x| x.to_string())
         .collect();
-    dates.sort();
+    // By the day they're on, with anything that isn't a date as older than any release.
+    dates.sort_by_cached_key(|date| (timestamp::parse_date(date), date.clone()));
     dates.reverse();
     dates.truncate(versions);
     dates
 }
 
-pub fn clean_old_files(
+/// Releases a sync is about to add to channel histories, by channel, as the date of each and
+/// the files in it. Nothing in them is old.
+pub type PlannedReleases = HashMap<String, (String, Vec<String>)>;
+
+/// A channel's history, along with the release planned for it, if there is one.
+fn history_with_planned(
+    path: &Path,
+    channel: &str,
+    planned: &PlannedReleases,
+) -> Option<ChannelHistoryFile> {
+    let history = get_channel_history(path, channel).ok();
+    match planned.get(channel) {
+        Some((date, files)) => {
+            let mut history = history.unwrap_or(ChannelHistoryFile {
+                versions: HashMap::new(),
+            });
+            history
+                .versions
+                .insert(timestamp::normalize_date(date), files.clone());
+            Some(history)
+        }
+        None => history,
+    }
+}
+
+/// Get the files in dist that are no longer part of a release being kept, relative to the
+/// mirror root, leaving out those clients downloaded in the last `keep_served_days` days.
+pub fn old_files(
     path: &Path,
     keep_stables: Option<usize>,
     keep_betas: Option<usize>,
     keep_nightlies: Option<usize>,
     pinned_rust_versions: Option<&Vec<String>>,
-    prefix: String,
-) -> Result<(), SyncError> {
+    keep_served_days: u64,
+    planned: &PlannedReleases,
+) -> Result<Vec<PathBuf>, SyncError> {
     let versions = [
         ("stable", keep_stables),
         ("beta", keep_betas),
@@ -456,9 +777,9 @@ pub fn clean_old_files(
     let mut files_to_keep: HashSet<PathBuf> = HashSet::new();
     for (channel, keep_version) in versions {
         if let Some(s) = keep_version {
-            let mut history = match get_channel_history(path, channel) {
-                Ok(c) => c,
-                Err(_) => continue,
+            let mut history = match history_with_planned(path, channel, planned) {
+                Some(c) => c,
+                None => continue,
             };
             let latest_dates = latest_dates_from_channel_history(&history, s);
             for date in latest_dates {
@@ -475,9 +796,9 @@ pub fn clean_old_files(
 
     if let Some(pinned_versions) = pinned_rust_versions {
         for version in pinned_versions {
-            let mut pinned = match get_channel_history(path, version) {
-                Ok(c) => c,
-                Err(_) => continue,
+            let mut pinned = match history_with_planned(path, version, planned) {
+                Some(c) => c,
+                None => continue,
             };
             let latest_dates = latest_dates_from_channel_history(&pinned, 1);
             for date in latest_dates {
@@ -496,50 +817,143 @@ pub fn clean_old_files(
     let dist_path = path.join("dist");
     let mut files_to_delete = Vec::new();
 
+    // Symlinks are never followed or removed while cleaning, so that a link pointing outside
+    // of the mirror can't cause files elsewhere to be deleted.
     for dir in fs::read_dir(dist_path)? {
-        let dir = dir?.path();
+        let dir = dir?;
+        if dir.file_type()?.is_symlink() {
+            continue;
+        }
+        let dir = dir.path();
         let dir = dir.as_path();
         if dir.is_dir() {
             for full_path in fs::read_dir(dir)? {
-                let full_path = full_path?.path();
+                let full_path = full_path?;
+                if full_path.file_type()?.is_symlink() {
+                    continue;
+                }
+                let full_path = full_path.path();
                 let file_path = full_path.strip_prefix(path)?;
 
-                if !files_to_keep.contains(file_path) {
+                // Extra sidecar files go along with the file they're for.
+                let kept = files_to_keep.contains(file_path)
+                    || hashes::sidecar_of(file_path).is_some_and(|f| files_to_keep.contains(&f));
+                if !kept {
                     files_to_delete.push(file_path.to_owned());
                 }
             }
+        }
+    }
 
-            // Remove directory if empty.
-            if dir.read_dir()?.next().is_none() {
-                fs::remove_dir(dir)?;
+    // Files clients downloaded recently are likely still in use, e.g. by a toolchain file the
+    // mirror doesn't know about, so removing them would break those builds. The whole release
+    // directory is kept, as a toolchain can't be installed with some of its files missing.
+    if keep_served_days > 0 {
+        let served = served::load(path);
+        let now = Timestamp::now();
+        let release_dir = |f: &Path| f.parent().unwrap_or(f).to_path_buf();
+        let mut recently_served: BTreeMap<PathBuf, u64> = BTreeMap::new();
+        for f in &files_to_delete {
+            match days_since_served(&served, &served::key(f), now) {
+                Some(days) if days < keep_served_days => {
+                    let last = recently_served.entry(release_dir(f)).or_insert(days);
+                    *last = (*last).min(days);
+                }
+                _ => {}
             }
         }
+        files_to_delete.retain(|f| !recently_served.contains_key(&release_dir(f)));
+        for (dir, days) in &recently_served {
+            eprintln!(
+                "{}",
+                style(format!(
+                    "Not removing {}, which clients downloaded {days} day(s) ago.",
+                    dir.display()
+                ))
+                .yellow()
+                .bold()
+            );
+        }
+        if !recently_served.is_empty() {
+            eprintln!(
+                "Pin these versions to keep them, or sync with --force to remove them anyway."
+            );
+        }
     }
 
-    // Progress bar!
-    let pb = panamax_progress_bar(files_to_delete.len(), prefix);
+    Ok(files_to_delete)
+}
 
-    for f in files_to_delete {
-        if let Err(e) = fs::remove_file(path.join(&f)) {
-            eprintln!("Could not remove file {}: {:?}", f.to_string_lossy(), e);
+pub fn clean_old_files(
+    path: &Path,
+    keep_stables: Option<usize>,
+    keep_betas: Option<usize>,
+    keep_nightlies: Option<usize>,
+    pinned_rust_versions: Option<&Vec<String>>,
+    keep_served_days: u64,
+    prefix: String,
+) -> Result<(), SyncError> {
+    let files_to_delete = old_files(
+        path,
+        keep_stables,
+        keep_betas,
+        keep_nightlies,
+        pinned_rust_versions,
+        keep_served_days,
+        &PlannedReleases::new(),
+    )?;
+
+    // Progress bar!
+    let pb = progress_bar(files_to_delete.len(), prefix);
+    let _maintenance = MaintenanceGuard::start(path, "dist")?;
+
+    let full_paths: Vec<PathBuf> = files_to_delete.iter().map(|f| path.join(f)).collect();
+    let removed = storage::get().remove_files(&full_paths);
+    for ((f, full_path), res) in files_to_delete.iter().zip(&full_paths).zip(removed) {
+        info!("Removing {}", f.display());
+        match res {
+            Ok(()) => {
+                events::emit(Action::Deleted, full_path, None, None);
+                let _ = checksums::forget(full_path);
+            }
+            Err(e) => eprintln!("Could not remove file {}: {:?}", f.to_string_lossy(), e),
         }
         pb.inc(1);
     }
 
+    // Remove the release directories that are empty now.
+    for dir in fs::read_dir(path.join("dist"))? {
+        let dir = dir?;
+        if dir.file_type()?.is_dir() && dir.path().read_dir()?.next().is_none() {
+            fs::remove_dir(dir.path())?;
+        }
+    }
+
+    if let Err(e) = served::prune(path) {
+        eprintln!("Could not prune {}: {e}", served::SERVED_FILE);
+    }
+
     Ok(())
 }
 
 pub fn get_channel_history(path: &Path, channel: &str) -> Result<ChannelHistoryFile, SyncError> {
-    let channel_history_path = path.join(format!("mirror-{channel}-history.toml"));
+    let channel_history_path = state::path(path, &format!("mirror-{channel}-history.toml"));
     let ch_data = fs::read_to_string(channel_history_path)?;
-    Ok(toml_edit::easy::from_str(&ch_data)?)
+    let history: ChannelHistoryFile = toml_edit::easy::from_str(&ch_data)?;
+    Ok(ChannelHistoryFile {
+        versions: history
+            .versions
+            .into_iter()
+            .map(|(date, files)| (timestamp::normalize_date(&date), files))
+            .collect(),
+    })
 }
 
 pub fn add_to_channel_history(
     path: &Path,
     channel: &str,
     date: &str,
-    files: &[(String, String)],
+    files: &[(MirrorPath, String)],
     extra_files: &[String],
 ) -> Result<(), SyncError> {
     let mut channel_history = match get_channel_history(path, channel) {
@@ -555,11 +969,13 @@ pub fn add_to_channel_history(
 
     let files = files.chain(extra_files).collect();
 
-    channel_history.versions.insert(date.to_string(), files);
+    channel_history
+        .versions
+        .insert(timestamp::normalize_date(date), files);
 
     let ch_data = toml_edit::ser::to_string(&channel_history)?;
 
-    let channel_history_path = path.join(format!("mirror-{channel}-history.toml"));
+    let channel_history_path = state::path(path, &format!("mirror-{channel}-history.toml"));
     write_file_create_dir(&channel_history_path, &ch_data)?;
 
     Ok(())
@@ -571,101 +987,274 @@ pub fn get_rustup_version(path: &Path) -> Result<String, SyncError> {
     Ok(release_data.version)
 }
 
-/// Synchronize a rustup channel (stable, beta, or nightly).
+/// A channel's manifest, downloaded but not published yet, and the files it lists.
+struct FetchedChannel {
+    manifest: PendingManifest,
+    date: String,
+    files: Vec<(MirrorPath, String)>,
+    /// Files of the channel other than those the manifest lists, which are kept along with it.
+    extra_files: Vec<String>,
+}
+
+/// Download a channel's manifest next to where it's served from, with `part_suffix`, and
+/// list the files in it that are mirrored.
 #[allow(clippy::too_many_arguments)]
-pub async fn sync_rustup_channel(
+async fn fetch_channel(
+    client: &DownloadClient,
     path: &Path,
-    source: &str,
-    threads: usize,
-    prefix: String,
+    sources: &[String],
     channel: &str,
+    part_suffix: &str,
     retries: usize,
     user_agent: &HeaderValue,
     download_dev: bool,
     download_gz: bool,
     download_xz: bool,
     platforms: &Platforms,
-) -> Result<(), SyncError> {
+) -> Result<FetchedChannel, SyncError> {
     // Download channel file
-    let (channel_url, channel_path, extra_files) =
-        if let Some(inner_channel) = channel.strip_prefix("nightly-") {
-            let url = format!("{source}/dist/{inner_channel}/channel-rust-nightly.toml");
-            let path_chunk = format!("dist/{inner_channel}/channel-rust-nightly.toml");
-            let path = path.join(&path_chunk);
-            // Make sure the cleanup step doesn't delete the channel toml
-            let extra_files = vec![path_chunk.clone(), format!("{path_chunk}.sha256")];
-            (url, path, extra_files)
-        } else {
-            let url = format!("{source}/dist/channel-rust-{channel}.toml");
-            let path = path.join(format!("dist/channel-rust-{channel}.toml"));
-            (url, path, Vec::new())
-        };
-    let channel_part_path = append_to_path(&channel_path, ".part");
-    let client = Client::new();
-    download_with_sha256_file(
-        &client,
-        &channel_url,
-        &channel_part_path,
+    let (path_chunk, extra_files) = if let Some(inner_channel) = channel.strip_prefix("nightly-") {
+        let path_chunk = format!("dist/{inner_channel}/channel-rust-nightly.toml");
+        // Make sure the cleanup step doesn't delete the channel toml
+        let extra_files = vec![path_chunk.clone(), format!("{path_chunk}.sha256")];
+        (path_chunk, extra_files)
+    } else {
+        (format!("dist/channel-rust-{channel}.toml"), Vec::new())
+    };
+    // The channel name comes from mirror.toml or a toolchain file, so make sure it can't lead
+    // the manifest outside the mirror.
+    let channel_path = MirrorPath::parse(&path_chunk)?.on_disk(path);
+    let channel_urls = source_urls(sources, &path_chunk);
+    let manifest = PendingManifest::new(&channel_path, part_suffix, &channel_urls);
+    download_with_sha256_file_if_modified(
+        client,
+        &channel_urls,
+        &source_urls(sources, &format!("{path_chunk}.sha256")),
+        &manifest.part_path,
+        &channel_path,
         retries,
-        true,
         user_agent,
     )
     .await?;
 
     // Open toml file, find all files to download
     let (date, files) = rustup_download_list(
-        &channel_part_path,
+        &manifest.part_path,
         download_dev,
         download_gz,
         download_xz,
         platforms,
     )?;
-    move_if_exists_with_sha256(&channel_part_path, &channel_path)?;
 
-    let pb = panamax_progress_bar(files.len(), prefix);
-    pb.enable_steady_tick(Duration::from_millis(10));
+    Ok(FetchedChannel {
+        manifest,
+        date,
+        files,
+        extra_files,
+    })
+}
 
-    let mut errors_occurred = 0usize;
+/// The download task for a release file listed in a channel manifest.
+fn release_file_task(sources: &[String], file: &MirrorPath, hash: &str) -> DownloadTask {
+    DownloadTask {
+        file: file.clone(),
+        urls: source_urls(sources, file.as_str()),
+        sha256: Some(hash.to_string()),
+        size: typical_size(file.as_str()),
+    }
+}
 
-    let tasks = futures::stream::iter(files.iter())
-        .map(|(url, hash)| {
-            // Clone the variables that will be moved into the tokio task.
-            let client = client.clone();
-            let path = path.to_path_buf();
-            let source = source.to_string();
-            let user_agent = user_agent.clone();
-            let url = url.clone();
-            let hash = hash.clone();
-            let pb = pb.clone();
+/// Get the Rust versions to pin: those in pinned_rust_versions, and those only asked for by
+/// toolchain files. Each version is only in there once, as two syncs of the same version at
+/// the same time would write the same files.
+fn pinned_versions(rustup: &ConfigRustup) -> (Vec<String>, Vec<String>) {
+    let mut pinned_versions: Vec<String> = Vec::new();
+    for version in rustup.pinned_rust_versions.iter().flatten() {
+        if !pinned_versions.contains(version) {
+            pinned_versions.push(version.clone());
+        }
+    }
+    let toolchain_versions = rustup
+        .pin_from_toolchain_files
+        .as_ref()
+        .map(|patterns| toolchain_file_versions(patterns))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|version| !pinned_versions.contains(version))
+        .collect();
+    (pinned_versions, toolchain_versions)
+}
 
-            tokio::spawn(async move {
-                let out = sync_one_rustup_target(
-                    &client,
-                    &path,
-                    &source,
-                    &url,
-                    &hash,
-                    retries,
-                    &user_agent,
-                )
-                .await;
+/// Get every Rust version asked for by the toolchain files matching `patterns`, in a form that
+/// can be pinned.
+///
+/// The latest stable, beta and nightly are always mirrored, so toolchain files that just
+/// follow a channel are skipped.
+pub fn toolchain_file_versions(patterns: &[String]) -> Vec<String> {
+    let mut versions = BTreeSet::new();
+    for pattern in patterns {
+        let paths = match glob::glob(pattern) {
+            Ok(paths) => paths,
+            Err(e) => {
+                eprintln!("Invalid toolchain file pattern {pattern}: {e}");
+                continue;
+            }
+        };
+        for toolchain_file in paths.filter_map(|p| p.ok()) {
+            let contents = match fs::read_to_string(&toolchain_file) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Could not read {}: {e}", toolchain_file.display());
+                    continue;
+                }
+            };
+            match toolchain_file_version(&contents) {
+                Some(version) => {
+                    debug!("{} asks for Rust {version}", toolchain_file.display());
+                    versions.insert(version);
+                }
+                None => debug!("{} doesn't pin a Rust version", toolchain_file.display()),
+            }
+        }
+    }
+    versions.into_iter().collect()
+}
 
-                pb.inc(1);
+/// Get the Rust version a rust-toolchain.toml (or legacy rust-toolchain) file asks for, if it
+/// names a specific version or dated nightly.
+fn toolchain_file_version(contents: &str) -> Option<String> {
+    let channel = match contents.parse::<toml_edit::easy::Value>() {
+        Ok(value) => value
+            .get("toolchain")?
+            .get("channel")?
+            .as_str()?
+            .to_string(),
+        // The legacy format is just the name of the toolchain.
+        Err(_) => contents.trim().to_string(),
+    };
 
-                out
-            })
-        })
-        .buffer_unordered(threads)
-        .collect::<Vec<_>>()
-        .await;
+    // Drop any host from the end, e.g. "nightly-2023-06-01-x86_64-unknown-linux-gnu".
+    let parts: Vec<&str> = channel.split('-').collect();
+    let is_date = |parts: &[&str]| {
+        parts.len() == 3
+            && parts
+                .iter()
+                .zip([4, 2, 2])
+                .all(|(part, len)| part.len() == len && part.chars().all(|c| c.is_ascii_digit()))
+    };
+    match parts.as_slice() {
+        ["nightly", rest @ ..] if rest.len() >= 3 && is_date(&rest[..3]) => {
+            Some(parts[..4].join("-"))
+        }
+        [version, ..]
+            if version.chars().next()?.is_ascii_digit()
+                && version.chars().all(|c| c.is_ascii_digit() || c == '.') =>
+        {
+            Some(version.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Synchronize a rustup channel (stable, beta, or nightly).
+#[allow(clippy::too_many_arguments)]
+pub async fn sync_rustup_channel(
+    client: &DownloadClient,
+    path: &Path,
+    sources: &[String],
+    threads: usize,
+    prefix: String,
+    channel: &str,
+    retries: usize,
+    user_agent: &HeaderValue,
+    download_dev: bool,
+    download_gz: bool,
+    download_xz: bool,
+    platforms: &Platforms,
+    shared: &Arc<SharedDownloads>,
+    shard: Option<Shard>,
+    fail_threshold: FailThreshold,
+) -> Result<(), SyncError> {
+    // Every shard downloads the channel file, so they each need their own partial file.
+    let part_suffix = match shard {
+        Some(shard) => format!(".shard-{}.part", shard.index),
+        None => ".part".to_string(),
+    };
+    let FetchedChannel {
+        manifest,
+        date,
+        files,
+        extra_files,
+    } = fetch_channel(
+        client,
+        path,
+        sources,
+        channel,
+        &part_suffix,
+        retries,
+        user_agent,
+        download_dev,
+        download_gz,
+        download_xz,
+        platforms,
+    )
+    .await?;
+
+    // In a sharded sync, only this shard's part of the files is downloaded.
+    let shard_files: Vec<_> = files
+        .iter()
+        .filter(|(file, _)| shard.is_none_or(|shard| shard.contains(file.as_str())))
+        .collect();
 
-    for res in tasks {
-        // Unwrap the join result.
-        let res = res.unwrap();
+    let new_files = shard_files
+        .iter()
+        .filter(|(file, hash)| !shared.is_synced(file, hash))
+        .filter(|(file, _)| !file.on_disk(path).exists())
+        .count();
+    crate::disk::check_free_space(&path.join("dist"), new_files, DEFAULT_RELEASE_FILE_SIZE)?;
+
+    let total = shard_files.len();
+    let pb = progress_bar(total, prefix);
+
+    // Channels often share artifacts (e.g. a pinned version that is also the latest stable),
+    // so anything already synced by an earlier channel in this run doesn't need checking again.
+    let (already_synced, to_sync): (Vec<_>, Vec<_>) = shard_files
+        .into_iter()
+        .partition(|(file, hash)| shared.is_synced(file, hash));
+    pb.inc(already_synced.len() as u64);
+    let mut tasks: Vec<DownloadTask> = to_sync
+        .into_iter()
+        .map(|(file, hash)| release_file_task(sources, file, hash))
+        .collect();
+
+    // Small files first, so most of the release is usable while the big ones are still going.
+    if client.small_files_first() {
+        tasks.sort_by_key(|task| task.size);
+    }
+
+    let mut errors_occurred = 0usize;
+
+    let results = execute_downloads(
+        client, path, tasks, threads, retries, user_agent, &pb, shared,
+    )
+    .await;
+
+    for res in results {
+        // A panicking download is counted as a failure, rather than taking the sync down.
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                errors_occurred += 1;
+                eprintln!("Download task failed: {e}");
+                pb.inc(1);
+                continue;
+            }
+        };
 
         if let Err(e) = res {
             match e {
                 DownloadError::NotFound { .. } => {}
+                DownloadError::Interrupted => errors_occurred += 1,
                 _ => {
                     errors_occurred += 1;
                     eprintln!("Download failed: {e:?}");
@@ -674,47 +1263,74 @@ pub async fn sync_rustup_channel(
         }
     }
 
-    if errors_occurred == 0 {
-        // Write channel history file
-        add_to_channel_history(path, channel, &date, &files, &extra_files)?;
-        Ok(())
-    } else {
-        Err(SyncError::FailedDownloads {
+    // A stopped sync leaves many files undownloaded, which no threshold is meant for.
+    if errors_occurred > 0
+        && (crate::shutdown::requested() || !fail_threshold.allows(errors_occurred, total))
+    {
+        return Err(SyncError::FailedDownloads {
             count: errors_occurred,
-        })
+        });
+    }
+    if errors_occurred > 0 {
+        eprintln!(
+            "{errors_occurred} of {total} downloads failed, which is within fail_threshold, so \
+             the release is published without them. The next sync will try them again."
+        );
     }
+
+    // Publish the manifest and write channel history file, unless other shards may still
+    // be downloading
+    if shard.is_none() {
+        manifest.publish()?;
+        add_to_channel_history(path, channel, &date, &files, &extra_files)?;
+    }
+    Ok(())
 }
 
-/// Synchronize rustup.
+/// Synchronize rustup. Returns whether every release was synced, or was published with no more
+/// failed downloads than fail_threshold allows.
 pub async fn sync(
+    client: &DownloadClient,
     path: &Path,
     mirror: &ConfigMirror,
     rustup: &ConfigRustup,
     user_agent: &HeaderValue,
-) -> Result<(), MirrorError> {
-    let platforms = get_platforms(rustup).await?;
+    shard: Option<Shard>,
+) -> Result<bool, MirrorError> {
+    let platforms = get_platforms(client, rustup, user_agent).await?;
+    let fail_threshold = mirror.fail_threshold.unwrap_or_default();
     // Default to not downloading rustc-dev
     let download_dev = rustup.download_dev.unwrap_or(false);
 
     let download_gz = rustup.download_gz.unwrap_or(false);
     let download_xz = rustup.download_xz.unwrap_or(true);
 
-    let num_pinned_versions = rustup.pinned_rust_versions.as_ref().map_or(0, |v| v.len());
+    let (pinned_versions, toolchain_versions) = pinned_versions(rustup);
+
+    let num_pinned_versions = pinned_versions.len() + toolchain_versions.len();
     let num_steps = 1 + // sync rustup-init
                     1 + 1 + 1 + // sync latest stable, beta, nightly
                     num_pinned_versions + // sync pinned rust versions
                     1; // clean old files
     let mut step = 0;
 
-    eprintln!("{}", style("Syncing Rustup repositories...").bold());
+    step_message(style("Syncing Rustup repositories...").bold());
 
     // Mirror rustup-init
+    let mut init_failed = false;
     step += 1;
     let prefix = padded_prefix_message(step, num_steps, "Syncing rustup-init files");
-    if let Err(e) = sync_rustup_init(
+    if shard.is_some() {
+        // There are only a few of these, so leave them to the final sync.
+        step_message(format!(
+            "{} Skipping rustup-init files in a sharded sync.",
+            current_step_prefix(step, num_steps)
+        ));
+    } else if let Err(e) = sync_rustup_init(
+        client,
         path,
         rustup.download_threads,
-        &rustup.source,
+        rustup.source.all(),
         prefix,
         mirror.retries,
         user_agent,
@@ -722,19 +1338,23 @@ pub async fn sync(
     )
     .await
     {
+        init_failed = true;
         eprintln!("Downloading rustup init files failed: {e:?}");
         eprintln!("You will need to sync again to finish this download.");
     }
 
     let mut failures = false;
 
+    let shared = SharedDownloads::new(rustup.download_threads);
+
     // Mirror stable
     step += 1;
     if rustup.keep_latest_stables != Some(0) {
         let prefix = padded_prefix_message(step, num_steps, "Syncing latest stable");
         if let Err(e) = sync_rustup_channel(
+            client,
             path,
-            &rustup.source,
+            rustup.source.all(),
             rustup.download_threads,
             prefix,
             "stable",
@@ -744,6 +1364,9 @@ pub async fn sync(
             download_gz,
             download_xz,
             &platforms,
+            &shared,
+            shard,
+            fail_threshold,
         )
         .await
         {
@@ -752,10 +1375,10 @@ pub async fn sync(
             eprintln!("You will need to sync again to finish this download.");
         }
     } else {
-        eprintln!(
+        step_message(format!(
             "{} Skipping syncing stable.",
             current_step_prefix(step, num_steps)
-        );
+        ));
     }
 
     // Mirror beta
@@ -763,8 +1386,9 @@ pub async fn sync(
     if rustup.keep_latest_betas != Some(0) {
         let prefix = padded_prefix_message(step, num_steps, "Syncing latest beta");
         if let Err(e) = sync_rustup_channel(
+            client,
             path,
-            &rustup.source,
+            rustup.source.all(),
             rustup.download_threads,
             prefix,
             "beta",
@@ -774,6 +1398,9 @@ pub async fn sync(
             download_gz,
             download_xz,
             &platforms,
+            &shared,
+            shard,
+            fail_threshold,
         )
         .await
         {
@@ -782,10 +1409,10 @@ pub async fn sync(
             eprintln!("You will need to sync again to finish this download.");
         }
     } else {
-        eprintln!(
+        step_message(format!(
             "{} Skipping syncing beta.",
             current_step_prefix(step, num_steps)
-        );
+        ));
     }
 
     // Mirror nightly
@@ -793,8 +1420,9 @@ pub async fn sync(
     if rustup.keep_latest_nightlies != Some(0) {
         let prefix = padded_prefix_message(step, num_steps, "Syncing latest nightly");
         if let Err(e) = sync_rustup_channel(
+            client,
             path,
-            &rustup.source,
+            rustup.source.all(),
             rustup.download_threads,
             prefix,
             "nightly",
@@ -804,6 +1432,9 @@ pub async fn sync(
             download_gz,
             download_xz,
             &platforms,
+            &shared,
+            shard,
+            fail_threshold,
         )
         .await
         {
@@ -812,35 +1443,64 @@ pub async fn sync(
             eprintln!("You will need to sync again to finish this download.");
         }
     } else {
-        eprintln!(
+        step_message(format!(
             "{} Skipping syncing nightly.",
             current_step_prefix(step, num_steps)
-        );
+        ));
     }
 
-    // Mirror pinned rust versions
-    if let Some(pinned_versions) = &rustup.pinned_rust_versions {
-        for version in pinned_versions {
-            step += 1;
+    // Mirror pinned rust versions, then those asked for by toolchain files. Several can sync at
+    // once, sharing the download_threads budget, with their errors reported once all are done.
+    let parallel = rustup.parallel_pinned_syncs.unwrap_or(1).max(1);
+    let pinned = pinned_versions.iter().map(|version| (version, false));
+    let from_toolchain_files = toolchain_versions.iter().map(|version| (version, true));
+    let (shared, platforms) = (&shared, &platforms);
+    let group = (parallel > 1).then(crate::progress_bar::group);
+    let results = futures::stream::iter(pinned.chain(from_toolchain_files).enumerate())
+        .map(|(i, (version, from_toolchain_file))| {
+            let step = step + 1 + i;
             let prefix =
                 padded_prefix_message(step, num_steps, &format!("Syncing pinned rust {version}"));
-            if let Err(e) = sync_rustup_channel(
-                path,
-                &rustup.source,
-                rustup.download_threads,
-                prefix,
-                version,
-                mirror.retries,
-                user_agent,
-                download_dev,
-                download_gz,
-                download_xz,
-                &platforms,
-            )
-            .await
-            {
-                failures = true;
-                if let SyncError::Download(DownloadError::NotFound { .. }) = e {
+            async move {
+                let res = sync_rustup_channel(
+                    client,
+                    path,
+                    rustup.source.all(),
+                    rustup.download_threads,
+                    prefix,
+                    version,
+                    mirror.retries,
+                    user_agent,
+                    download_dev,
+                    download_gz,
+                    download_xz,
+                    platforms,
+                    shared,
+                    shard,
+                    fail_threshold,
+                )
+                .await;
+                (step, version, from_toolchain_file, res)
+            }
+        })
+        .buffered(parallel)
+        .collect::<Vec<_>>()
+        .await;
+    drop(group);
+    step += num_pinned_versions;
+
+    for (step, version, from_toolchain_file, res) in results {
+        if let Err(e) = res {
+            match e {
+                // A toolchain file with a typo shouldn't stop the whole mirror from syncing.
+                SyncError::Download(DownloadError::NotFound { .. }) if from_toolchain_file => {
+                    eprintln!(
+                        "{} Rust version {} from a toolchain file could not be found.",
+                        current_step_prefix(step, num_steps),
+                        version
+                    );
+                }
+                SyncError::Download(DownloadError::NotFound { .. }) => {
                     eprintln!(
                         "{} Pinned rust version {} could not be found.",
                         current_step_prefix(step, num_steps),
@@ -849,7 +1509,9 @@ pub async fn sync(
                     return Err(MirrorError::Config(format!(
                         "Pinned rust version {version} could not be found"
                     )));
-                } else {
+                }
+                _ => {
+                    failures = true;
                     eprintln!("Downloading pinned rust {version} failed: {e:?}");
                     eprintln!("You will need to sync again to finish this download.");
                 }
@@ -859,19 +1521,24 @@ pub async fn sync(
 
     // If all succeeds, clean files
     step += 1;
-    if rustup.keep_latest_stables.is_none()
+    if shard.is_some() {
+        step_message(format!(
+            "{} Skipping cleaning files in a sharded sync.",
+            current_step_prefix(step, num_steps)
+        ));
+    } else if rustup.keep_latest_stables.is_none()
         && rustup.keep_latest_betas.is_none()
         && rustup.keep_latest_nightlies.is_none()
     {
-        eprintln!(
+        step_message(format!(
             "{} Skipping cleaning files.",
             current_step_prefix(step, num_steps)
-        );
+        ));
     } else if failures {
-        eprintln!(
+        step_message(format!(
             "{} Skipping cleaning files due to download failures.",
             current_step_prefix(step, num_steps)
-        );
+        ));
     } else {
         let prefix = padded_prefix_message(step, num_steps, "Cleaning old files");
         if let Err(e) = clean_old_files(
@@ -879,7 +1546,8 @@ pub async fn sync(
             rustup.keep_latest_stables,
             rustup.keep_latest_betas,
             rustup.keep_latest_nightlies,
-            rustup.pinned_rust_versions.as_ref(),
+            Some(&[pinned_versions, toolchain_versions].concat()),
+            rustup.keep_served_days.unwrap_or(DEFAULT_KEEP_SERVED_DAYS),
             prefix,
         ) {
             eprintln!("Cleaning old files failed: {e:?}");
@@ -887,7 +1555,393 @@ pub async fn sync(
         }
     }
 
-    eprintln!("{}", style("Syncing Rustup repositories complete!").bold());
+    step_message(style("Syncing Rustup repositories complete!").bold());
 
-    Ok(())
+    Ok(!failures && !init_failed)
+}
+
+/// Work out what a rustup sync would download and remove, without changing the mirror.
+///
+/// The release file and channel manifests are downloaded next to the ones being served, to
+/// see what's in them, and removed again afterwards.
+pub async fn plan(
+    client: &DownloadClient,
+    path: &Path,
+    mirror: &ConfigMirror,
+    rustup: &ConfigRustup,
+    user_agent: &HeaderValue,
+) -> Result<Vec<Task>, MirrorError> {
+    let plan_error = |what: &str, e: SyncError| MirrorError::Plan(format!("{what}: {e}"));
+    let platforms = get_platforms(client, rustup, user_agent).await?;
+    let sources = rustup.source.all();
+    let mut tasks = Vec::new();
+
+    // rustup-init
+    let release_url = source_urls(sources, "rustup/release-stable.toml");
+    let release_path = path.join("rustup/release-stable.toml");
+    let release = PendingManifest::new(&release_path, PLAN_PART_SUFFIX, &release_url);
+    download_if_modified(
+        client,
+        &release_url,
+        &release.part_path,
+        &release_path,
+        None,
+        mirror.retries,
+        user_agent,
+    )
+    .await?;
+    let rustup_version = get_rustup_version(&release.part_path)
+        .map_err(|e| plan_error("Reading the rustup release file failed", e))?;
+    let init_files = platforms
+        .unix
+        .iter()
+        .map(|platform| format!("{platform}/rustup-init"))
+        .chain(
+            platforms
+                .windows
+                .iter()
+                .map(|platform| format!("{platform}/rustup-init.exe")),
+        );
+    for init_file in init_files {
+        let file = MirrorPath::parse(&format!("rustup/archive/{rustup_version}/{init_file}"))
+            .map_err(|e| plan_error("Reading the rustup release file failed", e.into()))?;
+        if !file.on_disk(path).exists() {
+            tasks.push(Task::Download(DownloadTask {
+                urls: source_urls(sources, &format!("rustup/dist/{init_file}")),
+                sha256: None,
+                size: typical_size(file.as_str()),
+                file,
+            }));
+        }
+    }
+
+    // Channels, then pinned versions, then those asked for by toolchain files.
+    let channels = [
+        ("stable", rustup.keep_latest_stables),
+        ("beta", rustup.keep_latest_betas),
+        ("nightly", rustup.keep_latest_nightlies),
+    ]
+    .into_iter()
+    .filter(|(_, keep)| *keep != Some(0))
+    .map(|(channel, _)| (channel.to_string(), false));
+    let (pinned_versions, toolchain_versions) = pinned_versions(rustup);
+    let pinned = pinned_versions
+        .iter()
+        .map(|version| (version.clone(), false));
+    let from_toolchain_files = toolchain_versions
+        .iter()
+        .map(|version| (version.clone(), true));
+
+    let mut planned = PlannedReleases::new();
+    for (channel, from_toolchain_file) in channels.chain(pinned).chain(from_toolchain_files) {
+        let fetched = match fetch_channel(
+            client,
+            path,
+            sources,
+            &channel,
+            PLAN_PART_SUFFIX,
+            mirror.retries,
+            user_agent,
+            rustup.download_dev.unwrap_or(false),
+            rustup.download_gz.unwrap_or(false),
+            rustup.download_xz.unwrap_or(true),
+            &platforms,
+        )
+        .await
+        {
+            Ok(fetched) => fetched,
+            Err(SyncError::Download(DownloadError::NotFound { .. })) if from_toolchain_file => {
+                eprintln!("Rust version {channel} from a toolchain file could not be found.");
+                continue;
+            }
+            Err(e) => return Err(plan_error(&format!("Fetching {channel} failed"), e)),
+        };
+        for (file, hash) in &fetched.files {
+            if !file.on_disk(path).exists() {
+                tasks.push(Task::Download(release_file_task(sources, file, hash)));
+            }
+        }
+        let files = fetched.files.iter().map(|(file, _)| file.to_string());
+        let files = files.chain(fetched.extra_files.iter().cloned()).collect();
+        planned.insert(channel, (fetched.date, files));
+    }
+
+    // Old files
+    let cleans = rustup.keep_latest_stables.is_some()
+        || rustup.keep_latest_betas.is_some()
+        || rustup.keep_latest_nightlies.is_some();
+    if cleans && path.join("dist").is_dir() {
+        let old = old_files(
+            path,
+            rustup.keep_latest_stables,
+            rustup.keep_latest_betas,
+            rustup.keep_latest_nightlies,
+            Some(&[pinned_versions, toolchain_versions].concat()),
+            rustup.keep_served_days.unwrap_or(DEFAULT_KEEP_SERVED_DAYS),
+            &planned,
+        )
+        .map_err(|e| plan_error("Finding old files failed", e))?;
+        tasks.extend(
+            old.into_iter()
+                .map(|file| Task::Delete(DeleteTask { file })),
+        );
+    }
+
+    Ok(tasks)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod fail_threshold {
+        use serde::Deserialize;
+
+        use crate::rustup::FailThreshold;
+
+        #[derive(Deserialize)]
+        struct Config {
+            fail_threshold: FailThreshold,
+        }
+
+        fn parse(s: &str) -> Result<FailThreshold, toml_edit::de::Error> {
+            toml_edit::easy::from_str::<Config>(&format!("fail_threshold = {s}"))
+                .map(|config| config.fail_threshold)
+        }
+
+        #[test]
+        fn files_or_percent() {
+            assert_eq!(parse("50").unwrap(), FailThreshold::Files(50));
+            assert_eq!(parse("\"1%\"").unwrap(), FailThreshold::Percent(1.0));
+            assert_eq!(parse("\"0.5%\"").unwrap(), FailThreshold::Percent(0.5));
+            assert!(parse("\"1\"").is_err());
+            assert!(parse("\"150%\"").is_err());
+            assert!(parse("-1").is_err());
+        }
+
+        #[test]
+        fn allows() {
+            assert!(FailThreshold::default().allows(0, 100));
+            assert!(!FailThreshold::default().allows(1, 100));
+            assert!(FailThreshold::Files(2).allows(2, 10));
+            assert!(!FailThreshold::Files(2).allows(3, 1000));
+            assert!(FailThreshold::Percent(1.0).allows(10, 1000));
+            assert!(!FailThreshold::Percent(1.0).allows(11, 1000));
+            assert!(!FailThreshold::Percent(1.0).allows(1, 50));
+        }
+    }
+
+    mod pinned_versions {
+        use crate::mirror::ConfigRustup;
+        use crate::rustup::pinned_versions;
+
+        #[test]
+        fn deduped() {
+            let rustup: ConfigRustup = toml_edit::easy::from_str(
+                "sync = true\n\
+                 download_threads = 1\n\
+                 source = \"https://static.rust-lang.org\"\n\
+                 pinned_rust_versions = [\"1.70.0\", \"1.65.0\", \"1.70.0\"]\n",
+            )
+            .unwrap();
+            let (pinned, from_toolchain_files) = pinned_versions(&rustup);
+            assert_eq!(pinned, ["1.70.0", "1.65.0"]);
+            assert!(from_toolchain_files.is_empty());
+        }
+    }
+
+    mod toolchain_file_version {
+        use crate::rustup::toolchain_file_version;
+
+        #[test]
+        fn toml() {
+            let contents = "[toolchain]\nchannel = \"1.70.0\"\ncomponents = [\"clippy\"]\n";
+            assert_eq!(toolchain_file_version(contents).unwrap(), "1.70.0");
+            let contents = "[toolchain]\nchannel = \"nightly-2023-06-01\"\n";
+            assert_eq!(
+                toolchain_file_version(contents).unwrap(),
+                "nightly-2023-06-01"
+            );
+        }
+
+        #[test]
+        fn legacy() {
+            assert_eq!(toolchain_file_version("1.65\n").unwrap(), "1.65");
+            assert_eq!(
+                toolchain_file_version("nightly-2023-06-01-x86_64-unknown-linux-gnu\n").unwrap(),
+                "nightly-2023-06-01"
+            );
+        }
+
+        #[test]
+        fn not_pinned() {
+            assert!(toolchain_file_version("stable").is_none());
+            assert!(toolchain_file_version("nightly\n").is_none());
+            assert!(toolchain_file_version("[toolchain]\nchannel = \"beta\"\n").is_none());
+            assert!(toolchain_file_version("[toolchain]\npath = \"/opt/rust\"\n").is_none());
+            assert!(toolchain_file_version("1.70.0/../../etc").is_none());
+        }
+    }
+
+    mod download_list {
+        use crate::rustup::{rustup_download_list, Platforms};
+
+        const CHANNEL: &str = r#"
+manifest-version = "2"
+date = "2023-01-01"
+
+[pkg.cargo]
+version = "1.66.0"
+[pkg.cargo.target.x86_64-unknown-linux-gnu]
+available = true
+url = "https://example.com/dist/2023-01-01/cargo-x86_64-unknown-linux-gnu.tar.gz"
+hash = "a"
+xz_url = "https://example.com/dist/2023-01-01/cargo-x86_64-unknown-linux-gnu.tar.xz"
+xz_hash = "b"
+[pkg.cargo.target.wasm32-unknown-unknown]
+available = false
+
+[pkg.rust-std]
+version = "1.66.0"
+[pkg.rust-std.target.x86_64-unknown-linux-gnu]
+available = true
+url = "https://example.com/dist/2023-01-01/rust-std-x86_64-unknown-linux-gnu.tar.gz"
+hash = "c"
+xz_url = "https://example.com/dist/2023-01-01/rust-std-x86_64-unknown-linux-gnu.tar.xz"
+xz_hash = "d"
+[pkg.rust-std.target.wasm32-unknown-unknown]
+available = true
+url = "https://example.com/dist/2023-01-01/rust-std-wasm32-unknown-unknown.tar.gz"
+hash = "e"
+xz_url = "https://example.com/dist/2023-01-01/rust-std-wasm32-unknown-unknown.tar.xz"
+xz_hash = "f"
+[pkg.rust-std.target.aarch64-unknown-linux-gnu]
+available = true
+url = "https://example.com/dist/2023-01-01/rust-std-aarch64-unknown-linux-gnu.tar.gz"
+hash = "g"
+xz_url = "https://example.com/dist/2023-01-01/rust-std-aarch64-unknown-linux-gnu.tar.xz"
+xz_hash = "h"
+"#;
+
+        #[test]
+        fn target_platforms_only_get_rust_std() {
+            let file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(file.path(), CHANNEL).unwrap();
+
+            let platforms = Platforms {
+                unix: vec!["x86_64-unknown-linux-gnu".to_string()],
+                windows: vec![],
+                targets: vec!["wasm32-unknown-unknown".to_string()],
+            };
+            let (date, files) =
+                rustup_download_list(file.path(), false, false, true, &platforms).unwrap();
+
+            let mut urls: Vec<String> = files
+                .into_iter()
+                .map(|(file, _)| file.to_string())
+                .collect();
+            urls.sort();
+            assert_eq!(date, "2023-01-01");
+            assert_eq!(
+                urls,
+                vec![
+                    "dist/2023-01-01/cargo-x86_64-unknown-linux-gnu.tar.xz",
+                    "dist/2023-01-01/rust-std-wasm32-unknown-unknown.tar.xz",
+                    "dist/2023-01-01/rust-std-x86_64-unknown-linux-gnu.tar.xz",
+                ]
+            );
+        }
+    }
+
+    mod latest_dates {
+        use std::collections::HashMap;
+
+        use crate::rustup::{latest_dates_from_channel_history, ChannelHistoryFile};
+
+        #[test]
+        fn by_day() {
+            let history = ChannelHistoryFile {
+                versions: HashMap::from(
+                    ["2023-10-01", "2023-9-15", "2023-09-30", "unknown"]
+                        .map(|date| (date.to_string(), Vec::new())),
+                ),
+            };
+            assert_eq!(
+                latest_dates_from_channel_history(&history, 3),
+                ["2023-10-01", "2023-09-30", "2023-9-15"]
+            );
+        }
+    }
+
+    mod old_files {
+        use std::collections::BTreeMap;
+        use std::fs;
+        use std::path::PathBuf;
+
+        use crate::rustup::{old_files, PlannedReleases};
+        use crate::served::SERVED_FILE;
+        use crate::timestamp::Timestamp;
+
+        #[test]
+        fn served_releases_kept_whole() {
+            let dir = tempfile::tempdir().unwrap();
+            for file in [
+                "dist/2023-01-01/cargo.tar.xz",
+                "dist/2023-01-01/rustc.tar.xz",
+                "dist/2024-01-01/cargo.tar.xz",
+                "dist/2024-01-01/rustc.tar.xz",
+            ] {
+                let path = dir.path().join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, file).unwrap();
+            }
+            let served =
+                BTreeMap::from([("dist/2024-01-01/cargo.tar.xz".to_string(), Timestamp::now())]);
+            fs::write(
+                dir.path().join(SERVED_FILE),
+                serde_json::to_vec(&served).unwrap(),
+            )
+            .unwrap();
+
+            let mut old = old_files(
+                dir.path(),
+                None,
+                None,
+                None,
+                None,
+                30,
+                &PlannedReleases::new(),
+            )
+            .unwrap();
+            old.sort();
+            assert_eq!(
+                old,
+                [
+                    PathBuf::from("dist/2023-01-01/cargo.tar.xz"),
+                    PathBuf::from("dist/2023-01-01/rustc.tar.xz"),
+                ]
+            );
+        }
+    }
+
+    mod typical_size {
+        use crate::rustup::typical_size;
+
+        #[test]
+        fn packages() {
+            let size = |file: &str| typical_size(&format!("dist/2023-06-01/{file}"));
+            assert!(
+                size("clippy-1.70.0-x86_64-unknown-linux-gnu.tar.xz")
+                    < size("cargo-1.70.0-x86_64-unknown-linux-gnu.tar.xz")
+            );
+            assert!(
+                size("rustc-1.70.0-x86_64-unknown-linux-gnu.tar.xz")
+                    < size("rustc-dev-1.70.0-x86_64-unknown-linux-gnu.tar.xz")
+            );
+            assert!(
+                size("rust-std-1.70.0-x86_64-unknown-linux-gnu.tar.xz")
+                    < size("rust-1.70.0-x86_64-unknown-linux-gnu.tar.xz")
+            );
+            assert!(size("rust-src-1.70.0.tar.xz") < size("rust-src-1.70.0.tar.gz"));
+        }
+    }
 }
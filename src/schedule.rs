@@ -0,0 +1,112 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::state;
+use crate::timestamp::{self, Timestamp};
+
+/// Name of the file tracking scheduled maintenance, in the state directory.
+pub const SCHEDULE_FILE: &str = ".panamax-maintenance-schedule";
+
+/// When scheduled maintenance last ran on a mirror.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Schedule {
+    /// Syncs since maintenance last ran.
+    syncs_since: u32,
+    /// The UNIX epoch if maintenance has never run.
+    last_run: Timestamp,
+}
+
+impl Schedule {
+    /// Load the schedule of a mirror. A missing or unreadable file means maintenance has
+    /// never run.
+    pub fn load(mirror_path: &Path) -> Self {
+        fs::read(state::path(mirror_path, SCHEDULE_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, mirror_path: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec(self).expect("schedules should always serialize");
+        fs::write(state::path(mirror_path, SCHEDULE_FILE), data)
+    }
+
+    /// When maintenance last ran, if it has.
+    pub fn last_run(&self) -> Option<Timestamp> {
+        (self.last_run != Timestamp(0)).then_some(self.last_run)
+    }
+
+    /// Count a sync finishing at `now`, and return whether maintenance is due, either because
+    /// `every_syncs` syncs have run or `every_days` days have passed since it last ran.
+    ///
+    /// Days are counted by the calendar in the display time zone, so daily maintenance runs
+    /// after the first sync of each day, rather than drifting later with each run.
+    pub fn record_sync(
+        &mut self,
+        every_syncs: Option<u32>,
+        every_days: Option<u64>,
+        now: Timestamp,
+    ) -> bool {
+        self.syncs_since += 1;
+        let syncs_due = every_syncs.is_some_and(|n| self.syncs_since >= n);
+        let days_due = every_days.is_some_and(|days| {
+            timestamp::local_day(now).saturating_sub(timestamp::local_day(self.last_run)) >= days
+        });
+        syncs_due || days_due
+    }
+
+    /// Record that maintenance ran at `now`.
+    pub fn ran(&mut self, now: Timestamp) {
+        self.syncs_since = 0;
+        self.last_run = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod record_sync {
+        use crate::schedule::Schedule;
+        use crate::timestamp::{Timestamp, DAY};
+
+        #[test]
+        fn every_n_syncs() {
+            let mut schedule = Schedule::default();
+            assert!(!schedule.record_sync(Some(3), None, Timestamp(0)));
+            assert!(!schedule.record_sync(Some(3), None, Timestamp(0)));
+            assert!(schedule.record_sync(Some(3), None, Timestamp(0)));
+            schedule.ran(Timestamp(0));
+            assert!(!schedule.record_sync(Some(3), None, Timestamp(0)));
+        }
+
+        #[test]
+        fn every_n_days() {
+            let mut schedule = Schedule::default();
+            // Maintenance that has never run is due right away.
+            assert!(schedule.record_sync(None, Some(7), Timestamp(100 * DAY)));
+            schedule.ran(Timestamp(100 * DAY));
+            assert!(!schedule.record_sync(None, Some(7), Timestamp(106 * DAY)));
+            assert!(schedule.record_sync(None, Some(7), Timestamp(107 * DAY)));
+        }
+
+        #[test]
+        fn calendar_days() {
+            let mut schedule = Schedule::default();
+            // Running at 23:00 leaves an hour of that day, after which maintenance is due again.
+            schedule.ran(Timestamp(100 * DAY + 23 * 60 * 60));
+            assert!(!schedule.record_sync(None, Some(1), Timestamp(100 * DAY + 23 * 60 * 60 + 5)));
+            assert!(schedule.record_sync(None, Some(1), Timestamp(101 * DAY + 60 * 60)));
+        }
+
+        #[test]
+        fn not_scheduled() {
+            let mut schedule = Schedule::default();
+            for _ in 0..100 {
+                assert!(!schedule.record_sync(None, None, Timestamp(100 * DAY)));
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::checksums;
+use crate::download::file_hash;
+use crate::hashes::parse_expected;
+
+/// How long to wait before checking again, when there's nothing to scrub yet.
+const EMPTY_RETRY: Duration = Duration::from_secs(60 * 60);
+
+/// Continuously verify mirrored files against the checksum database, at `files_per_hour`.
+///
+/// This runs alongside serve, so silent disk corruption is found before a client downloads
+/// a broken file. Files are checked one at a time, spaced out evenly, to keep the extra disk
+/// load low. Problems are only logged, for the operator to repair.
+pub async fn run(mirror_path: PathBuf, files_per_hour: u32) {
+    let interval = Duration::from_secs(60 * 60) / files_per_hour.max(1);
+
+    loop {
+        // Reload on every pass, to pick up files added by syncs since the last one.
+        let entries = match checksums::load(&mirror_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Scrubber could not read the checksum database: {e}");
+                Vec::new()
+            }
+        };
+        if entries.is_empty() {
+            tokio::time::sleep(EMPTY_RETRY).await;
+            continue;
+        }
+
+        let mut corrupt = 0usize;
+        for (path, expected) in &entries {
+            tokio::time::sleep(interval).await;
+
+            let (algorithm, digest) = parse_expected(expected);
+            match file_hash(&mirror_path.join(path), algorithm).await {
+                Ok(actual) if actual == digest => {}
+                Ok(actual) => {
+                    corrupt += 1;
+                    error!("Scrubber found a corrupt file {path}: expected hash '{expected}', got '{actual}'");
+                }
+                // Old files are expected to be cleaned up by sync.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Scrubber could not read {path}: {e}"),
+            }
+        }
+
+        info!(
+            "Scrubber checked {} files, {corrupt} corrupt",
+            entries.len()
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+
+use crate::download::file_hash;
+use crate::hashes::parse_expected;
+use crate::mirror::load_mirror_toml;
+use crate::rng::Xorshift;
+use crate::rustup::Channel;
+use crate::url_path::MirrorPath;
+
+/// How many mirrored rustup files to hash during the spot check.
+const SPOT_CHECK_FILES: usize = 8;
+
+/// How many manifests of dated and pinned releases to parse, on top of the current ones. A
+/// mirror that keeps every release has thousands, too many to parse on each startup.
+const SPOT_CHECK_MANIFESTS: usize = 8;
+
+/// How strictly to treat problems found when checking the mirror on startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Strictness {
+    /// Don't check the mirror.
+    Off,
+    /// Print any problems found, but serve anyway.
+    Warn,
+    /// Refuse to serve if any problems are found.
+    Strict,
+}
+
+/// Run quick sanity checks on a mirror, returning a description of each problem found.
+///
+/// This is meant to be fast enough to run every time the mirror is served, so only the current
+/// channel manifests and a small random sample of older ones are parsed, and a sample of the
+/// rustup files they list have their hashes checked.
+pub async fn self_check(path: &Path) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut rng = Xorshift::from_time();
+
+    check_index(path, &mut problems);
+    check_overlay(path, &mut problems);
+    let files = check_channel_manifests(path, &mut rng, &mut problems);
+    spot_check_hashes(files, &mut rng, &mut problems).await;
+
+    problems
+}
+
+/// Check that the index repository can be opened, and that config.json matches mirror.toml.
+fn check_index(path: &Path, problems: &mut Vec<String>) {
+    let config = match load_mirror_toml(path) {
+        Ok(c) => c,
+        Err(e) => {
+            problems.push(format!("Could not load mirror.toml: {e}"));
+            return;
+        }
+    };
+    let crates = match config.crates {
+        Some(c) if c.sync => c,
+        _ => return,
+    };
+
+    let repo_path = path.join("crates.io-index");
+    let repo = match Repository::open(&repo_path) {
+        Ok(r) => r,
+        Err(e) => {
+            problems.push(format!("Could not open crates.io-index repository: {e}"));
+            return;
+        }
+    };
+    if let Err(e) = repo
+        .find_reference("refs/heads/master")
+        .and_then(|r| r.peel_to_commit())
+    {
+        problems.push(format!("crates.io-index has no master commit: {e}"));
+    }
+
+    if let Some(base_url) = &crates.base_url {
+        let config_json = fs::read(repo_path.join("config.json"))
+            .map_err(|e| e.to_string())
+            .and_then(|d| {
+                serde_json::from_slice::<serde_json::Value>(&d).map_err(|e| e.to_string())
+            });
+        match config_json {
+            Ok(config_json) => {
+                let dl = config_json["dl"].as_str().unwrap_or_default();
+                if let Some(dl_template) = &crates.dl_template {
+                    if dl != dl_template {
+                        problems.push(format!(
+                            "crates.io-index config.json has dl `{dl}`, which doesn't match dl_template `{dl_template}`"
+                        ));
+                    }
+                } else if !dl.starts_with(base_url.as_str()) {
+                    problems.push(format!(
+                        "crates.io-index config.json has dl `{dl}`, which doesn't match base_url `{base_url}`"
+                    ));
+                }
+            }
+            Err(e) => problems.push(format!("Could not read crates.io-index config.json: {e}")),
+        }
+    }
+}
+
+/// Check that no crate in the overlay registry clashes with a crate on crates.io.
+fn check_overlay(path: &Path, problems: &mut Vec<String>) {
+    match crate::overlay::collisions(path) {
+        Ok(collisions) => {
+            for (overlay_name, crates_io_name) in collisions {
+                problems.push(format!(
+                    "Overlay crate `{overlay_name}` clashes with `{crates_io_name}` on crates.io, which it hides from the overlay registry"
+                ));
+            }
+        }
+        Err(e) => problems.push(format!("Could not check the overlay for clashes: {e}")),
+    }
+}
+
+/// Check that the current channel manifests and a sample of the older ones parse, returning
+/// every mirrored file they reference along with its expected hash.
+fn check_channel_manifests(
+    path: &Path,
+    rng: &mut Xorshift,
+    problems: &mut Vec<String>,
+) -> Vec<(PathBuf, String)> {
+    let dist = glob::Pattern::escape(&path.join("dist").to_string_lossy());
+    let mut manifests: Vec<PathBuf> = glob::glob(&format!("{dist}/channel-rust-*.toml"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect();
+    let mut dated: Vec<PathBuf> = glob::glob(&format!("{dist}/*/channel-rust-*.toml"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect();
+    for _ in 0..SPOT_CHECK_MANIFESTS {
+        if dated.is_empty() {
+            break;
+        }
+        manifests.push(dated.swap_remove(rng.below(dated.len())));
+    }
+
+    let mut files = Vec::new();
+    for manifest in manifests {
+        let channel = fs::read_to_string(&manifest)
+            .map_err(|e| e.to_string())
+            .and_then(|s| toml_edit::easy::from_str::<Channel>(&s).map_err(|e| e.to_string()));
+        let channel = match channel {
+            Ok(c) => c,
+            Err(e) => {
+                problems.push(format!("Could not parse {}: {e}", manifest.display()));
+                continue;
+            }
+        };
+
+        for target in channel
+            .pkg
+            .into_values()
+            .flat_map(|p| p.target.into_values())
+        {
+            if let Some(urls) = target.target_urls {
+                for (url, hash) in [(urls.url, urls.hash), (urls.xz_url, urls.xz_hash)] {
+                    let Ok(mirror_path) = MirrorPath::from_url(&url) else {
+                        continue;
+                    };
+                    let local_path = mirror_path.on_disk(path);
+                    if local_path.exists() {
+                        files.push((local_path, hash));
+                    }
+                }
+            }
+        }
+    }
+
+    files
+}
+
+/// Hash a random sample of files, and compare them to their expected hashes.
+async fn spot_check_hashes(
+    mut files: Vec<(PathBuf, String)>,
+    rng: &mut Xorshift,
+    problems: &mut Vec<String>,
+) {
+    for _ in 0..SPOT_CHECK_FILES {
+        if files.is_empty() {
+            break;
+        }
+        let (file, expected) = files.swap_remove(rng.below(files.len()));
+
+        let (algorithm, digest) = parse_expected(&expected);
+        match file_hash(&file, algorithm).await {
+            Ok(actual) if actual == digest => {}
+            Ok(actual) => problems.push(format!(
+                "{} has hash {actual}, expected {expected}",
+                file.display()
+            )),
+            Err(e) => problems.push(format!("Could not read {}: {e}", file.display())),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use console::style;
+
+use crate::graph::newest_release;
+use crate::mirror::MirrorError;
+
+/// The version of panamax that's running.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Get the newest panamax release in a mirror's own index, if it's newer than the one running.
+///
+/// Panamax is published on crates.io, so a mirror of it has every release, and an offline
+/// mirror can tell when it's out of date without reaching the internet.
+pub fn newer_release(mirror_path: &Path) -> Option<String> {
+    match newest_release(mirror_path, "panamax", VERSION) {
+        Ok(Some((newest, true))) => Some(newest),
+        _ => None,
+    }
+}
+
+/// Print whether the mirror has a newer release of panamax than the one running, and how to
+/// install it.
+pub fn check_update(mirror_path: &Path) -> Result<(), MirrorError> {
+    match newest_release(mirror_path, "panamax", VERSION)? {
+        Some((newest, true)) => {
+            println!(
+                "{}",
+                style(format!(
+                    "Panamax {newest} is available in the mirror, this is {VERSION}."
+                ))
+                .yellow()
+                .bold()
+            );
+            println!("With cargo set up to use the mirror, update with:");
+            println!("  cargo install panamax --version {newest} --locked");
+        }
+        Some((newest, false)) => {
+            println!("Panamax {VERSION} is up to date, the newest in the mirror is {newest}.")
+        }
+        None => println!("The mirror's index has no releases of panamax to check against."),
+    }
+    Ok(())
+}
@@ -0,0 +1,383 @@
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::path::Path;
+use std::process::Output;
+use std::time::Duration;
+
+use console::style;
+use git2::{Repository, RepositoryInitOptions, Signature};
+use sha2::{Digest, Sha256};
+use tokio::process::Command;
+
+use crate::crates::{crate_prefix, get_crate_path};
+use crate::crates_index::dl_url;
+use crate::download::write_sha256_file;
+use crate::mirror::MirrorError;
+
+/// A client to test against the synthetic mirror.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SelftestClient {
+    /// Fetch a crate with cargo, over both the sparse and the git index.
+    Cargo,
+    /// Install a minimal stable toolchain with rustup.
+    Rustup,
+}
+
+/// The only crate in the synthetic mirror.
+const CRATE_NAME: &str = "panamax-selftest";
+const CRATE_VERSION: &str = "0.1.0";
+
+/// The synthetic Rust release, as the latest stable.
+const RELEASE_DATE: &str = "2024-01-01";
+const RUST_VERSION: &str = "1.0.0";
+
+/// How long to wait for the server to start taking connections.
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lines of a failed client's output to show.
+const OUTPUT_LINES: usize = 20;
+
+/// Serve a tiny synthetic mirror on a random local port, and check that real cargo and rustup
+/// clients can use it, e.g. after upgrading panamax or the clients.
+///
+/// Everything is done in a temporary directory, including the clients' homes, so the user's
+/// own caches and toolchains are left alone. It's removed afterwards, unless a check failed.
+pub async fn selftest(client: Option<SelftestClient>) -> Result<(), MirrorError> {
+    // A new directory with a name no one else can have picked beforehand, as it's in a shared
+    // temporary directory.
+    let temp_dir = tempfile::Builder::new()
+        .prefix("panamax-selftest-")
+        .tempdir()?;
+    let dir = temp_dir.path().to_path_buf();
+    let mirror_path = dir.join("mirror");
+    fs::create_dir_all(&mirror_path)?;
+
+    // The port is only free until it's bound again by the server, but nothing else should
+    // take it in between.
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port();
+    let socket_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+
+    let (checks, failed) = tokio::select! {
+        res = crate::serve::serve(mirror_path.clone(), socket_addr, None, None, None, None, None, false, None) => {
+            res?;
+            return Err(MirrorError::SelfTest("the test server stopped".to_string()));
+        }
+        res = run_checks(client, &dir, &mirror_path, socket_addr) => res?,
+    };
+
+    if failed > 0 {
+        eprintln!("The test files were left in {}.", temp_dir.keep().display());
+        return Err(MirrorError::SelfTest(format!(
+            "{failed} of {checks} client checks failed"
+        )));
+    }
+    temp_dir.close()?;
+    Ok(())
+}
+
+/// Run the checks of `client`, or of every client, against the server. Returns how many were
+/// run, and how many of them failed.
+async fn run_checks(
+    client: Option<SelftestClient>,
+    dir: &Path,
+    mirror_path: &Path,
+    socket_addr: SocketAddr,
+) -> Result<(usize, usize), MirrorError> {
+    wait_for_server(socket_addr).await?;
+    let server = format!("http://{socket_addr}");
+
+    let mut checks = 0;
+    let mut failed = 0;
+    if client != Some(SelftestClient::Rustup) {
+        write_crate(dir, mirror_path, &server)?;
+        let registries = [
+            ("sparse", format!("sparse+{server}/index/")),
+            ("git", format!("{server}/git/crates.io-index")),
+        ];
+        for (protocol, registry) in registries {
+            checks += 1;
+            let res = cargo_fetch(dir, protocol, &registry).await;
+            if !report(&format!("cargo fetch over the {protocol} index"), res) {
+                failed += 1;
+            }
+        }
+    }
+    if client != Some(SelftestClient::Cargo) {
+        checks += 1;
+        let res = rustup_install(dir, mirror_path, &server).await;
+        if !report("rustup toolchain install", res) {
+            failed += 1;
+        }
+    }
+    Ok((checks, failed))
+}
+
+/// Wait until the server is taking connections.
+async fn wait_for_server(socket_addr: SocketAddr) -> Result<(), MirrorError> {
+    let started = tokio::time::Instant::now();
+    while tokio::net::TcpStream::connect(socket_addr).await.is_err() {
+        if started.elapsed() > SERVER_START_TIMEOUT {
+            return Err(MirrorError::SelfTest(format!(
+                "the test server didn't start on {socket_addr}"
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Ok(())
+}
+
+/// Print how a check went, returning whether it passed.
+fn report(check: &str, res: Result<(), String>) -> bool {
+    match res {
+        Ok(()) => {
+            println!("{check}... {}", style("ok").green());
+            true
+        }
+        Err(e) => {
+            println!("{check}... {}", style("failed").red().bold());
+            for line in e.lines() {
+                println!("    {line}");
+            }
+            false
+        }
+    }
+}
+
+/// Add the synthetic crate to the mirror, along with an index listing it.
+fn write_crate(dir: &Path, mirror_path: &Path, server: &str) -> Result<(), MirrorError> {
+    let package = format!("{CRATE_NAME}-{CRATE_VERSION}");
+    let package_dir = dir.join("crate");
+    write(
+        &package_dir.join(&package).join("Cargo.toml"),
+        &format!(
+            "[package]\nname = \"{CRATE_NAME}\"\nversion = \"{CRATE_VERSION}\"\nedition = \"2021\"\n"
+        ),
+    )?;
+    write(&package_dir.join(&package).join("src/lib.rs"), "")?;
+    let crate_path =
+        get_crate_path(mirror_path, CRATE_NAME, CRATE_VERSION).expect("the crate has a name");
+    tar_gz(&package_dir, &package, &crate_path)?;
+
+    let index_path = mirror_path.join("crates.io-index");
+    let repo = Repository::init_opts(
+        &index_path,
+        RepositoryInitOptions::new().initial_head("master"),
+    )?;
+    let config = serde_json::json!({ "dl": dl_url(&format!("{server}/crates"), None)? });
+    write(&index_path.join("config.json"), &config.to_string())?;
+    let entry_file = format!(
+        "{}/{CRATE_NAME}",
+        crate_prefix(CRATE_NAME).expect("the crate has a name")
+    );
+    let entry = serde_json::json!({
+        "name": CRATE_NAME,
+        "vers": CRATE_VERSION,
+        "deps": [],
+        "cksum": sha256(&crate_path)?,
+        "features": {},
+        "yanked": false,
+    });
+    write(&index_path.join(&entry_file), &format!("{entry}\n"))?;
+
+    let mut index = repo.index()?;
+    index.add_path(Path::new("config.json"))?;
+    index.add_path(Path::new(&entry_file))?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    index.write()?;
+    let signature = Signature::now("Panamax", "panamax@panamax")?;
+    repo.commit(
+        Some("refs/heads/master"),
+        &signature,
+        &signature,
+        "Add the self-test crate",
+        &tree,
+        &[],
+    )?;
+    Ok(())
+}
+
+/// Fetch the synthetic crate with cargo, from `registry`.
+async fn cargo_fetch(dir: &Path, protocol: &str, registry: &str) -> Result<(), String> {
+    let cargo_home = dir.join(format!("cargo-home-{protocol}"));
+    let project = dir.join(format!("cargo-{protocol}"));
+    let setup = || -> io::Result<()> {
+        write(
+            &cargo_home.join("config.toml"),
+            &format!(
+                "[source.crates-io]\nreplace-with = \"panamax\"\n\n[source.panamax]\nregistry = \"{registry}\"\n"
+            ),
+        )?;
+        write(
+            &project.join("Cargo.toml"),
+            &format!(
+                "[package]\nname = \"panamax-selftest-client\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n{CRATE_NAME} = \"{CRATE_VERSION}\"\n"
+            ),
+        )?;
+        write(&project.join("src/lib.rs"), "")
+    };
+    setup().map_err(|e| e.to_string())?;
+
+    let output = Command::new("cargo")
+        .arg("fetch")
+        .current_dir(&project)
+        .env("CARGO_HOME", &cargo_home)
+        .env_remove("CARGO_NET_OFFLINE")
+        .output()
+        .await;
+    check_output("cargo", output)
+}
+
+/// Install the synthetic Rust release with rustup.
+async fn rustup_install(dir: &Path, mirror_path: &Path, server: &str) -> Result<(), String> {
+    let rustup = |args: &[&str]| {
+        let mut cmd = Command::new("rustup");
+        cmd.args(args)
+            .env("RUSTUP_HOME", dir.join("rustup-home"))
+            .env("CARGO_HOME", dir.join("cargo-home-rustup"))
+            .env("RUSTUP_DIST_SERVER", server)
+            .env("RUSTUP_UPDATE_ROOT", format!("{server}/rustup"))
+            .env_remove("RUSTUP_TOOLCHAIN");
+        cmd
+    };
+
+    // The release is made for whatever rustup thinks this machine is.
+    let output = rustup(&["show"]).output().await;
+    let output = output.map_err(|e| format!("couldn't run rustup: {e}"))?;
+    let host = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("Default host: "))
+        .map(|host| host.trim().to_string())
+        .ok_or_else(|| "rustup show didn't say what the default host is".to_string())?;
+    write_release(dir, mirror_path, &host).map_err(|e| e.to_string())?;
+
+    let output = rustup(&[
+        "toolchain",
+        "install",
+        "stable",
+        "--profile",
+        "minimal",
+        "--no-self-update",
+    ])
+    .output()
+    .await;
+    check_output("rustup", output)
+}
+
+/// Add a Rust release for `host` to the mirror as the latest stable, with only what the
+/// minimal profile installs.
+fn write_release(dir: &Path, mirror_path: &Path, host: &str) -> Result<(), MirrorError> {
+    let version = format!("{RUST_VERSION} (000000000 {RELEASE_DATE})");
+    let components = [
+        ("rustc", "rustc".to_string(), "bin/rustc"),
+        ("cargo", "cargo".to_string(), "bin/cargo"),
+        (
+            "rust-std",
+            format!("rust-std-{host}"),
+            &*format!("lib/rustlib/{host}/lib/libstd-selftest.rlib"),
+        ),
+    ];
+
+    let release_dir = dir.join("release");
+    let mut manifest = format!("manifest-version = \"2\"\ndate = \"{RELEASE_DATE}\"\n");
+    let mut rustc_url = String::new();
+    let mut rustc_hash = String::new();
+    for (pkg, component, file) in &components {
+        // Each package is an archive in rustup's installer format, with one component.
+        let package = format!("{pkg}-{RUST_VERSION}-{host}");
+        let package_dir = release_dir.join(&package);
+        write(&package_dir.join("rust-installer-version"), "3\n")?;
+        write(&package_dir.join("components"), &format!("{component}\n"))?;
+        write(
+            &package_dir.join(component).join("manifest.in"),
+            &format!("file:{file}\n"),
+        )?;
+        let file_path = package_dir.join(component).join(file);
+        write(
+            &file_path,
+            &format!("#!/bin/sh\necho \"{pkg} {version}\"\n"),
+        )?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755))?;
+        }
+
+        let archive = format!("{package}.tar.gz");
+        let archive_path = mirror_path.join("dist").join(RELEASE_DATE).join(&archive);
+        tar_gz(&release_dir, &package, &archive_path)?;
+        // Mirrored manifests keep upstream's URLs, which rustup points at the mirror.
+        let url = format!("https://static.rust-lang.org/dist/{RELEASE_DATE}/{archive}");
+        let hash = sha256(&archive_path)?;
+        manifest.push_str(&format!(
+            "\n[pkg.{pkg}]\nversion = \"{version}\"\n\n[pkg.{pkg}.target.{host}]\navailable = true\nurl = \"{url}\"\nhash = \"{hash}\"\n"
+        ));
+        if *pkg == "rustc" {
+            (rustc_url, rustc_hash) = (url, hash);
+        }
+    }
+
+    // The rust package lists what a toolchain is made of. Its own archive isn't downloaded.
+    manifest.push_str(&format!(
+        "\n[pkg.rust]\nversion = \"{version}\"\n\n[pkg.rust.target.{host}]\navailable = true\nurl = \"{rustc_url}\"\nhash = \"{rustc_hash}\"\nextensions = []\n"
+    ));
+    for (pkg, _, _) in &components {
+        manifest.push_str(&format!(
+            "\n[[pkg.rust.target.{host}.components]]\npkg = \"{pkg}\"\ntarget = \"{host}\"\n"
+        ));
+    }
+    manifest
+        .push_str("\n[renames]\n\n[profiles]\nminimal = [\"rustc\", \"cargo\", \"rust-std\"]\n");
+
+    let manifest_path = mirror_path.join("dist/channel-rust-stable.toml");
+    write(&manifest_path, &manifest)?;
+    write_sha256_file(&manifest_path)?;
+    Ok(())
+}
+
+/// Pack `dir/name` into a gzipped tarball, which is what crate files and Rust release
+/// archives are, with the system's tar.
+fn tar_gz(dir: &Path, name: &str, to: &Path) -> Result<(), MirrorError> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let status = std::process::Command::new("tar")
+        .arg("-czf")
+        .arg(to)
+        .arg("-C")
+        .arg(dir)
+        .arg(name)
+        .status()?;
+    if !status.success() {
+        return Err(MirrorError::SelfTest(format!(
+            "tar couldn't create {}",
+            to.display()
+        )));
+    }
+    Ok(())
+}
+
+fn write(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    Ok(format!("{:x}", Sha256::digest(fs::read(path)?)))
+}
+
+/// Turn a client's exit into a check result, with the end of its output if it failed.
+fn check_output(program: &str, output: io::Result<Output>) -> Result<(), String> {
+    let output = output.map_err(|e| format!("couldn't run {program}: {e}"))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    let tail = lines[lines.len().saturating_sub(OUTPUT_LINES)..].join("\n");
+    Err(format!("{program} failed ({}):\n{tail}", output.status))
+}
@@ -1,14 +1,24 @@
-use std::{collections::HashMap, io, net::SocketAddr, path::PathBuf, process::Stdio};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex, OnceLock},
+};
 
 use askama::Template;
 use bytes::BytesMut;
 use futures_util::stream::TryStreamExt;
+use git2::Repository;
 use include_dir::{include_dir, Dir};
+use log::{debug, warn};
 use thiserror::Error;
 use tokio::{
     fs::File,
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    process::{ChildStdout, Command},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    process::Command,
 };
 use tokio_stream::StreamExt;
 use tokio_util::codec::{BytesCodec, FramedRead};
@@ -16,12 +26,22 @@ use warp::{
     host::Authority,
     http,
     hyper::{body::Sender, Body, Response},
-    path::Tail,
+    path::{FullPath, Peek, Tail},
     reject::Reject,
-    Filter, Rejection, Stream,
+    Filter, Rejection, Reply, Stream,
 };
 
-use crate::crates::get_crate_path;
+use crate::build_cache::{self, BuildCache};
+use crate::changelog::atom_feed;
+use crate::crate_packs;
+use crate::crates::{crate_file, crate_prefix, get_crate_path};
+use crate::image::Image;
+use crate::maintenance::{is_under_maintenance, RETRY_AFTER_SECS};
+use crate::overlay::{index_file, overlay_path};
+use crate::pack_cache::PackCache;
+use crate::rustup::{mirrored_channels, mirrored_targets};
+use crate::served;
+use crate::url_path::MirrorPath;
 
 pub struct TlsConfig {
     pub cert_path: PathBuf,
@@ -39,6 +59,9 @@ pub struct Platform {
 struct IndexTemplate {
     platforms: Vec<Platform>,
     host: String,
+    /// A newer release of panamax in the mirror, if the home page should tell about it.
+    newer_panamax: Option<String>,
+    panamax_version: &'static str,
 }
 
 const STATIC_DIR: Dir = include_dir!("static");
@@ -57,9 +80,32 @@ pub enum ServeError {
 
 impl Reject for ServeError {}
 
-pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<TlsConfig>) {
+/// The URL the mirror is served at, from the base_url of its crates directory in mirror.toml,
+/// if that's the mirror's own crates directory rather than somewhere else, like a CDN.
+pub fn mirror_url(base_url: &str) -> Option<String> {
+    base_url
+        .trim_end_matches('/')
+        .strip_suffix("/crates")
+        .map(str::to_string)
+}
+
+/// Serve a mirror. `mirror_url` is used for URLs in responses that can't be relative, and the
+/// Host header of the request when it isn't known.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    path: PathBuf,
+    socket_addr: SocketAddr,
+    tls_paths: Option<TlsConfig>,
+    unix_socket: Option<PathBuf>,
+    pack_cache: Option<PackCache>,
+    build_cache: Option<BuildCache>,
+    auth_token: Option<String>,
+    update_notice: bool,
+    mirror_url: Option<String>,
+) -> Result<(), ServeError> {
     let index_path = path.clone();
     let is_tls = tls_paths.is_some();
+    let auth_required = auth_token.is_some();
 
     // Handle the homepage
     let index = warp::path::end().and(warp::host::optional()).and_then(
@@ -67,6 +113,17 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
             let mirror_path = index_path.clone();
             let protocol = if is_tls { "https://" } else { "http://" };
             async move {
+                let newer_panamax = if update_notice {
+                    let mirror_path = mirror_path.clone();
+                    tokio::task::spawn_blocking(move || {
+                        crate::self_update::newer_release(&mirror_path)
+                    })
+                    .await
+                    .ok()
+                    .flatten()
+                } else {
+                    None
+                };
                 get_rustup_platforms(mirror_path)
                     .await
                     .map(|platforms| IndexTemplate {
@@ -74,6 +131,8 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
                         host: authority
                             .map(|a| format!("{}{}", protocol, a.as_str()))
                             .unwrap_or_else(|| "http://panamax.internal".to_string()),
+                        newer_panamax,
+                        panamax_version: crate::self_update::VERSION,
                     })
                     .map_err(|_| {
                         warp::reject::custom(ServeError::Other(
@@ -84,6 +143,74 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
         },
     );
 
+    // An Atom feed of what recent syncs changed, at /feed.atom
+    let feed_path = path.clone();
+    let feed_id = Arc::new(crate::changelog::feed_id(&path, mirror_url.as_deref()));
+    let feed_mirror_url = mirror_url.clone();
+    let feed = warp::path!("feed.atom")
+        .and(warp::host::optional())
+        .and_then(move |authority: Option<Authority>| {
+            let mirror_path = feed_path.clone();
+            let feed_id = feed_id.clone();
+            // The ID stays the same whatever hostname is used, but the self link is where
+            // this request got the feed from.
+            let protocol = if is_tls { "https://" } else { "http://" };
+            let feed_url = authority
+                .map(|a| format!("{}{}", protocol, a.as_str()))
+                .or_else(|| feed_mirror_url.clone())
+                .map_or_else(
+                    || "http://panamax.internal/feed.atom".to_string(),
+                    |url| format!("{url}/feed.atom"),
+                );
+            async move {
+                tokio::task::spawn_blocking(move || atom_feed(&mirror_path, &feed_id, &feed_url))
+                    .await
+                    .map(|feed| {
+                        warp::reply::with_header(
+                            feed,
+                            http::header::CONTENT_TYPE,
+                            "application/atom+xml",
+                        )
+                    })
+                    .map_err(|e| warp::reject::custom(ServeError::Other(e.to_string())))
+            }
+        });
+
+    // The mirror's health, at /health for readiness probes, which get a 503 while it's below
+    // health_threshold, and at /metrics for Prometheus.
+    let health_path = path.clone();
+    let health = warp::path!("health").and_then(move || {
+        let mirror_path = health_path.clone();
+        async move {
+            let health = tokio::task::spawn_blocking(move || crate::health::current(&mirror_path))
+                .await
+                .map_err(|e| warp::reject::custom(ServeError::Other(e.to_string())))?;
+            let status = match &health {
+                Some(health) if !health.healthy => http::StatusCode::SERVICE_UNAVAILABLE,
+                _ => http::StatusCode::OK,
+            };
+            Ok::<_, Rejection>(warp::reply::with_status(warp::reply::json(&health), status))
+        }
+    });
+    let metrics_path = path.clone();
+    let metrics = warp::path!("metrics").and_then(move || {
+        let mirror_path = metrics_path.clone();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                crate::health::metrics(crate::health::current(&mirror_path).as_ref())
+            })
+            .await
+            .map(|metrics| {
+                warp::reply::with_header(
+                    metrics,
+                    http::header::CONTENT_TYPE,
+                    "text/plain; version=0.0.4",
+                )
+            })
+            .map_err(|e| warp::reject::custom(ServeError::Other(e.to_string())))
+        }
+    });
+
     // Handle all files baked into the binary with include_dir, at /static
     let static_dir =
         warp::path::path("static")
@@ -95,8 +222,34 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
                     .map(|f| f.contents().to_vec())
             });
 
-    let dist_dir = warp::path::path("dist").and(warp::fs::dir(path.join("dist")));
-    let rustup_dir = warp::path::path("rustup").and(warp::fs::dir(path.join("rustup")));
+    let dist_dir = warp::path::path("dist")
+        .and(within_mirror(path.clone(), path.join("dist")))
+        .and(warp::fs::dir(path.join("dist")));
+    let rustup_dir = warp::path::path("rustup")
+        .and(within_mirror(path.clone(), path.join("rustup")))
+        .and(warp::fs::dir(path.join("rustup")));
+
+    // Different rustup versions, and a RUSTUP_DIST_SERVER or RUSTUP_UPDATE_ROOT with or
+    // without a trailing /dist or /rustup, ask for paths in the other layout. These are only
+    // tried when the paths above don't exist, so they never shadow a real file:
+    // - "/release-stable.toml" for "/rustup/release-stable.toml"
+    // - "/dist/x86_64-unknown-linux-gnu/rustup-init" for "/rustup/dist/..."
+    // - "/rustup/dist/channel-rust-stable.toml" for "/dist/..."
+    // - "/dist/dist/channel-rust-stable.toml" for "/dist/..."
+    let release_stable_fallback = warp::path!("release-stable.toml")
+        .and(warp::fs::file(path.join("rustup/release-stable.toml")));
+    let rustup_dist_fallback = warp::path::path("dist")
+        .and(within_mirror(path.clone(), path.join("rustup/dist")))
+        .and(warp::fs::dir(path.join("rustup/dist")));
+    let dist_fallback = warp::path::path("rustup")
+        .or(warp::path::path("dist"))
+        .unify()
+        .and(warp::path::path("dist"))
+        .and(within_mirror(path.clone(), path.join("dist")))
+        .and(warp::fs::dir(path.join("dist")));
+    let rustup_layout_fallbacks = release_stable_fallback
+        .or(rustup_dist_fallback)
+        .or(dist_fallback);
 
     // Handle crates requests in the format of "/crates/ripgrep/0.1.0/download"
     // This format is the default for cargo, and will be used if an external process rewrites config.json in crates.io-index
@@ -108,6 +261,41 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
         },
     );
 
+    // JSON listings of the mirrored rustup channels, and the components available for each
+    // target in a channel, for tooling to check before installing.
+    let channels_path = path.clone();
+    let rustup_channels = warp::path!("api" / "rustup" / "channels").and_then(move || {
+        let mirror_path = channels_path.clone();
+        async move {
+            tokio::task::spawn_blocking(move || mirrored_channels(&mirror_path))
+                .await
+                .map(|channels| warp::reply::json(&channels))
+                .map_err(|e| warp::reject::custom(ServeError::Other(e.to_string())))
+        }
+    });
+    let targets_path = path.clone();
+    let rustup_channel_targets = warp::path!("api" / "rustup" / "channels" / String / "targets")
+        .and_then(move |channel: String| {
+            let mirror_path = targets_path.clone();
+            async move {
+                tokio::task::spawn_blocking(move || mirrored_targets(&mirror_path, &channel))
+                    .await
+                    .map_err(|e| warp::reject::custom(ServeError::Other(e.to_string())))?
+                    .map(|targets| warp::reply::json(&targets))
+                    .ok_or_else(warp::reject::not_found)
+            }
+        });
+
+    // Handle crates requests in the format of "/api/v1/crates/ripgrep/0.1.0/download"
+    // This is the crates.io API layout, which artifact managers such as JFrog Artifactory and
+    // Sonatype Nexus use to fetch crates when panamax is set as their upstream registry URL.
+    let crates_mirror_path_api = path.clone();
+    let crates_dir_api_format = warp::path!("api" / "v1" / "crates" / String / String / "download")
+        .and_then(move |name: String, version: String| {
+            let mirror_path = crates_mirror_path_api.clone();
+            async move { get_crate_file(mirror_path, &name, &version).await }
+        });
+
     // Handle crates requests in the format of either :
     // - "/crates/1/u/0.2.0/u-0.2.0.crate"
     // - "/crates/2/bm/0.11.0/bm-0.11.0.crate"
@@ -157,6 +345,7 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
 
     // Handle git client requests to /git/crates.io-index
     let path_for_git = path.clone();
+    let pack_cache = pack_cache.map(Arc::new);
     let git = warp::path("git")
         .and(warp::path("crates.io-index"))
         .and(warp::path::tail())
@@ -168,6 +357,7 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
         .and_then(
             move |path_tail, method, content_type, remote, body, query| {
                 let mirror_path = path_for_git.clone();
+                let pack_cache = pack_cache.clone();
                 async move {
                     handle_git(
                         mirror_path,
@@ -177,23 +367,398 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
                         remote,
                         body,
                         query,
+                        pack_cache,
                     )
                     .await
                 }
             },
         );
 
+    // Handle the overlay registry at /overlay/, which serves the internal crates in the
+    // mirror's overlay directory merged over crates.io, for use under a separate registry name.
+    // Only the sparse protocol is supported.
+    let index_repo = Arc::new(IndexRepo::new(&path));
+    let overlay_index_path = path.clone();
+    let overlay_index_repo = index_repo.clone();
+    let overlay_mirror_url = mirror_url.clone();
+    let overlay_index = warp::path!("overlay" / "index" / ..)
+        .and(warp::get().or(warp::head()).unify())
+        .and(warp::path::tail())
+        .and(warp::host::optional())
+        .and(warp::header::optional::<String>("If-None-Match"))
+        .and_then(
+            move |path_tail: Tail, authority: Option<Authority>, if_none_match: Option<String>| {
+                let mirror_path = overlay_index_path.clone();
+                let index_repo = overlay_index_repo.clone();
+                let protocol = if is_tls { "https://" } else { "http://" };
+                let host = overlay_mirror_url
+                    .clone()
+                    .or_else(|| authority.map(|a| format!("{}{}", protocol, a.as_str())));
+                async move {
+                    get_overlay_index_file(
+                        mirror_path,
+                        index_repo,
+                        path_tail.as_str(),
+                        host,
+                        if_none_match,
+                        auth_required,
+                    )
+                    .await
+                }
+            },
+        );
+    let overlay_crates_path = path.clone();
+    let overlay_crates = warp::path!("overlay" / "crates" / String / String / "download").and_then(
+        move |name: String, version: String| {
+            let mirror_path = overlay_crates_path.clone();
+            async move {
+                let overlay_path =
+                    overlay_path(&mirror_path).ok_or_else(warp::reject::not_found)?;
+                // Crates in the overlay index are only ever served from the overlay.
+                match index_file(&overlay_path, &name) {
+                    Some(index_file) if index_file.is_file() => {
+                        get_crate_file(overlay_path, &name, &version).await
+                    }
+                    _ => get_crate_file(mirror_path, &name, &version).await,
+                }
+            }
+        },
+    );
+
     // Handle sparse index requests at /index/
-    let sparse_index = warp::path("index").and(warp::fs::dir(path.join("crates.io-index")));
+    let sparse_index = warp::path("index")
+        .and(warp::get().or(warp::head()).unify())
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("If-None-Match"))
+        .and_then(move |path_tail: Tail, if_none_match: Option<String>| {
+            let index_repo = index_repo.clone();
+            async move {
+                get_sparse_index_file(
+                    index_repo,
+                    path_tail.as_str().to_string(),
+                    if_none_match,
+                    auth_required,
+                )
+                .await
+            }
+        });
+
+    // Handle compiler cache requests at /build-cache/
+    let build_cache = build_cache::routes(build_cache.map(Arc::new));
+
+    // Answer with a 503 for anything in a directory that is currently being modified by a sync.
+    let maintenance_path = path.clone();
+    let maintenance = warp::path::peek().and_then(move |peek: Peek| {
+        let mirror_path = maintenance_path.clone();
+        async move {
+            let mut segments = peek.segments();
+            let dir = match segments.next() {
+                Some("dist") => "dist",
+                Some("rustup") => "rustup",
+                Some("api") if segments.next() == Some("rustup") => "dist",
+                Some("crates") | Some("api") => "crates",
+                Some("index") | Some("git") => "crates.io-index",
+                _ => return Err(warp::reject::not_found()),
+            };
+            if is_under_maintenance(&mirror_path, dir) {
+                Ok(warp::reply::with_header(
+                    warp::reply::with_status(
+                        "Mirror is being updated, please try again shortly.\n",
+                        http::StatusCode::SERVICE_UNAVAILABLE,
+                    ),
+                    http::header::RETRY_AFTER,
+                    RETRY_AFTER_SECS.to_string(),
+                ))
+            } else {
+                Err(warp::reject::not_found())
+            }
+        }
+    });
 
-    let routes = index
-        .or(static_dir)
-        .or(dist_dir)
-        .or(rustup_dir)
-        .or(crates_dir_native_format)
-        .or(crates_dir_condensed_format)
-        .or(sparse_index)
-        .or(git);
+    // Every route is behind the request path check, as the server often has filesystem access
+    // to more than just the mirror directory.
+    let routes = safe_request_path()
+        .and(
+            registry_auth(auth_token.map(Arc::new))
+                .or(maintenance)
+                .or(index)
+                .or(feed)
+                .or(health)
+                .or(metrics)
+                .or(static_dir)
+                .or(dist_dir)
+                .or(rustup_dir)
+                .or(crates_dir_native_format)
+                .or(crates_dir_condensed_format)
+                .or(crates_dir_api_format)
+                .or(rustup_channels)
+                .or(rustup_channel_targets)
+                .or(sparse_index)
+                .or(overlay_index)
+                .or(overlay_crates)
+                .or(git)
+                .or(rustup_layout_fallbacks)
+                .or(build_cache)
+                .or(misconfiguration_hints()),
+        )
+        .with(warp::log("panamax::serve"))
+        .with(warp::log::custom(|info| {
+            served::record(info.path(), info.status())
+        }));
+
+    run(routes, socket_addr, tls_paths, unix_socket).await
+}
+
+/// Answer with a 401 for registry requests without the token in protected mode, and reject
+/// everything else so the other routes handle it.
+///
+/// The 401 carries the `WWW-Authenticate: Cargo` challenge from RFC 3139, so cargo asks for a
+/// token (`cargo login --registry ...`) rather than failing with a bare HTTP error. Rustup
+/// can't send a token, so the Rust releases aren't protected.
+fn registry_auth(
+    token: Option<Arc<String>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path::peek()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |peek: Peek, authorization: Option<String>| {
+            let token = token.clone();
+            async move {
+                let Some(token) = token else {
+                    return Err(warp::reject::not_found());
+                };
+                if !is_registry_path(peek.as_str().trim_start_matches('/'))
+                    || authorization.is_some_and(|a| is_token(&a, &token))
+                {
+                    return Err(warp::reject::not_found());
+                }
+                Ok(warp::reply::with_header(
+                    warp::reply::with_status(
+                        "This registry requires a token, see `cargo login`.\n",
+                        http::StatusCode::UNAUTHORIZED,
+                    ),
+                    http::header::WWW_AUTHENTICATE,
+                    "Cargo",
+                ))
+            }
+        })
+}
+
+/// Whether a request path, without its leading slash, is part of the crates registry.
+fn is_registry_path(path: &str) -> bool {
+    let mut segments = path.split('/');
+    match segments.next() {
+        Some("index" | "crates" | "git" | "overlay") => true,
+        Some("api") => segments.next() == Some("v1"),
+        _ => false,
+    }
+}
+
+/// Whether an Authorization header holds the token. Cargo sends a token as it was given to
+/// `cargo login`, so it may or may not have a "Bearer " in front of it.
+fn is_token(authorization: &str, token: &str) -> bool {
+    let authorization = authorization
+        .strip_prefix("Bearer ")
+        .unwrap_or(authorization);
+    build_cache::constant_time_eq(authorization, token)
+}
+
+/// The index's config.json with auth-required set, so cargo sends its token with every request.
+fn with_auth_required(config_json: &[u8]) -> Option<Vec<u8>> {
+    let mut config: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(config_json).ok()?;
+    config.insert("auth-required".to_string(), true.into());
+    serde_json::to_vec(&config).ok()
+}
+
+/// Serve an archive image made by `panamax pack`, reading each file straight out of it.
+///
+/// Only plain files are served: the channel manifests and release files, crate files and the
+/// sparse index. There's no git repository in an image to serve the git index from.
+pub async fn serve_image(
+    image: Image,
+    socket_addr: SocketAddr,
+    tls_paths: Option<TlsConfig>,
+    unix_socket: Option<PathBuf>,
+) -> Result<(), ServeError> {
+    let image = Arc::new(image);
+    let files = warp::get()
+        .or(warp::head())
+        .unify()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("Range"))
+        .and(warp::header::optional::<String>("If-None-Match"))
+        .and_then(
+            move |path: FullPath, range: Option<String>, if_none_match: Option<String>| {
+                let image = image.clone();
+                async move {
+                    let file = image_file(path.as_str()).ok_or_else(warp::reject::not_found)?;
+                    if file.is_empty() {
+                        return Ok(Response::new(Body::from(format!(
+                            "Panamax mirror image, packed {}, with {} files.\n",
+                            image.packed(),
+                            image.file_count()
+                        ))));
+                    }
+                    let entry = image.get(&file).ok_or_else(warp::reject::not_found)?;
+                    let etag = format!("\"{}\"", entry.sha256);
+                    if if_none_match.as_deref() == Some(etag.as_str()) {
+                        let mut resp = Response::new(Body::empty());
+                        *resp.status_mut() = http::StatusCode::NOT_MODIFIED;
+                        return Ok(resp);
+                    }
+                    let mut resp =
+                        file_range(image.path(), entry.offset, entry.len, range.as_deref()).await?;
+                    resp.headers_mut().insert(
+                        http::header::ETAG,
+                        http::HeaderValue::from_str(&etag)
+                            .expect("hex digests should always be valid headers"),
+                    );
+                    if file.ends_with(".json") {
+                        resp.headers_mut().insert(
+                            http::header::CONTENT_TYPE,
+                            http::HeaderValue::from_static("application/json"),
+                        );
+                    }
+                    Ok::<_, Rejection>(resp)
+                }
+            },
+        );
+
+    let routes = safe_request_path()
+        .and(files.or(misconfiguration_hints()))
+        .with(warp::log("panamax::serve"));
+
+    run(routes, socket_addr, tls_paths, unix_socket).await
+}
+
+/// The file within an image that a request path is for, or an empty string for the home page.
+fn image_file(request_path: &str) -> Option<String> {
+    let path = crate::url_path::percent_decode(request_path);
+    let path = path.trim_start_matches('/');
+    let segments: Vec<&str> = path.split('/').collect();
+    match segments[..] {
+        [""] => Some(String::new()),
+        ["release-stable.toml"] => Some("rustup/release-stable.toml".to_string()),
+        ["crates", name, version, "download"]
+        | ["api", "v1", "crates", name, version, "download"] => Some(format!(
+            "crates/{}/{name}/{version}/{name}-{version}.crate",
+            crate_prefix(name)?
+        )),
+        ["dist", ..] | ["rustup", ..] | ["crates", ..] => Some(path.to_string()),
+        ["index", ".git", ..] => None,
+        ["index", ..] => Some(format!("crates.io-index/{}", &path["index/".len()..])),
+        _ => None,
+    }
+}
+
+/// Respond with `len` bytes of a file starting at `offset`, or the part of them asked for by
+/// a `Range` header.
+async fn file_range(
+    path: &Path,
+    offset: u64,
+    len: u64,
+    range: Option<&str>,
+) -> Result<Response<Body>, Rejection> {
+    let (start, end) = match range {
+        Some(range) => match byte_range(range, len) {
+            Some(range) => range,
+            None => {
+                let mut resp = Response::new(Body::empty());
+                *resp.status_mut() = http::StatusCode::RANGE_NOT_SATISFIABLE;
+                resp.headers_mut().insert(
+                    http::header::CONTENT_RANGE,
+                    http::HeaderValue::from_str(&format!("bytes */{len}"))
+                        .expect("numbers should always be valid headers"),
+                );
+                return Ok(resp);
+            }
+        },
+        None => (0, len),
+    };
+
+    let mut file = File::open(path)
+        .await
+        .map_err(|e| warp::reject::custom(ServeError::Io(e)))?;
+    file.seek(io::SeekFrom::Start(offset + start))
+        .await
+        .map_err(|e| warp::reject::custom(ServeError::Io(e)))?;
+    let stream =
+        FramedRead::new(file.take(end - start), BytesCodec::new()).map_ok(BytesMut::freeze);
+
+    let mut resp = Response::new(Body::wrap_stream(stream));
+    resp.headers_mut()
+        .insert(http::header::CONTENT_LENGTH, (end - start).into());
+    resp.headers_mut().insert(
+        http::header::ACCEPT_RANGES,
+        http::HeaderValue::from_static("bytes"),
+    );
+    if range.is_some() {
+        *resp.status_mut() = http::StatusCode::PARTIAL_CONTENT;
+        resp.headers_mut().insert(
+            http::header::CONTENT_RANGE,
+            http::HeaderValue::from_str(&format!("bytes {start}-{}/{len}", end - 1))
+                .expect("numbers should always be valid headers"),
+        );
+    }
+    Ok(resp)
+}
+
+/// The bytes of a file of `len` bytes that a `Range` header asks for, as a start and an
+/// exclusive end, or None if none of them can be given. Only a single range is supported,
+/// which is all download clients ask for.
+fn byte_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (len.saturating_sub(suffix), len)
+        }
+        (start, "") => (start.parse().ok()?, len),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.saturating_add(1).min(len),
+        ),
+    };
+    (start < end).then_some((start, end))
+}
+
+/// Listen for requests to `routes`, on a Unix socket if one is given, or on `socket_addr`,
+/// with TLS if it's configured.
+async fn run<F, R>(
+    routes: F,
+    socket_addr: SocketAddr,
+    tls_paths: Option<TlsConfig>,
+    unix_socket: Option<PathBuf>,
+) -> Result<(), ServeError>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    // Serving on a Unix socket lets panamax sit behind an existing web server, where
+    // binding a new TCP port isn't allowed.
+    #[cfg(unix)]
+    if let Some(socket_path) = unix_socket {
+        use std::os::unix::fs::FileTypeExt;
+
+        // Only remove a socket left over from a previous run, never any other kind of file.
+        if let Ok(meta) = std::fs::symlink_metadata(&socket_path) {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(&socket_path)?;
+            }
+        }
+        let listener = tokio::net::UnixListener::bind(&socket_path)?;
+        println!("Running HTTP on {}", socket_path.display());
+        warp::serve(routes)
+            .run_incoming(tokio_stream::wrappers::UnixListenerStream::new(listener))
+            .await;
+        return Ok(());
+    }
+    #[cfg(not(unix))]
+    if unix_socket.is_some() {
+        return Err(ServeError::Other(
+            "Unix sockets are not supported on this platform.".to_string(),
+        ));
+    }
 
     match tls_paths {
         Some(TlsConfig {
@@ -213,6 +778,114 @@ pub async fn serve(path: PathBuf, socket_addr: SocketAddr, tls_paths: Option<Tls
             warp::serve(routes).run(socket_addr).await;
         }
     }
+
+    Ok(())
+}
+
+/// Reject any request whose file, after following symlinks, would be outside of the mirror.
+///
+/// Symlinks inside the mirror are followed when serving, as long as their target is
+/// still within the mirror root. This filter does not consume the path, so it must be
+/// followed by the filter that actually serves `base`.
+fn within_mirror(
+    mirror_path: PathBuf,
+    base: PathBuf,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::peek()
+        .and_then(move |tail: Peek| {
+            let mirror_path = mirror_path.clone();
+            let requested = MirrorPath::from_request(tail.as_str()).map(|p| p.on_disk(&base));
+            async move {
+                match requested {
+                    Ok(requested) if is_within_mirror(&mirror_path, &requested) => Ok(()),
+                    _ => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Check whether `path` resolves (through any symlinks) to a location within `mirror_path`.
+///
+/// Paths that don't exist are allowed through, as they'll be turned into a 404 by
+/// whatever is serving them.
+fn is_within_mirror(mirror_path: &Path, path: &Path) -> bool {
+    let root = match mirror_path.canonicalize() {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+
+    match path.canonicalize() {
+        Ok(p) => p.starts_with(root),
+        Err(_) => path.symlink_metadata().is_err(),
+    }
+}
+
+/// Reject any request whose path could be used for directory traversal.
+fn safe_request_path() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::full()
+        .and_then(|full_path: FullPath| async move {
+            if is_safe_request_path(full_path.as_str()) {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// A filter that never matches, but logs a hint for requests that didn't match any other
+/// route and look like they come from a misconfigured client.
+fn misconfiguration_hints() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::path::full().and_then(|path: FullPath| async move {
+        if let Some(hint) = misconfiguration_hint(path.as_str()) {
+            // Clients tend to retry, so only warn about each kind of mistake once.
+            static WARNED: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+            let mut warned = WARNED
+                .get_or_init(Default::default)
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            if warned.insert(hint) {
+                warn!(
+                    "Client requested {}, which isn't served. {hint}",
+                    path.as_str()
+                );
+            } else {
+                debug!(
+                    "Client requested {}, which isn't served. {hint}",
+                    path.as_str()
+                );
+            }
+        }
+        Err::<String, _>(warp::reject::not_found())
+    })
+}
+
+/// Guess what a client got wrong, from a request path that isn't served.
+fn misconfiguration_hint(path: &str) -> Option<&'static str> {
+    if path.starts_with("/crates.io-index") {
+        Some("The git index is served at /git/crates.io-index, and the sparse index at /index/. Check the registry in the client's cargo config.")
+    } else if path.starts_with("/index/") && path.ends_with("/info/refs") {
+        Some("The sparse index at /index/ needs a \"sparse+\" prefix on the registry in the client's cargo config. Git clients should use /git/crates.io-index instead.")
+    } else if path == "/api/v1/crates/new" || path.starts_with("/api/v1/me") {
+        Some("The mirror is read-only, so crates can't be published to it.")
+    } else if path == "/api/v1/crates" {
+        Some("The mirror doesn't serve the crates.io web API, so `cargo search` doesn't work against it.")
+    } else if path.ends_with("/rustup-init") || path.ends_with("/rustup-init.exe") {
+        if path.starts_with("/rustup/dist/") || path.starts_with("/dist/") {
+            Some("This platform's rustup-init isn't mirrored. Check platforms_unix and platforms_windows in mirror.toml.")
+        } else {
+            Some("rustup-init files are served at /rustup/dist/<platform>/rustup-init.")
+        }
+    } else {
+        None
+    }
+}
+
+/// Check that a (percent-encoded) request path contains no traversal segments, in the same way
+/// sync checks the paths it mirrors files at.
+fn is_safe_request_path(path: &str) -> bool {
+    MirrorPath::from_request(path).is_ok()
 }
 
 /// Get all rustup platforms available on the mirror.
@@ -258,10 +931,21 @@ async fn get_crate_file(
 ) -> Result<Response<Body>, Rejection> {
     let full_path =
         get_crate_path(&mirror_path, name, version).ok_or_else(warp::reject::not_found)?;
+    if !is_within_mirror(&mirror_path, &full_path) {
+        return Err(warp::reject::not_found());
+    }
 
-    let file = File::open(full_path)
-        .await
-        .map_err(|_| warp::reject::not_found())?;
+    let file = match File::open(&full_path).await {
+        Ok(file) => file,
+        // Crate files synced with packed = true are in a pack instead.
+        Err(_) => {
+            let packs = crate_packs::get(&mirror_path);
+            let entry = crate_file(name, version)
+                .and_then(|file| packs.get(&file))
+                .ok_or_else(warp::reject::not_found)?;
+            return file_range(&packs.pack_path(entry.pack), entry.offset, entry.len, None).await;
+        }
+    };
     let meta = file
         .metadata()
         .await
@@ -277,7 +961,139 @@ async fn get_crate_file(
     Ok(resp)
 }
 
+/// Return a file from the sparse index as an HTTP response.
+///
+/// Files are read from the commit git clients are served, rather than from the working tree,
+/// so both protocols always give the same index, even while a sync is moving it forward.
+async fn get_sparse_index_file(
+    index_repo: Arc<IndexRepo>,
+    file: String,
+    if_none_match: Option<String>,
+    auth_required: bool,
+) -> Result<Response<Body>, Rejection> {
+    let is_json = file.ends_with(".json");
+    let is_config = auth_required && file == "config.json";
+    let (oid, mut contents) =
+        tokio::task::spawn_blocking(move || index_repo.read_file(Path::new(&file)))
+            .await
+            .ok()
+            .flatten()
+            .ok_or_else(warp::reject::not_found)?;
+
+    // The blob id changes exactly when the file does, which lets cargo skip unchanged files.
+    let mut etag = format!("\"{oid}\"");
+    if is_config {
+        contents = with_auth_required(&contents).ok_or_else(warp::reject::not_found)?;
+        etag = format!("\"{oid}-auth\"");
+    }
+    let mut resp = if if_none_match.as_deref() == Some(etag.as_str()) {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = http::StatusCode::NOT_MODIFIED;
+        resp
+    } else {
+        let mut resp = Response::new(Body::from(contents));
+        if is_json {
+            resp.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/json"),
+            );
+        }
+        resp
+    };
+    resp.headers_mut().insert(
+        http::header::ETAG,
+        http::HeaderValue::from_str(&etag).expect("Blob ids should always be valid headers"),
+    );
+    Ok(resp)
+}
+
+/// Return a file from the overlay registry's index as an HTTP response, falling back to the
+/// crates.io index for crates that aren't in the overlay.
+async fn get_overlay_index_file(
+    mirror_path: PathBuf,
+    index_repo: Arc<IndexRepo>,
+    file: &str,
+    host: Option<String>,
+    if_none_match: Option<String>,
+    auth_required: bool,
+) -> Result<Response<Body>, Rejection> {
+    let overlay_path = overlay_path(&mirror_path).ok_or_else(warp::reject::not_found)?;
+
+    if file == "config.json" {
+        // Every crate is downloaded through the overlay, which knows where each one lives.
+        let host = host.unwrap_or_else(|| "http://panamax.internal".to_string());
+        let mut config_json = serde_json::json!({
+            "dl": format!("{host}/overlay/crates/{{crate}}/{{version}}/download"),
+        });
+        if auth_required {
+            config_json["auth-required"] = true.into();
+        }
+        return Ok(warp::reply::json(&config_json).into_response());
+    }
+
+    let overlay_file = MirrorPath::from_request(file)
+        .map_err(|_| warp::reject::not_found())?
+        .on_disk(&overlay_path.join("index"));
+    if overlay_file.is_file() && is_within_mirror(&mirror_path, &overlay_file) {
+        let contents = tokio::fs::read(&overlay_file)
+            .await
+            .map_err(|_| warp::reject::not_found())?;
+        return Ok(Response::new(Body::from(contents)));
+    }
+
+    get_sparse_index_file(index_repo, file.to_string(), if_none_match, false).await
+}
+
+/// The mirror's git index, shared by all sparse index requests.
+///
+/// The repository is opened on first use, since serve may be started before the first sync
+/// has cloned the index. Only the reference lookup is repeated per request, so requests see
+/// the master branch move forward as a sync commits to it.
+struct IndexRepo {
+    path: PathBuf,
+}
+
+thread_local! {
+    /// The index as opened by this thread, with the path it was opened from. A `Repository`
+    /// can't be used by two threads at once, so each blocking thread serving the sparse index
+    /// keeps its own rather than waiting for the others' reads.
+    static INDEX_REPO: RefCell<Option<(PathBuf, Repository)>> = const { RefCell::new(None) };
+}
+
+impl IndexRepo {
+    fn new(mirror_path: &Path) -> Self {
+        IndexRepo {
+            path: mirror_path.join("crates.io-index"),
+        }
+    }
+
+    /// Read a file from the tree at the index's master branch, returning its blob id and
+    /// contents.
+    fn read_file(&self, file: &Path) -> Option<(git2::Oid, Vec<u8>)> {
+        INDEX_REPO.with_borrow_mut(|opened| {
+            if opened.as_ref().is_none_or(|(path, _)| *path != self.path) {
+                *opened = Some((self.path.clone(), Repository::open(&self.path).ok()?));
+            }
+            let (_, repo) = opened.as_ref()?;
+            let tree = repo
+                .find_reference("refs/heads/master")
+                .ok()?
+                .peel_to_tree()
+                .ok()?;
+            let blob = tree
+                .get_path(file)
+                .ok()?
+                .to_object(repo)
+                .ok()?
+                .into_blob()
+                .ok()?;
+            Some((blob.id(), blob.content().to_vec()))
+        })
+    }
+}
+
 /// Handle a request from a git client.
+#[allow(clippy::too_many_arguments)]
 async fn handle_git<S, B>(
     mirror_path: PathBuf,
     path_tail: Tail,
@@ -286,6 +1102,7 @@ async fn handle_git<S, B>(
     remote: Option<SocketAddr>,
     mut body: S,
     query: String,
+    pack_cache: Option<Arc<PackCache>>,
 ) -> Result<Response<Body>, Rejection>
 where
     S: Stream<Item = Result<B, warp::Error>> + Send + Unpin + 'static,
@@ -302,7 +1119,7 @@ where
     // Clear environment variables, and set needed variables
     // See: https://git-scm.com/docs/git-http-backend
     cmd.env_clear();
-    cmd.env("GIT_PROJECT_ROOT", mirror_path);
+    cmd.env("GIT_PROJECT_ROOT", &mirror_path);
     cmd.env(
         "PATH_INFO",
         format!("/crates.io-index/{}", path_tail.as_str()),
@@ -315,10 +1132,46 @@ where
         cmd.env("CONTENT_TYPE", content_type);
     }
     cmd.env("GIT_HTTP_EXPORT_ALL", "true");
+    // Read-only mounts (NFS exports, squashfs snapshots) are often owned by a different user
+    // than the one serving them, which git would otherwise refuse to read from.
+    cmd.env("GIT_CONFIG_COUNT", "1");
+    cmd.env("GIT_CONFIG_KEY_0", "safe.directory");
+    cmd.env("GIT_CONFIG_VALUE_0", "*");
     cmd.stderr(Stdio::inherit());
     cmd.stdout(Stdio::piped());
     cmd.stdin(Stdio::piped());
 
+    // Full clones are answered from the pack cache, which needs the whole request to tell
+    // them apart from other fetches.
+    if let Some(pack_cache) = pack_cache
+        .filter(|_| method == http::Method::POST && path_tail.as_str() == "git-upload-pack")
+    {
+        let mut request = BytesMut::new();
+        while let Some(Ok(mut buf)) = body.next().await {
+            request.extend_from_slice(&buf.copy_to_bytes(buf.remaining()));
+        }
+        let request = crate::pack_cache::decompress(request.to_vec());
+
+        if let Some(name) = pack_cache.entry_name(&mirror_path, &request).await {
+            let cached = pack_cache
+                .response(&name, cmd, &request)
+                .await
+                .map_err(ServeError::from)?;
+            return git_response(BufReader::new(cached)).await;
+        }
+
+        let p = cmd.spawn().map_err(ServeError::from)?;
+        let mut git_input = p.stdin.expect("Process should always have stdin");
+        git_input
+            .write_all(&request)
+            .await
+            .map_err(ServeError::from)?;
+        return git_response(BufReader::new(
+            p.stdout.expect("Process should always have stdout"),
+        ))
+        .await;
+    }
+
     let p = cmd.spawn().map_err(ServeError::from)?;
 
     // Handle sending git client body to http-backend, if any
@@ -330,8 +1183,18 @@ where
             .map_err(ServeError::from)?;
     }
 
+    git_response(BufReader::new(
+        p.stdout.expect("Process should always have stdout"),
+    ))
+    .await
+}
+
+/// Turn the output of git's CGI into a response.
+async fn git_response<R>(mut git_output: BufReader<R>) -> Result<Response<Body>, Rejection>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
     // Collect headers from git CGI output
-    let mut git_output = BufReader::new(p.stdout.expect("Process should always have stdout"));
     let mut headers = HashMap::new();
     loop {
         let mut line = String::new();
@@ -371,10 +1234,10 @@ where
 
 /// Send data from git CGI process to hyper Sender, until there is no more
 /// data left.
-async fn send_git(
-    mut sender: Sender,
-    mut git_output: BufReader<ChildStdout>,
-) -> Result<(), ServeError> {
+async fn send_git<R>(mut sender: Sender, mut git_output: BufReader<R>) -> Result<(), ServeError>
+where
+    R: AsyncRead + Unpin,
+{
     loop {
         let mut bytes_out = BytesMut::new();
         git_output.read_buf(&mut bytes_out).await?;
@@ -384,3 +1247,237 @@ async fn send_git(
         sender.send_data(bytes_out.freeze()).await?;
     }
 }
+
+#[cfg(test)]
+mod test {
+
+    mod mirror_url {
+        use crate::serve::mirror_url;
+
+        #[test]
+        fn from_base_url() {
+            assert_eq!(
+                mirror_url("http://panamax.internal/crates").as_deref(),
+                Some("http://panamax.internal")
+            );
+            assert_eq!(
+                mirror_url("https://example.com/panamax/crates/").as_deref(),
+                Some("https://example.com/panamax")
+            );
+            assert_eq!(mirror_url("https://cdn.example.com/rust"), None);
+        }
+    }
+
+    mod registry_auth {
+        use crate::serve::{is_registry_path, is_token, with_auth_required};
+
+        #[test]
+        fn registry_paths() {
+            assert!(is_registry_path("index/config.json"));
+            assert!(is_registry_path("index/se/rd/serde"));
+            assert!(is_registry_path(
+                "crates/se/rd/serde/1.0.130/serde-1.0.130.crate"
+            ));
+            assert!(is_registry_path("api/v1/crates/serde/1.0.130/download"));
+            assert!(is_registry_path("git/crates.io-index/info/refs"));
+            assert!(is_registry_path("overlay/index/config.json"));
+            assert!(!is_registry_path(""));
+            assert!(!is_registry_path("dist/channel-rust-stable.toml"));
+            assert!(!is_registry_path("rustup/release-stable.toml"));
+            assert!(!is_registry_path("api/rustup/channels"));
+            assert!(!is_registry_path("health"));
+        }
+
+        #[test]
+        fn tokens() {
+            assert!(is_token("secret", "secret"));
+            assert!(is_token("Bearer secret", "secret"));
+            assert!(!is_token("Bearer other", "secret"));
+            assert!(!is_token("", "secret"));
+        }
+
+        #[test]
+        fn config_json() {
+            let config = with_auth_required(br#"{"dl":"http://panamax.internal/crates"}"#);
+            let config: serde_json::Value = serde_json::from_slice(&config.unwrap()).unwrap();
+            assert_eq!(config["auth-required"], true);
+            assert_eq!(config["dl"], "http://panamax.internal/crates");
+            assert_eq!(with_auth_required(b"not json"), None);
+        }
+    }
+
+    mod misconfiguration_hint {
+        use crate::serve::misconfiguration_hint;
+
+        #[test]
+        fn known_mistakes() {
+            assert!(misconfiguration_hint("/crates.io-index/info/refs").is_some());
+            assert!(misconfiguration_hint("/index/info/refs").is_some());
+            assert!(misconfiguration_hint("/api/v1/crates/new").is_some());
+            assert!(misconfiguration_hint("/api/v1/crates").is_some());
+            assert!(misconfiguration_hint("/rustup-init").is_some());
+        }
+
+        #[test]
+        fn missing_files() {
+            assert_eq!(
+                misconfiguration_hint("/dist/channel-rust-stable.toml"),
+                None
+            );
+            assert_eq!(misconfiguration_hint("/crates/serde/1.0.0/download"), None);
+        }
+    }
+
+    mod image {
+        use crate::serve::{byte_range, image_file};
+
+        #[test]
+        fn request_paths() {
+            assert_eq!(image_file("/").as_deref(), Some(""));
+            assert_eq!(
+                image_file("/dist/channel-rust-stable.toml").as_deref(),
+                Some("dist/channel-rust-stable.toml")
+            );
+            assert_eq!(
+                image_file("/crates/serde/1.0.130/download").as_deref(),
+                Some("crates/se/rd/serde/1.0.130/serde-1.0.130.crate")
+            );
+            assert_eq!(
+                image_file("/api/v1/crates/u/0.2.0/download").as_deref(),
+                Some("crates/1/u/0.2.0/u-0.2.0.crate")
+            );
+            assert_eq!(
+                image_file("/index/se/rd/serde").as_deref(),
+                Some("crates.io-index/se/rd/serde")
+            );
+            assert_eq!(image_file("/index/.git/config"), None);
+            assert_eq!(image_file("/mirror.toml"), None);
+        }
+
+        #[test]
+        fn ranges() {
+            assert_eq!(byte_range("bytes=0-9", 100), Some((0, 10)));
+            assert_eq!(byte_range("bytes=90-", 100), Some((90, 100)));
+            assert_eq!(byte_range("bytes=-10", 100), Some((90, 100)));
+            assert_eq!(byte_range("bytes=90-200", 100), Some((90, 100)));
+            assert_eq!(byte_range("bytes=100-", 100), None);
+            assert_eq!(byte_range("bytes=5-2", 100), None);
+            assert_eq!(byte_range("items=0-9", 100), None);
+        }
+    }
+
+    mod request_path {
+        use crate::rng::Xorshift;
+        use crate::serve::is_safe_request_path;
+        use crate::url_path::percent_decode;
+        use std::path::{Component, Path};
+
+        #[test]
+        fn normal_paths() {
+            assert!(is_safe_request_path("/"));
+            assert!(is_safe_request_path("/dist/channel-rust-stable.toml"));
+            assert!(is_safe_request_path(
+                "/crates/se/rd/serde/1.0.130/serde-1.0.130.crate"
+            ));
+            assert!(is_safe_request_path("/git/crates.io-index/info/refs"));
+        }
+
+        #[test]
+        fn plain_traversal() {
+            assert!(!is_safe_request_path("/dist/../mirror.toml"));
+            assert!(!is_safe_request_path("/dist/./../../etc/passwd"));
+            assert!(!is_safe_request_path("/.."));
+        }
+
+        #[test]
+        fn encoded_traversal() {
+            assert!(!is_safe_request_path("/dist/%2e%2e/mirror.toml"));
+            assert!(!is_safe_request_path("/dist/%2E%2E/mirror.toml"));
+            assert!(!is_safe_request_path("/dist/.%2e/mirror.toml"));
+            assert!(!is_safe_request_path("/dist/..%2fmirror.toml"));
+            assert!(!is_safe_request_path("/dist/..%5cmirror.toml"));
+            assert!(!is_safe_request_path("/dist/%252e%252e/mirror.toml"));
+            assert!(!is_safe_request_path("/dist/foo%00.toml"));
+        }
+
+        #[test]
+        fn fuzz_accepted_paths_never_traverse() {
+            // Seeded, so the test is deterministic.
+            let mut rng = Xorshift::new(0x2545_f491_4f6c_dd1d);
+            let pieces = [
+                "/", ".", "..", "%2e", "%2E", "%2f", "%2F", "%5c", "%25", "%00", "%", "%g", "a",
+                "dist", "\\",
+            ];
+
+            for _ in 0..100_000 {
+                let len = rng.below(12);
+                let path: String = (0..len).map(|_| pieces[rng.below(pieces.len())]).collect();
+
+                if is_safe_request_path(&path) {
+                    let decoded = percent_decode(&path);
+                    assert!(
+                        Path::new(&decoded)
+                            .components()
+                            .all(|c| matches!(c, Component::RootDir | Component::Normal(_))),
+                        "accepted traversing path {path:?}"
+                    );
+                    assert!(!decoded.contains(['\\', '\0']), "accepted path {path:?}");
+                }
+            }
+        }
+    }
+
+    mod index_repo {
+        use std::path::Path;
+
+        use git2::{Repository, Signature};
+
+        use crate::serve::IndexRepo;
+
+        /// Commit a single file to the master branch of the repository.
+        fn commit(repo: &Repository, file: &str, content: &str) {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            let mut builder = repo.treebuilder(None).unwrap();
+            builder.insert(file, blob, 0o100644).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let parent = repo
+                .find_reference("refs/heads/master")
+                .and_then(|master| master.peel_to_commit())
+                .ok();
+            let signature = Signature::now("Panamax", "panamax@panamax").unwrap();
+            repo.commit(
+                Some("refs/heads/master"),
+                &signature,
+                &signature,
+                "Update",
+                &tree,
+                &parent.iter().collect::<Vec<_>>(),
+            )
+            .unwrap();
+        }
+
+        #[test]
+        fn read_from_many_threads() {
+            let mirror = tempfile::tempdir().unwrap();
+            let repo = Repository::init(mirror.path().join("crates.io-index")).unwrap();
+            let index_repo = IndexRepo::new(mirror.path());
+            assert!(index_repo.read_file(Path::new("foo")).is_none());
+
+            commit(&repo, "foo", "1");
+            std::thread::scope(|s| {
+                for _ in 0..4 {
+                    s.spawn(|| {
+                        let (_, content) = index_repo.read_file(Path::new("foo")).unwrap();
+                        assert_eq!(content, b"1");
+                    });
+                }
+            });
+
+            // The master branch is followed as it moves forward.
+            commit(&repo, "foo", "2");
+            let (_, content) = index_repo.read_file(Path::new("foo")).unwrap();
+            assert_eq!(content, b"2");
+            assert!(index_repo.read_file(Path::new("bar")).is_none());
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::warn;
+use warp::http::StatusCode;
+
+use crate::state;
+use crate::timestamp::{Timestamp, DAY};
+
+/// Name of the file recording when rustup files were last served, in the state directory.
+pub const SERVED_FILE: &str = ".panamax-served";
+
+/// How often serve writes down the files it has served.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Files served since the last flush, by path relative to the mirror root, with the time they
+/// were last served.
+static PENDING: Mutex<BTreeMap<String, Timestamp>> = Mutex::new(BTreeMap::new());
+
+/// Whether served files are being written down, which they aren't on a read-only mirror.
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+/// Get when each rustup file was last served, by path relative to the mirror root. A missing or
+/// unreadable file is treated as nothing having been served.
+pub fn load(mirror_path: &Path) -> BTreeMap<String, Timestamp> {
+    fs::read(state::path(mirror_path, SERVED_FILE))
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+/// The key a file is recorded under, from its path relative to the mirror root.
+pub fn key(relative_path: &Path) -> String {
+    relative_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// How many whole days ago a file was last served, if it was served at all.
+pub fn days_since_served(
+    served: &BTreeMap<String, Timestamp>,
+    key: &str,
+    now: Timestamp,
+) -> Option<u64> {
+    served
+        .get(key)
+        .map(|last| now.0.saturating_sub(last.0) / DAY)
+}
+
+/// Note a request answered by serve, if it was for a rustup release file.
+pub fn record(request_path: &str, status: StatusCode) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+    if !(status.is_success() || status == StatusCode::NOT_MODIFIED) {
+        return;
+    }
+    let Some(file) = request_path.strip_prefix("/dist/") else {
+        return;
+    };
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    pending.insert(format!("dist/{file}"), Timestamp::now());
+}
+
+/// Periodically write down the files served, so sync can avoid cleaning up toolchains that
+/// clients still use. This runs alongside serve.
+///
+/// A read-only mirror just won't have this protection, so nothing is recorded for it.
+pub async fn run(mirror_path: PathBuf) {
+    let tmp_path = tmp_path(&mirror_path);
+    if let Err(e) = fs::write(&tmp_path, "").and_then(|()| fs::remove_file(&tmp_path)) {
+        warn!("Not recording served files, as {SERVED_FILE} can't be written: {e}");
+        return;
+    }
+    RECORDING.store(true, Ordering::Relaxed);
+
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pending = std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()));
+        if pending.is_empty() {
+            continue;
+        }
+        if let Err(e) = flush(&mirror_path, pending) {
+            warn!("Could not record served files in {SERVED_FILE}: {e}");
+        }
+    }
+}
+
+/// Merge newly served files into the file on disk. Other servers may share the mirror
+/// directory, so it's read again each time.
+fn flush(mirror_path: &Path, pending: BTreeMap<String, Timestamp>) -> io::Result<()> {
+    let mut served = load(mirror_path);
+    for (key, time) in pending {
+        let last = served.entry(key).or_default();
+        *last = (*last).max(time);
+    }
+    save(mirror_path, &served)
+}
+
+/// Where this process writes the file before moving it into place. Other servers may share
+/// the mirror directory, so each has its own.
+fn tmp_path(mirror_path: &Path) -> PathBuf {
+    state::path(
+        mirror_path,
+        &format!("{SERVED_FILE}.{}.tmp", std::process::id()),
+    )
+}
+
+fn save(mirror_path: &Path, served: &BTreeMap<String, Timestamp>) -> io::Result<()> {
+    let data = serde_json::to_vec_pretty(served).expect("served files should always serialize");
+    let tmp_path = tmp_path(mirror_path);
+    fs::write(&tmp_path, data)?;
+    fs::rename(tmp_path, state::path(mirror_path, SERVED_FILE))
+}
+
+/// Forget the files that are no longer in the mirror, after sync cleaned them up, so the file
+/// only grows with the mirror.
+pub fn prune(mirror_path: &Path) -> io::Result<()> {
+    let mut served = load(mirror_path);
+    let before = served.len();
+    served.retain(|key, _| mirror_path.join(key).exists());
+    if served.len() == before {
+        return Ok(());
+    }
+    save(mirror_path, &served)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod days_since_served {
+        use std::collections::BTreeMap;
+        use std::path::Path;
+
+        use crate::served::{days_since_served, key};
+        use crate::timestamp::{Timestamp, DAY};
+
+        #[test]
+        fn served() {
+            let served = BTreeMap::from([(
+                "dist/2023-06-01/cargo.tar.xz".to_string(),
+                Timestamp(10 * DAY),
+            )]);
+            let key = key(Path::new("dist/2023-06-01/cargo.tar.xz"));
+            assert_eq!(
+                days_since_served(&served, &key, Timestamp(10 * DAY + 5)),
+                Some(0)
+            );
+            assert_eq!(
+                days_since_served(&served, &key, Timestamp(13 * DAY)),
+                Some(3)
+            );
+        }
+
+        #[test]
+        fn never_served() {
+            let served = BTreeMap::new();
+            assert_eq!(
+                days_since_served(&served, "dist/old.tar.xz", Timestamp(DAY)),
+                None
+            );
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::mirror::Shard;
+use crate::progress_bar::step_message;
+
+/// How often to check whether the leader has fetched the index.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for the leader before assuming it has died.
+const PLAN_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Coordinates the shards of a sync, through files in the shared mirror directory.
+///
+/// The first shard to start becomes the leader, and fetches the index that every shard
+/// plans its downloads from. The last shard to finish then completes the mirror with a normal
+/// sync, which fast-forwards the index, records channel history, and cleans old files.
+///
+/// This relies on exclusive file creation being atomic, which holds for local filesystems
+/// and NFSv3 or later.
+pub struct ShardCoordinator {
+    dir: PathBuf,
+    shard: Shard,
+}
+
+impl ShardCoordinator {
+    pub fn new(mirror_path: &Path, shard: Shard) -> io::Result<Self> {
+        let dir = mirror_path.join(format!(".panamax-shards-{}", shard.count));
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, shard })
+    }
+
+    /// Try to become the leader. Exactly one shard of a sync succeeds.
+    pub fn try_lead(&self) -> io::Result<bool> {
+        create_exclusive(&self.dir.join("leader"), self.shard.index)
+    }
+
+    /// Let the other shards know the index has been fetched.
+    pub fn plan_ready(&self) -> io::Result<()> {
+        fs::write(self.dir.join("plan-ready"), "")
+    }
+
+    /// Wait for the leader to fetch the index.
+    pub async fn wait_for_plan(&self) -> io::Result<()> {
+        let ready_path = self.dir.join("plan-ready");
+        if ready_path.exists() {
+            return Ok(());
+        }
+
+        step_message("Waiting for the leading shard to fetch the index...");
+        let start = Instant::now();
+        while !ready_path.exists() {
+            if start.elapsed() > PLAN_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "the leading shard never fetched the index. If it was stopped, remove {} and start the sync again",
+                        self.dir.display()
+                    ),
+                ));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Mark this shard as done. Returns true if every shard is done, and this one should
+    /// complete the mirror.
+    pub fn finish(&self) -> io::Result<bool> {
+        fs::write(self.dir.join(format!("done-{}", self.shard.index)), "")?;
+
+        let done = (1..=self.shard.count)
+            .filter(|index| self.dir.join(format!("done-{index}")).exists())
+            .count() as u64;
+        if done < self.shard.count {
+            return Ok(false);
+        }
+
+        // Shards finishing at the same time may all see every shard as done.
+        create_exclusive(&self.dir.join("finisher"), self.shard.index)
+    }
+
+    /// Remove the coordination files, once the mirror is complete.
+    pub fn cleanup(self) -> io::Result<()> {
+        fs::remove_dir_all(self.dir)
+    }
+}
+
+/// Create a file only if it doesn't exist, returning whether this call created it.
+fn create_exclusive(path: &Path, shard_index: u64) -> io::Result<bool> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(mut f) => {
+            writeln!(f, "{shard_index}")?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod coordinator {
+        use crate::mirror::Shard;
+        use crate::shards::ShardCoordinator;
+
+        #[test]
+        fn one_leader_and_one_finisher() {
+            let mirror = tempfile::tempdir().unwrap();
+            let shards: Vec<ShardCoordinator> = (1..=3)
+                .map(|index| ShardCoordinator::new(mirror.path(), Shard { index, count: 3 }))
+                .collect::<Result<_, _>>()
+                .unwrap();
+
+            let leaders = shards.iter().filter(|s| s.try_lead().unwrap()).count();
+            assert_eq!(leaders, 1);
+
+            let finishers: Vec<bool> = shards.iter().map(|s| s.finish().unwrap()).collect();
+            assert_eq!(finishers, vec![false, false, true]);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use console::style;
+
+use crate::progress_bar::print_above_progress;
+
+/// Whether the sync was asked to stop.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit status after a second signal, which is what shells report for a process stopped by
+/// Ctrl-C.
+const FORCED_EXIT_CODE: i32 = 130;
+
+/// Stop the sync cleanly on Ctrl-C or SIGTERM, for the rest of this run.
+///
+/// The first signal stops new downloads from starting. The ones in progress finish, and the
+/// sync stops after them, without publishing any release or index update it didn't finish
+/// downloading, so the next sync picks up where it left off. A second signal exits straight
+/// away, leaving partial downloads in the staging directory for the next sync to resume.
+pub fn listen() {
+    tokio::spawn(async {
+        if signal().await.is_err() {
+            return;
+        }
+        REQUESTED.store(true, Ordering::Relaxed);
+        print_above_progress(
+            style(
+                "Stopping once the downloads in progress finish. Press Ctrl-C again to stop now.",
+            )
+            .yellow()
+            .bold(),
+        );
+
+        if signal().await.is_err() {
+            return;
+        }
+        // End the line a progress bar was being drawn on, so the shell's prompt isn't on it.
+        eprintln!();
+        eprintln!("Stopped. Partial downloads will be resumed by the next sync.");
+        std::process::exit(FORCED_EXIT_CODE);
+    });
+}
+
+/// Whether the sync was asked to stop, so nothing new should be started.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Wait for Ctrl-C, or SIGTERM, which is how service managers and container runtimes stop
+/// a process.
+#[cfg(unix)]
+async fn signal() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
@@ -0,0 +1,498 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sha1::Sha1;
+use sha2::Digest;
+
+use crate::checksums::{self, CHECKSUMS_FILE};
+use crate::download::{append_to_path, file_hash};
+use crate::hashes::{find_sums_file, manifest_hash, HashAlgorithm};
+use crate::mirror::{default_user_agent, MirrorError};
+use crate::progress_bar::{padded_prefix_message, progress_bar, progress_bar_bytes, StepsProgress};
+use crate::staging;
+use crate::state;
+use crate::storage;
+use crate::timestamp::Timestamp;
+use crate::url_path::MirrorPath;
+
+/// Directories of mirrored content, which is what a snapshot holds.
+const CONTENT_DIRS: [&str; 4] = ["dist", "rustup", "crates", "crates.io-index"];
+
+/// Prefix of the snapshot directories made by `panamax snapshot publish`.
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+
+/// Torrent pieces are at least this long, and doubled until there are at most `MAX_PIECES` of
+/// them, up to `MAX_PIECE_LENGTH`.
+const MIN_PIECE_LENGTH: u64 = 256 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+const MAX_PIECES: u64 = 100_000;
+
+/// How a snapshot is to be shared.
+#[derive(Debug, Default)]
+pub struct PublishOptions {
+    /// Write a .torrent file for the snapshot.
+    pub torrent: bool,
+    /// Trackers to list in the .torrent file. Without any, clients find peers through the DHT.
+    pub trackers: Vec<String>,
+    /// URLs the snapshot's directory is also served at over HTTP, which clients can download
+    /// from when there are too few peers.
+    pub web_seeds: Vec<String>,
+}
+
+/// A snapshot that was published.
+pub struct Published {
+    pub dir: PathBuf,
+    /// The .torrent file written for it, and its magnet link.
+    pub torrent: Option<(PathBuf, String)>,
+}
+
+/// What importing a snapshot did.
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub added: usize,
+    /// Files the mirror already had, which were left alone.
+    pub present: usize,
+    /// Files that didn't match the manifest, which sync will download instead.
+    pub mismatched: Vec<String>,
+}
+
+/// Whether a state file is part of a snapshot. The channel history lets sync clean up old
+/// releases the snapshot came with, and the checksum database carries over the hashes
+/// upstream first gave for them. The rest only make sense for the mirror they came from.
+pub(crate) fn is_snapshot_state_file(name: &str) -> bool {
+    state::is_history_file(name) || name == CHECKSUMS_FILE
+}
+
+/// The mirrored files within a mirror, relative to its root and sorted, skipping files left
+/// behind by failed downloads.
+pub(crate) fn content_files(mirror_path: &Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for dir in CONTENT_DIRS {
+        // A mirror that doesn't sync rustup or crates may not have their directories.
+        if !mirror_path.join(dir).exists() {
+            continue;
+        }
+        // Symlinks are not followed, same as when cleaning old files during sync.
+        for entry in walkdir::WalkDir::new(mirror_path.join(dir)).sort_by_file_name() {
+            let entry = entry.map_err(io::Error::from)?;
+            let is_leftover = entry
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "part" || ext == "notfound" || ext == "badsha256");
+            if !entry.file_type().is_file() || is_leftover {
+                continue;
+            }
+            let relative = entry
+                .path()
+                .strip_prefix(mirror_path)
+                .expect("walked paths are within the mirror");
+            let Some(relative) = relative.to_str() else {
+                continue;
+            };
+            files.push(relative.replace('\\', "/"));
+        }
+    }
+    Ok(files)
+}
+
+/// Whether a file in a snapshot may be written to in place once it's in a mirror. Git updates
+/// the crates.io-index repository's files, and sync appends to state files, such as those at
+/// the top of the snapshot.
+fn is_written_in_place(file: &str) -> bool {
+    file.starts_with("crates.io-index/") || !file.contains('/')
+}
+
+/// Hard link a file from a mirror to a snapshot or back, or copy it if it may be written to in
+/// place or can't be linked, creating `to`'s directory if needed.
+///
+/// Panamax always replaces other mirrored files rather than writing to them, so a hard link
+/// keeps the contents the file had when it was linked.
+fn link_or_copy(from: &Path, to: &Path, file: &str) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if is_written_in_place(file) || fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Freeze a mirror's content into a new snapshot directory within `dest`, with a manifest of
+/// its hashes, and a .torrent file next to it if asked for.
+///
+/// Files are hard linked where possible, so the snapshot takes little extra space and stays as
+/// it is while the mirror keeps syncing. This shouldn't be run while a sync is in progress.
+pub async fn publish(
+    mirror_path: &Path,
+    dest: &Path,
+    options: &PublishOptions,
+) -> Result<Published, MirrorError> {
+    let name = format!("{SNAPSHOT_PREFIX}{}", Timestamp::now().0);
+    let snapshot = dest.join(&name);
+    if snapshot.exists() {
+        return Err(MirrorError::CmdLine(format!(
+            "{} already exists.",
+            snapshot.display()
+        )));
+    }
+    fs::create_dir_all(&snapshot)?;
+
+    let content = content_files(mirror_path)?;
+    let steps = 2;
+    let progress = StepsProgress::new(steps, "Publishing snapshot");
+    let pb = progress_bar(
+        content.len(),
+        padded_prefix_message(1, steps, "Linking mirror files"),
+    );
+    for file in &content {
+        link_or_copy(&mirror_path.join(file), &snapshot.join(file), file)?;
+        pb.inc(1);
+    }
+    pb.finish();
+    progress.step_done();
+    let mut files = content;
+    let state_dir = state::dir(mirror_path);
+    for entry in fs::read_dir(&state_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if is_snapshot_state_file(&name) {
+            link_or_copy(&state_dir.join(&name), &snapshot.join(&name), &name)?;
+            files.push(name);
+        }
+    }
+    files.sort();
+
+    let sizes = files
+        .iter()
+        .map(|file| fs::metadata(snapshot.join(file)).map(|m| m.len()))
+        .collect::<io::Result<Vec<u64>>>()?;
+    let total = sizes.iter().sum();
+    let mut pieces = options.torrent.then(|| Pieces::new(piece_length(total)));
+    let pb = progress_bar_bytes(total, padded_prefix_message(2, steps, "Hashing snapshot"));
+    let algorithm = manifest_hash();
+    let mut manifest = String::new();
+    for (file, size) in files.iter().zip(sizes) {
+        let hash = match &mut pieces {
+            // Every byte has to be read for the torrent's pieces anyway.
+            Some(pieces) => hash_into(&snapshot.join(file), algorithm, pieces)?,
+            // State files are at the top of the snapshot, and mirrored files never are.
+            None if file.contains('/') => {
+                checksums::hash(&mirror_path.join(file), algorithm).await?
+            }
+            None => file_hash(&snapshot.join(file), algorithm).await?,
+        };
+        manifest.push_str(&format!("{hash}  {file}\n"));
+        pb.inc(size);
+    }
+    pb.finish();
+    progress.step_done();
+    fs::write(snapshot.join(algorithm.sums_file()), &manifest)?;
+
+    let torrent = match pieces {
+        Some(mut pieces) => {
+            // The manifest is the last file in the torrent, so it can be hashed last.
+            pieces.update(manifest.as_bytes());
+            files.push(algorithm.sums_file().to_string());
+            let torrent_path = append_to_path(&snapshot, ".torrent");
+            let magnet = write_torrent(&snapshot, &name, &files, pieces, options, &torrent_path)?;
+            Some((torrent_path, magnet))
+        }
+        None => None,
+    };
+
+    Ok(Published {
+        dir: snapshot,
+        torrent,
+    })
+}
+
+/// Hash a file for the manifest, also adding it to the torrent's pieces.
+fn hash_into(path: &Path, algorithm: HashAlgorithm, pieces: &mut Pieces) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.write_all(&buf[..n])?;
+        pieces.update(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+fn piece_length(total: u64) -> u64 {
+    let mut length = MIN_PIECE_LENGTH;
+    while length < MAX_PIECE_LENGTH && total / length > MAX_PIECES {
+        length *= 2;
+    }
+    length
+}
+
+/// The SHA-1 hashes of every piece of a torrent, which spans all its files in order.
+struct Pieces {
+    length: u64,
+    hasher: Sha1,
+    filled: u64,
+    hashes: Vec<u8>,
+}
+
+impl Pieces {
+    fn new(length: u64) -> Self {
+        Pieces {
+            length,
+            hasher: Sha1::new(),
+            filled: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = data.len().min((self.length - self.filled) as usize);
+            self.hasher.update(&data[..n]);
+            self.filled += n as u64;
+            data = &data[n..];
+            if self.filled == self.length {
+                self.hashes.extend(self.hasher.finalize_reset());
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.filled > 0 {
+            self.hashes.extend(self.hasher.finalize_reset());
+        }
+        self.hashes
+    }
+}
+
+/// A bencoded value, as .torrent files are made of.
+enum Bencode {
+    Int(u64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    /// Keys are written sorted, as bencoding requires.
+    Dict(BTreeMap<&'static str, Bencode>),
+}
+
+impl Bencode {
+    fn str(s: &str) -> Self {
+        Bencode::Bytes(s.as_bytes().to_vec())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(i) => out.extend(format!("i{i}e").as_bytes()),
+            Bencode::Bytes(bytes) => {
+                out.extend(format!("{}:", bytes.len()).as_bytes());
+                out.extend(bytes);
+            }
+            Bencode::List(items) => {
+                out.push(b'l');
+                items.iter().for_each(|item| item.encode(out));
+                out.push(b'e');
+            }
+            Bencode::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    Bencode::str(key).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+/// Write a multi-file .torrent for the snapshot, returning its magnet link.
+fn write_torrent(
+    snapshot: &Path,
+    name: &str,
+    files: &[String],
+    pieces: Pieces,
+    options: &PublishOptions,
+    torrent_path: &Path,
+) -> io::Result<String> {
+    let mut file_list = Vec::new();
+    for file in files {
+        let length = fs::metadata(snapshot.join(file))?.len();
+        let path = file.split('/').map(Bencode::str).collect();
+        file_list.push(Bencode::Dict(BTreeMap::from([
+            ("length", Bencode::Int(length)),
+            ("path", Bencode::List(path)),
+        ])));
+    }
+    let piece_length = pieces.length;
+    let info = Bencode::Dict(BTreeMap::from([
+        ("files", Bencode::List(file_list)),
+        ("name", Bencode::str(name)),
+        ("piece length", Bencode::Int(piece_length)),
+        ("pieces", Bencode::Bytes(pieces.finish())),
+    ]));
+    let mut info_data = Vec::new();
+    info.encode(&mut info_data);
+    let info_hash = format!("{:x}", Sha1::digest(&info_data));
+
+    let mut torrent = BTreeMap::from([
+        ("created by", Bencode::str(&default_user_agent())),
+        ("creation date", Bencode::Int(Timestamp::now().0)),
+        ("info", info),
+    ]);
+    if let Some(tracker) = options.trackers.first() {
+        torrent.insert("announce", Bencode::str(tracker));
+        let tiers = options
+            .trackers
+            .iter()
+            .map(|tracker| Bencode::List(vec![Bencode::str(tracker)]))
+            .collect();
+        torrent.insert("announce-list", Bencode::List(tiers));
+    }
+    if !options.web_seeds.is_empty() {
+        let web_seeds = options.web_seeds.iter().map(|url| Bencode::str(url));
+        torrent.insert("url-list", Bencode::List(web_seeds.collect()));
+    }
+    let mut data = Vec::new();
+    Bencode::Dict(torrent).encode(&mut data);
+    fs::write(torrent_path, data)?;
+
+    Ok(format!("magnet:?xt=urn:btih:{info_hash}&dn={name}"))
+}
+
+/// Where a file listed in a snapshot's manifest goes in the mirror, if it's something a
+/// snapshot can hold.
+pub(crate) fn import_path(mirror_path: &Path, file: &str) -> Option<PathBuf> {
+    let path = MirrorPath::parse(file).ok()?;
+    match file.split_once('/') {
+        Some((dir, _)) if CONTENT_DIRS.contains(&dir) => Some(path.on_disk(mirror_path)),
+        None if is_snapshot_state_file(file) => Some(state::path(mirror_path, file)),
+        _ => None,
+    }
+}
+
+/// Add the files of a snapshot, fetched with a BitTorrent or IPFS client, to a mirror.
+///
+/// Every file is checked against the manifest as it's added, and files the mirror already has
+/// are left alone, so this can be used to seed a new mirror before syncing it over HTTP. Files
+/// are hard linked where possible, so the snapshot can keep being shared from where it was
+/// downloaded.
+pub async fn import(mirror_path: &Path, snapshot: &Path) -> Result<Imported, MirrorError> {
+    let Some((algorithm, manifest_path)) = find_sums_file(snapshot) else {
+        return Err(MirrorError::Snapshot(format!(
+            "{} has no SHA256SUMS, SHA512SUMS or B3SUMS manifest",
+            snapshot.display()
+        )));
+    };
+    let manifest = fs::read_to_string(&manifest_path).map_err(|e| {
+        MirrorError::Snapshot(format!("Could not read {}: {e}", manifest_path.display()))
+    })?;
+    let mut files = Vec::new();
+    for line in manifest.lines() {
+        let Some((hash, file)) = line.split_once("  ") else {
+            continue;
+        };
+        let Some(path) = import_path(mirror_path, file) else {
+            return Err(MirrorError::Snapshot(format!(
+                "{} lists {file}, which isn't part of a mirror",
+                snapshot.display()
+            )));
+        };
+        files.push((file, hash, path));
+    }
+
+    // Files of two different index repositories can't be mixed.
+    let has_index = mirror_path.join("crates.io-index").join(".git").exists();
+    let mut imported = Imported::default();
+    let pb = progress_bar(
+        files.len(),
+        padded_prefix_message(1, 1, "Importing snapshot"),
+    );
+    for (file, hash, path) in files {
+        pb.inc(1);
+        if path.exists() || (has_index && file.starts_with("crates.io-index/")) {
+            imported.present += 1;
+            continue;
+        }
+        let part_path = staging::part_path(&path, ".part");
+        link_or_copy(&snapshot.join(file), &part_path, file)?;
+        if file_hash(&part_path, algorithm).await? != hash {
+            fs::remove_file(&part_path)?;
+            imported.mismatched.push(file.to_string());
+            continue;
+        }
+        storage::get().rename(&part_path, &path)?;
+        checksums::record(&path, algorithm, hash)?;
+        imported.added += 1;
+    }
+    pb.finish();
+
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod torrent {
+        use std::collections::BTreeMap;
+
+        use crate::snapshot::{piece_length, Bencode, Pieces};
+
+        #[test]
+        fn bencode() {
+            let mut out = Vec::new();
+            Bencode::Dict(BTreeMap::from([
+                ("name", Bencode::str("spam")),
+                ("length", Bencode::Int(3)),
+                (
+                    "path",
+                    Bencode::List(vec![Bencode::str("a"), Bencode::str("bc")]),
+                ),
+            ]))
+            .encode(&mut out);
+            assert_eq!(out, b"d6:lengthi3e4:name4:spam4:pathl1:a2:bcee");
+        }
+
+        #[test]
+        fn pieces_span_files() {
+            let mut pieces = Pieces::new(4);
+            pieces.update(b"abc");
+            pieces.update(b"defghi");
+            let hashes = pieces.finish();
+            // "abcd", "efgh" and "i".
+            assert_eq!(hashes.len(), 3 * 20);
+            let mut one = Pieces::new(4);
+            one.update(b"efgh");
+            assert_eq!(&hashes[20..40], &one.finish()[..]);
+        }
+
+        #[test]
+        fn piece_lengths() {
+            assert_eq!(piece_length(0), 256 * 1024);
+            assert_eq!(piece_length(100 * 1024 * 1024 * 1024), 2 * 1024 * 1024);
+            assert_eq!(piece_length(u64::MAX), 16 * 1024 * 1024);
+        }
+    }
+
+    mod import_path {
+        use std::path::Path;
+
+        use crate::snapshot::import_path;
+
+        #[test]
+        fn mirror_files() {
+            let mirror = Path::new("/srv/mirror");
+            assert_eq!(
+                import_path(mirror, "crates/se/rd/serde/serde-1.0.0.crate").unwrap(),
+                Path::new("/srv/mirror/crates/se/rd/serde/serde-1.0.0.crate")
+            );
+            assert!(import_path(mirror, "mirror-stable-history.toml").is_some());
+            assert!(import_path(mirror, "mirror.toml").is_none());
+            assert!(import_path(mirror, ".panamax-served").is_none());
+            assert!(import_path(mirror, "crates/../mirror.toml").is_none());
+            assert!(import_path(mirror, "static/index.html").is_none());
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::download::append_to_path;
+use crate::mirror::ConfigMirror;
+
+/// Default directory partial downloads are written to, in the mirror root. Nothing under it
+/// is served, and it's on the same filesystem as the rest of the mirror, so finished files
+/// are moved into place with a cheap rename.
+pub const DEFAULT_STAGING_DIR: &str = ".panamax-staging";
+
+/// The mirror root and the directory its partial downloads are written to.
+static STAGING: OnceLock<(PathBuf, PathBuf)> = OnceLock::new();
+
+/// Use the staging_dir from mirror.toml for the rest of this run. A relative staging_dir is
+/// within the mirror root.
+pub fn init(mirror_path: &Path, mirror: &ConfigMirror) {
+    let staging_dir = mirror
+        .staging_dir
+        .as_deref()
+        .unwrap_or(Path::new(DEFAULT_STAGING_DIR));
+    // Only one mirror is synced per run, so any later call is ignored.
+    let _ = STAGING.set((mirror_path.to_path_buf(), mirror_path.join(staging_dir)));
+}
+
+/// Get the directory partial downloads are written to.
+pub fn dir(mirror_path: &Path) -> PathBuf {
+    match STAGING.get() {
+        Some((_, staging_dir)) => staging_dir.clone(),
+        None => mirror_path.join(DEFAULT_STAGING_DIR),
+    }
+}
+
+/// Get the path to write a file to until it's complete and verified, which is `path` with
+/// `suffix` appended, in the staging directory.
+///
+/// Files outside the mirror, and files that are already in the staging directory, are staged
+/// next to where they'll end up.
+pub fn part_path(path: &Path, suffix: &str) -> PathBuf {
+    match STAGING.get() {
+        Some((mirror_path, staging_dir)) => staged_path(mirror_path, staging_dir, path, suffix),
+        None => append_to_path(path, suffix),
+    }
+}
+
+fn staged_path(mirror_path: &Path, staging_dir: &Path, path: &Path, suffix: &str) -> PathBuf {
+    if path.starts_with(staging_dir) {
+        return append_to_path(path, suffix);
+    }
+    match path.strip_prefix(mirror_path) {
+        Ok(relative) => append_to_path(&staging_dir.join(relative), suffix),
+        Err(_) => append_to_path(path, suffix),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod staged_path {
+        use std::path::Path;
+
+        use crate::staging::staged_path;
+
+        #[test]
+        fn in_mirror() {
+            let staged = staged_path(
+                Path::new("/srv/mirror"),
+                Path::new("/srv/mirror/.panamax-staging"),
+                Path::new("/srv/mirror/dist/2023-06-01/cargo.tar.xz"),
+                ".part",
+            );
+            assert_eq!(
+                staged,
+                Path::new("/srv/mirror/.panamax-staging/dist/2023-06-01/cargo.tar.xz.part")
+            );
+        }
+
+        #[test]
+        fn already_staged() {
+            let staged = staged_path(
+                Path::new("/srv/mirror"),
+                Path::new("/scratch"),
+                Path::new("/scratch/dist/channel-rust-stable.toml.part"),
+                ".part",
+            );
+            assert_eq!(
+                staged,
+                Path::new("/scratch/dist/channel-rust-stable.toml.part.part")
+            );
+        }
+
+        #[test]
+        fn outside_mirror() {
+            let staged = staged_path(
+                Path::new("/srv/mirror"),
+                Path::new("/scratch"),
+                Path::new("/home/user/vendor/serde.crate"),
+                ".part",
+            );
+            assert_eq!(staged, Path::new("/home/user/vendor/serde.crate.part"));
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::bundle::BUNDLES_FILE;
+use crate::changelog::SYNCS_FILE;
+use crate::checksums::CHECKSUMS_FILE;
+use crate::hashes::{find_sums_file, manifest_hash, HashAlgorithm};
+use crate::health::HEALTH_FILE;
+use crate::mirror::{ConfigMirror, MirrorError};
+use crate::progress_bar::{padded_prefix_message, progress_bar, StepsProgress};
+use crate::schedule::SCHEDULE_FILE;
+use crate::served::SERVED_FILE;
+use crate::validators::VALIDATORS_FILE;
+
+/// Prefix of the snapshot directories made by `panamax state export`.
+const SNAPSHOT_PREFIX: &str = "state-";
+
+/// Directory holding the mirror's state files, if mirror.toml moves them out of the mirror root.
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Whether a file is part of what panamax knows about a mirror, rather than mirrored content.
+///
+/// This is the channel history of the Rust releases kept, the checksum database, upstream's
+/// validators, what serve handed out recently, the maintenance schedule, the mirror's health,
+/// and the log of what each sync changed. Maintenance markers and shard coordination files
+/// only matter while a sync runs, so they aren't included.
+fn is_state_file(name: &str) -> bool {
+    is_history_file(name)
+        || [
+            CHECKSUMS_FILE,
+            VALIDATORS_FILE,
+            SERVED_FILE,
+            SCHEDULE_FILE,
+            HEALTH_FILE,
+            SYNCS_FILE,
+            BUNDLES_FILE,
+        ]
+        .contains(&name)
+}
+
+/// Whether a file is the history of a Rust release channel, e.g. `mirror-stable-history.toml`.
+pub fn is_history_file(name: &str) -> bool {
+    name.starts_with("mirror-") && name.ends_with("-history.toml") && !name.contains(['/', '\\'])
+}
+
+/// Use the state_dir from mirror.toml for the rest of this run, if it's set.
+///
+/// State files still in the mirror root, from before state_dir was set, are moved over.
+pub fn init(mirror_path: &Path, mirror: &ConfigMirror) -> io::Result<()> {
+    let Some(state_dir) = &mirror.state_dir else {
+        return Ok(());
+    };
+    fs::create_dir_all(state_dir)?;
+    for name in files(mirror_path)? {
+        let to = state_dir.join(&name);
+        if to.exists() {
+            eprintln!(
+                "{name} is in both the mirror and {}, using the one in {}.",
+                state_dir.display(),
+                state_dir.display()
+            );
+            continue;
+        }
+        // The state directory may be on another filesystem, so this can't just be a rename.
+        fs::copy(mirror_path.join(&name), &to)?;
+        fs::remove_file(mirror_path.join(&name))?;
+        eprintln!("Moved {name} to {}.", state_dir.display());
+    }
+
+    // Only one state directory is used per run, so any later call is ignored.
+    let _ = STATE_DIR.set(state_dir.clone());
+    Ok(())
+}
+
+/// Get the directory holding a mirror's state files.
+pub fn dir(mirror_path: &Path) -> PathBuf {
+    STATE_DIR
+        .get()
+        .cloned()
+        .unwrap_or_else(|| mirror_path.to_path_buf())
+}
+
+/// Get the path of one of a mirror's state files.
+pub fn path(mirror_path: &Path, name: &str) -> PathBuf {
+    dir(mirror_path).join(name)
+}
+
+/// Names of the state files in a directory, sorted.
+pub fn files(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_file() && is_state_file(&name) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn hash(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    let mut hasher = algorithm.hasher();
+    hasher.write_all(&fs::read(path)?)?;
+    Ok(hasher.finish())
+}
+
+/// Copy a mirror's state files to a new snapshot directory within `dest`, along with a manifest
+/// of their hashes, then remove all but the `keep` newest snapshots. Returns the snapshot's path.
+pub fn export(mirror_path: &Path, dest: &Path, keep: usize) -> Result<PathBuf, MirrorError> {
+    let state_dir = dir(mirror_path);
+    let snapshot = dest.join(format!(
+        "{SNAPSHOT_PREFIX}{}",
+        crate::timestamp::Timestamp::now().0
+    ));
+    if snapshot.exists() {
+        return Err(MirrorError::CmdLine(format!(
+            "{} already exists.",
+            snapshot.display()
+        )));
+    }
+    fs::create_dir_all(&snapshot)?;
+
+    let names = files(&state_dir)?;
+    let pb = progress_bar(names.len(), padded_prefix_message(1, 1, "Exporting state"));
+    let algorithm = manifest_hash();
+    let mut manifest = String::new();
+    for name in names {
+        let to = snapshot.join(&name);
+        fs::copy(state_dir.join(&name), &to)?;
+        // Hash the copy, so the manifest matches what was written even if a sync is running.
+        manifest.push_str(&format!("{}  {name}\n", hash(&to, algorithm)?));
+        pb.inc(1);
+    }
+    pb.finish();
+    fs::write(snapshot.join(algorithm.sums_file()), manifest)?;
+
+    let mut snapshots = snapshots(dest)?;
+    snapshots.reverse();
+    for old in snapshots.into_iter().skip(keep.max(1)) {
+        fs::remove_dir_all(&old)?;
+    }
+
+    Ok(snapshot)
+}
+
+/// The snapshot directories within `dir`, oldest first.
+fn snapshots(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut snapshots: Vec<(u64, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let time = name
+            .strip_prefix(SNAPSHOT_PREFIX)
+            .and_then(|time| time.parse().ok());
+        if let (Some(time), true) = (time, entry.file_type()?.is_dir()) {
+            snapshots.push((time, entry.path()));
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Restore a mirror's state files from a snapshot made by `export`, or from the newest
+/// snapshot if `src` is the directory given to `export`.
+///
+/// Every file is checked against the manifest before any are restored, so a damaged snapshot
+/// is never half restored.
+pub fn import(mirror_path: &Path, src: &Path) -> Result<PathBuf, MirrorError> {
+    let snapshot = if find_sums_file(src).is_some() {
+        src.to_path_buf()
+    } else {
+        snapshots(src)?.pop().ok_or_else(|| {
+            MirrorError::CmdLine(format!("No state snapshot found in {}.", src.display()))
+        })?
+    };
+
+    let Some((algorithm, manifest_path)) = find_sums_file(&snapshot) else {
+        return Err(MirrorError::StateSnapshot(format!(
+            "{} has no manifest",
+            snapshot.display()
+        )));
+    };
+    let manifest = fs::read_to_string(manifest_path)?;
+    let progress = StepsProgress::new(2, "Importing state");
+    let pb = progress_bar(
+        manifest.lines().count(),
+        padded_prefix_message(1, 2, "Checking snapshot"),
+    );
+    let mut names = Vec::new();
+    for line in manifest.lines() {
+        pb.inc(1);
+        let Some((hash, name)) = line.split_once("  ") else {
+            continue;
+        };
+        if !is_state_file(name) {
+            return Err(MirrorError::StateSnapshot(format!(
+                "{} lists {name}, which isn't a state file",
+                snapshot.display()
+            )));
+        }
+        if self::hash(&snapshot.join(name), algorithm)? != hash {
+            return Err(MirrorError::StateSnapshot(format!(
+                "{name} in {} doesn't match its checksum",
+                snapshot.display()
+            )));
+        }
+        names.push(name);
+    }
+    pb.finish();
+    progress.step_done();
+
+    let state_dir = dir(mirror_path);
+    fs::create_dir_all(&state_dir)?;
+    let pb = progress_bar(names.len(), padded_prefix_message(2, 2, "Restoring state"));
+    for name in names {
+        // Written next to the destination first, so a failed copy never leaves half a file.
+        let part_path = state_dir.join(format!("{name}.part"));
+        fs::copy(snapshot.join(name), &part_path)?;
+        fs::rename(part_path, state_dir.join(name))?;
+        pb.inc(1);
+    }
+    pb.finish();
+    progress.step_done();
+
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod is_state_file {
+        use crate::state::is_state_file;
+
+        #[test]
+        fn state_files() {
+            assert!(is_state_file(".panamax-checksums"));
+            assert!(is_state_file("mirror-stable-history.toml"));
+            assert!(is_state_file("mirror-nightly-2023-01-01-history.toml"));
+        }
+
+        #[test]
+        fn content() {
+            assert!(!is_state_file("mirror.toml"));
+            assert!(!is_state_file("crates.io-index"));
+            assert!(!is_state_file("channel-rust-stable.toml"));
+            assert!(!is_state_file("../mirror-stable-history.toml"));
+            assert!(!is_state_file("mirror-/../../etc/x-history.toml"));
+        }
+    }
+}
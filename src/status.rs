@@ -0,0 +1,180 @@
+use std::io::{BufRead, Cursor};
+use std::path::Path;
+
+use console::style;
+use git2::{ObjectType, Repository, TreeWalkMode, TreeWalkResult};
+use walkdir::WalkDir;
+
+use crate::crates::{has_crate_file, CrateEntry};
+use crate::mirror::{load_mirror_toml, MirrorError};
+use crate::progress_bar::progress_spinner;
+use crate::replica::Replica;
+use crate::rustup::get_channel_history;
+use crate::schedule::Schedule;
+use crate::timestamp::{format_display, Timestamp};
+
+/// Print how complete a mirror is: how many of the crate files referenced by its index are
+/// present, which Rust releases it keeps, when it was last synced, and how much space it takes up.
+pub fn status(path: &Path) -> Result<(), MirrorError> {
+    if !path.join("mirror.toml").exists() {
+        eprintln!(
+            "Mirror base not found! Run panamax init {} first.",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    crate::mirror::init_mirror(path, &load_mirror_toml(path)?.mirror)?;
+
+    let pb = progress_spinner("Inspecting mirror...".to_string());
+    let crates = crate_coverage(path)?;
+    let size = mirror_size(path);
+    pb.finish_and_clear();
+
+    println!("{}", style("Crates").bold());
+    match crates {
+        Some((0, _)) => println!("  The index doesn't reference any crates yet."),
+        Some((total, present)) => println!(
+            "  {present} of {total} crate files referenced by the index are present ({:.1}%).",
+            present as f64 * 100.0 / total as f64
+        ),
+        None => println!("  No crates.io-index repository has been synced yet."),
+    }
+
+    println!("{}", style("Rust releases").bold());
+    for channel in ["stable", "beta", "nightly"] {
+        let mut dates: Vec<String> = match get_channel_history(path, channel) {
+            Ok(history) => history.versions.into_keys().collect(),
+            Err(_) => Vec::new(),
+        };
+        dates.sort();
+        match (dates.first(), dates.last()) {
+            (Some(first), Some(last)) => println!(
+                "  {channel}: {} release(s), from {first} to {last}.",
+                dates.len()
+            ),
+            _ => println!("  {channel}: none."),
+        }
+    }
+
+    println!("{}", style("Syncs").bold());
+    match crate::changelog::last_sync(path) {
+        Some(finished) => println!("  Last sync finished {}.", format_display(finished)),
+        None => println!("  No syncs have been logged yet."),
+    }
+    match crate::replica::load(path) {
+        Some(Replica {
+            primary,
+            replicated: Some(replicated),
+        }) => println!(
+            "  Replica of {}, last replicated {}.",
+            primary.display(),
+            format_display(replicated)
+        ),
+        Some(Replica { primary, .. }) => println!(
+            "  Replica of {}, still being copied for the first time.",
+            primary.display()
+        ),
+        None => {}
+    }
+    if let Some(last_run) = Schedule::load(path).last_run() {
+        println!(
+            "  Scheduled maintenance last ran {}.",
+            format_display(last_run)
+        );
+    }
+
+    println!("{}", style("Health").bold());
+    match crate::health::current(path) {
+        Some(health) => {
+            let score = format!("{}/100", health.score);
+            let score = match health.healthy {
+                true => style(score).green(),
+                false => style(score).red(),
+            };
+            println!(
+                "  Score {score}, with a threshold of {}.",
+                crate::health::threshold()
+            );
+            println!(
+                "  {:.1}% of the last sync's downloads failed.",
+                health.failure_ratio * 100.0
+            );
+            match health.last_complete {
+                Timestamp(0) => println!("  No sync has finished completely yet."),
+                last_complete => println!(
+                    "  Last complete sync {}, {} day(s) ago.",
+                    format_display(last_complete),
+                    health.stale_days
+                ),
+            }
+            println!(
+                "  {} crate file(s) were missing when last verified.",
+                health.verification_errors
+            );
+        }
+        None => println!("  No sync has recorded the mirror's health yet."),
+    }
+
+    println!("{}", style("Disk usage").bold());
+    for (dir, bytes) in size {
+        println!("  {dir}: {:.2} GB", bytes as f64 / 1e9);
+    }
+
+    Ok(())
+}
+
+/// Count the crate files referenced by the served index, and how many of them are present.
+fn crate_coverage(path: &Path) -> Result<Option<(usize, usize)>, MirrorError> {
+    let repo_path = path.join("crates.io-index");
+    if !repo_path.join(".git").exists() {
+        return Ok(None);
+    }
+    let repo = Repository::open(repo_path)?;
+    let master_tree = match repo.find_reference("refs/heads/master") {
+        Ok(master) => master.peel_to_tree()?,
+        Err(_) => return Ok(None),
+    };
+
+    let mut total = 0;
+    let mut present = 0;
+    master_tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() != Some(ObjectType::Blob) || root.is_empty() || root.starts_with(".github")
+        {
+            return TreeWalkResult::Ok;
+        }
+        let Ok(blob) = repo.find_blob(entry.id()) else {
+            return TreeWalkResult::Ok;
+        };
+
+        for line in Cursor::new(blob.content()).lines().map_while(Result::ok) {
+            let Ok(crate_entry) = serde_json::from_str::<CrateEntry>(&line) else {
+                continue;
+            };
+            total += 1;
+            if has_crate_file(path, crate_entry.get_name(), crate_entry.get_vers()) {
+                present += 1;
+            }
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    Ok(Some((total, present)))
+}
+
+/// Add up the size of the files in each part of the mirror.
+fn mirror_size(path: &Path) -> Vec<(&'static str, u64)> {
+    ["dist", "rustup", "crates", "crates.io-index"]
+        .into_iter()
+        .map(|dir| {
+            let bytes = WalkDir::new(path.join(dir))
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter_map(|e| e.metadata().ok())
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+                .sum();
+            (dir, bytes)
+        })
+        .collect()
+}
@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::download::append_to_path;
+use crate::mirror::ConfigMirror;
+use crate::permissions;
+
+/// Files removed at once on NFS, each in its own thread, so the server's round trips overlap.
+const NFS_PARALLEL_REMOVES: usize = 16;
+
+/// What kind of filesystem the mirror is on, which decides how it's written to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// A local disk, or anything else that's cheap to check.
+    #[default]
+    Local,
+    /// An NFS share, where every operation on a file or directory is a round trip to the server.
+    Nfs,
+}
+
+/// The operations sync and maintenance change the mirror's files with.
+///
+/// Files are never written to in place: they're written next to their destination, or in the
+/// staging directory, and moved into place. Unless `fsync` is set in mirror.toml, none of the
+/// operations wait for data to reach the disk, as most mirrored files are checked against their
+/// hash, so a file lost in a crash is just downloaded again.
+///
+/// Copies use `fs::copy`, which on Linux asks the filesystem to do the copy, so it's a reflink
+/// on btrfs or XFS, and a server-side copy on NFS 4.2.
+pub trait Storage: Send + Sync {
+    /// Recursively create a directory, applying the configured mode and ownership to every
+    /// directory that didn't exist yet.
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Run `op` on a file, making sure its directory exists.
+    ///
+    /// By default, `op` is tried first, and only if the directory is missing is it created and
+    /// `op` tried again, as it usually exists already.
+    fn in_dir(&self, path: &Path, op: &mut dyn FnMut() -> io::Result<()>) -> io::Result<()> {
+        match op() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if let Some(parent) = path.parent() {
+                    self.create_dir_all(parent)?;
+                }
+                op()
+            }
+            res => res,
+        }
+    }
+
+    /// Create a file to write to, creating its directory if needed.
+    fn create(&self, path: &Path) -> io::Result<File> {
+        let mut file = None;
+        self.in_dir(path, &mut || {
+            file = Some(File::create(path)?);
+            Ok(())
+        })?;
+        permissions::apply_to_file(path)?;
+        Ok(file.expect("the file was created"))
+    }
+
+    /// Write a small file, like a .sha256 file, creating its directory if needed.
+    ///
+    /// The file is written next to its destination and then moved into place, so it's never
+    /// seen half written, and hard links to the old file, like those in a published snapshot,
+    /// keep the old contents.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let part_path = append_to_path(path, ".part");
+        self.in_dir(path, &mut || fs::write(&part_path, contents))?;
+        permissions::apply_to_file(&part_path)?;
+        if fsync() {
+            sync_file(&part_path)?;
+        }
+        fs::rename(part_path, path)?;
+        if fsync() {
+            sync_parent(path)?;
+        }
+        Ok(())
+    }
+
+    /// Move a file into place, creating its directory if needed. If it's staged on another
+    /// filesystem, it's copied next to its destination first, so it still appears there all
+    /// at once.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        // The data has to be on disk before the rename is, or a crash can leave an empty file
+        // at `to`.
+        if fsync() {
+            sync_file(from)?;
+        }
+        match self.in_dir(to, &mut || fs::rename(from, to)) {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                let copy_path = append_to_path(to, ".part");
+                fs::copy(from, &copy_path)?;
+                permissions::apply_to_file(&copy_path)?;
+                if fsync() {
+                    sync_file(&copy_path)?;
+                }
+                fs::rename(&copy_path, to)?;
+                fs::remove_file(from)?;
+            }
+            res => {
+                res?;
+                // A file staged in another directory still has that directory's label and ACL.
+                if from.parent() != to.parent() {
+                    if let Err(e) = permissions::inherit_from_dir(to) {
+                        permissions::warn_inherit_failed(to, &e);
+                    }
+                }
+            }
+        }
+        if fsync() {
+            sync_parent(to)?;
+        }
+        Ok(())
+    }
+
+    /// Copy a file, creating `to`'s directory if needed.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.in_dir(to, &mut || fs::copy(from, to).map(|_| ()))?;
+        permissions::apply_to_file(to)?;
+        if fsync() {
+            sync_file(to)?;
+            sync_parent(to)?;
+        }
+        Ok(())
+    }
+
+    /// Remove files, returning whether each one was removed, in the same order.
+    fn remove_files(&self, paths: &[PathBuf]) -> Vec<io::Result<()>> {
+        paths.iter().map(fs::remove_file).collect()
+    }
+}
+
+/// Whether files are flushed to disk as they're moved into place, from `fsync` in mirror.toml.
+static FSYNC: AtomicBool = AtomicBool::new(false);
+
+fn fsync() -> bool {
+    FSYNC.load(Ordering::Relaxed)
+}
+
+/// Wait for a file's data to reach the disk.
+fn sync_file(path: &Path) -> io::Result<()> {
+    // Windows can only flush a file that's open for writing.
+    let file = if cfg!(windows) {
+        File::options().write(true).open(path)?
+    } else {
+        File::open(path)?
+    };
+    file.sync_all()
+}
+
+/// Wait for the directory entry of a file that was just created or renamed to reach the disk.
+/// Windows has no way to flush a directory, and doesn't need one.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if cfg!(unix) => File::open(parent)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+/// A mirror on a local disk.
+struct Local;
+
+impl Storage for Local {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        permissions::create_dir_all(dir)
+    }
+}
+
+/// A mirror on an NFS share.
+///
+/// Directories are remembered once they're known to exist, so they're only checked or created
+/// once per run, and a file in a new directory doesn't cost a failed operation first. Files
+/// are removed many at a time, which is most of the time maintenance and cleaning up old
+/// releases take.
+#[derive(Default)]
+struct Nfs {
+    dirs: Mutex<HashSet<PathBuf>>,
+}
+
+impl Storage for Nfs {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        if self
+            .dirs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(dir)
+        {
+            return Ok(());
+        }
+        permissions::create_dir_all(dir)?;
+        let mut dirs = self.dirs.lock().unwrap_or_else(|e| e.into_inner());
+        dirs.extend(dir.ancestors().map(Path::to_path_buf));
+        Ok(())
+    }
+
+    fn in_dir(&self, path: &Path, op: &mut dyn FnMut() -> io::Result<()>) -> io::Result<()> {
+        let Some(parent) = path.parent() else {
+            return op();
+        };
+        self.create_dir_all(parent)?;
+        match op() {
+            // The directory was removed since it was seen, e.g. by cleaning up old releases.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut dirs = self.dirs.lock().unwrap_or_else(|e| e.into_inner());
+                dirs.retain(|dir| !dir.starts_with(parent));
+                drop(dirs);
+                self.create_dir_all(parent)?;
+                op()
+            }
+            res => res,
+        }
+    }
+
+    fn remove_files(&self, paths: &[PathBuf]) -> Vec<io::Result<()>> {
+        let chunk_size = paths.len().div_ceil(NFS_PARALLEL_REMOVES).max(1);
+        std::thread::scope(|scope| {
+            let removes: Vec<_> = paths
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || chunk.iter().map(fs::remove_file).collect::<Vec<_>>())
+                })
+                .collect();
+            removes
+                .into_iter()
+                .flat_map(|remove| remove.join().expect("removing files shouldn't panic"))
+                .collect()
+        })
+    }
+}
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+/// Use the storage set in mirror.toml for the rest of this run.
+pub fn init(mirror: &ConfigMirror) {
+    let storage: Box<dyn Storage> = match mirror.storage.unwrap_or_default() {
+        StorageKind::Local => Box::new(Local),
+        StorageKind::Nfs => Box::<Nfs>::default(),
+    };
+    FSYNC.store(mirror.fsync.unwrap_or(false), Ordering::Relaxed);
+    // Only one mirror is synced per run, so any later call is ignored.
+    let _ = STORAGE.set(storage);
+}
+
+/// Get the storage the mirror is on. This is a local disk unless `init` was told otherwise.
+pub fn get() -> &'static dyn Storage {
+    match STORAGE.get() {
+        Some(storage) => storage.as_ref(),
+        None => &Local,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod storage {
+        use std::fs;
+
+        use crate::storage::{Local, Nfs, Storage};
+
+        #[test]
+        fn both_kinds() {
+            let storages: [(&str, &dyn Storage); 2] = [("local", &Local), ("nfs", &Nfs::default())];
+            for (name, storage) in storages {
+                let tmp = tempfile::Builder::new()
+                    .prefix(&format!("panamax-storage-{name}"))
+                    .tempdir()
+                    .unwrap();
+                let dir = tmp.path();
+                let file = dir.join("crates/se/rd/serde-1.0.0.crate");
+                storage.write(&file, b"serde").unwrap();
+                assert_eq!(fs::read(&file).unwrap(), b"serde");
+
+                let copied = dir.join("dist/2023-06-01/serde.crate");
+                storage.copy(&file, &copied).unwrap();
+                let moved = dir.join("crates/se/rd/serde/serde-1.0.0.crate");
+                storage.rename(&copied, &moved).unwrap();
+                assert_eq!(fs::read(&moved).unwrap(), b"serde");
+
+                let removed = storage.remove_files(&[file.clone(), moved.clone(), copied]);
+                assert!(removed[0].is_ok() && removed[1].is_ok() && removed[2].is_err());
+                assert!(!file.exists() && !moved.exists());
+            }
+        }
+    }
+
+    mod fsync {
+        use std::fs;
+
+        use crate::storage::{sync_file, sync_parent};
+
+        #[test]
+        fn file_and_dir() {
+            let dir = tempfile::tempdir().unwrap();
+            let file = dir.path().join("serde-1.0.0.crate");
+            fs::write(&file, "serde").unwrap();
+            sync_file(&file).unwrap();
+            sync_parent(&file).unwrap();
+            assert!(sync_file(&dir.path().join("missing")).is_err());
+        }
+    }
+}
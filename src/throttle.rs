@@ -0,0 +1,245 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::mirror::ConfigMirror;
+use crate::timestamp::{self, TimeOfDay, Timestamp};
+
+/// How often downloads check whether the schedule has moved to another limit.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A time of day when a different bandwidth limit applies, from bandwidth_schedule in
+/// mirror.toml.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BandwidthWindow {
+    /// Start of the window, in display_time_zone.
+    pub from: TimeOfDay,
+    /// End of the window. If it's earlier than `from`, the window goes past midnight.
+    pub to: TimeOfDay,
+    /// Bytes per second, or 0 for no limit.
+    pub limit: u64,
+}
+
+impl BandwidthWindow {
+    fn contains(&self, time: TimeOfDay) -> bool {
+        match self.from.cmp(&self.to) {
+            std::cmp::Ordering::Less => self.from <= time && time < self.to,
+            std::cmp::Ordering::Greater => self.from <= time || time < self.to,
+            std::cmp::Ordering::Equal => true,
+        }
+    }
+}
+
+/// A token bucket, shared by every download in a sync.
+struct Bucket {
+    /// Bytes per second.
+    rate: u64,
+    /// Bytes that can be downloaded right away. Goes negative when downloads run ahead of
+    /// the limit, and those downloads then wait for it to refill.
+    available: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Bucket {
+            rate,
+            available: rate as f64,
+            last: now,
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before they're allowed.
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let rate = self.rate as f64;
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        // At most one second's worth of bytes can build up, to limit bursts.
+        self.available = (self.available + elapsed * rate).min(rate) - bytes as f64;
+        self.last = now;
+
+        if self.available < 0.0 {
+            Duration::from_secs_f64(-self.available / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// The limit on all downloads, which can change with the time of day during a long sync.
+struct Throttle {
+    /// The limit outside of the schedule's windows.
+    limit: Option<u64>,
+    schedule: Vec<BandwidthWindow>,
+    bucket: Option<Bucket>,
+    /// When the schedule was last checked.
+    checked: Option<Instant>,
+}
+
+impl Throttle {
+    /// The limit at a time of day: that of the first window of the schedule it falls in, or
+    /// else the usual one.
+    fn limit_at(&self, time: TimeOfDay) -> Option<u64> {
+        match self.schedule.iter().find(|window| window.contains(time)) {
+            Some(window) => Some(window.limit),
+            None => self.limit,
+        }
+        .filter(|&rate| rate > 0)
+    }
+
+    /// Switch to the limit for `time`, if it isn't the one in use already.
+    fn update(&mut self, time: TimeOfDay, now: Instant) {
+        let limit = self.limit_at(time);
+        if limit == self.bucket.as_ref().map(|bucket| bucket.rate) {
+            return;
+        }
+        if self.checked.is_some() {
+            match limit {
+                Some(rate) => info!("Bandwidth schedule: limiting downloads to {rate} bytes/s"),
+                None => info!("Bandwidth schedule: no longer limiting downloads"),
+            }
+        }
+        self.bucket = limit.map(|rate| Bucket::new(rate, now));
+    }
+}
+
+static THROTTLE: Mutex<Throttle> = Mutex::new(Throttle {
+    limit: None,
+    schedule: Vec::new(),
+    bucket: None,
+    checked: None,
+});
+
+/// Use the bandwidth_schedule from mirror.toml for the rest of this run.
+pub fn init(mirror: &ConfigMirror) {
+    let mut throttle = THROTTLE.lock().unwrap_or_else(|e| e.into_inner());
+    throttle.schedule = mirror.bandwidth_schedule.clone().unwrap_or_default();
+}
+
+/// Limit all downloads to `bytes_per_sec` from now on, or remove the limit with None, outside
+/// of the windows of the bandwidth schedule.
+///
+/// The rustup and crates sections of a sync run one after the other, so this is set at the
+/// start of each with its own limit.
+pub fn set_limit(bytes_per_sec: Option<u64>) {
+    let mut throttle = THROTTLE.lock().unwrap_or_else(|e| e.into_inner());
+    throttle.limit = bytes_per_sec;
+    throttle.checked = None;
+    let time = timestamp::local_time_of_day(Timestamp::now());
+    throttle.update(time, Instant::now());
+}
+
+/// Wait until `bytes` more can be downloaded without going over the limit.
+///
+/// The schedule is checked every second or so, so a sync that runs into a window slows down
+/// (or speeds up) as soon as it starts.
+pub async fn consume(bytes: usize) {
+    let wait = {
+        let mut throttle = THROTTLE.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let due = throttle
+            .checked
+            .is_none_or(|checked| now.duration_since(checked) >= SCHEDULE_CHECK_INTERVAL);
+        if due && !throttle.schedule.is_empty() {
+            let time = timestamp::local_time_of_day(Timestamp::now());
+            throttle.update(time, now);
+            throttle.checked = Some(now);
+        }
+        match throttle.bucket.as_mut() {
+            Some(bucket) => bucket.take(bytes, now),
+            None => return,
+        }
+    };
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod schedule {
+        use std::time::Instant;
+
+        use crate::throttle::{BandwidthWindow, Throttle};
+
+        fn window(from: &str, to: &str, limit: u64) -> BandwidthWindow {
+            BandwidthWindow {
+                from: from.parse().unwrap(),
+                to: to.parse().unwrap(),
+                limit,
+            }
+        }
+
+        #[test]
+        fn windows() {
+            let throttle = Throttle {
+                limit: Some(1000),
+                schedule: vec![window("08:00", "18:00", 500), window("22:00", "06:00", 0)],
+                bucket: None,
+                checked: None,
+            };
+            let at = |time: &str| throttle.limit_at(time.parse().unwrap());
+            assert_eq!(at("08:00"), Some(500));
+            assert_eq!(at("17:59"), Some(500));
+            assert_eq!(at("18:00"), Some(1000));
+            // Windows can go past midnight, and a limit of 0 lifts the limit.
+            assert_eq!(at("23:00"), None);
+            assert_eq!(at("05:00"), None);
+            assert_eq!(at("06:00"), Some(1000));
+        }
+
+        #[test]
+        fn switches_limit() {
+            let mut throttle = Throttle {
+                limit: None,
+                schedule: vec![window("08:00", "18:00", 500)],
+                bucket: None,
+                checked: None,
+            };
+            let now = Instant::now();
+            throttle.update("07:59".parse().unwrap(), now);
+            assert!(throttle.bucket.is_none());
+            throttle.update("08:00".parse().unwrap(), now);
+            assert_eq!(
+                throttle.bucket.as_ref().map(|bucket| bucket.rate),
+                Some(500)
+            );
+            throttle.update("18:00".parse().unwrap(), now);
+            assert!(throttle.bucket.is_none());
+        }
+    }
+
+    mod bucket {
+        use std::time::{Duration, Instant};
+
+        use crate::throttle::Bucket;
+
+        #[test]
+        fn waits_once_over_the_limit() {
+            let start = Instant::now();
+            let mut bucket = Bucket::new(1000, start);
+            assert_eq!(bucket.take(1000, start), Duration::ZERO);
+            assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+            // Another download has to wait behind the first.
+            assert_eq!(bucket.take(500, start), Duration::from_secs(1));
+        }
+
+        #[test]
+        fn refills_over_time() {
+            let start = Instant::now();
+            let mut bucket = Bucket::new(1000, start);
+            bucket.take(1000, start);
+            assert_eq!(
+                bucket.take(500, start + Duration::from_millis(500)),
+                Duration::ZERO
+            );
+            // Idle time doesn't build up more than a second's worth.
+            assert_eq!(
+                bucket.take(1500, start + Duration::from_secs(10)),
+                Duration::from_millis(500)
+            );
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::mirror::ConfigMirror;
+
+pub const DAY: u64 = 24 * 60 * 60;
+
+/// A point in time, as seconds since the UNIX epoch, which is what any math on it is done with.
+///
+/// Timestamps are recorded in state files, events and reports as RFC 3339 dates in UTC, e.g.
+/// `2023-06-01T12:34:56Z`. Files written by older versions have seconds since the UNIX epoch
+/// instead, which are still read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Timestamp(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        )
+    }
+
+    /// Parse an RFC 3339 date, such as `2023-06-01T12:34:56Z` or `2023-06-01T14:34:56.5+02:00`.
+    /// Fractions of a second are dropped.
+    pub fn parse_rfc3339(s: &str) -> Option<Self> {
+        let (date, time) = s.split_once(['T', 't', ' '])?;
+        let days = parse_date(date)?;
+
+        let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+            Some(time) => (time, UtcOffset::UTC),
+            None => {
+                let split = time.rfind(['+', '-'])?;
+                (&time[..split], time[split..].parse().ok()?)
+            }
+        };
+        let time = time.split_once('.').map_or(time, |(time, fraction)| {
+            if fraction.bytes().all(|b| b.is_ascii_digit()) {
+                time
+            } else {
+                ""
+            }
+        });
+        let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+        let (hour, minute, second) = (parts.next()??, parts.next()??, parts.next()??);
+        // A leap second is allowed, and counted as the first second of the next minute.
+        if parts.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+
+        let local = days * DAY + hour * 3600 + minute * 60 + second;
+        let utc = local.checked_add_signed(-offset.seconds)?;
+        Some(Timestamp(utc))
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (year, month, day) = civil_from_days(self.0 / DAY);
+        let seconds = self.0 % DAY;
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Recorded {
+            Seconds(u64),
+            Rfc3339(String),
+        }
+
+        match Recorded::deserialize(deserializer)? {
+            Recorded::Seconds(seconds) => Ok(Timestamp(seconds)),
+            Recorded::Rfc3339(s) => Timestamp::parse_rfc3339(&s)
+                .ok_or_else(|| serde::de::Error::custom(format!("{s} is not an RFC 3339 date"))),
+        }
+    }
+}
+
+/// A fixed offset from UTC, such as `+02:00`, or `UTC` itself.
+///
+/// Without a time zone database, daylight saving time isn't followed, so the offset needs
+/// changing by hand when the clocks do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UtcOffset {
+    seconds: i64,
+}
+
+impl UtcOffset {
+    pub const UTC: UtcOffset = UtcOffset { seconds: 0 };
+}
+
+impl FromStr for UtcOffset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("UTC") || s.eq_ignore_ascii_case("Z") {
+            return Ok(Self::UTC);
+        }
+        let invalid = || format!("{s} is not UTC or an offset from it like +02:00");
+        let (sign, offset) = match s.split_at_checked(1) {
+            Some(("+", offset)) => (1, offset),
+            Some(("-", offset)) => (-1, offset),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = offset.split_once(':').ok_or_else(invalid)?;
+        let two_digits = |part: &str| part.len() == 2 && part.bytes().all(|b| b.is_ascii_digit());
+        if !two_digits(hours) || !two_digits(minutes) {
+            return Err(invalid());
+        }
+        let (Ok(hours), Ok(minutes)) = (hours.parse::<i64>(), minutes.parse::<i64>()) else {
+            return Err(invalid());
+        };
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(UtcOffset {
+            seconds: sign * (hours * 3600 + minutes * 60),
+        })
+    }
+}
+
+impl TryFrom<String> for UtcOffset {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for UtcOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.seconds == 0 {
+            return write!(f, "UTC");
+        }
+        let sign = if self.seconds < 0 { '-' } else { '+' };
+        let minutes = self.seconds.abs() / 60;
+        write!(f, "{sign}{:02}:{:02}", minutes / 60, minutes % 60)
+    }
+}
+
+impl From<UtcOffset> for String {
+    fn from(offset: UtcOffset) -> Self {
+        offset.to_string()
+    }
+}
+
+/// A time of day as `HH:MM`, in minutes since midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    minutes: u32,
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{s} is not a time of day like 08:00");
+        let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+        let two_digits = |part: &str| part.len() == 2 && part.bytes().all(|b| b.is_ascii_digit());
+        if !two_digits(hours) || !two_digits(minutes) {
+            return Err(invalid());
+        }
+        let (Ok(hours), Ok(minutes)) = (hours.parse::<u32>(), minutes.parse::<u32>()) else {
+            return Err(invalid());
+        };
+        // 24:00 is allowed, for the end of the day.
+        if hours * 60 + minutes > 24 * 60 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+/// The time zone that times are shown in, and that days are counted in for scheduling.
+static DISPLAY_TIME_ZONE: OnceLock<UtcOffset> = OnceLock::new();
+
+/// Use the display_time_zone from mirror.toml for the rest of this run.
+pub fn init(mirror: &ConfigMirror) {
+    // Only one mirror is used per run, so any later call is ignored.
+    let _ = DISPLAY_TIME_ZONE.set(mirror.display_time_zone.unwrap_or_default());
+}
+
+fn display_time_zone() -> UtcOffset {
+    DISPLAY_TIME_ZONE.get().copied().unwrap_or_default()
+}
+
+/// Seconds since the UNIX epoch, as the wall clock reads in a time zone.
+fn local_seconds(time: Timestamp, zone: UtcOffset) -> u64 {
+    time.0.saturating_add_signed(zone.seconds)
+}
+
+/// Format a time as `YYYY-MM-DD HH:MM`, followed by the display time zone.
+pub fn format_display(time: Timestamp) -> String {
+    format_in(time, display_time_zone())
+}
+
+fn format_in(time: Timestamp, zone: UtcOffset) -> String {
+    let local = local_seconds(time, zone);
+    let (year, month, day) = civil_from_days(local / DAY);
+    let seconds = local % DAY;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} {zone}",
+        seconds / 3600,
+        seconds / 60 % 60
+    )
+}
+
+/// The calendar day a time falls on in the display time zone, as days since the UNIX epoch.
+pub fn local_day(time: Timestamp) -> u64 {
+    local_seconds(time, display_time_zone()) / DAY
+}
+
+/// The time of day the wall clock reads in the display time zone.
+pub fn local_time_of_day(time: Timestamp) -> TimeOfDay {
+    TimeOfDay {
+        minutes: (local_seconds(time, display_time_zone()) % DAY / 60) as u32,
+    }
+}
+
+/// When a calendar day starts in the display time zone. `days` is days since the UNIX epoch.
+pub fn local_midnight(days: u64) -> Timestamp {
+    Timestamp((days * DAY).saturating_add_signed(-display_time_zone().seconds))
+}
+
+/// Parse a date as `YYYY-MM-DD`, returning days since the UNIX epoch. Dates before 1970 aren't
+/// accepted.
+pub fn parse_date(s: &str) -> Option<u64> {
+    let mut parts = s.split('-').map(|part| part.parse::<u64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some()
+        || year < 1970
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+    {
+        return None;
+    }
+    Some(days_from_civil(year, month, day))
+}
+
+/// Write a date as `YYYY-MM-DD`, if it's a date at all, so the same date is always written the
+/// same way, e.g. in channel histories, where the dates are compared as strings.
+pub fn normalize_date(s: &str) -> String {
+    match parse_date(s) {
+        Some(days) => {
+            let (year, month, day) = civil_from_days(days);
+            format!("{year:04}-{month:02}-{day:02}")
+        }
+        None => s.to_string(),
+    }
+}
+
+/// Days since the UNIX epoch of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146097 + day_of_era).saturating_sub(719468)
+}
+
+/// The date of a number of days since the UNIX epoch, as (year, month, day).
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod timestamp {
+        use crate::timestamp::{normalize_date, parse_date, Timestamp, DAY};
+
+        // 2023-06-01 12:34:56 UTC.
+        const TIME: Timestamp = Timestamp(1685622896);
+
+        #[test]
+        fn rfc3339() {
+            assert_eq!(TIME.to_string(), "2023-06-01T12:34:56Z");
+            assert_eq!(Timestamp(0).to_string(), "1970-01-01T00:00:00Z");
+            assert_eq!(Timestamp::parse_rfc3339("2023-06-01T12:34:56Z"), Some(TIME));
+            assert_eq!(
+                Timestamp::parse_rfc3339("2023-06-01T14:34:56.789+02:00"),
+                Some(TIME)
+            );
+            assert_eq!(
+                Timestamp::parse_rfc3339("2023-06-01 07:04:56-05:30"),
+                Some(TIME)
+            );
+            assert_eq!(Timestamp::parse_rfc3339("2023-06-01T12:34Z"), None);
+            assert_eq!(Timestamp::parse_rfc3339("2023-06-01"), None);
+        }
+
+        #[test]
+        fn recorded() {
+            let json = serde_json::to_string(&TIME).unwrap();
+            assert_eq!(json, "\"2023-06-01T12:34:56Z\"");
+            assert_eq!(serde_json::from_str::<Timestamp>(&json).unwrap(), TIME);
+            // Older versions recorded seconds since the UNIX epoch.
+            assert_eq!(
+                serde_json::from_str::<Timestamp>("1685622896").unwrap(),
+                TIME
+            );
+            assert!(serde_json::from_str::<Timestamp>("\"yesterday\"").is_err());
+        }
+
+        #[test]
+        fn dates() {
+            assert_eq!(parse_date("1970-01-02"), Some(1));
+            assert_eq!(parse_date("2023-06-01"), Some(TIME.0 / DAY));
+            assert_eq!(parse_date("2023-13-01"), None);
+            assert_eq!(parse_date("0000-01-01"), None);
+            assert_eq!(normalize_date("2023-6-1"), "2023-06-01");
+            assert_eq!(normalize_date("2023-06-01"), "2023-06-01");
+            assert_eq!(normalize_date("1.70.0"), "1.70.0");
+        }
+    }
+
+    mod time_of_day {
+        use crate::timestamp::TimeOfDay;
+
+        #[test]
+        fn parse() {
+            assert_eq!("08:30".parse(), Ok(TimeOfDay { minutes: 510 }));
+            assert_eq!("24:00".parse(), Ok(TimeOfDay { minutes: 1440 }));
+            assert_eq!(TimeOfDay { minutes: 65 }.to_string(), "01:05");
+            assert!("8:30".parse::<TimeOfDay>().is_err());
+            assert!("24:30".parse::<TimeOfDay>().is_err());
+            assert!("08:60".parse::<TimeOfDay>().is_err());
+        }
+    }
+
+    mod utc_offset {
+        use crate::timestamp::{format_in, Timestamp, UtcOffset};
+
+        #[test]
+        fn parse() {
+            assert_eq!("UTC".parse(), Ok(UtcOffset::UTC));
+            assert_eq!("+00:00".parse(), Ok(UtcOffset::UTC));
+            assert_eq!("+02:00".parse(), Ok(UtcOffset { seconds: 7200 }));
+            assert_eq!("-05:30".parse(), Ok(UtcOffset { seconds: -19800 }));
+            assert!("Europe/Berlin".parse::<UtcOffset>().is_err());
+            assert!("+24:00".parse::<UtcOffset>().is_err());
+            assert!("+-1:00".parse::<UtcOffset>().is_err());
+        }
+
+        #[test]
+        fn display() {
+            let time = Timestamp(1685622896);
+            assert_eq!(format_in(time, UtcOffset::UTC), "2023-06-01 12:34 UTC");
+            assert_eq!(
+                format_in(time, "+02:00".parse().unwrap()),
+                "2023-06-01 14:34 +02:00"
+            );
+            assert_eq!(
+                format_in(time, "-13:00".parse().unwrap()),
+                "2023-05-31 23:34 -13:00"
+            );
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+use url::Url;
+
+#[derive(Error, Debug)]
+pub enum PathError {
+    #[error("{0} is not an http or https URL")]
+    NotHttp(String),
+
+    #[error("{0} has an empty, `.` or `..` segment, or an encoded or invalid character")]
+    Unsafe(String),
+}
+
+/// The path of a file within the mirror, relative to its root, such as
+/// `dist/2023-06-01/cargo-1.70.0-x86_64-unknown-linux-gnu.tar.xz`.
+///
+/// This is also the file's path upstream and when served, so it's what URLs are mapped to and
+/// from. Every segment is checked, so it can't point outside the mirror: none are empty, `.` or
+/// `..`, or contain a path separator, a NUL byte or a percent sign.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MirrorPath(String);
+
+impl MirrorPath {
+    /// Check a path relative to the mirror root, with `/` between segments.
+    pub fn parse(path: &str) -> Result<Self, PathError> {
+        if path.split('/').all(is_safe_segment) {
+            Ok(MirrorPath(path.to_string()))
+        } else {
+            Err(PathError::Unsafe(path.to_string()))
+        }
+    }
+
+    /// Get the path a file from an absolute upstream URL is mirrored at, which is the URL's path,
+    /// e.g. `dist/2023-06-01/cargo.tar.xz` for `https://static.rust-lang.org/dist/2023-06-01/cargo.tar.xz`.
+    /// Any query string or fragment is left out.
+    ///
+    /// The path is checked as written, rather than as URL parsing would resolve it, so a
+    /// manifest can't reach `mirror.toml` with `dist/../mirror.toml`.
+    pub fn from_url(url: &str) -> Result<Self, PathError> {
+        let parsed = Url::parse(url).map_err(|_| PathError::NotHttp(url.to_string()))?;
+        let after_host = url
+            .strip_prefix(parsed.scheme())
+            .and_then(|rest| rest.strip_prefix("://"))
+            .filter(|_| matches!(parsed.scheme(), "http" | "https"))
+            .ok_or_else(|| PathError::NotHttp(url.to_string()))?;
+        let path = after_host
+            .split_once('/')
+            .map_or("", |(_, path)| path)
+            .split(['?', '#'])
+            .next()
+            .unwrap_or_default();
+        decode(path, false).ok_or_else(|| PathError::Unsafe(url.to_string()))
+    }
+
+    /// Get the path a (percent-encoded) request is for.
+    ///
+    /// Each segment is decoded once. Empty segments, as in `/dist/`, are skipped, so `/` is the
+    /// mirror root.
+    pub fn from_request(path: &str) -> Result<Self, PathError> {
+        decode(path, true).ok_or_else(|| PathError::Unsafe(path.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Get where the file is on disk, in the mirror at `root`.
+    pub fn on_disk(&self, root: &Path) -> PathBuf {
+        self.0
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .fold(root.to_path_buf(), |path, segment| path.join(segment))
+    }
+}
+
+impl fmt::Display for MirrorPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Decode each segment of a percent-encoded path, checking it's safe.
+fn decode(path: &str, skip_empty: bool) -> Option<MirrorPath> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        if skip_empty && segment.is_empty() {
+            continue;
+        }
+        let segment = percent_decode(segment);
+        if !is_safe_segment(&segment) {
+            return None;
+        }
+        segments.push(segment);
+    }
+    Some(MirrorPath(segments.join("/")))
+}
+
+/// Check a decoded segment of a path. Nothing mirrored legitimately has a segment that fails
+/// this, and a percent sign left after decoding would mean the path was encoded twice.
+fn is_safe_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment != "."
+        && segment != ".."
+        && !segment.contains(['/', '\\', '\0', '%'])
+}
+
+/// Decode a percent-encoded URL path. Invalid escapes are left as-is.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = &bytes[i + 1..i + 3];
+            if hex.iter().all(u8::is_ascii_hexdigit) {
+                let hex = std::str::from_utf8(hex).expect("hex digits are valid UTF-8");
+                out.push(u8::from_str_radix(hex, 16).expect("hex digits are valid hex"));
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+
+    mod mirror_path {
+        use std::path::Path;
+
+        use crate::url_path::MirrorPath;
+
+        #[test]
+        fn from_url() {
+            let path = MirrorPath::from_url(
+                "https://static.rust-lang.org/dist/2023-06-01/cargo-1.70.0-x86_64-unknown-linux-gnu.tar.xz",
+            )
+            .unwrap();
+            assert_eq!(
+                path.as_str(),
+                "dist/2023-06-01/cargo-1.70.0-x86_64-unknown-linux-gnu.tar.xz"
+            );
+            assert_eq!(
+                path.on_disk(Path::new("/srv/mirror")),
+                Path::new(
+                    "/srv/mirror/dist/2023-06-01/cargo-1.70.0-x86_64-unknown-linux-gnu.tar.xz"
+                )
+            );
+
+            // Where a file is mirrored doesn't depend on the upstream host, or any query string.
+            assert_eq!(
+                MirrorPath::from_url("http://mirror.example:8080/dist/cargo.tar.xz?token=abc")
+                    .unwrap()
+                    .as_str(),
+                "dist/cargo.tar.xz"
+            );
+        }
+
+        #[test]
+        fn unsafe_urls() {
+            assert!(MirrorPath::from_url("dist/2023-06-01/cargo.tar.xz").is_err());
+            assert!(MirrorPath::from_url("file:///etc/passwd").is_err());
+            assert!(MirrorPath::from_url("https://static.rust-lang.org/").is_err());
+            assert!(
+                MirrorPath::from_url("https://static.rust-lang.org/dist//cargo.tar.xz").is_err()
+            );
+            assert!(
+                MirrorPath::from_url("https://static.rust-lang.org/dist/..%2fmirror.toml").is_err()
+            );
+            assert!(
+                MirrorPath::from_url("https://static.rust-lang.org/dist/../mirror.toml").is_err()
+            );
+            assert!(
+                MirrorPath::from_url("https://static.rust-lang.org/dist/%2e%2e/mirror.toml")
+                    .is_err()
+            );
+        }
+
+        #[test]
+        fn parse() {
+            assert!(MirrorPath::parse("dist/channel-rust-stable.toml").is_ok());
+            assert!(MirrorPath::parse("dist/channel-rust-../../etc.toml").is_err());
+            assert!(MirrorPath::parse("/dist/channel-rust-stable.toml").is_err());
+            assert!(MirrorPath::parse("").is_err());
+        }
+
+        #[test]
+        fn from_request() {
+            assert_eq!(MirrorPath::from_request("/").unwrap().as_str(), "");
+            assert_eq!(
+                MirrorPath::from_request("/dist/2023-06-01/rust%2Dsrc.tar.xz")
+                    .unwrap()
+                    .as_str(),
+                "dist/2023-06-01/rust-src.tar.xz"
+            );
+            assert!(MirrorPath::from_request("/dist/%2e%2e/mirror.toml").is_err());
+        }
+    }
+}
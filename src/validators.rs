@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use reqwest::header::{
+    HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::state;
+
+/// Name of the file holding the validators of mirrored manifests, in the state directory.
+pub const VALIDATORS_FILE: &str = ".panamax-validators";
+
+/// What upstream said identifies a version of a file, so it can be asked for only if it has
+/// changed since.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Get the validators from a response's headers, if it has any.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        (validators != Validators::default()).then_some(validators)
+    }
+
+    /// Headers that make a request conditional on the file having changed.
+    pub fn request_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        // If-None-Match takes precedence when both are sent, but not every server sends an ETag.
+        let mut insert = |name, value: &Option<String>| {
+            if let Some(value) = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(name, value);
+            }
+        };
+        insert(IF_NONE_MATCH, &self.etag);
+        insert(IF_MODIFIED_SINCE, &self.last_modified);
+        headers
+    }
+}
+
+/// The validators of every manifest in the mirror, by URL.
+struct Store {
+    path: PathBuf,
+    entries: BTreeMap<String, Validators>,
+}
+
+static STORE: OnceLock<Mutex<Store>> = OnceLock::new();
+
+/// Load the validators of a mirror. A missing or unreadable file is treated as empty, which
+/// only means every manifest is downloaded again.
+pub fn open(mirror_path: &Path) {
+    let path = state::path(mirror_path, VALIDATORS_FILE);
+    let entries = fs::read(&path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+
+    // Only one store is open per run, so any later call is ignored.
+    let _ = STORE.set(Mutex::new(Store { path, entries }));
+}
+
+/// Get the validators last seen for a URL. Always None if no store was opened.
+pub fn get(url: &str) -> Option<Validators> {
+    let store = STORE.get()?.lock().unwrap_or_else(|e| e.into_inner());
+    store.entries.get(url).cloned()
+}
+
+/// Record the validators upstream gave for a URL, or forget them if it gave none.
+pub fn record(url: &str, validators: Option<Validators>) -> io::Result<()> {
+    let Some(store) = STORE.get() else {
+        return Ok(());
+    };
+    let mut store = store.lock().unwrap_or_else(|e| e.into_inner());
+
+    let changed = match validators {
+        Some(validators) => {
+            store.entries.insert(url.to_string(), validators.clone()) != Some(validators)
+        }
+        None => store.entries.remove(url).is_some(),
+    };
+    if changed {
+        let data =
+            serde_json::to_vec_pretty(&store.entries).expect("validators should always serialize");
+        fs::write(&store.path, data)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    mod validators {
+        use reqwest::header::{
+            HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        };
+
+        use crate::validators::Validators;
+
+        #[test]
+        fn round_trip() {
+            let mut headers = HeaderMap::new();
+            headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+            headers.insert(
+                LAST_MODIFIED,
+                HeaderValue::from_static("Tue, 01 Aug 2023 00:00:00 GMT"),
+            );
+            let request = Validators::from_headers(&headers)
+                .unwrap()
+                .request_headers();
+            assert_eq!(request[IF_NONE_MATCH], "\"abc\"");
+            assert_eq!(request[IF_MODIFIED_SINCE], "Tue, 01 Aug 2023 00:00:00 GMT");
+        }
+
+        #[test]
+        fn etag_only() {
+            let mut headers = HeaderMap::new();
+            headers.insert(ETAG, HeaderValue::from_static("W/\"abc\""));
+            let request = Validators::from_headers(&headers)
+                .unwrap()
+                .request_headers();
+            assert_eq!(request[IF_NONE_MATCH], "W/\"abc\"");
+            assert!(!request.contains_key(IF_MODIFIED_SINCE));
+        }
+
+        #[test]
+        fn none() {
+            assert!(Validators::from_headers(&HeaderMap::new()).is_none());
+        }
+    }
+}
@@ -5,24 +5,22 @@ use std::{
     ops::RangeInclusive,
     path::{Path, PathBuf},
     str::FromStr,
-    time::Duration,
 };
 
 use console::style;
 use futures::StreamExt;
 use git2::Repository;
-use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
-use reqwest::Client;
-use warp::http::HeaderValue;
+use reqwest::header::HeaderMap;
 
 use crate::{
     crates::{
-        cargo_lock_to_mirror_entries, get_crate_path, sync_one_crate_entry,
+        cargo_lock_to_mirror_entries, has_crate_file, sync_one_crate_entry,
         vendor_path_to_mirror_entries, CrateEntry,
     },
-    download::DownloadError,
-    mirror::{default_user_agent, ConfigCrates, ConfigMirror, MirrorError},
-    progress_bar::padded_prefix_message,
+    download::{new_client, DownloadError},
+    mirror::{user_agent, ConfigCrates, ConfigMirror, MirrorError},
+    progress_bar::{padded_prefix_message, progress_bar},
+    throttle,
 };
 
 ///
@@ -162,23 +160,14 @@ pub(crate) async fn verify_mirror(
         "Comparing local crates.io and mirror coherence",
     );
 
-    let pb = ProgressBar::new_spinner()
-        .with_style(
-            ProgressStyle::default_bar()
-                .template("{prefix} {wide_bar} {spinner} [{elapsed_precise}]")
-                .expect("Something went wrong with the template.")
-                .progress_chars("  "),
-        )
-        .with_prefix(prefix)
-        .with_finish(ProgressFinish::AndLeave);
-    pb.enable_steady_tick(Duration::from_millis(10));
-
     // Getting diff tree from local crates.io repository.
     let repo = Repository::open(repo_path)?;
     let master = repo.find_reference("refs/heads/master")?;
     let master_tree = master.peel_to_tree()?;
     let diff = repo.diff_tree_to_tree(None, Some(&master_tree), None)?;
 
+    let pb = progress_bar(diff.deltas().len(), prefix);
+
     let mut missing_crates = Vec::new();
 
     let is_crate_whitelist_only = vendor_path.is_some() || cargo_lock_filepath.is_some();
@@ -189,6 +178,7 @@ pub(crate) async fn verify_mirror(
 
     diff.foreach(
         &mut |delta, _| {
+            pb.inc(1);
             let df = delta.new_file();
             let p = df.path().unwrap();
             if p == Path::new("config.json") {
@@ -225,15 +215,11 @@ pub(crate) async fn verify_mirror(
                     continue;
                 }
 
-                // Building crates local path.
-                let file_path =
-                    get_crate_path(&path, crate_entry.get_name(), crate_entry.get_vers()).unwrap();
-
                 // Checking if crate is missing.
                 if !CRATES_403
                     .iter()
                     .any(|it| it.0 == crate_entry.get_name() && it.1 == crate_entry.get_vers())
-                    && !file_path.exists()
+                    && !has_crate_file(&path, crate_entry.get_name(), crate_entry.get_vers())
                 {
                     missing_crates.push(crate_entry);
                 }
@@ -314,58 +300,25 @@ pub(crate) async fn handle_user_input(
 pub(crate) async fn fix_mirror(
     mirror_config: &ConfigMirror,
     crates_config: &ConfigCrates,
+    source_headers: Vec<(String, HeaderMap)>,
     path: PathBuf,
     crates_to_fetch: Vec<CrateEntry>,
     current_step: &mut usize,
     steps: usize,
 ) -> Result<(), MirrorError> {
     let prefix = padded_prefix_message(*current_step, steps, "Repairing mirror");
+    crate::crate_packs::init(crates_config);
 
-    let pb = ProgressBar::new(crates_to_fetch.len() as u64)
-        .with_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{prefix} {wide_bar} {pos}/{len} [{elapsed_precise} / {duration_precise}]",
-                )
-                .expect("Something went wrong with the template.")
-                .progress_chars("█▉▊▋▌▍▎▏  "),
-        )
-        .with_prefix(prefix)
-        .with_finish(ProgressFinish::AndLeave);
-    pb.enable_steady_tick(Duration::from_millis(10));
-
-    // Getting crates' source from config
-    let crates_source = if crates_config.source != "https://crates.io/api/v1/crates" {
-        Some(crates_config.source.as_str())
-    } else {
-        None
-    };
-
-    // Handle the contact information
-    let user_agent_str =
-        mirror_config
-            .contact
-            .as_ref()
-            .map_or_else(default_user_agent, |contact| {
-                if contact != "your@email.com" {
-                    format!("Panamax/{} ({})", env!("CARGO_PKG_VERSION"), contact)
-                } else {
-                    default_user_agent()
-                }
-            });
-
-    // Set the user agent with contact information.
-    let user_agent = match HeaderValue::from_str(&user_agent_str) {
-        Ok(h) => h,
-        Err(e) => {
-            eprintln!("Your contact information contains invalid characters!");
-            eprintln!("It's recommended to use a URL or email address as contact information.");
-            eprintln!("{e:?}");
-            return Ok(());
-        }
-    };
+    let pb = progress_bar(crates_to_fetch.len(), prefix);
+
+    let user_agent = user_agent(mirror_config, Some(crates_config))?;
+    throttle::set_limit(
+        crates_config
+            .bandwidth_limit
+            .or(mirror_config.bandwidth_limit),
+    );
 
-    let client = Client::new();
+    let client = new_client(mirror_config, source_headers, &user_agent)?;
 
     // This code is copied from `crates::sync_crates_files` and could be mutualised in a future commit.
     // For example in a function within module crates (e.g. `crates::build_and_run_tasks`)
@@ -375,7 +328,7 @@ pub(crate) async fn fix_mirror(
             let client = client.clone();
             let path = path.clone();
             let mirror_retries = mirror_config.retries;
-            let crates_source = crates_source.map(|s| s.to_string());
+            let crates_sources = crates_config.source.all().to_vec();
             let user_agent = user_agent.to_owned();
             let pb = pb.clone();
 
@@ -383,7 +336,7 @@ pub(crate) async fn fix_mirror(
                 let out = sync_one_crate_entry(
                     &client,
                     &path,
-                    crates_source.as_deref(),
+                    &crates_sources,
                     mirror_retries,
                     &c,
                     &user_agent,
@@ -399,8 +352,19 @@ pub(crate) async fn fix_mirror(
         .collect::<Vec<_>>()
         .await;
 
+    let mut failed = 0usize;
     for t in tasks {
-        let res = t.unwrap();
+        let res = match t {
+            Ok(res) => res,
+            Err(e) => {
+                // A panicking download shouldn't take the rest of the step down with it, but
+                // its crate is still missing.
+                eprintln!("Download task failed: {e}");
+                pb.inc(1);
+                failed += 1;
+                continue;
+            }
+        };
         match res {
             Ok(())
             | Err(DownloadError::NotFound {
@@ -415,12 +379,16 @@ pub(crate) async fn fix_mirror(
 
             Err(e) => {
                 eprintln!("Downloading failed: {e:?}");
+                failed += 1;
             }
         }
     }
 
     pb.finish_and_clear();
     *current_step += 1;
+    if failed > 0 {
+        return Err(MirrorError::RepairIncomplete(failed));
+    }
     Ok(())
 }
 
@@ -574,4 +542,66 @@ mod test {
             assert_eq!(expected_result, result);
         }
     }
+
+    mod fix_mirror {
+        use std::fs;
+
+        use crate::checksums::{self, CHECKSUMS_FILE};
+        use crate::crates::{crate_file, CrateEntry};
+        use crate::mirror::{Config, MirrorError};
+        use crate::verify::fix_mirror;
+
+        #[cfg(unix)]
+        #[tokio::test]
+        async fn changed_upstream_not_repaired() {
+            let dir = tempfile::tempdir().unwrap();
+            let (mirror, source) = (dir.path().join("mirror"), dir.path().join("source"));
+            fs::create_dir_all(&mirror).unwrap();
+            fs::create_dir_all(&source).unwrap();
+            let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+            for name in ["a", "b"] {
+                fs::write(source.join(format!("{name}-1.0.0.crate")), "abc").unwrap();
+            }
+            // `a` was first seen with other contents than upstream serves now.
+            let a_file = crate_file("a", "1.0.0").unwrap();
+            let first_seen = format!("{}  {a_file}\n", "0".repeat(64));
+            fs::write(mirror.join(CHECKSUMS_FILE), first_seen).unwrap();
+            checksums::open(&mirror);
+
+            let config: Config = toml_edit::easy::from_str(&format!(
+                r#"
+                [mirror]
+                retries = 0
+                [crates]
+                sync = true
+                download_threads = 1
+                source = "{}/{{crate}}-{{version}}.crate"
+                source_index = "https://github.com/rust-lang/crates.io-index"
+                "#,
+                source.display()
+            ))
+            .unwrap();
+            let missing = ["a", "b"]
+                .map(|name| {
+                    let entry = format!(r#"{{"name":"{name}","vers":"1.0.0","cksum":"{hash}"}}"#);
+                    serde_json::from_str::<CrateEntry>(&entry).unwrap()
+                })
+                .to_vec();
+
+            let fixed = fix_mirror(
+                &config.mirror,
+                config.crates.as_ref().unwrap(),
+                Vec::new(),
+                mirror.clone(),
+                missing,
+                &mut 1,
+                1,
+            )
+            .await;
+            assert!(matches!(fixed, Err(MirrorError::RepairIncomplete(1))));
+            assert!(!mirror.join(a_file).exists());
+            let b_file = crate_file("b", "1.0.0").unwrap();
+            assert_eq!(fs::read_to_string(mirror.join(b_file)).unwrap(), "abc");
+        }
+    }
 }